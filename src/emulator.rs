@@ -1,60 +1,174 @@
+use std::{thread, time};
+
 use self::csr::*;
 
-mod bus;
+pub mod bus;
+pub mod clint;
+mod compressed;
 mod csr;
-mod constants;
+pub mod constants;
+pub mod disk;
 mod dram;
-mod errors;
+pub mod elf;
+pub mod errors;
+mod fpu;
 mod instructions;
 mod interrupt;
+mod mmu;
 mod plic;
-mod uart;
+mod pmp;
+mod reader;
+pub mod uart;
+pub mod virtio;
 
+// Named like the privilege levels in the specification
 type Mode = u64;
+#[allow(non_upper_case_globals)]
 const User: Mode = 0; // 0b00
+#[allow(non_upper_case_globals)]
 const Supervisor: Mode = 1; // 0b01
+#[allow(non_upper_case_globals)]
 const Machine: Mode = 3; // 0b11
 
 pub struct Cpu {
     pub regs: [u64; 32],
+    // Floating-point registers, holding single precision values NaN-boxed
+    pub fregs: [u64; 32],
     pub pc: u64,
+    // Size in bytes of the instruction being executed, 2 for compressed instructions
+    pub inst_len: u64,
     pub bus: bus::Bus,
     pub csr: csr::Csr,
     pub mode: Mode,
+    // Address reserved by the last LR, which a following SC needs to succeed
+    pub reservation: Option<u64>,
+    // End of the image loaded into DRAM. run() returns once the pc leaves the image
+    pub image_end: u64,
+    // Set by WFI, stalling the hart until an interrupt is pending
+    pub waiting_for_interrupt: bool,
+    // Cached address translations, along with hit/miss counters
+    pub tlb: mmu::Tlb,
+    // The SEIP bit of mip as last written by software, which the PLIC's SEIP is ORed into
+    pub software_seip: bool,
 }
 
 impl Cpu {
 
     pub fn new(code: Vec<u8>) -> Self {
+        return Self::with_reset_vector(code, constants::RESET_VECTOR);
+    }
+
+    // Places the image at the start of DRAM and starts executing at reset_vector
+    pub fn with_reset_vector(code: Vec<u8>, reset_vector: u64) -> Self {
+        return Self::with_time_source(code, reset_vector, clint::TimeSource::Instructions);
+    }
+
+    // Like with_reset_vector, choosing what drives the CLINT's mtime
+    pub fn with_time_source(code: Vec<u8>, reset_vector: u64, time_source: clint::TimeSource) -> Self {
+        return Self::with_devices(code, reset_vector, time_source, Box::new(uart::backends::StdioBackend));
+    }
+
+    // Like with_time_source, also choosing where the UART's character stream goes
+    pub fn with_devices(code: Vec<u8>, reset_vector: u64, time_source: clint::TimeSource,
+            uart_backend: Box<dyn uart::backends::UartBackend>) -> Self {
+        let image_end = constants::DRAM_BASE + code.len() as u64;
         let dram = dram::Dram::new(code);
-        let bus = bus::Bus::new(dram);
+        let bus = bus::Bus::with_devices(dram, time_source, uart_backend);
         let csr = csr::Csr::new();
         let mode = Machine;
         let mut cpu = Self { 
             regs: [0; 32], 
-            pc: 0, 
+            fregs: [0; 32],
+            pc: reset_vector, 
+            inst_len: 4,
             bus: bus,
             csr: csr,
             mode: mode,
+            reservation: None,
+            image_end: image_end,
+            waiting_for_interrupt: false,
+            tlb: mmu::Tlb::new(),
+            software_seip: false,
         };
 
-        let MEMORY_SIZE = 1024*1024*128; // Define 10MiB memory
-        cpu.regs[2] = MEMORY_SIZE; // Set stack pointer to end of memory (because it expands upwards)
+        cpu.regs[2] = constants::DRAM_END + 1; // Set stack pointer to end of memory (because it grows downwards)
         cpu.regs[0] = 0;  // Set zero register to 0s
 
+        // There is no firmware to switch the FPU on before running the program, so start it in
+        // the Initial state rather than Off
+        cpu.csr.store(csr::MSTATUS, csr::FS_INITIAL << 13);
+        cpu.csr.store(csr::MISA, csr::MISA_MXL_64 | csr::MISA_I | csr::MISA_M | csr::MISA_A | csr::MISA_F
+            | csr::MISA_D | csr::MISA_C | csr::MISA_S | csr::MISA_U);
+
         return cpu;
     }
 
+    // Loads every PT_LOAD segment at its physical address, with BSS zero-filled, and starts
+    // executing at the entry point
+    pub fn from_elf(elf: &elf::Elf) -> Result<Self, elf::ElfError> {
+        let mut cpu = Self::with_reset_vector(Vec::new(), elf.entry);
+        cpu.load_elf(elf)?;
+        return Ok(cpu);
+    }
+
+    // Loads the ELF's segments into memory and starts executing at its entry point
+    pub fn load_elf(&mut self, elf: &elf::Elf) -> Result<(), elf::ElfError> {
+        for segment in elf.segments.iter() {
+            // Check the range before allocating the zero-filled part, which may be huge
+            let end = segment.paddr.checked_add(segment.mem_size);
+            if (segment.mem_size != 0 && (segment.paddr < constants::DRAM_BASE || end.is_none_or(|end| end > constants::DRAM_BASE + constants::DRAM_SIZE))) {
+                return Err(elf::ElfError::SegmentOutOfRange(segment.paddr));
+            }
+            let mut data = segment.data.clone();
+            data.resize(segment.mem_size as usize, 0);
+            if (self.bus.write_bytes(segment.paddr, &data).is_err()) {
+                return Err(elf::ElfError::SegmentOutOfRange(segment.paddr));
+            }
+        }
+        self.pc = elf.entry;
+        self.image_end = elf.image_end();
+        return Ok(());
+    }
+
     pub fn run(&mut self) {
-        while (self.pc < self.bus.dram.dram.len() as u64) {
+        while ((constants::DRAM_BASE..self.image_end).contains(&self.pc)) {
+            match(self.bus.device_mut::<uart::Uart>().and_then(|uart| uart.command())) {
+                Some(uart::console::ConsoleCommand::Quit) => return,
+                Some(uart::console::ConsoleCommand::Monitor(line)) if (!self.monitor(&line)) => return,
+                _ => {},
+            }
+            if (self.waiting_for_interrupt) {
+                self.update_device_interrupts();
+                // Any interrupt pending in mie wakes the hart up, even if it is disabled globally
+                // and thus resumes execution rather than trapping
+                if let Some(interrupt) = self.check_pending_interrupt() {
+                    self.waiting_for_interrupt = false;
+                    self.handle_interrupt(interrupt);
+                } else if (self.csr.load(csr::MIE) & self.csr.load(csr::MIP) != 0) {
+                    self.waiting_for_interrupt = false;
+                } else if (self.bus.device::<clint::Clint>().is_some_and(|clint| clint.source() == clint::TimeSource::Instructions)) {
+                    // Time only moves on while instructions retire, so count the idle hart as one
+                    self.bus.tick();
+                } else {
+                    thread::sleep(time::Duration::from_millis(1));
+                }
+                continue;
+            }
+
             let instr = match self.fetch() {
-                Ok(instr) => instr as u32,
+                Ok(instr) => instr,
+                Err(e) => {
+                    self.handle_error(e);
+                    continue;
+                }
+            };
+            let instr_decoded = match self.decode(instr) {
+                Ok(instr_decoded) => instr_decoded,
                 Err(e) => {
                     self.handle_error(e);
                     continue;
                 }
             };
-            let instr_decoded = self.decode(instr);
             let new_pc = match self.execute(instr_decoded) {
                 Ok(pc) => pc,
                 Err(e) => {
@@ -63,36 +177,66 @@ impl Cpu {
                 }
             };
             self.pc = new_pc;
+            self.regs[0] = 0; // x0 is hardwired to zero, so discard anything written to it
+            self.bus.tick();
+            self.update_device_interrupts();
 
-            match self.check_pending_interrupt() {
-                Some(interrupt) => self.handle_interrupt(interrupt),
-                None => ()
+            if let Some(interrupt) = self.check_pending_interrupt() {
+                self.handle_interrupt(interrupt);
             }
         }
     }
 
-    fn fetch(&self) -> Result<u64, errors::Exception> {
-        // Read 32 bit instruction from memory
-        let index = self.pc as usize;
-        let instr = self.bus.load((index as u64), 32);
-        //let instr = (self.dram[index+3] as u32) | ((self.dram[index + 2] as u32) << 8) | ((self.dram[index + 1] as u32) << 16) | ((self.dram[index+0] as u32) << 24);
-        
-        return instr;
+    fn fetch(&mut self) -> Result<u32, errors::Exception> {
+        // Instructions come in 16 bit parcels. Read the first one to find out whether this is a
+        // compressed instruction, and only then read the upper half of a 32 bit one
+        let parcel = self.fetch_parcel(self.pc)?;
+        if (compressed::is_compressed(parcel)) {
+            if (!self.compressed_enabled()) {
+                return Err(errors::Exception::IllegalInstruction(parcel as u64));
+            }
+            self.inst_len = 2;
+            return compressed::expand(parcel as u16);
+        }
+
+        let upper = self.fetch_parcel(self.pc.wrapping_add(2))?;
+        self.inst_len = 4;
+        return Ok((upper << 16) | parcel);
     }
 
-    fn decode(&self, inst: u32) -> instructions::R_Instr {
-        println!("Decoding inst {:b}", inst);
-        // Decode instruction
-        let op: u32 = inst & 0x7f; // Note: 0x7f -> 0b0111_1111 , which acts as a mask
-        let rd = ((inst >> 7) & 0x1f) as usize;
-        let funct3 = ((inst >> 12) & 0x7) as usize;
-        let rs1 = ((inst >> 15) & 0x1f) as usize;
-        let rs2 = ((inst >> 20) & 0x1f) as usize;
-        let funct7 = ((inst >> 25) & 0x7f) as usize;
-        let instr_decoded = instructions::R_Instr { opcode: op, rd: rd, funct3: funct3, rs1: rs1, rs2: rs2, funct7: funct7 };
+    // Each parcel is translated on its own, since a 32 bit instruction may straddle two pages
+    fn fetch_parcel(&mut self, addr: u64) -> Result<u32, errors::Exception> {
+        let paddr = self.physical_address(addr, 16, mmu::AccessType::Instruction)?;
+        return Ok(self.bus.load(paddr, 16).map_err(|_| errors::Exception::InstructionAccessFault(addr))? as u32);
+    }
+
+    fn compressed_enabled(&self) -> bool {
+        return self.csr.load(csr::MISA) & csr::MISA_C != 0;
+    }
 
-        println!("Decoded as {:?}", instr_decoded);
-        return instr_decoded;
+    fn decode(&self, inst: u32) -> Result<instructions::Instruction, errors::Exception> {
+        return instructions::Instruction::decode(inst);
+    }
+
+    // Runs a command typed at the console's monitor, returning false to stop the emulator
+    fn monitor(&mut self, line: &str) -> bool {
+        let output = match(line) {
+            "q" | "quit" => return false,
+            "regs" | "info registers" => {
+                let mut output = format!("pc  {:#018x}  mode {}\n", self.pc, self.mode);
+                for i in (0..self.regs.len()) {
+                    output += &format!("x{:<2} {:#018x}{}", i, self.regs[i], if (i % 4 == 3) { "\n" } else { "  " });
+                }
+                output
+            },
+            "help" => "regs   print the registers\nquit   exit the emulator\n".to_string(),
+            _ => format!("unknown command: {}\n", line),
+        };
+        if let Some(uart) = self.bus.device_mut::<uart::Uart>() {
+            uart.print(&output);
+            uart.print(uart::console::MONITOR_PROMPT);
+        }
+        return true;
     }
 
     pub fn dump_registers(&self) {
@@ -102,18 +246,22 @@ impl Cpu {
     }
 
     pub fn handle_interrupt(&mut self, interrupt: interrupt::Interrupt) {
+        // Taking a trap is a context switch, so any LR/SC sequence in flight must fail
+        self.reservation = None;
         let pc = self.pc;
         let mode = self.mode;
         let cause = interrupt.code();
 
-        let delegate_to_s_mode = mode != Machine && (self.csr.load(csr::MIDELEG) & (1 << cause) > 0);
+        let index = cause & !interrupt::MASK_INTERRUPT_BIT;
+        let delegate_to_s_mode = mode != Machine && (self.csr.load(csr::MIDELEG) & (1 << index) > 0);
         if (delegate_to_s_mode) {
+            self.mode = Supervisor;
             // Determine whether CPU is setup to use vectorized or bare interrupt handling
             let tvec = self.csr.load(csr::STVEC);
             if (tvec & 0b11 == 0) {
                 self.pc = tvec & !0b11;
             } else {
-                self.pc = tvec & !0b11 + cause << 2;
+                self.pc = (tvec & !0b11) + (index << 2);
             }
 
             // Store state before interrupt to restore later
@@ -123,16 +271,18 @@ impl Cpu {
 
             // Update state to handle interrupt
             let mut status = self.csr.load(csr::SSTATUS);
-            status |= csr::MASK_SPIE; // If we're handing an interrupt, we can assume SIE=1
+            let ie = (status & csr::MASK_SIE) >> 1;
+            status = (status & !csr::MASK_SPIE) | (ie << 5);
             status &= !csr::MASK_SIE;
             status = (status & !csr::MASK_SPP) | mode << 8;
             self.csr.store(csr::SSTATUS, status);
         } else {
-           let tvec = self.csr.load(csr::MTVEC);
+            self.mode = Machine;
+            let tvec = self.csr.load(csr::MTVEC);
             if (tvec & 0b11 == 0) {
                 self.pc = tvec & !0b11;
             } else {
-                self.pc = tvec & !0b11 + cause << 2;
+                self.pc = (tvec & !0b11) + (index << 2);
             }
 
             // Store state before interrupt to restore later
@@ -142,7 +292,8 @@ impl Cpu {
 
             // Update state to handle interrupt
             let mut status = self.csr.load(csr::MSTATUS);
-            status |= csr::MASK_MPIE; // If we're handing an interrupt, we can assume MIE=1
+            let ie = (status & csr::MASK_MIE) >> 3;
+            status = (status & !csr::MASK_MPIE) | (ie << 7);
             status &= !csr::MASK_MIE;
             status = (status & !csr::MASK_MPP) | mode << 11;
             self.csr.store(csr::MSTATUS, status);
@@ -157,6 +308,7 @@ impl Cpu {
 
         let mode = self.mode;
         let pc = self.pc;
+        self.reservation = None;
 
         // Update privilege level
         // - Check level's medeleg to see if should be s or m
@@ -168,16 +320,18 @@ impl Cpu {
             self.mode = Supervisor;
             // Save PC
             self.csr.store(csr::SEPC, pc);
-            // Update PC to trap handler
-            self.pc = self.csr.load(csr::STVEC);
+            // Update PC to trap handler. Exceptions always go to the base address, even in
+            // vectored mode
+            self.pc = self.csr.load(csr::STVEC) & !0b11;
 
             self.csr.store(csr::SCAUSE, error.code());
             self.csr.store(csr::STVAL, error.value());
         
             let mut status = self.csr.load(csr::SSTATUS);
-            let ie = status & csr::MASK_SIE >> 1;
+            let ie = (status & csr::MASK_SIE) >> 1;
             // First, we clear the flag bit, then we set it to new value
-            status = status & !csr::MASK_SPIE | (ie << 1);
+            status = (status & !csr::MASK_SPIE) | (ie << 5);
+            status &= !csr::MASK_SIE;
             let spp = mode;
             status = status & !csr::MASK_SPP | (spp << 8);
             self.csr.store(csr::SSTATUS, status);
@@ -187,38 +341,45 @@ impl Cpu {
             // Save PC
             self.csr.store(csr::MEPC, pc);
             // Update PC to trap handler
-            self.pc = self.csr.load(csr::MTVEC);
+            self.pc = self.csr.load(csr::MTVEC) & !0b11;
             
             self.csr.store(csr::MCAUSE, error.code());
             self.csr.store(csr::MTVAL, error.value());
             
             let mut status = self.csr.load(csr::MSTATUS);
-            let ie = status & csr::MASK_MIE >> 3;
-            status = status & !csr::MASK_MPIE | (ie << 3);
+            let ie = (status & csr::MASK_MIE) >> 3;
+            status = (status & !csr::MASK_MPIE) | (ie << 7);
+            status &= !csr::MASK_MIE;
             let mpp = mode;
             status = status & !csr::MASK_MPP | (mpp << 11);
             self.csr.store(csr::MSTATUS, status);
         }
     }
 
-    pub fn check_pending_interrupt(&mut self) -> Option<interrupt::Interrupt> {
-        // If MachineMode and machine interrupts disabled, ignore pending interrupts
-        if (self.mode == Machine && (self.csr.load(MSTATUS) & MASK_MIE) == 0) {
-            return None;
-        }
+    // MSIP and MTIP in mip follow the CLINT's msip and mtimecmp registers, MEIP and SEIP the PLIC
+    // contexts of this hart, which in turn follow the devices' interrupt lines. SEIP is also set
+    // for as long as software has set it
+    pub fn update_device_interrupts(&mut self) {
+        let mip = self.csr.load(MIP) & !(MASK_MSIP | MASK_MTIP | MASK_MEIP | MASK_SEIP);
+        let software_seip = if (self.software_seip) { MASK_SEIP } else { 0 };
+        self.csr.store(MIP, mip | software_seip | self.bus.update_interrupts());
+    }
 
-        // If Supervisor mode and supervisor interrupts disabled, ignore pending interrupts
-        if ((self.mode == Supervisor) &&  (self.csr.load(MSTATUS) & MASK_SIE) == 0) {
-            return None;
+    pub fn check_pending_interrupt(&mut self) -> Option<interrupt::Interrupt> {
+        // Interrupts that stay in M are taken below M, or in M when mstatus.MIE is set. Delegated
+        // ones go to S and are taken below S, or in S when mstatus.SIE is set, but never in M
+        let status = self.csr.load(MSTATUS);
+        let mideleg = self.csr.load(MIDELEG);
+        let mut enabled = 0;
+        if (self.mode != Machine || status & MASK_MIE != 0) {
+            enabled |= !mideleg;
         }
-
-        if (self.bus.uart.is_interrupting()) {
-            self.bus.store(plic::PLIC_CLAIM_ADDR, 32, uart::UART_IRQ).unwrap();
-            self.csr.store(csr::MIP, self.csr.load(csr::MIP) | !csr::MASK_SEIP);
+        if (self.mode == User || (self.mode == Supervisor && status & MASK_SIE != 0)) {
+            enabled |= mideleg;
         }
 
         // Load a list of interrupts that are both enabled and pending
-        let pending = self.csr.load(MIE) & self.csr.load(MIP);
+        let pending = self.csr.load(MIE) & self.csr.load(MIP) & enabled;
 
         if (pending & MASK_MEIP) != 0 {
             self.csr.store(csr::MIP, self.csr.load(csr::MIP) & !MASK_MEIP);
@@ -253,6 +414,7 @@ impl Cpu {
         return None;
     }
 
+    #[allow(dead_code)]
     fn get_status_flag(status: &u64, flag_index: u64) -> bool {
         let flag_mask = (1 << flag_index);
         return ((status & flag_mask) > 0);
@@ -267,124 +429,712 @@ impl Cpu {
         status |= (val << flag_index);
     }*/
 
-    fn execute(&mut self, inst: instructions::R_Instr) -> Result<u64, errors::Exception> {
+    #[allow(non_upper_case_globals)]
+    fn execute(&mut self, inst: instructions::Instruction) -> Result<u64, errors::Exception> {
+        use instructions::Instruction::*;
+
         // Execute instruction 
-        match(inst.opcode) {
-            0x33 => {
-                match (inst.funct3) {
-                    0x0 => {
-                        match (inst.funct7) {
-                            0x0 => { // ADD
-                                self.regs[inst.rd] = self.regs[inst.rs1] + self.regs[inst.rs2];
-                            },
-                            _ => { // SUB
-                                self.regs[inst.rd] = self.regs[inst.rs2] - self.regs[inst.rs1];
-                            },
-                        }
-                    }
-                    _ => {}
+        match(inst) {
+            Lui { rd, imm } => { // LUI (load 12-31 bits into register)
+                self.regs[rd] = imm as u64;
+            }
+            Auipc { rd, imm } => { // AUIPC (add upper immediate to pc)
+                self.regs[rd] = self.pc.wrapping_add(imm as u64);
+            }
+            Jal { rd, imm } => {
+                let new_pc = self.jump_target(self.pc.wrapping_add(imm as u64))?;
+                self.regs[rd] = self.pc.wrapping_add(self.inst_len);
+                return Ok(new_pc);
+            }
+            Jalr { rd, rs1, imm } => {
+                // Compute the target before writing rd, in case rd and rs1 are the same register
+                let target = self.regs[rs1].wrapping_add(imm as u64) & !1;
+                let new_pc = self.jump_target(target)?;
+                self.regs[rd] = self.pc.wrapping_add(self.inst_len);
+                return Ok(new_pc);
+            }
+            Beq { rs1, rs2, imm } => return self.branch(self.regs[rs1] == self.regs[rs2], imm),
+            Bne { rs1, rs2, imm } => return self.branch(self.regs[rs1] != self.regs[rs2], imm),
+            Blt { rs1, rs2, imm } => return self.branch((self.regs[rs1] as i64) < (self.regs[rs2] as i64), imm),
+            Bge { rs1, rs2, imm } => return self.branch((self.regs[rs1] as i64) >= (self.regs[rs2] as i64), imm),
+            Bltu { rs1, rs2, imm } => return self.branch(self.regs[rs1] < self.regs[rs2], imm),
+            Bgeu { rs1, rs2, imm } => return self.branch(self.regs[rs1] >= self.regs[rs2], imm),
+            Lb { rd, rs1, imm } => {
+                self.regs[rd] = self.load(self.regs[rs1].wrapping_add(imm as u64), 8)? as i8 as i64 as u64;
+            }
+            Lh { rd, rs1, imm } => {
+                self.regs[rd] = self.load(self.regs[rs1].wrapping_add(imm as u64), 16)? as i16 as i64 as u64;
+            }
+            Lw { rd, rs1, imm } => {
+                self.regs[rd] = self.load(self.regs[rs1].wrapping_add(imm as u64), 32)? as i32 as i64 as u64;
+            }
+            Ld { rd, rs1, imm } => {
+                self.regs[rd] = self.load(self.regs[rs1].wrapping_add(imm as u64), 64)?;
+            }
+            Lbu { rd, rs1, imm } => {
+                self.regs[rd] = self.load(self.regs[rs1].wrapping_add(imm as u64), 8)?;
+            }
+            Lhu { rd, rs1, imm } => {
+                self.regs[rd] = self.load(self.regs[rs1].wrapping_add(imm as u64), 16)?;
+            }
+            Lwu { rd, rs1, imm } => {
+                self.regs[rd] = self.load(self.regs[rs1].wrapping_add(imm as u64), 32)?;
+            }
+            Sb { rs1, rs2, imm } => self.store(self.regs[rs1].wrapping_add(imm as u64), 8, self.regs[rs2])?,
+            Sh { rs1, rs2, imm } => self.store(self.regs[rs1].wrapping_add(imm as u64), 16, self.regs[rs2])?,
+            Sw { rs1, rs2, imm } => self.store(self.regs[rs1].wrapping_add(imm as u64), 32, self.regs[rs2])?,
+            Sd { rs1, rs2, imm } => self.store(self.regs[rs1].wrapping_add(imm as u64), 64, self.regs[rs2])?,
+            Addi { rd, rs1, imm } => {
+                self.regs[rd] = self.regs[rs1].wrapping_add(imm as u64);
+            }
+            Slti { rd, rs1, imm } => { // SLTI (set less than immediate)
+                self.regs[rd] = ((self.regs[rs1] as i64) < imm) as u64;
+            }
+            Sltiu { rd, rs1, imm } => {
+                self.regs[rd] = (self.regs[rs1] < imm as u64) as u64;
+            }
+            Xori { rd, rs1, imm } => {
+                self.regs[rd] = self.regs[rs1] ^ imm as u64;
+            }
+            Ori { rd, rs1, imm } => {
+                self.regs[rd] = self.regs[rs1] | imm as u64;
+            }
+            Andi { rd, rs1, imm } => {
+                self.regs[rd] = self.regs[rs1] & imm as u64;
+            }
+            Slli { rd, rs1, shamt } => {
+                self.regs[rd] = self.regs[rs1] << shamt;
+            }
+            Srli { rd, rs1, shamt } => { // SRLI (Logical shift right)
+                self.regs[rd] = self.regs[rs1] >> shamt;
+            }
+            Srai { rd, rs1, shamt } => { // SRAI (Arithmetic shift right)
+                self.regs[rd] = ((self.regs[rs1] as i64) >> shamt) as u64;
+            }
+            Add { rd, rs1, rs2 } => self.regs[rd] = self.regs[rs1].wrapping_add(self.regs[rs2]),
+            Sub { rd, rs1, rs2 } => self.regs[rd] = self.regs[rs1].wrapping_sub(self.regs[rs2]),
+            // Register shifts only use the low 6 bits of rs2
+            Sll { rd, rs1, rs2 } => self.regs[rd] = self.regs[rs1] << (self.regs[rs2] & 0x3f),
+            Slt { rd, rs1, rs2 } => self.regs[rd] = ((self.regs[rs1] as i64) < (self.regs[rs2] as i64)) as u64,
+            Sltu { rd, rs1, rs2 } => self.regs[rd] = (self.regs[rs1] < self.regs[rs2]) as u64,
+            Xor { rd, rs1, rs2 } => self.regs[rd] = self.regs[rs1] ^ self.regs[rs2],
+            Srl { rd, rs1, rs2 } => self.regs[rd] = self.regs[rs1] >> (self.regs[rs2] & 0x3f),
+            Sra { rd, rs1, rs2 } => self.regs[rd] = ((self.regs[rs1] as i64) >> (self.regs[rs2] & 0x3f)) as u64,
+            Or { rd, rs1, rs2 } => self.regs[rd] = self.regs[rs1] | self.regs[rs2],
+            And { rd, rs1, rs2 } => self.regs[rd] = self.regs[rs1] & self.regs[rs2],
+            // Word operations compute on the low 32 bits and sign-extend the result to 64 bits.
+            // Word shifts only use the low 5 bits of rs2
+            Addiw { rd, rs1, imm } => {
+                self.regs[rd] = (self.regs[rs1] as i32).wrapping_add(imm as i32) as i64 as u64;
+            }
+            Slliw { rd, rs1, shamt } => {
+                self.regs[rd] = ((self.regs[rs1] as u32) << shamt) as i32 as i64 as u64;
+            }
+            Srliw { rd, rs1, shamt } => {
+                self.regs[rd] = ((self.regs[rs1] as u32) >> shamt) as i32 as i64 as u64;
+            }
+            Sraiw { rd, rs1, shamt } => {
+                self.regs[rd] = ((self.regs[rs1] as i32) >> shamt) as i64 as u64;
+            }
+            Addw { rd, rs1, rs2 } => {
+                self.regs[rd] = (self.regs[rs1] as i32).wrapping_add(self.regs[rs2] as i32) as i64 as u64;
+            }
+            Subw { rd, rs1, rs2 } => {
+                self.regs[rd] = (self.regs[rs1] as i32).wrapping_sub(self.regs[rs2] as i32) as i64 as u64;
+            }
+            Sllw { rd, rs1, rs2 } => {
+                self.regs[rd] = ((self.regs[rs1] as u32) << (self.regs[rs2] & 0x1f)) as i32 as i64 as u64;
+            }
+            Srlw { rd, rs1, rs2 } => {
+                self.regs[rd] = ((self.regs[rs1] as u32) >> (self.regs[rs2] & 0x1f)) as i32 as i64 as u64;
+            }
+            Sraw { rd, rs1, rs2 } => {
+                self.regs[rd] = ((self.regs[rs1] as i32) >> (self.regs[rs2] & 0x1f)) as i64 as u64;
+            }
+            // M extension. Division never traps: division by zero and signed overflow produce the
+            // results fixed by the spec (wrapping_div/wrapping_rem already give the overflow ones)
+            Mul { rd, rs1, rs2 } => self.regs[rd] = self.regs[rs1].wrapping_mul(self.regs[rs2]),
+            Mulh { rd, rs1, rs2 } => {
+                let product = (self.regs[rs1] as i64 as i128) * (self.regs[rs2] as i64 as i128);
+                self.regs[rd] = (product >> 64) as u64;
+            }
+            Mulhsu { rd, rs1, rs2 } => {
+                let product = (self.regs[rs1] as i64 as i128).wrapping_mul(self.regs[rs2] as i128);
+                self.regs[rd] = (product >> 64) as u64;
+            }
+            Mulhu { rd, rs1, rs2 } => {
+                let product = (self.regs[rs1] as u128) * (self.regs[rs2] as u128);
+                self.regs[rd] = (product >> 64) as u64;
+            }
+            Div { rd, rs1, rs2 } => {
+                let (dividend, divisor) = (self.regs[rs1] as i64, self.regs[rs2] as i64);
+                self.regs[rd] = match(divisor) {
+                    0 => u64::MAX,
+                    _ => dividend.wrapping_div(divisor) as u64,
+                };
+            }
+            Divu { rd, rs1, rs2 } => {
+                self.regs[rd] = match(self.regs[rs2]) {
+                    0 => u64::MAX,
+                    divisor => self.regs[rs1] / divisor,
+                };
+            }
+            Rem { rd, rs1, rs2 } => {
+                let (dividend, divisor) = (self.regs[rs1] as i64, self.regs[rs2] as i64);
+                self.regs[rd] = match(divisor) {
+                    0 => dividend as u64,
+                    _ => dividend.wrapping_rem(divisor) as u64,
+                };
+            }
+            Remu { rd, rs1, rs2 } => {
+                self.regs[rd] = match(self.regs[rs2]) {
+                    0 => self.regs[rs1],
+                    divisor => self.regs[rs1] % divisor,
+                };
+            }
+            Mulw { rd, rs1, rs2 } => {
+                self.regs[rd] = (self.regs[rs1] as i32).wrapping_mul(self.regs[rs2] as i32) as i64 as u64;
+            }
+            Divw { rd, rs1, rs2 } => {
+                let (dividend, divisor) = (self.regs[rs1] as i32, self.regs[rs2] as i32);
+                self.regs[rd] = match(divisor) {
+                    0 => u64::MAX,
+                    _ => dividend.wrapping_div(divisor) as i64 as u64,
+                };
+            }
+            Divuw { rd, rs1, rs2 } => {
+                let (dividend, divisor) = (self.regs[rs1] as u32, self.regs[rs2] as u32);
+                self.regs[rd] = match(divisor) {
+                    0 => u64::MAX,
+                    _ => (dividend / divisor) as i32 as i64 as u64,
+                };
+            }
+            Remw { rd, rs1, rs2 } => {
+                let (dividend, divisor) = (self.regs[rs1] as i32, self.regs[rs2] as i32);
+                self.regs[rd] = match(divisor) {
+                    0 => dividend as i64 as u64,
+                    _ => dividend.wrapping_rem(divisor) as i64 as u64,
+                };
+            }
+            Remuw { rd, rs1, rs2 } => {
+                let (dividend, divisor) = (self.regs[rs1] as u32, self.regs[rs2] as u32);
+                self.regs[rd] = match(divisor) {
+                    0 => dividend as i32 as i64 as u64,
+                    _ => (dividend % divisor) as i32 as i64 as u64,
+                };
+            }
+            // A extension
+            LrW { rd, rs1 } => self.load_reserved(rd, rs1, 32)?,
+            ScW { rd, rs1, rs2 } => self.store_conditional(rd, rs1, rs2, 32)?,
+            AmoswapW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 32, |_, src| src)?,
+            AmoaddW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 32, |mem, src| mem.wrapping_add(src))?,
+            AmoxorW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 32, |mem, src| mem ^ src)?,
+            AmoandW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 32, |mem, src| mem & src)?,
+            AmoorW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 32, |mem, src| mem | src)?,
+            AmominW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 32, |mem, src| (mem as i64).min(src as i64) as u64)?,
+            AmomaxW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 32, |mem, src| (mem as i64).max(src as i64) as u64)?,
+            AmominuW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 32, |mem, src| mem.min(src))?,
+            AmomaxuW { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 32, |mem, src| mem.max(src))?,
+            LrD { rd, rs1 } => self.load_reserved(rd, rs1, 64)?,
+            ScD { rd, rs1, rs2 } => self.store_conditional(rd, rs1, rs2, 64)?,
+            AmoswapD { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 64, |_, src| src)?,
+            AmoaddD { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 64, |mem, src| mem.wrapping_add(src))?,
+            AmoxorD { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 64, |mem, src| mem ^ src)?,
+            AmoandD { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 64, |mem, src| mem & src)?,
+            AmoorD { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 64, |mem, src| mem | src)?,
+            AmominD { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 64, |mem, src| (mem as i64).min(src as i64) as u64)?,
+            AmomaxD { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 64, |mem, src| (mem as i64).max(src as i64) as u64)?,
+            AmominuD { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 64, |mem, src| mem.min(src))?,
+            AmomaxuD { rd, rs1, rs2 } => self.amo(rd, rs1, rs2, 64, |mem, src| mem.max(src))?,
+            Fp(inst) => return self.execute_fp(inst),
+            Fence => {
+                // Memory accesses are performed in program order on a single hart, so a fence
+                // has nothing to wait for
+            }
+            // CSRRW doesn't read the CSR when rd is x0, and CSRRS/CSRRC don't write it when rs1 is
+            // x0 (or the immediate is 0), so neither triggers the side effects of that access
+            Csrrw { rd, rs1, csr } => self.csr_access(rd, csr, rd != 0, true, self.regs[rs1], |_, value| value)?,
+            Csrrs { rd, rs1, csr } => self.csr_access(rd, csr, true, rs1 != 0, self.regs[rs1], |old, value| old | value)?,
+            Csrrc { rd, rs1, csr } => self.csr_access(rd, csr, true, rs1 != 0, self.regs[rs1], |old, value| old & !value)?,
+            Csrrwi { rd, uimm, csr } => self.csr_access(rd, csr, rd != 0, true, uimm, |_, value| value)?,
+            Csrrsi { rd, uimm, csr } => self.csr_access(rd, csr, true, uimm != 0, uimm, |old, value| old | value)?,
+            Csrrci { rd, uimm, csr } => self.csr_access(rd, csr, true, uimm != 0, uimm, |old, value| old & !value)?,
+            Ecall => {
+                // tval is left zero for environment calls
+                return match(self.mode) {
+                    User => Err(errors::Exception::EnvironmentCallFromUMode(0)),
+                    Supervisor => Err(errors::Exception::EnvironmentCallFromSMode(0)),
+                    _ => Err(errors::Exception::EnvironmentCallFromMMode(0)),
+                };
+            }
+            Ebreak => return Err(errors::Exception::Breakpoint(self.pc)),
+            Sret => {
+                // mstatus.TSR traps SRET in S-mode, so M-mode can emulate it
+                let tsr = self.csr.load(csr::MSTATUS) & csr::MASK_TSR != 0;
+                if (self.mode == User || (self.mode == Supervisor && tsr)) {
+                    return Err(errors::Exception::IllegalInstruction(0));
                 }
+
+                // Return to the privilege in SPP, restoring SIE from SPIE, then set SPIE and
+                // reset SPP to the least privileged mode. SPP is never Machine, so MPRV is cleared
+                let mut status = self.csr.load(csr::MSTATUS);
+                self.mode = (status & csr::MASK_SPP) >> 8;
+                let spie = (status & csr::MASK_SPIE) >> 5;
+                status = (status & !csr::MASK_SIE) | (spie << 1);
+                status |= csr::MASK_SPIE;
+                status &= !csr::MASK_SPP;
+                status &= !csr::MASK_MPRV;
+                self.csr.store(csr::MSTATUS, status);
+
+                return Ok(self.trap_return_target(csr::SEPC));
             }
-            0x13 => {
-                let imm = ((inst.funct7 as u64) << 5) | inst.rs2 as u64;
-                match(inst.funct3) {
-                    0x0 => { // ADDI
-                        // TODO: Worry about overflow from addition
-                        self.regs[inst.rd] = imm + self.regs[inst.rs1];
-                        return Ok(self.pc + 4);
-                    },
-                    0x1 => { // SLLI
-                        let shift = inst.rs2;
-                        self.regs[inst.rd] = self.regs[inst.rs1] << shift;
-                    },
-                    0x2 => { // SLTI (set less than immediate)
-                        let result = ((self.regs[inst.rs1] as i64) < imm as i64);
-                        self.regs[inst.rd] = result as u64;
-                    },
-                    0x3 => { // STLIU
-                        let result = ((self.regs[inst.rs1] as u64) < imm);
-                        self.regs[inst.rd] = result as u64;
-                    },
-                    0x4 => { // XORI
-                        self.regs[inst.rd] = self.regs[inst.rs1] ^ imm;
-                    },
-                    0x5 => {
-                        match(inst.funct7) {
-                            0x0 => { // SRLI (Logical shift right)
-                                let shift = inst.rs2;
-                                self.regs[inst.rd] = self.regs[inst.rs1] >> shift;
-                            }
-                            _ => { // SRAI (Arithmetic shift right)
-                                let sign = self.regs[inst.rs1] & (1 << 31);
-                                let shift = inst.rs2;
-                                self.regs[inst.rd] = (self.regs[inst.rs1] >> shift) | sign;
-                            }
-                        } 
-                    },
-                    0x6 => { // ORI
-                        self.regs[inst.rd] = self.regs[inst.rs1] | imm;
-                    },
-                    0x7 => { // ANDI
-                        self.regs[inst.rd] = self.regs[inst.rs1] & imm;
-                    }
-                    _ => {}
+            Mret => {
+                if (self.mode != Machine) {
+                    return Err(errors::Exception::IllegalInstruction(0));
+                }
+
+                // Same as SRET, with MPP/MIE/MPIE. MPRV is only kept when returning to M-mode
+                let mut status = self.csr.load(csr::MSTATUS);
+                self.mode = (status & csr::MASK_MPP) >> 11;
+                let mpie = (status & csr::MASK_MPIE) >> 7;
+                status = (status & !csr::MASK_MIE) | (mpie << 3);
+                status |= csr::MASK_MPIE;
+                status = (status & !csr::MASK_MPP) | (User << 11);
+                if (self.mode != Machine) {
+                    status &= !csr::MASK_MPRV;
                 }
+                self.csr.store(csr::MSTATUS, status);
+
+                return Ok(self.trap_return_target(csr::MEPC));
             }
-            0x37 => { // LUI (load 12-31 bits into register)
-                let imm = ((inst.funct7 as u64) << 13) | ((inst.rs2 as u64) << 8) | ((inst.rs1 as u64) << 3) | inst.funct3 as u64;
-                self.regs[inst.rd] = (imm << 12);
-                return Ok(self.pc + 4);
-            }
-            0x73 => { // Diff CSR instructions have the same OP code, but differing rs2/funct7
-                match(inst.rs2, inst.funct7) {
-                    (0x3, _) => { // csrrc
-                        let csr = (inst.funct7 << 5) | inst.rs2;
-                        let temp = self.csr.load(csr);
-                        let csr_value = temp & !self.regs[inst.rs1];
-                        self.csr.store(csr, csr_value);
-                        self.regs[inst.rd] = temp;
-                        return Ok(self.pc + 4);
-                    }
-                    (0x2, 0x8) => { // sret
-                        // Below is just fancy bit manipulation of sstatus to update certain flags
-                        let mut updated_sstatus = self.csr.load(csr::SSTATUS);
-                        
-                        // Set the current mode to be the SPP (supervisor previous privilege) bit,
-                        // which is either 0 for user or 1 for supervisor
-                        let SPP_FLAG_POS = 8; 
-                        self.mode = (updated_sstatus & (1 << SPP_FLAG_POS) >> SPP_FLAG_POS);
-                        
-                        // Set current IE (interrupt enabled) flag to be previous IE flag before
-                        // interrupt 
-                        let SPIE_FLAG_POS = 5;
-                        let SIE_FLAG_POS = 1;
-                        let spie = (updated_sstatus & (1 << SPIE_FLAG_POS) >> SPIE_FLAG_POS);
-                        updated_sstatus = (updated_sstatus & !(1 << SIE_FLAG_POS)) | (spie << SIE_FLAG_POS);
-                        
-                        // Set Previous IE to be 1
-                        updated_sstatus |= (1 << SPIE_FLAG_POS);
-                    
-                        // Set previous privilege mode to be user mode (which is lowest privilege)
-                        updated_sstatus &= !(1 << SPP_FLAG_POS);
-                        self.csr.store(csr::SSTATUS, updated_sstatus);
-
-                        // Return the program counter position before interrupt, to restore program
-                        return Ok(self.csr.load(csr::SEPC) & !0b11);
-                    }
-                    (_, _) => {
-                        println!("CSR instruction not supported yet!");
-                        return Ok(self.pc + 4);
-                    }
+            Wfi => {
+                // Never let U-mode stall the hart, and let M-mode catch S-mode's WFI with TW
+                let tw = self.csr.load(csr::MSTATUS) & csr::MASK_TW != 0;
+                if (self.mode == User || (self.mode == Supervisor && tw)) {
+                    return Err(errors::Exception::IllegalInstruction(0));
                 }
+                self.waiting_for_interrupt = true;
+            }
+            SfenceVma { rs1, rs2 } => {
+                // mstatus.TVM traps S-mode attempts at managing virtual memory
+                let tvm = self.csr.load(csr::MSTATUS) & csr::MASK_TVM != 0;
+                if (self.mode == User || (self.mode == Supervisor && tvm)) {
+                    return Err(errors::Exception::IllegalInstruction(0));
+                }
+                // x0 as rs1 means every address, and as rs2 every address space
+                let addr = if (rs1 != 0) { Some(self.regs[rs1]) } else { None };
+                let asid = if (rs2 != 0) { Some(self.regs[rs2] & 0xffff) } else { None };
+                self.tlb.flush(addr, asid);
+            }
+        }
+        return Ok(self.pc.wrapping_add(self.inst_len));
+    }
+
+    fn execute_fp(&mut self, inst: instructions::FpInstruction) -> Result<u64, errors::Exception> {
+        use instructions::FpInstruction::*;
+        use fpu::{SINGLE, DOUBLE};
+
+        // While mstatus.FS is Off the FPU doesn't exist as far as software is concerned. Illegal
+        // instructions detected during execution report a zero tval, which the spec allows
+        if (self.fp_status() == csr::FS_OFF) {
+            return Err(errors::Exception::IllegalInstruction(0));
+        }
+
+        let mut flags = 0;
+        match(inst) {
+            Flw { rd, rs1, imm } => {
+                let value = self.load(self.regs[rs1].wrapping_add(imm as u64), 32)?;
+                self.write_fp(SINGLE, rd, value);
+            }
+            Fsw { rs1, rs2, imm } => self.store(self.regs[rs1].wrapping_add(imm as u64), 32, self.fregs[rs2])?,
+            Fld { rd, rs1, imm } => {
+                self.fregs[rd] = self.load(self.regs[rs1].wrapping_add(imm as u64), 64)?;
+            }
+            Fsd { rs1, rs2, imm } => self.store(self.regs[rs1].wrapping_add(imm as u64), 64, self.fregs[rs2])?,
+
+            FmaddS { rd, rs1, rs2, rs3, rm } => self.fp_fused(SINGLE, rd, [rs1, rs2, rs3], rm, false, false, &mut flags)?,
+            FmsubS { rd, rs1, rs2, rs3, rm } => self.fp_fused(SINGLE, rd, [rs1, rs2, rs3], rm, false, true, &mut flags)?,
+            FnmsubS { rd, rs1, rs2, rs3, rm } => self.fp_fused(SINGLE, rd, [rs1, rs2, rs3], rm, true, false, &mut flags)?,
+            FnmaddS { rd, rs1, rs2, rs3, rm } => self.fp_fused(SINGLE, rd, [rs1, rs2, rs3], rm, true, true, &mut flags)?,
+            FmaddD { rd, rs1, rs2, rs3, rm } => self.fp_fused(DOUBLE, rd, [rs1, rs2, rs3], rm, false, false, &mut flags)?,
+            FmsubD { rd, rs1, rs2, rs3, rm } => self.fp_fused(DOUBLE, rd, [rs1, rs2, rs3], rm, false, true, &mut flags)?,
+            FnmsubD { rd, rs1, rs2, rs3, rm } => self.fp_fused(DOUBLE, rd, [rs1, rs2, rs3], rm, true, false, &mut flags)?,
+            FnmaddD { rd, rs1, rs2, rs3, rm } => self.fp_fused(DOUBLE, rd, [rs1, rs2, rs3], rm, true, true, &mut flags)?,
+
+            FaddS { rd, rs1, rs2, rm } => self.fp_arithmetic(SINGLE, rd, rs1, rs2, rm, fpu::add, &mut flags)?,
+            FsubS { rd, rs1, rs2, rm } => self.fp_arithmetic(SINGLE, rd, rs1, rs2, rm, fpu::sub, &mut flags)?,
+            FmulS { rd, rs1, rs2, rm } => self.fp_arithmetic(SINGLE, rd, rs1, rs2, rm, fpu::mul, &mut flags)?,
+            FdivS { rd, rs1, rs2, rm } => self.fp_arithmetic(SINGLE, rd, rs1, rs2, rm, fpu::div, &mut flags)?,
+            FaddD { rd, rs1, rs2, rm } => self.fp_arithmetic(DOUBLE, rd, rs1, rs2, rm, fpu::add, &mut flags)?,
+            FsubD { rd, rs1, rs2, rm } => self.fp_arithmetic(DOUBLE, rd, rs1, rs2, rm, fpu::sub, &mut flags)?,
+            FmulD { rd, rs1, rs2, rm } => self.fp_arithmetic(DOUBLE, rd, rs1, rs2, rm, fpu::mul, &mut flags)?,
+            FdivD { rd, rs1, rs2, rm } => self.fp_arithmetic(DOUBLE, rd, rs1, rs2, rm, fpu::div, &mut flags)?,
+            FsqrtS { rd, rs1, rm } => {
+                let result = fpu::sqrt(SINGLE, self.read_fp(SINGLE, rs1), self.rounding_mode(rm)?, &mut flags);
+                self.write_fp(SINGLE, rd, result);
+            }
+            FsqrtD { rd, rs1, rm } => {
+                let result = fpu::sqrt(DOUBLE, self.read_fp(DOUBLE, rs1), self.rounding_mode(rm)?, &mut flags);
+                self.write_fp(DOUBLE, rd, result);
+            }
+
+            // Sign injection takes the magnitude of rs1 and the sign of rs2 (inverted for FSGNJN,
+            // xored with the sign of rs1 for FSGNJX)
+            FsgnjS { rd, rs1, rs2 } => self.fp_sign_inject(SINGLE, rd, rs1, rs2, |_, sign| sign),
+            FsgnjnS { rd, rs1, rs2 } => self.fp_sign_inject(SINGLE, rd, rs1, rs2, |_, sign| !sign),
+            FsgnjxS { rd, rs1, rs2 } => self.fp_sign_inject(SINGLE, rd, rs1, rs2, |own, sign| own ^ sign),
+            FsgnjD { rd, rs1, rs2 } => self.fp_sign_inject(DOUBLE, rd, rs1, rs2, |_, sign| sign),
+            FsgnjnD { rd, rs1, rs2 } => self.fp_sign_inject(DOUBLE, rd, rs1, rs2, |_, sign| !sign),
+            FsgnjxD { rd, rs1, rs2 } => self.fp_sign_inject(DOUBLE, rd, rs1, rs2, |own, sign| own ^ sign),
+
+            FminS { rd, rs1, rs2 } => {
+                let result = fpu::min(SINGLE, self.read_fp(SINGLE, rs1), self.read_fp(SINGLE, rs2), &mut flags);
+                self.write_fp(SINGLE, rd, result);
+            }
+            FmaxS { rd, rs1, rs2 } => {
+                let result = fpu::max(SINGLE, self.read_fp(SINGLE, rs1), self.read_fp(SINGLE, rs2), &mut flags);
+                self.write_fp(SINGLE, rd, result);
+            }
+            FminD { rd, rs1, rs2 } => {
+                let result = fpu::min(DOUBLE, self.read_fp(DOUBLE, rs1), self.read_fp(DOUBLE, rs2), &mut flags);
+                self.write_fp(DOUBLE, rd, result);
             }
-            _ => {
-                println!("Not implemented");
-                println!("Op {} not implemented yet!", inst.opcode);
-                return Ok(self.pc + 4);
+            FmaxD { rd, rs1, rs2 } => {
+                let result = fpu::max(DOUBLE, self.read_fp(DOUBLE, rs1), self.read_fp(DOUBLE, rs2), &mut flags);
+                self.write_fp(DOUBLE, rd, result);
             }
 
+            FeqS { rd, rs1, rs2 } => self.regs[rd] = fpu::eq(SINGLE, self.read_fp(SINGLE, rs1), self.read_fp(SINGLE, rs2), &mut flags) as u64,
+            FltS { rd, rs1, rs2 } => self.regs[rd] = fpu::lt(SINGLE, self.read_fp(SINGLE, rs1), self.read_fp(SINGLE, rs2), &mut flags) as u64,
+            FleS { rd, rs1, rs2 } => self.regs[rd] = fpu::le(SINGLE, self.read_fp(SINGLE, rs1), self.read_fp(SINGLE, rs2), &mut flags) as u64,
+            FeqD { rd, rs1, rs2 } => self.regs[rd] = fpu::eq(DOUBLE, self.read_fp(DOUBLE, rs1), self.read_fp(DOUBLE, rs2), &mut flags) as u64,
+            FltD { rd, rs1, rs2 } => self.regs[rd] = fpu::lt(DOUBLE, self.read_fp(DOUBLE, rs1), self.read_fp(DOUBLE, rs2), &mut flags) as u64,
+            FleD { rd, rs1, rs2 } => self.regs[rd] = fpu::le(DOUBLE, self.read_fp(DOUBLE, rs1), self.read_fp(DOUBLE, rs2), &mut flags) as u64,
+            FclassS { rd, rs1 } => self.regs[rd] = fpu::classify(SINGLE, self.read_fp(SINGLE, rs1)),
+            FclassD { rd, rs1 } => self.regs[rd] = fpu::classify(DOUBLE, self.read_fp(DOUBLE, rs1)),
+
+            FcvtWS { rd, rs1, rm } => self.fp_to_int(SINGLE, rd, rs1, rm, true, 32, &mut flags)?,
+            FcvtWuS { rd, rs1, rm } => self.fp_to_int(SINGLE, rd, rs1, rm, false, 32, &mut flags)?,
+            FcvtLS { rd, rs1, rm } => self.fp_to_int(SINGLE, rd, rs1, rm, true, 64, &mut flags)?,
+            FcvtLuS { rd, rs1, rm } => self.fp_to_int(SINGLE, rd, rs1, rm, false, 64, &mut flags)?,
+            FcvtWD { rd, rs1, rm } => self.fp_to_int(DOUBLE, rd, rs1, rm, true, 32, &mut flags)?,
+            FcvtWuD { rd, rs1, rm } => self.fp_to_int(DOUBLE, rd, rs1, rm, false, 32, &mut flags)?,
+            FcvtLD { rd, rs1, rm } => self.fp_to_int(DOUBLE, rd, rs1, rm, true, 64, &mut flags)?,
+            FcvtLuD { rd, rs1, rm } => self.fp_to_int(DOUBLE, rd, rs1, rm, false, 64, &mut flags)?,
+            FcvtSW { rd, rs1, rm } => self.fp_from_int(SINGLE, rd, rs1, rm, true, 32, &mut flags)?,
+            FcvtSWu { rd, rs1, rm } => self.fp_from_int(SINGLE, rd, rs1, rm, false, 32, &mut flags)?,
+            FcvtSL { rd, rs1, rm } => self.fp_from_int(SINGLE, rd, rs1, rm, true, 64, &mut flags)?,
+            FcvtSLu { rd, rs1, rm } => self.fp_from_int(SINGLE, rd, rs1, rm, false, 64, &mut flags)?,
+            FcvtDW { rd, rs1, rm } => self.fp_from_int(DOUBLE, rd, rs1, rm, true, 32, &mut flags)?,
+            FcvtDWu { rd, rs1, rm } => self.fp_from_int(DOUBLE, rd, rs1, rm, false, 32, &mut flags)?,
+            FcvtDL { rd, rs1, rm } => self.fp_from_int(DOUBLE, rd, rs1, rm, true, 64, &mut flags)?,
+            FcvtDLu { rd, rs1, rm } => self.fp_from_int(DOUBLE, rd, rs1, rm, false, 64, &mut flags)?,
+            FcvtSD { rd, rs1, rm } => {
+                let result = fpu::convert(DOUBLE, SINGLE, self.read_fp(DOUBLE, rs1), self.rounding_mode(rm)?, &mut flags);
+                self.write_fp(SINGLE, rd, result);
+            }
+            FcvtDS { rd, rs1, rm } => {
+                let result = fpu::convert(SINGLE, DOUBLE, self.read_fp(SINGLE, rs1), self.rounding_mode(rm)?, &mut flags);
+                self.write_fp(DOUBLE, rd, result);
+            }
+
+            // Moves copy the raw bits, without checking or producing NaN-boxes
+            FmvXW { rd, rs1 } => self.regs[rd] = self.fregs[rs1] as i32 as i64 as u64,
+            FmvWX { rd, rs1 } => self.write_fp(SINGLE, rd, self.regs[rs1] & 0xffff_ffff),
+            FmvXD { rd, rs1 } => self.regs[rd] = self.fregs[rs1],
+            FmvDX { rd, rs1 } => self.fregs[rd] = self.regs[rs1],
+        }
+
+        self.csr.store(csr::FFLAGS, self.csr.load(csr::FFLAGS) | flags);
+        // Conservatively treat every FP instruction as modifying the FP state, which the spec
+        // allows
+        self.mark_fp_dirty();
+        return Ok(self.pc.wrapping_add(self.inst_len));
+    }
+
+    fn fp_status(&self) -> u64 {
+        return (self.csr.load(csr::MSTATUS) & csr::MASK_FS) >> 13;
+    }
+
+    fn mark_fp_dirty(&mut self) {
+        let status = self.csr.load(csr::MSTATUS);
+        self.csr.store(csr::MSTATUS, status | (csr::FS_DIRTY << 13) | csr::MASK_SD);
+    }
+
+    // Resolves an instruction's rm field, where 0b111 defers to the frm CSR
+    fn rounding_mode(&self, rm: u64) -> Result<fpu::RoundingMode, errors::Exception> {
+        let rm = if (rm == 0b111) { self.csr.load(csr::FRM) } else { rm };
+        return fpu::RoundingMode::from_bits(rm).ok_or(errors::Exception::IllegalInstruction(0));
+    }
+
+    // Single precision values live NaN-boxed in the 64 bit registers: the upper 32 bits are all
+    // ones, and a value that isn't properly boxed reads as the canonical NaN
+    fn read_fp(&self, fmt: fpu::Format, reg: usize) -> u64 {
+        let value = self.fregs[reg];
+        if (fmt == fpu::SINGLE) {
+            if (value >> 32 != 0xffff_ffff) {
+                return fpu::SINGLE.canonical_nan();
+            }
+            return value & 0xffff_ffff;
+        }
+        return value;
+    }
+
+    fn write_fp(&mut self, fmt: fpu::Format, reg: usize, value: u64) {
+        if (fmt == fpu::SINGLE) {
+            self.fregs[reg] = value | 0xffff_ffff_0000_0000;
+        } else {
+            self.fregs[reg] = value;
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn fp_arithmetic(&mut self, fmt: fpu::Format, rd: usize, rs1: usize, rs2: usize, rm: u64,
+            op: fn(fpu::Format, u64, u64, fpu::RoundingMode, &mut u64) -> u64, flags: &mut u64) -> Result<(), errors::Exception> {
+        let rm = self.rounding_mode(rm)?;
+        let result = op(fmt, self.read_fp(fmt, rs1), self.read_fp(fmt, rs2), rm, flags);
+        self.write_fp(fmt, rd, result);
+        return Ok(());
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn fp_fused(&mut self, fmt: fpu::Format, rd: usize, rs: [usize; 3], rm: u64, negate_product: bool,
+            negate_addend: bool, flags: &mut u64) -> Result<(), errors::Exception> {
+        let rm = self.rounding_mode(rm)?;
+        let (a, b, c) = (self.read_fp(fmt, rs[0]), self.read_fp(fmt, rs[1]), self.read_fp(fmt, rs[2]));
+        let result = fpu::fused_mul_add(fmt, a, b, c, negate_product, negate_addend, rm, flags);
+        self.write_fp(fmt, rd, result);
+        return Ok(());
+    }
+
+    fn fp_sign_inject(&mut self, fmt: fpu::Format, rd: usize, rs1: usize, rs2: usize, sign: fn(bool, bool) -> bool) {
+        let sign_bit = if (fmt == fpu::SINGLE) { 1 << 31 } else { 1 << 63 };
+        let (a, b) = (self.read_fp(fmt, rs1), self.read_fp(fmt, rs2));
+        let negative = sign(a & sign_bit != 0, b & sign_bit != 0);
+        self.write_fp(fmt, rd, (a & !sign_bit) | if (negative) { sign_bit } else { 0 });
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn fp_to_int(&mut self, fmt: fpu::Format, rd: usize, rs1: usize, rm: u64, signed: bool, width: u32,
+            flags: &mut u64) -> Result<(), errors::Exception> {
+        let rm = self.rounding_mode(rm)?;
+        self.regs[rd] = fpu::to_int(fmt, self.read_fp(fmt, rs1), signed, width, rm, flags);
+        return Ok(());
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn fp_from_int(&mut self, fmt: fpu::Format, rd: usize, rs1: usize, rm: u64, signed: bool, width: u32,
+            flags: &mut u64) -> Result<(), errors::Exception> {
+        let rm = self.rounding_mode(rm)?;
+        let result = fpu::from_int(fmt, self.regs[rs1], signed, width, rm, flags);
+        self.write_fp(fmt, rd, result);
+        return Ok(());
+    }
+
+    // Where xRET resumes: the saved epc, whose low bit is always clear and whose bit 1 is
+    // masked off when only 4-byte aligned instructions are allowed
+    fn trap_return_target(&self, epc: usize) -> u64 {
+        let alignment = if (self.compressed_enabled()) { 0b1 } else { 0b11 };
+        return self.csr.load(epc) & !alignment;
+    }
+
+    // Reads the CSR into rd and writes op(old value, value) back, each only if asked to
+    fn csr_access(&mut self, rd: usize, csr: usize, read: bool, write: bool, value: u64,
+            op: fn(u64, u64) -> u64) -> Result<(), errors::Exception> {
+        self.check_csr_access(csr, write)?;
+        let old = if (read) { self.csr.load(csr) } else { 0 };
+        if (write) {
+            // Writes to mip change the software SEIP bit, so a set or clear works from that
+            // rather than from the bit the PLIC may be driving
+            if (csr == csr::MIP) {
+                let software_seip = if (self.software_seip) { MASK_SEIP } else { 0 };
+                let written = op((old & !MASK_SEIP) | software_seip, value);
+                self.software_seip = written & MASK_SEIP != 0;
+                self.csr.store(csr, written);
+            } else {
+                self.csr.store(csr, op(old, value));
+            }
+            if (Self::is_fp_csr(csr)) {
+                self.mark_fp_dirty();
+            }
+            // Switching address spaces drops every cached translation
+            if (csr == csr::SATP) {
+                self.tlb.flush(None, None);
+            }
+        }
+        self.regs[rd] = old;
+        return Ok(());
+    }
+
+    // Checks that the current state allows the access to the CSR
+    fn check_csr_access(&self, csr: usize, write: bool) -> Result<(), errors::Exception> {
+        // Bits [9:8] of the address hold the lowest privilege level that can access the CSR
+        if (self.mode < ((csr >> 8) & 0b11) as Mode) {
+            return Err(errors::Exception::IllegalInstruction(0));
+        }
+        // and bits [11:10] == 0b11 mark it read-only
+        if (write && (csr >> 10) & 0b11 == 0b11) {
+            return Err(errors::Exception::IllegalInstruction(0));
+        }
+        // RV64 only has the even pmpcfg registers
+        if ((csr::PMPCFG0..=csr::PMPCFG15).contains(&csr) && csr % 2 == 1) {
+            return Err(errors::Exception::IllegalInstruction(0));
+        }
+        // mstatus.TVM traps S-mode accesses to satp
+        if (csr == csr::SATP && self.mode == Supervisor && self.csr.load(csr::MSTATUS) & csr::MASK_TVM != 0) {
+            return Err(errors::Exception::IllegalInstruction(0));
+        }
+        // The floating-point CSRs are part of the FPU state, and disappear along with it
+        if (Self::is_fp_csr(csr) && self.fp_status() == csr::FS_OFF) {
+            return Err(errors::Exception::IllegalInstruction(0));
+        }
+        return Ok(());
+    }
+
+    fn is_fp_csr(csr: usize) -> bool {
+        return [csr::FFLAGS, csr::FRM, csr::FCSR].contains(&csr);
+    }
+
+    // With mstatus.MPRV set, M-mode loads and stores use the privilege in MPP, including for
+    // address translation. Instruction fetches always use the current privilege
+    fn effective_privilege(&self) -> Mode {
+        let status = self.csr.load(csr::MSTATUS);
+        if (self.mode == Machine && status & csr::MASK_MPRV != 0) {
+            return (status & csr::MASK_MPP) >> 11;
+        }
+        return self.mode;
+    }
+
+    fn access_privilege(&self, access: mmu::AccessType) -> Mode {
+        match(access) {
+            mmu::AccessType::Instruction => self.mode,
+            _ => self.effective_privilege(),
+        }
+    }
+
+    fn translate(&mut self, addr: u64, size: u64, access: mmu::AccessType) -> Result<u64, errors::Exception> {
+        let privilege = self.access_privilege(access);
+        return mmu::translate(&mut self.bus, &mut self.tlb, &self.csr, privilege, addr, size, access);
+    }
+
+    // Translates the address, then checks the physical access against PMP
+    fn physical_address(&mut self, addr: u64, size: u64, access: mmu::AccessType) -> Result<u64, errors::Exception> {
+        let paddr = self.translate(addr, size, access)?;
+        if (!pmp::check(&self.csr, paddr, size / 8, self.access_privilege(access), access)) {
+            return Err(access.access_fault(addr));
+        }
+        return Ok(paddr);
+    }
+
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, errors::Exception> {
+        let paddr = self.physical_address(addr, size, mmu::AccessType::Load)?;
+        let value = self.bus.load(paddr, size).map_err(|_| errors::Exception::LoadAccessFault(addr))?;
+        if (self.big_endian_data()) {
+            return Ok(Self::swap_bytes(value, size));
+        }
+        return Ok(value);
+    }
+
+    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), errors::Exception> {
+        // Reservations cover the naturally aligned doubleword holding the reserved address, and
+        // any store overlapping it breaks the reservation
+        if let Some(reserved) = self.reservation {
+            let first = addr & !0x7;
+            let last = addr.wrapping_add(size / 8 - 1) & !0x7;
+            if ((first..=last).contains(&(reserved & !0x7))) {
+                self.reservation = None;
+            }
+        }
+        let paddr = self.physical_address(addr, size, mmu::AccessType::Store)?;
+        let value = if (self.big_endian_data()) { Self::swap_bytes(value, size) } else { value };
+        return self.bus.store(paddr, size, value).map_err(|_| errors::Exception::StoreAMOAccessFault(addr));
+    }
+
+    // Memory and devices are little-endian. mstatus.UBE/SBE/MBE switch data accesses (but not
+    // instruction fetches) in the corresponding mode to big-endian, done here by swapping the
+    // bytes of the value on its way to and from the bus
+    #[allow(non_upper_case_globals)]
+    fn big_endian_data(&self) -> bool {
+        let status = self.csr.load(csr::MSTATUS);
+        let mask = match(self.effective_privilege()) {
+            User => csr::MASK_UBE,
+            Supervisor => csr::MASK_SBE,
+            _ => csr::MASK_MBE,
+        };
+        return status & mask != 0;
+    }
+
+    fn swap_bytes(value: u64, size: u64) -> u64 {
+        match(size) {
+            16 => (value as u16).swap_bytes() as u64,
+            32 => (value as u32).swap_bytes() as u64,
+            64 => value.swap_bytes(),
+            _ => value,
+        }
+    }
+
+    fn load_reserved(&mut self, rd: usize, rs1: usize, size: u64) -> Result<(), errors::Exception> {
+        let addr = self.regs[rs1];
+        if (addr & (size / 8 - 1) != 0) {
+            // LR is a load, so unlike SC and the AMOs it reports a load misalignment
+            return Err(errors::Exception::LoadAccessMisaligned(addr));
+        }
+        let value = self.load(addr, size)?;
+        self.regs[rd] = if (size == 32) { value as i32 as i64 as u64 } else { value };
+        self.reservation = Some(addr);
+        return Ok(());
+    }
+
+    fn store_conditional(&mut self, rd: usize, rs1: usize, rs2: usize, size: u64) -> Result<(), errors::Exception> {
+        let addr = self.regs[rs1];
+        if (addr & (size / 8 - 1) != 0) {
+            return Err(errors::Exception::StoreAMOAddrMisaligned(addr));
         }
-        return Ok(self.pc + 4);
+        // Whether or not it succeeds, an SC consumes the reservation
+        let reserved = self.reservation.take() == Some(addr);
+        if (reserved) {
+            self.store(addr, size, self.regs[rs2])?;
+        }
+        self.regs[rd] = !reserved as u64;
+        return Ok(());
+    }
+
+    // Atomically loads the value at rs1 into rd and stores op(loaded value, rs2) back. Word
+    // operands are sign-extended first, which keeps both signed and unsigned min/max orderings
+    fn amo(&mut self, rd: usize, rs1: usize, rs2: usize, size: u64, op: fn(u64, u64) -> u64) -> Result<(), errors::Exception> {
+        let addr = self.regs[rs1];
+        if (addr & (size / 8 - 1) != 0) {
+            return Err(errors::Exception::StoreAMOAddrMisaligned(addr));
+        }
+        // AMOs need write permission, and report any fault as a store fault, so check the
+        // translation for the store before loading
+        self.physical_address(addr, size, mmu::AccessType::Store)?;
+        let mut mem = self.load(addr, size)?;
+        let mut src = self.regs[rs2];
+        if (size == 32) {
+            mem = mem as i32 as i64 as u64;
+            src = src as i32 as i64 as u64;
+        }
+        self.store(addr, size, op(mem, src))?;
+        self.regs[rd] = mem;
+        return Ok(());
+    }
+
+    fn branch(&self, taken: bool, imm: i64) -> Result<u64, errors::Exception> {
+        if (taken) {
+            return self.jump_target(self.pc.wrapping_add(imm as u64));
+        }
+        return Ok(self.pc.wrapping_add(self.inst_len));
+    }
+
+    // Control transfers must land on an instruction boundary (2 bytes with the C extension, 4
+    // bytes without), otherwise the jump itself faults
+    fn jump_target(&self, target: u64) -> Result<u64, errors::Exception> {
+        let alignment = if (self.compressed_enabled()) { 0b1 } else { 0b11 };
+        if (target & alignment != 0) {
+            return Err(errors::Exception::InstructionAddrMisaligned(target));
+        }
+        return Ok(target);
     }
 }
 
@@ -397,12 +1147,51 @@ mod tests {
     fn execute_instructions(cpu: &mut Cpu, instr: &[I]) {
         for inst in instr.iter() {
             let machine_code = u32::from(*inst);
-            let d = cpu.decode(machine_code);
+            let d = cpu.decode(machine_code).unwrap();
+
+            cpu.pc = cpu.execute(d).unwrap();
+            cpu.regs[0] = 0;
+        }
+    }
 
-            cpu.execute(d);
+    // asm_riscv only knows the 64 bit opcodes, so the RV64 word variants are encoded by
+    // swapping in the OP-IMM-32 / OP-32 opcode
+    fn word_variant(inst: I) -> u32 {
+        let machine_code = u32::from(inst);
+        match(machine_code & 0x7f) {
+            0x13 => (machine_code & !0x7f) | 0x1b,
+            0x33 => (machine_code & !0x7f) | 0x3b,
+            _ => panic!("No word variant for {:?}", inst),
         }
     }
 
+    // asm_riscv has no M extension, but each M instruction is the OP / OP-32 instruction with the
+    // same funct3 and funct7 set to 1 (MUL=ADD, MULH=SLL, MULHSU=SLT, MULHU=SLTU, DIV=XOR,
+    // DIVU=SRL, REM=OR, REMU=AND)
+    fn m_variant(machine_code: u32) -> u32 {
+        return (machine_code & !(0x7f << 25)) | (1 << 25);
+    }
+
+    // asm_riscv lays out branch and jump immediates like S and U types, so encode them by hand
+    fn encode_branch(funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+        let imm = imm as u32;
+        return (((imm >> 12) & 0x1) << 31) | (((imm >> 5) & 0x3f) << 25) | (rs2 << 20) | (rs1 << 15)
+            | (funct3 << 12) | (((imm >> 1) & 0xf) << 8) | (((imm >> 11) & 0x1) << 7) | 0x63;
+    }
+
+    fn encode_jal(rd: u32, imm: i32) -> u32 {
+        let imm = imm as u32;
+        return (((imm >> 20) & 0x1) << 31) | (((imm >> 1) & 0x3ff) << 21) | (((imm >> 11) & 0x1) << 20)
+            | (((imm >> 12) & 0xff) << 12) | (rd << 7) | 0x6f;
+    }
+
+    fn execute_machine_code(cpu: &mut Cpu, machine_code: u32) -> Result<u64, errors::Exception> {
+        let d = cpu.decode(machine_code)?;
+        let result = cpu.execute(d);
+        cpu.regs[0] = 0;
+        return result;
+    }
+
     #[test]
     fn test_execute_lui() {
         let mut cpu = Cpu::new(Vec::new());
@@ -461,7 +1250,7 @@ mod tests {
 
         execute_instructions(&mut cpu, &instr);
 
-        assert_eq!(cpu.regs[28], 2047);
+        assert_eq!(cpu.regs[28], -1i64 as u64);
     }
 
 
@@ -512,8 +1301,8 @@ mod tests {
 
         let inst = format!("{}{}{}{}{}", "000000000011", "00010", "011", "00001", "1110011");
         let inst_bin = u32::from_str_radix(&inst, 2).unwrap();
-        let inst_obj = cpu.decode(inst_bin);
-        cpu.execute(inst_obj);
+        let inst_obj = cpu.decode(inst_bin).unwrap();
+        cpu.execute(inst_obj).unwrap();
 
         let expected_csr_value = 1;
         let expected_reg_value = 1;
@@ -521,4 +1310,873 @@ mod tests {
         assert_eq!(expected_csr_value, cpu.csr.load(3));
         assert_eq!(expected_reg_value, cpu.regs[1]);
     }
+
+    #[test]
+    fn test_execute_addi_sign_extends() {
+        let mut cpu = Cpu::new(Vec::new());
+
+        let instr = [
+            I::ADDI { d: Reg::T3, s: Reg::ZERO, im: -5 },
+            I::ADDI { d: Reg::T4, s: Reg::T3, im: 2047 },
+        ];
+
+        execute_instructions(&mut cpu, &instr);
+
+        assert_eq!(cpu.regs[28], -5i64 as u64);
+        assert_eq!(cpu.regs[29], 2042);
+    }
+
+    #[test]
+    fn test_execute_register_ops() {
+        let mut cpu = Cpu::new(Vec::new());
+
+        let instr = [
+            I::ADDI { d: Reg::T1, s: Reg::ZERO, im: 12 },
+            I::ADDI { d: Reg::T2, s: Reg::ZERO, im: -3 },
+            I::SUB { d: Reg::T3, s1: Reg::T1, s2: Reg::T2 },
+            I::SLT { d: Reg::T4, s1: Reg::T2, s2: Reg::T1 },
+            I::SLTU { d: Reg::T5, s1: Reg::T2, s2: Reg::T1 },
+            I::SRA { d: Reg::T6, s1: Reg::T2, s2: Reg::T1 },
+            I::SRL { d: Reg::A0, s1: Reg::T2, s2: Reg::T1 },
+            I::SLL { d: Reg::A1, s1: Reg::T1, s2: Reg::T1 },
+            I::AND { d: Reg::A2, s1: Reg::T1, s2: Reg::T2 },
+            I::OR { d: Reg::A3, s1: Reg::T1, s2: Reg::T2 },
+            I::XOR { d: Reg::A4, s1: Reg::T1, s2: Reg::T2 },
+        ];
+
+        execute_instructions(&mut cpu, &instr);
+
+        assert_eq!(cpu.regs[28], 15);
+        assert_eq!(cpu.regs[29], 1);
+        assert_eq!(cpu.regs[30], 0);
+        assert_eq!(cpu.regs[31], -1i64 as u64);
+        assert_eq!(cpu.regs[10], (-3i64 as u64) >> 12);
+        assert_eq!(cpu.regs[11], 12 << 12);
+        assert_eq!(cpu.regs[12], 12 & -3i64 as u64);
+        assert_eq!(cpu.regs[13], 12 | -3i64 as u64);
+        assert_eq!(cpu.regs[14], 12 ^ -3i64 as u64);
+    }
+
+    #[test]
+    fn test_execute_x0_is_hardwired() {
+        let mut cpu = Cpu::new(Vec::new());
+
+        let instr = [
+            I::ADDI { d: Reg::ZERO, s: Reg::ZERO, im: 7 },
+        ];
+
+        execute_instructions(&mut cpu, &instr);
+
+        assert_eq!(cpu.regs[0], 0);
+    }
+
+    #[test]
+    fn test_execute_word_ops() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.regs[5] = 0x7fff_ffff;
+        cpu.regs[6] = 0xffff_ffff_0000_0010;
+
+        let instr = [
+            word_variant(I::ADDI { d: Reg::T3, s: Reg::T0, im: 1 }),
+            word_variant(I::SLLI { d: Reg::T4, s: Reg::T0, im: 4 }),
+            word_variant(I::SRAI { d: Reg::T5, s: Reg::T4, im: 4 }),
+            word_variant(I::SRLI { d: Reg::T6, s: Reg::T4, im: 4 }),
+            word_variant(I::ADD { d: Reg::A0, s1: Reg::T0, s2: Reg::T1 }),
+            word_variant(I::SUB { d: Reg::A1, s1: Reg::T1, s2: Reg::T0 }),
+            word_variant(I::SRA { d: Reg::A2, s1: Reg::T4, s2: Reg::T1 }),
+        ];
+
+        for machine_code in instr.iter() {
+            execute_machine_code(&mut cpu, *machine_code).unwrap();
+        }
+
+        assert_eq!(cpu.regs[28], 0xffff_ffff_8000_0000);
+        assert_eq!(cpu.regs[29], 0xffff_ffff_ffff_fff0);
+        assert_eq!(cpu.regs[30], 0xffff_ffff_ffff_ffff);
+        assert_eq!(cpu.regs[31], 0x0fff_ffff);
+        assert_eq!(cpu.regs[10], 0xffff_ffff_8000_000f);
+        assert_eq!(cpu.regs[11], 0xffff_ffff_8000_0011);
+        assert_eq!(cpu.regs[12], 0xffff_ffff_ffff_ffff);
+    }
+
+    #[test]
+    fn test_execute_lui_auipc_sign_extend() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.pc = 0x1000;
+
+        let instr = [
+            I::LUI { d: Reg::T3, im: 0x80000 },
+            I::AUIPC { d: Reg::T4, im: 1 },
+        ];
+
+        execute_instructions(&mut cpu, &instr);
+
+        assert_eq!(cpu.regs[28], 0xffff_ffff_8000_0000);
+        assert_eq!(cpu.regs[29], 0x2004);
+    }
+
+    #[test]
+    fn test_execute_branches() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.regs[5] = 1;
+        cpu.regs[6] = -1i64 as u64;
+        cpu.pc = 0x100;
+
+        // BLT x6, x5, -16 is taken because -1 < 1 when signed
+        assert_eq!(execute_machine_code(&mut cpu, encode_branch(0x4, 6, 5, -16)).unwrap(), 0xf0);
+        // BLTU x6, x5, -16 is not taken because 0xffff... > 1 when unsigned
+        assert_eq!(execute_machine_code(&mut cpu, encode_branch(0x6, 6, 5, -16)).unwrap(), 0x104);
+        // BNE x5, x6, 2048
+        assert_eq!(execute_machine_code(&mut cpu, encode_branch(0x1, 5, 6, 2048)).unwrap(), 0x900);
+        // BEQ x5, x5, 6 only needs 2-byte alignment with the C extension
+        assert_eq!(execute_machine_code(&mut cpu, encode_branch(0x0, 5, 5, 6)).unwrap(), 0x106);
+        // Without it, the same branch jumps to a misaligned address
+        cpu.csr.store(csr::MISA, cpu.csr.load(csr::MISA) & !csr::MISA_C);
+        assert!(matches!(execute_machine_code(&mut cpu, encode_branch(0x0, 5, 5, 6)),
+            Err(errors::Exception::InstructionAddrMisaligned(0x106))));
+    }
+
+    #[test]
+    fn test_execute_compressed_link_address() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.pc = 0x2000;
+        cpu.regs[10] = 0x3002;
+        cpu.inst_len = 2;
+
+        // c.jalr a0 links to the instruction 2 bytes after it
+        let c_jalr = compressed::expand(0x9502).unwrap();
+        cpu.pc = execute_machine_code(&mut cpu, c_jalr).unwrap();
+        assert_eq!(cpu.pc, 0x3002);
+        assert_eq!(cpu.regs[1], 0x2002);
+    }
+
+    #[test]
+    fn test_execute_jal_jalr() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.pc = 0x2000;
+
+        // JAL ra, -0x1000
+        cpu.pc = execute_machine_code(&mut cpu, encode_jal(1, -0x1000)).unwrap();
+        assert_eq!(cpu.pc, 0x1000);
+        assert_eq!(cpu.regs[1], 0x2004);
+
+        // JALR ra, 5(ra) clears the lowest bit of the target and writes the link after reading ra
+        cpu.pc = execute_machine_code(&mut cpu, u32::from(I::JALR { d: Reg::RA, s: Reg::RA, im: 5 })).unwrap();
+        assert_eq!(cpu.pc, 0x2008);
+        assert_eq!(cpu.regs[1], 0x1004);
+    }
+
+    #[test]
+    fn test_execute_unknown_instruction_is_illegal() {
+        let mut cpu = Cpu::new(Vec::new());
+
+        // ADD with an unassigned funct7
+        let machine_code = u32::from(I::ADD { d: Reg::T3, s1: Reg::T1, s2: Reg::T2 }) | (0x10 << 25);
+        assert!(matches!(execute_machine_code(&mut cpu, machine_code),
+            Err(errors::Exception::IllegalInstruction(code)) if code == machine_code as u64));
+    }
+
+    #[test]
+    fn test_execute_mul() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.regs[5] = -3i64 as u64;
+        cpu.regs[6] = 7;
+        cpu.regs[7] = u64::MAX;
+
+        let instr = [
+            m_variant(u32::from(I::ADD { d: Reg::T3, s1: Reg::T0, s2: Reg::T1 })), // MUL
+            m_variant(u32::from(I::SLL { d: Reg::T4, s1: Reg::T0, s2: Reg::T1 })), // MULH
+            m_variant(u32::from(I::SLT { d: Reg::T5, s1: Reg::T0, s2: Reg::T2 })), // MULHSU
+            m_variant(u32::from(I::SLTU { d: Reg::T6, s1: Reg::T2, s2: Reg::T2 })), // MULHU
+            m_variant(word_variant(I::ADD { d: Reg::A0, s1: Reg::T2, s2: Reg::T1 })), // MULW
+        ];
+
+        for machine_code in instr.iter() {
+            execute_machine_code(&mut cpu, *machine_code).unwrap();
+        }
+
+        assert_eq!(cpu.regs[28], -21i64 as u64);
+        assert_eq!(cpu.regs[29], u64::MAX);
+        // -3 * (2^64 - 1) = -3 * 2^64 + 3, whose upper half is -3
+        assert_eq!(cpu.regs[30], -3i64 as u64);
+        assert_eq!(cpu.regs[31], u64::MAX - 1);
+        assert_eq!(cpu.regs[10], -7i64 as u64);
+    }
+
+    #[test]
+    fn test_execute_div_rem() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.regs[5] = -7i64 as u64;
+        cpu.regs[6] = 2;
+
+        let instr = [
+            m_variant(u32::from(I::XOR { d: Reg::T3, s1: Reg::T0, s2: Reg::T1 })), // DIV
+            m_variant(u32::from(I::OR { d: Reg::T4, s1: Reg::T0, s2: Reg::T1 })), // REM
+            m_variant(u32::from(I::SRL { d: Reg::T5, s1: Reg::T0, s2: Reg::T1 })), // DIVU
+            m_variant(u32::from(I::AND { d: Reg::T6, s1: Reg::T0, s2: Reg::T1 })), // REMU
+            m_variant(word_variant(I::SRL { d: Reg::A0, s1: Reg::T0, s2: Reg::T1 })), // DIVUW
+            m_variant(word_variant(I::AND { d: Reg::A1, s1: Reg::T0, s2: Reg::T1 })), // REMUW
+        ];
+
+        for machine_code in instr.iter() {
+            execute_machine_code(&mut cpu, *machine_code).unwrap();
+        }
+
+        assert_eq!(cpu.regs[28], -3i64 as u64);
+        assert_eq!(cpu.regs[29], -1i64 as u64);
+        assert_eq!(cpu.regs[30], (-7i64 as u64) / 2);
+        assert_eq!(cpu.regs[31], 1);
+        // 0xffff_fff9 / 2 = 0x7fff_fffc, which is positive as a 32 bit value
+        assert_eq!(cpu.regs[10], 0x7fff_fffc);
+        assert_eq!(cpu.regs[11], 1);
+    }
+
+    #[test]
+    fn test_execute_div_by_zero_and_overflow() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.regs[5] = 42;
+        cpu.regs[6] = i64::MIN as u64;
+        cpu.regs[7] = -1i64 as u64;
+        cpu.regs[28] = i32::MIN as i64 as u64;
+
+        let instr = [
+            m_variant(u32::from(I::XOR { d: Reg::A0, s1: Reg::T0, s2: Reg::ZERO })), // DIV by 0
+            m_variant(u32::from(I::SRL { d: Reg::A1, s1: Reg::T0, s2: Reg::ZERO })), // DIVU by 0
+            m_variant(u32::from(I::OR { d: Reg::A2, s1: Reg::T0, s2: Reg::ZERO })), // REM by 0
+            m_variant(u32::from(I::AND { d: Reg::A3, s1: Reg::T0, s2: Reg::ZERO })), // REMU by 0
+            m_variant(u32::from(I::XOR { d: Reg::A4, s1: Reg::T1, s2: Reg::T2 })), // DIV overflow
+            m_variant(u32::from(I::OR { d: Reg::A5, s1: Reg::T1, s2: Reg::T2 })), // REM overflow
+            m_variant(word_variant(I::XOR { d: Reg::A6, s1: Reg::T3, s2: Reg::T2 })), // DIVW overflow
+            m_variant(word_variant(I::OR { d: Reg::A7, s1: Reg::T3, s2: Reg::T2 })), // REMW overflow
+            m_variant(word_variant(I::XOR { d: Reg::S2, s1: Reg::T0, s2: Reg::ZERO })), // DIVW by 0
+            m_variant(word_variant(I::OR { d: Reg::S3, s1: Reg::T3, s2: Reg::ZERO })), // REMW by 0
+        ];
+
+        for machine_code in instr.iter() {
+            execute_machine_code(&mut cpu, *machine_code).unwrap();
+        }
+
+        assert_eq!(cpu.regs[10], u64::MAX);
+        assert_eq!(cpu.regs[11], u64::MAX);
+        assert_eq!(cpu.regs[12], 42);
+        assert_eq!(cpu.regs[13], 42);
+        assert_eq!(cpu.regs[14], i64::MIN as u64);
+        assert_eq!(cpu.regs[15], 0);
+        assert_eq!(cpu.regs[16], i32::MIN as i64 as u64);
+        assert_eq!(cpu.regs[17], 0);
+        assert_eq!(cpu.regs[18], u64::MAX);
+        assert_eq!(cpu.regs[19], i32::MIN as i64 as u64);
+    }
+
+    #[test]
+    fn test_execute_sc_without_reservation_fails() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.regs[5] = constants::DRAM_BASE;
+        cpu.regs[6] = 42;
+
+        // sc.d t3, t1, (t0)
+        let sc_d = u32::from_str_radix("00011000011000101011111000101111", 2).unwrap();
+        execute_machine_code(&mut cpu, sc_d).unwrap();
+
+        assert_eq!(cpu.regs[28], 1);
+    }
+
+    #[test]
+    fn test_execute_lr_sc_amo() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.regs[5] = constants::DRAM_BASE + 0x200;
+        cpu.regs[6] = 5;
+        cpu.store(constants::DRAM_BASE + 0x200, 64, 37).unwrap();
+
+        // lr.d t3, (t0)
+        let lr_d = u32::from_str_radix("00010000000000101011111000101111", 2).unwrap();
+        // sc.d t3, t1, (t0)
+        let sc_d = u32::from_str_radix("00011000011000101011111000101111", 2).unwrap();
+        // amoadd.d t3, t1, (t0)
+        let amoadd_d = u32::from_str_radix("00000000011000101011111000101111", 2).unwrap();
+        // amomaxu.w t3, t1, (t0)
+        let amomaxu_w = u32::from_str_radix("11100000011000101010111000101111", 2).unwrap();
+
+        execute_machine_code(&mut cpu, lr_d).unwrap();
+        assert_eq!(cpu.regs[28], 37);
+        execute_machine_code(&mut cpu, sc_d).unwrap();
+        assert_eq!(cpu.regs[28], 0);
+        assert_eq!(cpu.load(constants::DRAM_BASE + 0x200, 64), Ok(5));
+        // The reservation is used up by the SC
+        execute_machine_code(&mut cpu, sc_d).unwrap();
+        assert_eq!(cpu.regs[28], 1);
+
+        execute_machine_code(&mut cpu, amoadd_d).unwrap();
+        assert_eq!(cpu.regs[28], 5);
+        assert_eq!(cpu.load(constants::DRAM_BASE + 0x200, 64), Ok(10));
+
+        // The W forms sign-extend the old value into rd
+        cpu.regs[6] = 0x8000_0000;
+        execute_machine_code(&mut cpu, amomaxu_w).unwrap();
+        assert_eq!(cpu.regs[28], 10);
+        assert_eq!(cpu.load(constants::DRAM_BASE + 0x200, 32), Ok(0x8000_0000));
+        execute_machine_code(&mut cpu, amomaxu_w).unwrap();
+        assert_eq!(cpu.regs[28], 0xffff_ffff_8000_0000);
+    }
+
+    #[test]
+    fn test_execute_misaligned_atomics() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.regs[5] = constants::DRAM_BASE + 4;
+        cpu.reservation = Some(constants::DRAM_BASE + 4);
+
+        // lr.d t3, (t0)
+        let lr_d = u32::from_str_radix("00010000000000101011111000101111", 2).unwrap();
+        // sc.d t3, t1, (t0)
+        let sc_d = u32::from_str_radix("00011000011000101011111000101111", 2).unwrap();
+        // amoadd.d t3, t1, (t0)
+        let amoadd_d = u32::from_str_radix("00000000011000101011111000101111", 2).unwrap();
+
+        assert_eq!(execute_machine_code(&mut cpu, lr_d), Err(errors::Exception::LoadAccessMisaligned(constants::DRAM_BASE + 4)));
+        assert_eq!(execute_machine_code(&mut cpu, sc_d), Err(errors::Exception::StoreAMOAddrMisaligned(constants::DRAM_BASE + 4)));
+        assert_eq!(execute_machine_code(&mut cpu, amoadd_d), Err(errors::Exception::StoreAMOAddrMisaligned(constants::DRAM_BASE + 4)));
+    }
+
+    #[test]
+    fn test_trap_clears_reservation() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.reservation = Some(constants::DRAM_BASE);
+
+        cpu.handle_error(errors::Exception::Breakpoint(0));
+
+        assert_eq!(cpu.reservation, None);
+    }
+
+    // Encodes an OP-FP instruction
+    fn encode_fp(funct7: u32, rs2: u32, rs1: u32, rm: u32, rd: u32) -> u32 {
+        return (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (rm << 12) | (rd << 7) | 0x53;
+    }
+
+    #[test]
+    fn test_execute_fadd_nan_boxing() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.fregs[1] = 0xffff_ffff_0000_0000 | 1.5f32.to_bits() as u64;
+        cpu.fregs[2] = 0xffff_ffff_0000_0000 | 2.25f32.to_bits() as u64;
+
+        // fadd.s f3, f1, f2
+        execute_machine_code(&mut cpu, encode_fp(0x00, 2, 1, 0, 3)).unwrap();
+        assert_eq!(cpu.fregs[3], 0xffff_ffff_0000_0000 | 3.75f32.to_bits() as u64);
+
+        // An improperly boxed single reads as the canonical NaN
+        cpu.fregs[1] = 1.5f32.to_bits() as u64;
+        execute_machine_code(&mut cpu, encode_fp(0x00, 2, 1, 0, 3)).unwrap();
+        assert_eq!(cpu.fregs[3], 0xffff_ffff_7fc0_0000);
+
+        // fmv.x.w sign extends the raw bits, whatever the upper half holds
+        cpu.fregs[4] = 0x1234_5678_bf80_0000;
+        execute_machine_code(&mut cpu, encode_fp(0x70, 0, 4, 0, 10)).unwrap();
+        assert_eq!(cpu.regs[10], 0xffff_ffff_bf80_0000);
+    }
+
+    #[test]
+    fn test_execute_fp_flags_and_dirty_state() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.fregs[1] = f64::NAN.to_bits();
+
+        // fcvt.w.d a0, f1, rtz
+        execute_machine_code(&mut cpu, encode_fp(0x61, 0, 1, 1, 10)).unwrap();
+
+        assert_eq!(cpu.regs[10], 0x7fff_ffff);
+        assert_eq!(cpu.csr.load(csr::FFLAGS), fpu::FLAG_NV);
+        assert_eq!((cpu.csr.load(csr::MSTATUS) & csr::MASK_FS) >> 13, csr::FS_DIRTY);
+        assert_ne!(cpu.csr.load(csr::MSTATUS) & csr::MASK_SD, 0);
+    }
+
+    #[test]
+    fn test_execute_fp_illegal() {
+        let mut cpu = Cpu::new(Vec::new());
+
+        // A dynamic rounding mode with a reserved value in frm is illegal
+        cpu.csr.store(csr::FRM, 0b101);
+        assert_eq!(execute_machine_code(&mut cpu, encode_fp(0x01, 2, 1, 0b111, 3)), Err(errors::Exception::IllegalInstruction(0)));
+
+        // With mstatus.FS Off, both FP instructions and the FP CSRs are illegal
+        cpu.csr.store(csr::MSTATUS, 0);
+        assert_eq!(execute_machine_code(&mut cpu, encode_fp(0x01, 2, 1, 0, 3)), Err(errors::Exception::IllegalInstruction(0)));
+        // csrrc a0, fcsr, zero
+        let csrrc = (csr::FCSR as u32) << 20 | (0b011 << 12) | (10 << 7) | 0x73;
+        assert_eq!(execute_machine_code(&mut cpu, csrrc), Err(errors::Exception::IllegalInstruction(0)));
+    }
+
+    #[test]
+    fn test_big_endian_data_accesses() {
+        let mut cpu = Cpu::new(Vec::new());
+        // With LCR.DLAB set, the UART divisor latch is a pair of plain storage bytes
+        cpu.bus.store(constants::UART_BASE + uart::UART_LCR_INDEX, 8, uart::UART_LCR_DLAB as u64).unwrap();
+        let addr = constants::UART_BASE + uart::UART_DLL_INDEX;
+
+        cpu.csr.store(csr::MSTATUS, cpu.csr.load(csr::MSTATUS) | csr::MASK_MBE);
+        cpu.store(addr, 16, 0x1234).unwrap();
+        assert_eq!(cpu.load(addr, 16), Ok(0x1234));
+        // The most significant byte went to the lowest address
+        assert_eq!(cpu.bus.load(addr, 8), Ok(0x12));
+
+        // MBE only applies to M-mode accesses
+        cpu.mode = Supervisor;
+        assert_eq!(cpu.load(addr, 16), Ok(0x3412));
+    }
+
+    fn encode_load(funct3: u32, rd: u32, rs1: u32, imm: i32) -> u32 {
+        return ((imm as u32 & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | 0x03;
+    }
+
+    fn encode_store(funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+        let imm = imm as u32;
+        return (((imm >> 5) & 0x7f) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1f) << 7) | 0x23;
+    }
+
+    #[test]
+    fn test_reset_state() {
+        let cpu = Cpu::new(Vec::new());
+        assert_eq!(cpu.pc, constants::DRAM_BASE);
+        assert_eq!(cpu.regs[2], constants::DRAM_BASE + constants::DRAM_SIZE);
+
+        let cpu = Cpu::with_reset_vector(Vec::new(), constants::DRAM_BASE + 0x1000);
+        assert_eq!(cpu.pc, constants::DRAM_BASE + 0x1000);
+    }
+
+    #[test]
+    fn test_execute_loads_stores() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.regs[5] = constants::DRAM_BASE + 0x100;
+        cpu.regs[6] = 0x8000_0000_ffff_ff80;
+
+        // sd t1, 8(t0)
+        execute_machine_code(&mut cpu, encode_store(0x3, 5, 6, 8)).unwrap();
+        // lb t2, 8(t0) sign extends the lowest byte
+        execute_machine_code(&mut cpu, encode_load(0x0, 7, 5, 8)).unwrap();
+        assert_eq!(cpu.regs[7], 0xffff_ffff_ffff_ff80);
+        // lwu t2, 12(t0) reads the upper word without sign extension
+        execute_machine_code(&mut cpu, encode_load(0x6, 7, 5, 12)).unwrap();
+        assert_eq!(cpu.regs[7], 0x8000_0000);
+        // ld t2, 8(t0)
+        execute_machine_code(&mut cpu, encode_load(0x3, 7, 5, 8)).unwrap();
+        assert_eq!(cpu.regs[7], 0x8000_0000_ffff_ff80);
+
+        // Accesses outside of DRAM fault instead of panicking
+        cpu.regs[5] = 0x10;
+        assert_eq!(execute_machine_code(&mut cpu, encode_load(0x3, 7, 5, 0)), Err(errors::Exception::LoadAccessFault(0x10)));
+        assert_eq!(execute_machine_code(&mut cpu, encode_store(0x3, 5, 6, 0)), Err(errors::Exception::StoreAMOAccessFault(0x10)));
+    }
+
+    #[test]
+    fn test_store_breaks_reservation() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.regs[5] = constants::DRAM_BASE + 0x200;
+        cpu.regs[6] = 5;
+
+        // lr.d t3, (t0)
+        let lr_d = u32::from_str_radix("00010000000000101011111000101111", 2).unwrap();
+        // sc.d t3, t1, (t0)
+        let sc_d = u32::from_str_radix("00011000011000101011111000101111", 2).unwrap();
+
+        // A store to the reserved doubleword breaks the reservation
+        execute_machine_code(&mut cpu, lr_d).unwrap();
+        cpu.store(constants::DRAM_BASE + 0x204, 32, 0).unwrap();
+        execute_machine_code(&mut cpu, sc_d).unwrap();
+        assert_eq!(cpu.regs[28], 1);
+        assert_eq!(cpu.load(constants::DRAM_BASE + 0x200, 64), Ok(0));
+    }
+
+    #[test]
+    fn test_run_elf() {
+        // The add-addi program, then jalr zero, 0(zero) to leave the image, followed by 8 bytes
+        // of BSS
+        let code = [0x93, 0x0e, 0x50, 0x00, 0x13, 0x0f, 0x50, 0x02, 0xb3, 0x0f, 0xdf, 0x01, 0x67, 0x00, 0x00, 0x00];
+        let entry = constants::DRAM_BASE + 0x1000;
+        let elf = elf::Elf::parse(&elf::tests::build_elf(entry, &code, 8)).unwrap();
+        let mut cpu = Cpu::from_elf(&elf).unwrap();
+        assert_eq!(cpu.image_end, entry + 24);
+        assert_eq!(cpu.load(entry + 16, 64), Ok(0));
+
+        assert_eq!(cpu.pc, entry);
+        cpu.run();
+        assert_eq!(cpu.regs[31], 42);
+    }
+
+    #[test]
+    fn test_elf_segment_outside_memory() {
+        let elf = elf::Elf::parse(&elf::tests::build_elf(0x100, &[0x13, 0, 0, 0], 0)).unwrap();
+        assert!(matches!(Cpu::from_elf(&elf), Err(elf::ElfError::SegmentOutOfRange(0x100))));
+
+        // A BSS far larger than memory is refused before anything is allocated for it
+        let elf = elf::Elf::parse(&elf::tests::build_elf(constants::DRAM_BASE, &[0x13, 0, 0, 0], 1 << 60)).unwrap();
+        assert!(matches!(Cpu::from_elf(&elf), Err(elf::ElfError::SegmentOutOfRange(constants::DRAM_BASE))));
+    }
+
+    fn encode_csr(funct3: u32, rd: u32, rs1: u32, csr: usize) -> u32 {
+        return ((csr as u32) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | 0x73;
+    }
+
+    #[test]
+    fn test_execute_csr_instructions() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.regs[5] = 0b1010;
+
+        // csrrw t1, mscratch, t0
+        execute_machine_code(&mut cpu, encode_csr(0x1, 6, 5, csr::MSCRATCH)).unwrap();
+        assert_eq!((cpu.regs[6], cpu.csr.load(csr::MSCRATCH)), (0, 0b1010));
+        // csrrsi t1, mscratch, 1
+        execute_machine_code(&mut cpu, encode_csr(0x6, 6, 1, csr::MSCRATCH)).unwrap();
+        assert_eq!((cpu.regs[6], cpu.csr.load(csr::MSCRATCH)), (0b1010, 0b1011));
+        // csrrci t1, mscratch, 2
+        execute_machine_code(&mut cpu, encode_csr(0x7, 6, 2, csr::MSCRATCH)).unwrap();
+        assert_eq!((cpu.regs[6], cpu.csr.load(csr::MSCRATCH)), (0b1011, 0b1001));
+        // csrrs t1, mscratch, t0
+        execute_machine_code(&mut cpu, encode_csr(0x2, 6, 5, csr::MSCRATCH)).unwrap();
+        assert_eq!((cpu.regs[6], cpu.csr.load(csr::MSCRATCH)), (0b1001, 0b1011));
+        // csrrwi zero, mscratch, 31
+        execute_machine_code(&mut cpu, encode_csr(0x5, 0, 31, csr::MSCRATCH)).unwrap();
+        assert_eq!((cpu.regs[0], cpu.csr.load(csr::MSCRATCH)), (0, 31));
+    }
+
+    #[test]
+    fn test_execute_csrrs_sip() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.mode = Supervisor;
+        cpu.csr.store(csr::MIDELEG, csr::MASK_SSIP | csr::MASK_STIP);
+        cpu.csr.store(csr::MIE, csr::MASK_MTIP);
+        cpu.csr.store(csr::MIP, csr::MASK_MTIP);
+        cpu.regs[5] = csr::MASK_SSIP | csr::MASK_STIP;
+
+        // csrrs t1, sip, t0 only sets SSIP, leaving the rest of mip alone
+        execute_machine_code(&mut cpu, encode_csr(0x2, 6, 5, csr::SIP)).unwrap();
+        assert_eq!(cpu.regs[6], 0);
+        assert_eq!(cpu.csr.load(csr::MIP), csr::MASK_MTIP | csr::MASK_SSIP);
+        assert_eq!(cpu.csr.load(csr::SIP), csr::MASK_SSIP);
+    }
+
+    #[test]
+    fn test_execute_csr_access_checks() {
+        let mut cpu = Cpu::new(Vec::new());
+        let illegal = Err(errors::Exception::IllegalInstruction(0));
+
+        // Reading a read-only CSR is fine as long as rs1 = x0 keeps CSRRS from writing it
+        execute_machine_code(&mut cpu, encode_csr(0x2, 6, 0, csr::MHARTID)).unwrap();
+        assert_eq!(execute_machine_code(&mut cpu, encode_csr(0x2, 6, 5, csr::MHARTID)), illegal);
+        assert_eq!(execute_machine_code(&mut cpu, encode_csr(0x1, 0, 0, csr::MHARTID)), illegal);
+        assert_eq!(execute_machine_code(&mut cpu, encode_csr(0x5, 0, 0, csr::MHARTID)), illegal);
+
+        // Machine level CSRs are out of reach from S-mode, supervisor ones from U-mode
+        cpu.mode = Supervisor;
+        assert_eq!(execute_machine_code(&mut cpu, encode_csr(0x2, 6, 0, csr::MSTATUS)), illegal);
+        execute_machine_code(&mut cpu, encode_csr(0x2, 6, 0, csr::SSTATUS)).unwrap();
+        cpu.mode = User;
+        assert_eq!(execute_machine_code(&mut cpu, encode_csr(0x2, 6, 0, csr::SSTATUS)), illegal);
+        execute_machine_code(&mut cpu, encode_csr(0x2, 6, 0, csr::FCSR)).unwrap();
+    }
+
+    #[test]
+    fn test_ecall_mret_round_trip() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.csr.store(csr::MTVEC, constants::DRAM_BASE + 0x801); // Vectored mode
+        cpu.csr.store(csr::MSTATUS, cpu.csr.load(csr::MSTATUS) | csr::MASK_MIE);
+        cpu.mode = User;
+        cpu.pc = constants::DRAM_BASE + 0x100;
+
+        // ecall
+        let error = execute_machine_code(&mut cpu, 0x0000_0073).unwrap_err();
+        assert_eq!(error, errors::Exception::EnvironmentCallFromUMode(0));
+        cpu.handle_error(error);
+
+        let status = cpu.csr.load(csr::MSTATUS);
+        assert_eq!(cpu.mode, Machine);
+        // Exceptions go to the vector base even in vectored mode
+        assert_eq!(cpu.pc, constants::DRAM_BASE + 0x800);
+        assert_eq!(cpu.csr.load(csr::MCAUSE), 8);
+        assert_eq!(cpu.csr.load(csr::MEPC), constants::DRAM_BASE + 0x100);
+        assert_eq!((status & csr::MASK_MPP) >> 11, User);
+        assert_eq!((status & csr::MASK_MIE, status & csr::MASK_MPIE), (0, csr::MASK_MPIE));
+
+        // The handler skips the ecall, then mret
+        cpu.csr.store(csr::MEPC, cpu.csr.load(csr::MEPC) + 4);
+        assert_eq!(execute_machine_code(&mut cpu, 0x3020_0073), Ok(constants::DRAM_BASE + 0x104));
+        let status = cpu.csr.load(csr::MSTATUS);
+        assert_eq!(cpu.mode, User);
+        assert_eq!((status & csr::MASK_MIE, status & csr::MASK_MPIE), (csr::MASK_MIE, csr::MASK_MPIE));
+
+        // mret is only available in M-mode
+        assert_eq!(execute_machine_code(&mut cpu, 0x3020_0073), Err(errors::Exception::IllegalInstruction(0)));
+    }
+
+    #[test]
+    fn test_execute_sret() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.mode = Supervisor;
+        cpu.csr.store(csr::SEPC, 0x2002);
+        cpu.csr.store(csr::SSTATUS, csr::MASK_SPIE | csr::MASK_SPP);
+
+        // sret returns to S-mode, as SPP says, and enables interrupts again from SPIE
+        assert_eq!(execute_machine_code(&mut cpu, 0x1020_0073), Ok(0x2002));
+        let status = cpu.csr.load(csr::SSTATUS);
+        assert_eq!(cpu.mode, Supervisor);
+        assert_eq!(status & (csr::MASK_SIE | csr::MASK_SPIE | csr::MASK_SPP), csr::MASK_SIE | csr::MASK_SPIE);
+
+        // With TSR set, sret traps in S-mode
+        cpu.csr.store(csr::MSTATUS, cpu.csr.load(csr::MSTATUS) | csr::MASK_TSR);
+        assert_eq!(execute_machine_code(&mut cpu, 0x1020_0073), Err(errors::Exception::IllegalInstruction(0)));
+    }
+
+    #[test]
+    fn test_execute_ebreak_wfi_sfence() {
+        let mut cpu = Cpu::new(Vec::new());
+        let illegal = Err(errors::Exception::IllegalInstruction(0));
+        cpu.pc = 0x1000;
+
+        // ebreak reports its own address
+        assert_eq!(execute_machine_code(&mut cpu, 0x0010_0073), Err(errors::Exception::Breakpoint(0x1000)));
+
+        // wfi stalls the hart in M-mode
+        assert_eq!(execute_machine_code(&mut cpu, 0x1050_0073), Ok(0x1004));
+        assert!(cpu.waiting_for_interrupt);
+        cpu.waiting_for_interrupt = false;
+
+        // but TW and TVM trap wfi and sfence.vma / satp accesses in S-mode
+        cpu.csr.store(csr::MSTATUS, cpu.csr.load(csr::MSTATUS) | csr::MASK_TW | csr::MASK_TVM);
+        cpu.mode = Supervisor;
+        assert_eq!(execute_machine_code(&mut cpu, 0x1050_0073), illegal);
+        assert_eq!(execute_machine_code(&mut cpu, 0x1200_0073), illegal);
+        assert_eq!(execute_machine_code(&mut cpu, encode_csr(0x2, 6, 0, csr::SATP)), illegal);
+        assert!(!cpu.waiting_for_interrupt);
+
+        // and they are never allowed in U-mode
+        cpu.csr.store(csr::MSTATUS, 0);
+        cpu.mode = User;
+        assert_eq!(execute_machine_code(&mut cpu, 0x1050_0073), illegal);
+        assert_eq!(execute_machine_code(&mut cpu, 0x1200_0073), illegal);
+    }
+
+    #[test]
+    fn test_wfi_wakes_on_disabled_interrupt() {
+        // wfi, then jalr zero, 0(zero) to leave the image
+        let mut cpu = Cpu::new(vec![0x73, 0x00, 0x50, 0x10, 0x67, 0x00, 0x00, 0x00]);
+        // A software interrupt is pending and enabled in mie, but not globally with mstatus.MIE
+        cpu.csr.store(csr::MIE, csr::MASK_MSIP);
+        cpu.bus.store(constants::CLINT_BASE + clint::CLINT_MSIP, 32, 1).unwrap();
+
+        cpu.run();
+
+        assert!(!cpu.waiting_for_interrupt);
+        assert_eq!(cpu.pc, 0);
+        assert_eq!(cpu.csr.load(csr::MCAUSE), 0);
+    }
+
+    #[test]
+    fn test_clint_timer_interrupt() {
+        // wfi, then jalr zero, 0(zero) to leave the image
+        let mut cpu = Cpu::new(vec![0x73, 0x00, 0x50, 0x10, 0x67, 0x00, 0x00, 0x00]);
+        cpu.csr.store(csr::MSTATUS, cpu.csr.load(csr::MSTATUS) | csr::MASK_MIE);
+        cpu.csr.store(csr::MIE, csr::MASK_MTIP);
+        cpu.bus.store(constants::CLINT_BASE + clint::CLINT_MTIMECMP, 64, 10).unwrap();
+
+        // The trap to mtvec (0) ends the run once mtime reaches mtimecmp
+        cpu.run();
+
+        assert_eq!(cpu.csr.load(csr::MCAUSE), interrupt::Interrupt::MachineTimerInterrupt.code());
+        assert!(cpu.bus.load(constants::CLINT_BASE + clint::CLINT_MTIME, 64).unwrap() >= 10);
+        assert_eq!(cpu.pc, 0);
+    }
+
+    #[test]
+    fn test_timer_interrupt_from_user_mode() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.mode = User;
+        cpu.pc = constants::DRAM_BASE + 0x100;
+        cpu.csr.store(csr::MTVEC, constants::DRAM_BASE + 0x800);
+        cpu.csr.store(csr::MIE, csr::MASK_MTIP);
+        cpu.bus.store(constants::CLINT_BASE + clint::CLINT_MTIMECMP, 64, 0).unwrap();
+
+        // Machine interrupts are always taken below M, whatever mstatus.MIE says
+        cpu.update_device_interrupts();
+        let interrupt = cpu.check_pending_interrupt().unwrap();
+        cpu.handle_interrupt(interrupt);
+
+        assert_eq!(cpu.mode, Machine);
+        assert_eq!(cpu.pc, constants::DRAM_BASE + 0x800);
+        assert_eq!(cpu.csr.load(csr::MEPC), constants::DRAM_BASE + 0x100);
+        assert_eq!(cpu.csr.load(csr::MCAUSE), interrupt::Interrupt::MachineTimerInterrupt.code());
+        // MPP holds U, and MPIE the MIE of before, which was clear
+        let status = cpu.csr.load(csr::MSTATUS);
+        assert_eq!(status & csr::MASK_MPP, User << 11);
+        assert_eq!(status & (csr::MASK_MPIE | csr::MASK_MIE), 0);
+    }
+
+    #[test]
+    fn test_interrupt_enables_by_privilege() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.csr.store(csr::MIE, csr::MASK_MTIP | csr::MASK_STIP);
+        cpu.csr.store(csr::MIDELEG, csr::MASK_STIP);
+
+        // In S with SIE clear, the machine timer still comes first, but the delegated one waits
+        cpu.mode = Supervisor;
+        cpu.csr.store(csr::MIP, csr::MASK_MTIP | csr::MASK_STIP);
+        assert!(matches!(cpu.check_pending_interrupt(), Some(interrupt::Interrupt::MachineTimerInterrupt)));
+        assert!(cpu.check_pending_interrupt().is_none());
+        cpu.csr.store(csr::MSTATUS, csr::MASK_SIE);
+        assert!(matches!(cpu.check_pending_interrupt(), Some(interrupt::Interrupt::SupervisorTimerInterrupt)));
+
+        // M never takes a delegated interrupt, even with MIE set
+        cpu.mode = Machine;
+        cpu.csr.store(csr::MSTATUS, csr::MASK_MIE | csr::MASK_SIE);
+        cpu.csr.store(csr::MIP, csr::MASK_STIP);
+        assert!(cpu.check_pending_interrupt().is_none());
+    }
+
+    #[test]
+    fn test_plic_external_interrupt() {
+        let mut cpu = Cpu::new(Vec::new());
+        let claim = constants::PLIC_BASE + plic::PLIC_CONTEXT + plic::PLIC_CONTEXT_STRIDE + plic::PLIC_CLAIM;
+        cpu.store(constants::PLIC_BASE + plic::PLIC_PRIORITY + 4 * uart::UART_IRQ, 32, 1).unwrap();
+        cpu.store(constants::PLIC_BASE + plic::PLIC_ENABLE + plic::PLIC_ENABLE_STRIDE, 32, 1 << uart::UART_IRQ).unwrap();
+
+        cpu.bus.device_mut::<plic::Plic>().unwrap().update(uart::UART_IRQ, true);
+        cpu.update_device_interrupts();
+        assert_eq!(cpu.csr.load(csr::MIP), csr::MASK_SEIP);
+
+        // SEIP stays up until the source is claimed, even once the trap cleared it
+        cpu.csr.store(csr::MIP, 0);
+        cpu.update_device_interrupts();
+        assert_eq!(cpu.csr.load(csr::MIP), csr::MASK_SEIP);
+        assert_eq!(cpu.load(claim, 32), Ok(uart::UART_IRQ));
+        cpu.update_device_interrupts();
+        assert_eq!(cpu.csr.load(csr::MIP), 0);
+        cpu.store(claim, 32, uart::UART_IRQ).unwrap();
+    }
+
+    #[test]
+    fn test_software_seip() {
+        let mut cpu = Cpu::new(Vec::new());
+        cpu.regs[5] = csr::MASK_SEIP;
+
+        // csrrs zero, mip, t0 raises SEIP with no device behind it, and it survives the update
+        execute_machine_code(&mut cpu, encode_csr(0x2, 0, 5, csr::MIP)).unwrap();
+        cpu.update_device_interrupts();
+        assert_eq!(cpu.csr.load(csr::MIP), csr::MASK_SEIP);
+
+        // With the PLIC also asserting it, clearing the software bit leaves the PLIC's
+        cpu.store(constants::PLIC_BASE + plic::PLIC_PRIORITY + 4 * uart::UART_IRQ, 32, 1).unwrap();
+        cpu.store(constants::PLIC_BASE + plic::PLIC_ENABLE + plic::PLIC_ENABLE_STRIDE, 32, 1 << uart::UART_IRQ).unwrap();
+        cpu.bus.device_mut::<plic::Plic>().unwrap().update(uart::UART_IRQ, true);
+        // csrrc t1, mip, t0
+        execute_machine_code(&mut cpu, encode_csr(0x3, 6, 5, csr::MIP)).unwrap();
+        assert_eq!(cpu.regs[6], csr::MASK_SEIP);
+        assert!(!cpu.software_seip);
+        cpu.update_device_interrupts();
+        assert_eq!(cpu.csr.load(csr::MIP), csr::MASK_SEIP);
+
+        // And once the source is claimed, SEIP is clear
+        let claim = constants::PLIC_BASE + plic::PLIC_CONTEXT + plic::PLIC_CONTEXT_STRIDE + plic::PLIC_CLAIM;
+        assert_eq!(cpu.load(claim, 32), Ok(uart::UART_IRQ));
+        cpu.update_device_interrupts();
+        assert_eq!(cpu.csr.load(csr::MIP), 0);
+    }
+
+    #[test]
+    fn test_virtual_memory() {
+        let mut cpu = Cpu::new(Vec::new());
+        let root = constants::DRAM_BASE + 0x10_0000;
+        // The first virtual gigapage maps to the start of DRAM, for user code
+        let pte = ((constants::DRAM_BASE >> 12) << 10) | mmu::PTE_R | mmu::PTE_W | mmu::PTE_X | mmu::PTE_U
+            | mmu::PTE_A | mmu::PTE_D | mmu::PTE_V;
+        cpu.bus.store(root, 64, pte).unwrap();
+        cpu.csr.store(csr::SATP, (csr::SATP_MODE_SV39 << 60) | (root >> 12));
+
+        cpu.mode = User;
+        cpu.store(0x100, 64, 0x1234).unwrap();
+        assert_eq!(cpu.bus.load(constants::DRAM_BASE + 0x100, 64), Ok(0x1234));
+        cpu.pc = 0x4000_0000;
+        assert_eq!(cpu.fetch(), Err(errors::Exception::InstructionPageFault(0x4000_0000)));
+
+        // M-mode accesses are physical, unless MPRV applies the privilege in MPP to them
+        cpu.mode = Machine;
+        assert_eq!(cpu.load(0x100, 64), Err(errors::Exception::LoadAccessFault(0x100)));
+        cpu.csr.store(csr::MSTATUS, (cpu.csr.load(csr::MSTATUS) & !csr::MASK_MPP) | csr::MASK_MPRV);
+        assert_eq!(cpu.load(0x100, 64), Ok(0x1234));
+
+        // Writing an unsupported paging mode leaves satp alone
+        cpu.csr.store(csr::SATP, 15 << 60);
+        assert_eq!(cpu.csr.load(csr::SATP) >> 60, csr::SATP_MODE_SV39);
+    }
+
+    #[test]
+    fn test_sfence_vma_flushes_tlb() {
+        let mut cpu = Cpu::new(Vec::new());
+        let root = constants::DRAM_BASE + 0x10_0000;
+        let pte = ((constants::DRAM_BASE >> 12) << 10) | mmu::PTE_R | mmu::PTE_W | mmu::PTE_U | mmu::PTE_A | mmu::PTE_D | mmu::PTE_V;
+        cpu.bus.store(root, 64, pte).unwrap();
+        cpu.csr.store(csr::SATP, (csr::SATP_MODE_SV39 << 60) | (root >> 12));
+        cpu.mode = User;
+
+        cpu.load(0x100, 64).unwrap();
+        cpu.load(0x100, 64).unwrap();
+        assert_eq!((cpu.tlb.hits, cpu.tlb.misses), (1, 1));
+
+        // sfence.vma zero, zero
+        cpu.mode = Supervisor;
+        execute_machine_code(&mut cpu, 0x1200_0073).unwrap();
+        cpu.mode = User;
+        cpu.load(0x100, 64).unwrap();
+        assert_eq!((cpu.tlb.hits, cpu.tlb.misses), (1, 2));
+
+        // csrrs zero, satp, zero only reads satp, but writing it flushes the TLB
+        cpu.mode = Supervisor;
+        execute_machine_code(&mut cpu, encode_csr(0x2, 0, 0, csr::SATP)).unwrap();
+        cpu.mode = User;
+        cpu.load(0x100, 64).unwrap();
+        assert_eq!((cpu.tlb.hits, cpu.tlb.misses), (2, 2));
+        cpu.mode = Supervisor;
+        cpu.regs[5] = cpu.csr.load(csr::SATP);
+        execute_machine_code(&mut cpu, encode_csr(0x1, 0, 5, csr::SATP)).unwrap();
+        cpu.mode = User;
+        cpu.load(0x100, 64).unwrap();
+        assert_eq!((cpu.tlb.hits, cpu.tlb.misses), (2, 3));
+    }
+
+    #[test]
+    fn test_physical_memory_protection() {
+        let mut cpu = Cpu::new(Vec::new());
+        let base = constants::DRAM_BASE;
+        // Entry 0: the first 64KiB of DRAM, read/execute. Entry 1: locked NA4 word at its end, no access
+        let napot = (base >> 2) | 0x1fff;
+        cpu.csr.store(csr::PMPADDR0, napot);
+        cpu.csr.store(csr::PMPADDR0 + 1, (base + 0x1_0000) >> 2);
+        let cfg = ((pmp::PMP_NAPOT << pmp::PMP_A_SHIFT) | pmp::PMP_R | pmp::PMP_X) as u64
+            | (((pmp::PMP_NA4 << pmp::PMP_A_SHIFT) | pmp::PMP_L) as u64) << 8;
+        cpu.csr.store(csr::PMPCFG0, cfg);
+
+        cpu.mode = User;
+        assert_eq!(cpu.load(base, 64), Ok(0));
+        assert_eq!(cpu.store(base, 64, 1), Err(errors::Exception::StoreAMOAccessFault(base)));
+        cpu.pc = base + 0x2_0000;
+        assert_eq!(cpu.fetch(), Err(errors::Exception::InstructionAccessFault(base + 0x2_0000)));
+
+        // M-mode skips the unlocked entry but not the locked one
+        cpu.mode = Machine;
+        cpu.store(base, 64, 1).unwrap();
+        assert_eq!(cpu.load(base + 0x1_0000, 32), Err(errors::Exception::LoadAccessFault(base + 0x1_0000)));
+
+        // The odd pmpcfg registers don't exist on RV64
+        assert_eq!(execute_machine_code(&mut cpu, encode_csr(0x2, 6, 0, csr::PMPCFG0 + 1)),
+            Err(errors::Exception::IllegalInstruction(0)));
+    }
+
+    #[test]
+    fn test_monitor_commands() {
+        let backend = uart::backends::MemoryBackend::new();
+        let mut cpu = Cpu::with_devices(Vec::new(), constants::RESET_VECTOR, clint::TimeSource::Instructions,
+            Box::new(backend.clone()));
+        cpu.regs[10] = 0x2a;
+
+        assert!(cpu.monitor("regs"));
+        let output = String::from_utf8(backend.output()).unwrap();
+        assert!(output.starts_with("pc  0x0000000080000000  mode 3\n"));
+        assert!(output.contains("x10 0x000000000000002a"));
+        assert!(output.ends_with(uart::console::MONITOR_PROMPT));
+        assert!(!cpu.monitor("quit"));
+    }
 }
@@ -1,35 +1,443 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{error::Error, fmt};
+use super::clint;
 use super::constants::*;
 use super::dram;
 use super::errors;
+use super::plic;
 use super::uart;
 
+// A memory-mapped peripheral. Accesses are given as an offset from the start of the device's
+// range, and any error is reported to the hart as an access fault at the physical address
+pub trait Device: Any + Send {
+    fn load(&mut self, offset: u64, size: u64) -> Result<u64, errors::Exception>;
+    fn store(&mut self, offset: u64, size: u64, value: u64) -> Result<(), errors::Exception>;
+
+    // Copies a block of bytes in, such as a program image
+    fn write_bytes(&mut self, offset: u64, data: &[u8]) -> Result<(), errors::Exception> {
+        for (i, byte) in data.iter().enumerate() {
+            self.store(offset + i as u64, 8, *byte as u64)?;
+        }
+        return Ok(());
+    }
+
+    fn read_bytes(&mut self, offset: u64, data: &mut [u8]) -> Result<(), errors::Exception> {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.load(offset + i as u64, 8)? as u8;
+        }
+        return Ok(());
+    }
+
+    fn reset(&mut self) {}
+
+    // Called once the device is attached. Devices with host threads start them here, and have
+    // them ring the doorbell whenever they deliver something
+    fn connect(&mut self, _doorbell: &Doorbell) {}
+
+    // Called once per retired instruction
+    fn tick(&mut self) {}
+
+    // The level of the device's interrupt line into the PLIC, for devices attached with one. It
+    // is only looked at after an access to the device or a ring of the doorbell
+    fn interrupt_pending(&self) -> bool {
+        return false;
+    }
+
+    // Bits of mip the device drives directly, like the CLINT's timer and software interrupts
+    fn hart_interrupts(&self) -> u64 {
+        return 0;
+    }
+
+    // For interrupt controllers, the level of each device's line as it changes
+    fn set_source_level(&mut self, _source: u64, _level: bool) {}
+
+    // Whether the device has work needing the rest of the bus, like a virtqueue notification.
+    // Looked at under the same conditions as the interrupt line
+    fn dma_pending(&self) -> bool {
+        return false;
+    }
+
+    // Does that work. The device itself is detached from the bus meanwhile
+    fn dma(&mut self, _bus: &mut Bus) {}
+}
+
+// Tells the bus that a device's state changed behind its back, such as input arriving from the
+// host, so its interrupt line and DMA need another look
+#[derive(Clone, Default)]
+pub struct Doorbell {
+    rung: Arc<AtomicBool>,
+}
+
+impl Doorbell {
+    pub fn ring(&self) {
+        self.rung.store(true, Ordering::Release);
+    }
+
+    // Whether it was rung since the last call
+    pub fn take(&self) -> bool {
+        return self.rung.swap(false, Ordering::Acquire);
+    }
+}
+
+// Stands in for a device while it is taken off the bus for DMA
+struct Detached;
+
+impl Device for Detached {
+    fn load(&mut self, offset: u64, _size: u64) -> Result<u64, errors::Exception> {
+        return Err(errors::Exception::LoadAccessFault(offset));
+    }
+
+    fn store(&mut self, offset: u64, _size: u64, _value: u64) -> Result<(), errors::Exception> {
+        return Err(errors::Exception::StoreAMOAccessFault(offset));
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AttachError {
+    Empty,
+    // The range would run past the end of the address space
+    OutOfRange,
+    // The range would overlap the device already attached at the given base
+    Overlap(u64),
+    // There is no PLIC source with this number, source 0 being reserved
+    BadIrq(u64),
+}
+
+impl Error for AttachError {}
+
+impl fmt::Display for AttachError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match(self) {
+            AttachError::Empty => write!(f, "device range is empty"),
+            AttachError::OutOfRange => write!(f, "device range runs past the end of the address space"),
+            AttachError::Overlap(base) => write!(f, "device range overlaps the device at {:#x}", base),
+            AttachError::BadIrq(irq) => write!(f, "no interrupt source {}", irq),
+        }
+    }
+}
+
+struct Region {
+    base: u64,
+    // Inclusive, so a range can reach the top of the address space
+    end: u64,
+    irq: Option<u64>,
+    device: Box<dyn Device>,
+}
+
 pub struct Bus {
-    pub dram: dram::Dram,
-    pub uart: uart::Uart,
+    // Sorted by base address, without overlaps
+    regions: Vec<Region>,
+    // The first region holding each type of device, and the interrupt controller in particular
+    types: HashMap<TypeId, usize>,
+    plic: Option<usize>,
+    doorbell: Doorbell,
+    // Set by accesses to devices with an interrupt line, whose state may have changed
+    changed: bool,
 }
 
 impl Bus {
-    pub fn new(dram: dram::Dram) -> Bus {
-        return Self { 
-            dram: dram, 
-            uart: uart::Uart::new() 
-        };
+    pub fn new() -> Bus {
+        return Self { regions: Vec::new(), types: HashMap::new(), plic: None, doorbell: Doorbell::default(), changed: false };
+    }
+
+    // The standard memory map, like QEMU's virt machine
+    pub fn with_devices(dram: dram::Dram, time_source: clint::TimeSource,
+            uart_backend: Box<dyn uart::backends::UartBackend>) -> Bus {
+        let mut bus = Self::new();
+        bus.attach(CLINT_BASE, CLINT_SIZE, None, Box::new(clint::Clint::new(time_source))).unwrap();
+        bus.attach(PLIC_BASE, PLIC_SIZE, None, Box::new(plic::Plic::new())).unwrap();
+        bus.attach(UART_BASE, UART_SIZE, Some(uart::UART_IRQ), Box::new(uart::Uart::with_backend(uart_backend))).unwrap();
+        bus.attach(DRAM_BASE, DRAM_SIZE, None, Box::new(dram)).unwrap();
+        return bus;
+    }
+
+    // Maps a device at [base, base + size), optionally raising the PLIC source irq
+    pub fn attach(&mut self, base: u64, size: u64, irq: Option<u64>, device: Box<dyn Device>) -> Result<(), AttachError> {
+        if (size == 0) {
+            return Err(AttachError::Empty);
+        }
+        let end = base.checked_add(size - 1).ok_or(AttachError::OutOfRange)?;
+        if let Some(irq) = irq.filter(|irq| *irq == 0 || *irq >= plic::NUM_SOURCES as u64) {
+            return Err(AttachError::BadIrq(irq));
+        }
+        let index = self.regions.partition_point(|region| region.base < base);
+        if let Some(next) = self.regions.get(index) {
+            if (next.base <= end) {
+                return Err(AttachError::Overlap(next.base));
+            }
+        }
+        if let Some(previous) = index.checked_sub(1).map(|i| &self.regions[i]) {
+            if (previous.end >= base) {
+                return Err(AttachError::Overlap(previous.base));
+            }
+        }
+        self.regions.insert(index, Region { base: base, end: end, irq: irq, device: device });
+        self.regions[index].device.connect(&self.doorbell);
+
+        // Later regions moved up by one
+        self.types.clear();
+        for (i, region) in self.regions.iter().enumerate().rev() {
+            self.types.insert((region.device.as_ref() as &dyn Any).type_id(), i);
+        }
+        self.plic = self.types.get(&TypeId::of::<plic::Plic>()).copied();
+        self.changed = true;
+        return Ok(());
     }
 
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, errors::Exception> {
-        match (addr) {
-            DRAM_BASE..=DRAM_END => self.dram.load(addr, size),
-            UART_BASE..=UART_END => self.uart.load(addr, size),
-            _ => Err(errors::Exception::LoadAccessFault(addr)),
+    fn region(&mut self, addr: u64) -> Option<&mut Region> {
+        let index = self.regions.partition_point(|region| region.base <= addr).checked_sub(1)?;
+        let region = &mut self.regions[index];
+        if (addr > region.end) {
+            return None;
+        }
+        self.changed |= region.irq.is_some();
+        return Some(region);
+    }
+
+    // The first attached device of type T
+    pub fn device<T: Device>(&self) -> Option<&T> {
+        let region = &self.regions[*self.types.get(&TypeId::of::<T>())?];
+        return (region.device.as_ref() as &dyn Any).downcast_ref::<T>();
+    }
+
+    pub fn device_mut<T: Device>(&mut self) -> Option<&mut T> {
+        let region = &mut self.regions[*self.types.get(&TypeId::of::<T>())?];
+        return (region.device.as_mut() as &mut dyn Any).downcast_mut::<T>();
+    }
+
+    // Takes &mut self since reading some device registers, like the PLIC's claim, has side effects
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, errors::Exception> {
+        let Some(region) = self.region(addr) else {
+            return Err(errors::Exception::LoadAccessFault(addr));
+        };
+        // The whole access has to fall within the device
+        if (addr.checked_add(size / 8 - 1).is_none_or(|last| last > region.end)) {
+            return Err(errors::Exception::LoadAccessFault(addr));
         }
+        return region.device.load(addr - region.base, size).map_err(|_| errors::Exception::LoadAccessFault(addr));
     }
 
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), errors::Exception> {
-        match(addr) {
-            DRAM_BASE..=DRAM_END => self.dram.store(addr, size, value),
-            UART_BASE..=UART_END => self.uart.store(addr, size, value),
-            _ => Err(errors::Exception::StoreAMOAccessFault(addr)),
+        let Some(region) = self.region(addr) else {
+            return Err(errors::Exception::StoreAMOAccessFault(addr));
+        };
+        if (addr.checked_add(size / 8 - 1).is_none_or(|last| last > region.end)) {
+            return Err(errors::Exception::StoreAMOAccessFault(addr));
+        }
+        return region.device.store(addr - region.base, size, value).map_err(|_| errors::Exception::StoreAMOAccessFault(addr));
+    }
+
+    // Reads a block of bytes from a physical address, such as a DMA buffer. It has to fall within
+    // one device
+    pub fn read_bytes(&mut self, addr: u64, data: &mut [u8]) -> Result<(), errors::Exception> {
+        let fault = errors::Exception::LoadAccessFault(addr);
+        if (data.is_empty()) {
+            return Ok(());
+        }
+        let Some(region) = self.region(addr) else {
+            return Err(fault);
+        };
+        if (addr.checked_add(data.len() as u64 - 1).is_none_or(|last| last > region.end)) {
+            return Err(fault);
+        }
+        return region.device.read_bytes(addr - region.base, data).map_err(|_| fault);
+    }
+
+    // Writes a block of bytes, such as a program image, at a physical address
+    pub fn write_bytes(&mut self, addr: u64, data: &[u8]) -> Result<(), errors::Exception> {
+        let fault = errors::Exception::StoreAMOAccessFault(addr);
+        if (data.is_empty()) {
+            return Ok(());
+        }
+        let Some(region) = self.region(addr) else {
+            return Err(fault);
+        };
+        if (addr.checked_add(data.len() as u64 - 1).is_none_or(|last| last > region.end)) {
+            return Err(fault);
+        }
+        return region.device.write_bytes(addr - region.base, data).map_err(|_| fault);
+    }
+
+    pub fn reset(&mut self) {
+        for region in self.regions.iter_mut() {
+            region.device.reset();
+        }
+        self.changed = true;
+    }
+
+    pub fn tick(&mut self) {
+        for region in self.regions.iter_mut() {
+            region.device.tick();
         }
+        self.service();
+    }
+
+    // Catches up with the devices that were accessed or rang the doorbell since the last call:
+    // does their DMA, then passes their interrupt lines on to the PLIC
+    fn service(&mut self) {
+        if (!(std::mem::take(&mut self.changed) | self.doorbell.take())) {
+            return;
+        }
+        for i in 0..self.regions.len() {
+            if (self.regions[i].device.dma_pending()) {
+                let mut device = std::mem::replace(&mut self.regions[i].device, Box::new(Detached));
+                device.dma(self);
+                self.regions[i].device = device;
+            }
+        }
+
+        let Some(plic) = self.plic else {
+            return;
+        };
+        for i in 0..self.regions.len() {
+            if let Some(irq) = self.regions[i].irq {
+                let level = self.regions[i].device.interrupt_pending();
+                self.regions[plic].device.set_source_level(irq, level);
+            }
+        }
+    }
+
+    // Passes any changed interrupt lines on to the PLIC, and returns the bits of mip the devices
+    // drive
+    pub fn update_interrupts(&mut self) -> u64 {
+        self.service();
+        return self.regions.iter().fold(0, |mip, region| mip | region.device.hart_interrupts());
     }
 }
 
+impl Default for Bus {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::csr;
+
+    // A register file of 32-bit registers with its interrupt line raised by a non-zero register 0
+    struct Registers {
+        values: [u32; 4],
+        doorbell: Doorbell,
+    }
+
+    impl Device for Registers {
+        fn load(&mut self, offset: u64, size: u64) -> Result<u64, errors::Exception> {
+            if (size != 32) {
+                return Err(errors::Exception::LoadAccessFault(offset));
+            }
+            return Ok(self.values[offset as usize / 4] as u64);
+        }
+
+        fn store(&mut self, offset: u64, size: u64, value: u64) -> Result<(), errors::Exception> {
+            if (size != 32) {
+                return Err(errors::Exception::StoreAMOAccessFault(offset));
+            }
+            self.values[offset as usize / 4] = value as u32;
+            return Ok(());
+        }
+
+        fn reset(&mut self) {
+            self.values = [0; 4];
+        }
+
+        fn connect(&mut self, doorbell: &Doorbell) {
+            self.doorbell = doorbell.clone();
+        }
+
+        fn interrupt_pending(&self) -> bool {
+            return self.values[0] != 0;
+        }
+    }
+
+    fn registers() -> Box<Registers> {
+        return Box::new(Registers { values: [0; 4], doorbell: Doorbell::default() });
+    }
+
+    #[test]
+    fn test_attach_and_access() {
+        let mut bus = Bus::new();
+        bus.attach(0x2000, 0x10, None, registers()).unwrap();
+        bus.attach(0x1000, 0x10, None, registers()).unwrap();
+
+        bus.store(0x2004, 32, 0x1234).unwrap();
+        assert_eq!(bus.load(0x2004, 32), Ok(0x1234));
+        assert_eq!(bus.load(0x1004, 32), Ok(0));
+        // Faults are reported at the physical address
+        assert_eq!(bus.load(0x2004, 8), Err(errors::Exception::LoadAccessFault(0x2004)));
+        assert_eq!(bus.load(0x1010, 32), Err(errors::Exception::LoadAccessFault(0x1010)));
+        assert_eq!(bus.load(0xfff, 32), Err(errors::Exception::LoadAccessFault(0xfff)));
+        assert_eq!(bus.store(0x200c, 64, 0), Err(errors::Exception::StoreAMOAccessFault(0x200c)));
+
+        bus.reset();
+        assert_eq!(bus.load(0x2004, 32), Ok(0));
+    }
+
+    #[test]
+    fn test_overlapping_ranges_are_rejected() {
+        let mut bus = Bus::new();
+        bus.attach(0x1000, 0x1000, None, registers()).unwrap();
+        assert_eq!(bus.attach(0x1fff, 0x10, None, registers()), Err(AttachError::Overlap(0x1000)));
+        assert_eq!(bus.attach(0x800, 0x801, None, registers()), Err(AttachError::Overlap(0x1000)));
+        assert_eq!(bus.attach(0x0, 0x10000, None, registers()), Err(AttachError::Overlap(0x1000)));
+        assert_eq!(bus.attach(0x3000, 0, None, registers()), Err(AttachError::Empty));
+        bus.attach(0x800, 0x800, None, registers()).unwrap();
+        bus.attach(0xffff_ffff_ffff_f000, 0x1000, None, registers()).unwrap();
+        assert_eq!(bus.attach(0xffff_ffff_ffff_f000, 0x2000, None, registers()), Err(AttachError::OutOfRange));
+        assert_eq!(bus.attach(0x4000, 0x10, Some(0), registers()), Err(AttachError::BadIrq(0)));
+        assert_eq!(bus.attach(0x4000, 0x10, Some(plic::NUM_SOURCES as u64), registers()), Err(AttachError::BadIrq(64)));
+    }
+
+    #[test]
+    fn test_interrupt_lines_reach_the_plic() {
+        let mut bus = Bus::new();
+        bus.attach(PLIC_BASE, PLIC_SIZE, None, Box::new(plic::Plic::new())).unwrap();
+        bus.attach(0x1000, 0x10, Some(3), registers()).unwrap();
+        // Priority 1 for source 3, enabled for the M-mode context
+        bus.store(PLIC_BASE + plic::PLIC_PRIORITY + 4 * 3, 32, 1).unwrap();
+        bus.store(PLIC_BASE + plic::PLIC_ENABLE, 32, 1 << 3).unwrap();
+
+        assert_eq!(bus.update_interrupts(), 0);
+        bus.store(0x1000, 32, 1).unwrap();
+        assert_eq!(bus.update_interrupts(), csr::MASK_MEIP);
+        assert!(bus.device::<plic::Plic>().unwrap().is_pending(3));
+    }
+
+    #[test]
+    fn test_lines_still_raised_pend_again_on_completion() {
+        let mut bus = Bus::new();
+        bus.attach(PLIC_BASE, PLIC_SIZE, None, Box::new(plic::Plic::new())).unwrap();
+        bus.attach(0x1000, 0x10, Some(3), registers()).unwrap();
+        bus.store(PLIC_BASE + plic::PLIC_PRIORITY + 4 * 3, 32, 1).unwrap();
+        bus.store(PLIC_BASE + plic::PLIC_ENABLE, 32, 1 << 3).unwrap();
+        bus.store(0x1000, 32, 1).unwrap();
+        assert_eq!(bus.update_interrupts(), csr::MASK_MEIP);
+
+        // The line stays high across the claim and the completion, with no access to the device
+        let claim = PLIC_BASE + plic::PLIC_CONTEXT + plic::PLIC_CLAIM;
+        assert_eq!(bus.load(claim, 32), Ok(3));
+        assert_eq!(bus.update_interrupts(), 0);
+        bus.store(claim, 32, 3).unwrap();
+        assert_eq!(bus.update_interrupts(), csr::MASK_MEIP);
+    }
+
+    #[test]
+    fn test_changes_behind_the_bus_need_the_doorbell() {
+        let mut bus = Bus::new();
+        bus.attach(PLIC_BASE, PLIC_SIZE, None, Box::new(plic::Plic::new())).unwrap();
+        bus.attach(0x1000, 0x10, Some(3), registers()).unwrap();
+        bus.store(PLIC_BASE + plic::PLIC_PRIORITY + 4 * 3, 32, 1).unwrap();
+        bus.store(PLIC_BASE + plic::PLIC_ENABLE, 32, 1 << 3).unwrap();
+        assert_eq!(bus.update_interrupts(), 0);
+
+        // Like a host thread delivering input, which the bus doesn't see by itself
+        bus.device_mut::<Registers>().unwrap().values[0] = 1;
+        assert_eq!(bus.update_interrupts(), 0);
+        bus.device::<Registers>().unwrap().doorbell.ring();
+        assert_eq!(bus.update_interrupts(), csr::MASK_MEIP);
+    }
+}
@@ -0,0 +1,179 @@
+use std::time::Instant;
+use super::bus;
+use super::csr;
+use super::errors;
+
+// Register offsets from CLINT_BASE, as laid out by SiFive's CLINT and the ACLINT MSWI/MTIMER
+pub const CLINT_MSIP: u64 = 0x0;
+pub const CLINT_MTIMECMP: u64 = 0x4000;
+pub const CLINT_MTIME: u64 = 0xbff8;
+
+// mtime frequency when it follows the host clock, the 10MHz QEMU's virt machine uses
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TimeSource {
+    // mtime counts retired instructions, which keeps runs deterministic
+    Instructions,
+    // mtime follows the host's monotonic clock at TIMEBASE_FREQUENCY
+    HostClock,
+}
+
+pub struct Clint {
+    source: TimeSource,
+    msip: u32,
+    mtimecmp: u64,
+    // The counter itself for Instructions, an offset added to the elapsed host time for HostClock
+    mtime: u64,
+    start: Instant,
+}
+
+impl Clint {
+    pub fn new(source: TimeSource) -> Self {
+        // mtimecmp is left at its maximum so no timer interrupt is pending out of reset
+        return Self { source: source, msip: 0, mtimecmp: u64::MAX, mtime: 0, start: Instant::now() };
+    }
+
+    pub fn source(&self) -> TimeSource {
+        return self.source;
+    }
+
+    pub fn mtime(&self) -> u64 {
+        match(self.source) {
+            TimeSource::Instructions => self.mtime,
+            TimeSource::HostClock => self.mtime.wrapping_add(self.host_ticks()),
+        }
+    }
+
+    fn host_ticks(&self) -> u64 {
+        return (self.start.elapsed().as_nanos() * TIMEBASE_FREQUENCY as u128 / 1_000_000_000) as u64;
+    }
+
+    fn set_mtime(&mut self, value: u64) {
+        match(self.source) {
+            TimeSource::Instructions => self.mtime = value,
+            TimeSource::HostClock => self.mtime = value.wrapping_sub(self.host_ticks()),
+        }
+    }
+
+    // The MSIP and MTIP bits of mip as driven by the CLINT
+    pub fn interrupts(&self) -> u64 {
+        let mut pending = 0;
+        if (self.msip & 1 != 0) {
+            pending |= csr::MASK_MSIP;
+        }
+        if (self.mtime() >= self.mtimecmp) {
+            pending |= csr::MASK_MTIP;
+        }
+        return pending;
+    }
+
+    // 64-bit registers can be accessed whole, or as two 32-bit halves
+    fn register(&self, offset: u64, size: u64) -> Option<(u64, u64)> {
+        let base = match(offset & !0x7) {
+            CLINT_MTIMECMP => CLINT_MTIMECMP,
+            CLINT_MTIME => CLINT_MTIME,
+            _ if (offset == CLINT_MSIP && size == 32) => return Some((CLINT_MSIP, 0)),
+            _ => return None,
+        };
+        match (size, offset - base) {
+            (64, 0) => Some((base, 0)),
+            (32, 0) | (32, 4) => Some((base, (offset - base) * 8)),
+            _ => None,
+        }
+    }
+}
+
+impl bus::Device for Clint {
+    fn load(&mut self, offset: u64, size: u64) -> Result<u64, errors::Exception> {
+        let Some((register, shift)) = self.register(offset, size) else {
+            return Err(errors::Exception::LoadAccessFault(offset));
+        };
+        let value = match(register) {
+            CLINT_MSIP => self.msip as u64,
+            CLINT_MTIMECMP => self.mtimecmp,
+            _ => self.mtime(),
+        };
+        return Ok((value >> shift) & (u64::MAX >> (64 - size)));
+    }
+
+    fn store(&mut self, offset: u64, size: u64, value: u64) -> Result<(), errors::Exception> {
+        let Some((register, shift)) = self.register(offset, size) else {
+            return Err(errors::Exception::StoreAMOAccessFault(offset));
+        };
+        // Only the written half of a 64-bit register changes
+        let mask = (u64::MAX >> (64 - size)) << shift;
+        let merge = |old: u64| (old & !mask) | ((value << shift) & mask);
+        match(register) {
+            // Only bit 0 of msip is implemented
+            CLINT_MSIP => self.msip = (value & 1) as u32,
+            CLINT_MTIMECMP => self.mtimecmp = merge(self.mtimecmp),
+            _ => {
+                let mtime = merge(self.mtime());
+                self.set_mtime(mtime);
+            }
+        }
+        return Ok(());
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.source);
+    }
+
+    // Called once per retired instruction
+    fn tick(&mut self) {
+        if (self.source == TimeSource::Instructions) {
+            self.mtime = self.mtime.wrapping_add(1);
+        }
+    }
+
+    fn hart_interrupts(&self) -> u64 {
+        return self.interrupts();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::bus::Device;
+
+    #[test]
+    fn test_timer_interrupt() {
+        let mut clint = Clint::new(TimeSource::Instructions);
+        assert_eq!(clint.interrupts(), 0);
+
+        clint.store(CLINT_MTIMECMP, 64, 2).unwrap();
+        clint.tick();
+        assert_eq!(clint.interrupts(), 0);
+        clint.tick();
+        assert_eq!(clint.interrupts(), csr::MASK_MTIP);
+        assert_eq!(clint.load(CLINT_MTIME, 64), Ok(2));
+
+        // Moving mtimecmp forward clears the interrupt again
+        clint.store(CLINT_MTIMECMP + 4, 32, 1).unwrap();
+        assert_eq!(clint.load(CLINT_MTIMECMP, 64), Ok((1 << 32) | 2));
+        assert_eq!(clint.interrupts(), 0);
+    }
+
+    #[test]
+    fn test_software_interrupt() {
+        let mut clint = Clint::new(TimeSource::Instructions);
+        clint.store(CLINT_MSIP, 32, 0xffff_ffff).unwrap();
+        assert_eq!(clint.load(CLINT_MSIP, 32), Ok(1));
+        assert_eq!(clint.interrupts(), csr::MASK_MSIP);
+        clint.store(CLINT_MSIP, 32, 0).unwrap();
+        assert_eq!(clint.interrupts(), 0);
+
+        assert!(clint.load(CLINT_MSIP, 64).is_err());
+        assert!(clint.store(0x8, 32, 1).is_err());
+    }
+
+    #[test]
+    fn test_host_clock() {
+        let mut clint = Clint::new(TimeSource::HostClock);
+        clint.store(CLINT_MTIME, 64, 1000).unwrap();
+        clint.tick();
+        let mtime = clint.load(CLINT_MTIME, 64).unwrap();
+        assert!((1000..1000 + TIMEBASE_FREQUENCY).contains(&mtime));
+    }
+}
@@ -0,0 +1,247 @@
+use super::errors;
+
+// Compressed registers (rd', rs1', rs2') are 3 bits wide and name x8-x15
+fn creg(inst: u32, shift: u32) -> u32 {
+    return ((inst >> shift) & 0x7) + 8;
+}
+
+fn bit(inst: u32, index: u32) -> u32 {
+    return (inst >> index) & 0x1;
+}
+
+fn bits(inst: u32, high: u32, low: u32) -> u32 {
+    return (inst >> low) & ((1 << (high - low + 1)) - 1);
+}
+
+// Sign extends the low `width` bits of value
+fn signed(value: u32, width: u32) -> i32 {
+    return ((value << (32 - width)) as i32) >> (32 - width);
+}
+
+// Encoders for the 32 bit formats the compressed instructions expand into
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    return (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode;
+}
+
+fn i_type(imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    return ((imm as u32 & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode;
+}
+
+fn s_type(imm: i32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    let imm = imm as u32;
+    return (((imm >> 5) & 0x7f) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1f) << 7) | opcode;
+}
+
+fn b_type(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    return (((imm >> 12) & 0x1) << 31) | (((imm >> 5) & 0x3f) << 25) | (rs2 << 20) | (rs1 << 15)
+        | (funct3 << 12) | (((imm >> 1) & 0xf) << 8) | (((imm >> 11) & 0x1) << 7) | 0x63;
+}
+
+fn j_type(imm: i32, rd: u32) -> u32 {
+    let imm = imm as u32;
+    return (((imm >> 20) & 0x1) << 31) | (((imm >> 1) & 0x3ff) << 21) | (((imm >> 11) & 0x1) << 20)
+        | (((imm >> 12) & 0xff) << 12) | (rd << 7) | 0x6f;
+}
+
+/// Returns whether an instruction parcel is a 16 bit compressed instruction. Every 32 bit
+/// instruction has its two lowest bits set.
+pub fn is_compressed(parcel: u32) -> bool {
+    return parcel & 0b11 != 0b11;
+}
+
+/// Expands an RV64C instruction into the 32 bit instruction it stands for, so it can go through
+/// the regular decoder. Reserved encodings are illegal instructions.
+pub fn expand(inst: u16) -> Result<u32, errors::Exception> {
+    let inst = inst as u32;
+    let illegal = errors::Exception::IllegalInstruction(inst as u64);
+    let funct3 = bits(inst, 15, 13);
+    // Full 5 bit register fields, used by the CR/CI/CSS formats
+    let rd = bits(inst, 11, 7);
+    let rs2 = bits(inst, 6, 2);
+    // The 6 bit immediate of the CI format, imm[5] in bit 12 and imm[4:0] in bits 6:2
+    let ci_imm = signed((bit(inst, 12) << 5) | bits(inst, 6, 2), 6);
+    let shamt = (bit(inst, 12) << 5) | bits(inst, 6, 2);
+
+    let expanded = match(inst & 0b11, funct3) {
+        // Quadrant 0
+        (0b00, 0b000) => { // C.ADDI4SPN
+            let imm = (bits(inst, 10, 7) << 6) | (bits(inst, 12, 11) << 4) | (bit(inst, 5) << 3) | (bit(inst, 6) << 2);
+            if (imm == 0) {
+                return Err(illegal);
+            }
+            i_type(imm as i32, 2, 0x0, creg(inst, 2), 0x13)
+        }
+        (0b00, 0b001) | (0b00, 0b011) => { // C.FLD, C.LD
+            let imm = (bits(inst, 6, 5) << 6) | (bits(inst, 12, 10) << 3);
+            let opcode = if (funct3 == 0b001) { 0x07 } else { 0x03 };
+            i_type(imm as i32, creg(inst, 7), 0x3, creg(inst, 2), opcode)
+        }
+        (0b00, 0b010) => { // C.LW
+            let imm = (bit(inst, 5) << 6) | (bits(inst, 12, 10) << 3) | (bit(inst, 6) << 2);
+            i_type(imm as i32, creg(inst, 7), 0x2, creg(inst, 2), 0x03)
+        }
+        (0b00, 0b101) | (0b00, 0b111) => { // C.FSD, C.SD
+            let imm = (bits(inst, 6, 5) << 6) | (bits(inst, 12, 10) << 3);
+            let opcode = if (funct3 == 0b101) { 0x27 } else { 0x23 };
+            s_type(imm as i32, creg(inst, 2), creg(inst, 7), 0x3, opcode)
+        }
+        (0b00, 0b110) => { // C.SW
+            let imm = (bit(inst, 5) << 6) | (bits(inst, 12, 10) << 3) | (bit(inst, 6) << 2);
+            s_type(imm as i32, creg(inst, 2), creg(inst, 7), 0x2, 0x23)
+        }
+
+        // Quadrant 1
+        (0b01, 0b000) => i_type(ci_imm, rd, 0x0, rd, 0x13), // C.ADDI (C.NOP for rd = 0)
+        (0b01, 0b001) => { // C.ADDIW
+            if (rd == 0) {
+                return Err(illegal);
+            }
+            i_type(ci_imm, rd, 0x0, rd, 0x1b)
+        }
+        (0b01, 0b010) => i_type(ci_imm, 0, 0x0, rd, 0x13), // C.LI
+        (0b01, 0b011) => {
+            if (rd == 2) { // C.ADDI16SP
+                let imm = (bit(inst, 12) << 9) | (bits(inst, 4, 3) << 7) | (bit(inst, 5) << 6)
+                    | (bit(inst, 2) << 5) | (bit(inst, 6) << 4);
+                if (imm == 0) {
+                    return Err(illegal);
+                }
+                i_type(signed(imm, 10), 2, 0x0, 2, 0x13)
+            } else { // C.LUI
+                if (ci_imm == 0) {
+                    return Err(illegal);
+                }
+                ((ci_imm as u32) << 12) | (rd << 7) | 0x37
+            }
+        }
+        (0b01, 0b100) => {
+            let rd = creg(inst, 7);
+            let rs2 = creg(inst, 2);
+            match(bits(inst, 11, 10), bit(inst, 12), bits(inst, 6, 5)) {
+                (0b00, _, _) => i_type(shamt as i32, rd, 0x5, rd, 0x13), // C.SRLI
+                (0b01, _, _) => i_type((0x400 | shamt) as i32, rd, 0x5, rd, 0x13), // C.SRAI
+                (0b10, _, _) => i_type(ci_imm, rd, 0x7, rd, 0x13), // C.ANDI
+                (0b11, 0, 0b00) => r_type(0x20, rs2, rd, 0x0, rd, 0x33), // C.SUB
+                (0b11, 0, 0b01) => r_type(0x00, rs2, rd, 0x4, rd, 0x33), // C.XOR
+                (0b11, 0, 0b10) => r_type(0x00, rs2, rd, 0x6, rd, 0x33), // C.OR
+                (0b11, 0, 0b11) => r_type(0x00, rs2, rd, 0x7, rd, 0x33), // C.AND
+                (0b11, 1, 0b00) => r_type(0x20, rs2, rd, 0x0, rd, 0x3b), // C.SUBW
+                (0b11, 1, 0b01) => r_type(0x00, rs2, rd, 0x0, rd, 0x3b), // C.ADDW
+                _ => return Err(illegal),
+            }
+        }
+        (0b01, 0b101) => { // C.J
+            let imm = (bit(inst, 12) << 11) | (bit(inst, 8) << 10) | (bits(inst, 10, 9) << 8) | (bit(inst, 6) << 7)
+                | (bit(inst, 7) << 6) | (bit(inst, 2) << 5) | (bit(inst, 11) << 4) | (bits(inst, 5, 3) << 1);
+            j_type(signed(imm, 12), 0)
+        }
+        (0b01, 0b110) | (0b01, 0b111) => { // C.BEQZ, C.BNEZ
+            let imm = (bit(inst, 12) << 8) | (bits(inst, 6, 5) << 6) | (bit(inst, 2) << 5)
+                | (bits(inst, 11, 10) << 3) | (bits(inst, 4, 3) << 1);
+            b_type(signed(imm, 9), 0, creg(inst, 7), funct3 & 0x1)
+        }
+
+        // Quadrant 2
+        (0b10, 0b000) => i_type(shamt as i32, rd, 0x1, rd, 0x13), // C.SLLI
+        (0b10, 0b001) | (0b10, 0b011) => { // C.FLDSP, C.LDSP
+            if (funct3 == 0b011 && rd == 0) {
+                return Err(illegal);
+            }
+            let imm = (bits(inst, 4, 2) << 6) | (bit(inst, 12) << 5) | (bits(inst, 6, 5) << 3);
+            let opcode = if (funct3 == 0b001) { 0x07 } else { 0x03 };
+            i_type(imm as i32, 2, 0x3, rd, opcode)
+        }
+        (0b10, 0b010) => { // C.LWSP
+            if (rd == 0) {
+                return Err(illegal);
+            }
+            let imm = (bits(inst, 3, 2) << 6) | (bit(inst, 12) << 5) | (bits(inst, 6, 4) << 2);
+            i_type(imm as i32, 2, 0x2, rd, 0x03)
+        }
+        (0b10, 0b100) => {
+            match(bit(inst, 12), rd, rs2) {
+                (0, 0, 0) => return Err(illegal),
+                (0, rs1, 0) => i_type(0, rs1, 0x0, 0, 0x67), // C.JR
+                (0, rd, rs2) => r_type(0x00, rs2, 0, 0x0, rd, 0x33), // C.MV
+                (1, 0, 0) => 0x0010_0073, // C.EBREAK
+                (1, rs1, 0) => i_type(0, rs1, 0x0, 1, 0x67), // C.JALR
+                (_, rd, rs2) => r_type(0x00, rs2, rd, 0x0, rd, 0x33), // C.ADD
+            }
+        }
+        (0b10, 0b101) | (0b10, 0b111) => { // C.FSDSP, C.SDSP
+            let imm = (bits(inst, 9, 7) << 6) | (bits(inst, 12, 10) << 3);
+            let opcode = if (funct3 == 0b101) { 0x27 } else { 0x23 };
+            s_type(imm as i32, rs2, 2, 0x3, opcode)
+        }
+        (0b10, 0b110) => { // C.SWSP
+            let imm = (bits(inst, 8, 7) << 6) | (bits(inst, 12, 9) << 2);
+            s_type(imm as i32, rs2, 2, 0x2, 0x23)
+        }
+        _ => return Err(illegal),
+    };
+
+    return Ok(expanded);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::instructions::Instruction;
+
+    #[test]
+    fn test_expand_integer_ops() {
+        // c.addi a0, 1 -> addi a0, a0, 1
+        assert_eq!(expand(0x0505), Ok(0x0015_0513));
+        // c.li a0, -1 -> addi a0, zero, -1
+        assert_eq!(expand(0x557d), Ok(0xfff0_0513));
+        // c.mv a0, a1 -> add a0, zero, a1
+        assert_eq!(expand(0x852e), Ok(0x00b0_0533));
+        // c.addi16sp sp, -16 -> addi sp, sp, -16
+        assert_eq!(expand(0x1141), Ok(0xff01_0113));
+        // c.lui a0, 0xfffff -> lui a0, 0xfffff
+        assert_eq!(expand(0x757d), Ok(0xffff_f537));
+        // c.sub s0, s1 -> sub s0, s0, s1
+        assert_eq!(expand(0x8c05), Ok(0x4094_0433));
+        // c.srai s0, 63 -> srai s0, s0, 63
+        assert_eq!(expand(0x947d), Ok(0x43f4_5413));
+    }
+
+    #[test]
+    fn test_expand_loads_stores() {
+        // c.sdsp ra, 8(sp) -> sd ra, 8(sp)
+        assert_eq!(expand(0xe406), Ok(0x0011_3423));
+        // c.ldsp ra, 8(sp) -> ld ra, 8(sp)
+        assert_eq!(expand(0x60a2), Ok(0x0081_3083));
+        // c.lw a0, 4(a1) -> lw a0, 4(a1)
+        assert_eq!(expand(0x41c8), Ok(0x0045_a503));
+        // c.sd a0, 8(a1) -> sd a0, 8(a1)
+        assert_eq!(expand(0xe588), Ok(0x00a5_b423));
+    }
+
+    #[test]
+    fn test_expand_control_flow() {
+        // c.jr ra -> jalr zero, 0(ra)
+        assert_eq!(expand(0x8082), Ok(0x0000_8067));
+        // c.jalr a0 -> jalr ra, 0(a0)
+        assert_eq!(expand(0x9502), Ok(0x0005_00e7));
+        // c.ebreak
+        assert_eq!(expand(0x9002), Ok(0x0010_0073));
+        // c.j -4
+        let jump = Instruction::decode(expand(0xbff5).unwrap()).unwrap();
+        assert_eq!(jump, Instruction::Jal { rd: 0, imm: -4 });
+        // c.bnez s0, 6
+        let branch = Instruction::decode(expand(0xe019).unwrap()).unwrap();
+        assert_eq!(branch, Instruction::Bne { rs1: 8, rs2: 0, imm: 6 });
+    }
+
+    #[test]
+    fn test_expand_reserved() {
+        // The all-zero parcel is defined to be illegal
+        assert_eq!(expand(0x0000), Err(errors::Exception::IllegalInstruction(0)));
+        // c.lwsp with rd = 0
+        assert_eq!(expand(0x4002), Err(errors::Exception::IllegalInstruction(0x4002)));
+        // c.jr with rs1 = 0
+        assert_eq!(expand(0x8002), Err(errors::Exception::IllegalInstruction(0x8002)));
+    }
+}
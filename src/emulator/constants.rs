@@ -3,6 +3,23 @@ pub const DRAM_BASE: u64 = 0x8000_0000;
 pub const DRAM_SIZE: u64 = 1024 * 1024 * 128;
 pub const DRAM_END: u64 = DRAM_BASE + DRAM_SIZE - 1;
 
+// Where execution starts after reset, by default the start of the loaded image
+pub const RESET_VECTOR: u64 = DRAM_BASE;
+
+pub const CLINT_BASE: u64 = 0x0200_0000;
+pub const CLINT_SIZE: u64 = 0x1_0000;
+pub const CLINT_END: u64 = CLINT_BASE + CLINT_SIZE - 1;
+
+pub const PLIC_BASE: u64 = 0x0c00_0000;
+pub const PLIC_SIZE: u64 = 0x400_0000;
+pub const PLIC_END: u64 = PLIC_BASE + PLIC_SIZE - 1;
+
 pub const UART_BASE: u64 = 0x1000_0000;
 pub const UART_SIZE: u64 = 0x100;
 pub const UART_END: u64 = UART_BASE + UART_SIZE - 1;
+
+// virtio-mmio transports, one page each with consecutive PLIC sources
+pub const VIRTIO_BASE: u64 = 0x1000_1000;
+pub const VIRTIO_SIZE: u64 = 0x1000;
+pub const VIRTIO_COUNT: u64 = 8;
+pub const VIRTIO_IRQ: u64 = 1;
@@ -1,5 +1,30 @@
+// The whole table of CSR addresses and fields, including ones nothing refers to by name yet
+#![allow(dead_code)]
+
+use super::pmp;
+
+// Unprivileged floating-point CSRs.
+/// Floating-point accrued exceptions.
+pub const FFLAGS: usize = 0x001;
+/// Floating-point dynamic rounding mode.
+pub const FRM: usize = 0x002;
+/// Floating-point control and status register (frm + fflags).
+pub const FCSR: usize = 0x003;
+
+// Machine information registers, which are read-only.
+/// Vendor ID.
+pub const MVENDORID: usize = 0xf11;
+/// Architecture ID.
+pub const MARCHID: usize = 0xf12;
+/// Implementation ID.
+pub const MIMPID: usize = 0xf13;
+/// Hardware thread ID.
+pub const MHARTID: usize = 0xf14;
+
 /// Machine status register.
 pub const MSTATUS: usize = 0x300;
+/// ISA and extensions.
+pub const MISA: usize = 0x301;
 /// Machine exception delefation register.
 pub const MEDELEG: usize = 0x302;
 /// Machine interrupt delefation register.
@@ -20,6 +45,13 @@ pub const MCAUSE: usize = 0x342;
 pub const MTVAL: usize = 0x343;
 /// Machine interrupt pending.
 pub const MIP: usize = 0x344;
+/// Physical memory protection configuration. Only the even ones exist on RV64, each holding
+/// the configuration of 8 entries.
+pub const PMPCFG0: usize = 0x3a0;
+pub const PMPCFG15: usize = 0x3af;
+/// Physical memory protection addresses.
+pub const PMPADDR0: usize = 0x3b0;
+pub const PMPADDR63: usize = 0x3ef;
 
 // Supervisor-level CSRs.
 /// Supervisor status register.
@@ -66,6 +98,38 @@ pub const MASK_SD: u64 = 1 << 63;
 pub const MASK_SSTATUS: u64 = MASK_SIE | MASK_SPIE | MASK_UBE | MASK_SPP | MASK_FS 
                             | MASK_XS  | MASK_SUM  | MASK_MXR | MASK_UXL | MASK_SD;
 
+// mstatus.FS values, tracking whether the floating-point state needs saving on a context switch
+pub const FS_OFF: u64 = 0;
+pub const FS_INITIAL: u64 = 1;
+pub const FS_CLEAN: u64 = 2;
+pub const FS_DIRTY: u64 = 3;
+
+// misa fields: MXL in the top two bits, then one bit per extension letter
+pub const MISA_MXL_64: u64 = 2 << 62;
+pub const MISA_A: u64 = 1 << 0;
+pub const MISA_C: u64 = 1 << 2;
+pub const MISA_D: u64 = 1 << 3;
+pub const MISA_F: u64 = 1 << 5;
+pub const MISA_I: u64 = 1 << 8;
+pub const MISA_M: u64 = 1 << 12;
+pub const MISA_S: u64 = 1 << 18;
+pub const MISA_U: u64 = 1 << 20;
+
+// satp fields
+pub const SATP_PPN_MASK: u64 = (1 << 44) - 1;
+pub const SATP_ASID_MASK: u64 = 0xffff << 44;
+// satp.MODE values
+pub const SATP_MODE_BARE: u64 = 0;
+pub const SATP_MODE_SV39: u64 = 8;
+pub const SATP_MODE_SV48: u64 = 9;
+pub const SATP_MODE_SV57: u64 = 10;
+
+// PMP entries implemented by default, and at most
+pub const DEFAULT_PMP_ENTRIES: usize = 16;
+pub const MAX_PMP_ENTRIES: usize = 64;
+// pmpaddr holds bits 55:2 of an address
+const PMPADDR_MASK: u64 = (1 << 54) - 1;
+
 // MIP / SIP field mask
 pub const MASK_SSIP: u64 = 1 << 1;
 pub const MASK_MSIP: u64 = 1 << 3;
@@ -76,11 +140,64 @@ pub const MASK_MEIP: u64 = 1 << 11;
 
 pub struct Csr {
     csrs: [u64; 4096],
+    pmp_entries: usize,
 }
 
 impl Csr {
     pub fn new() -> Csr {
-        Self { csrs: [0; 4096] }
+        Self { csrs: [0; 4096], pmp_entries: DEFAULT_PMP_ENTRIES }
+    }
+
+    pub fn pmp_entries(&self) -> usize {
+        return self.pmp_entries;
+    }
+
+    // Entries past the count are hardwired to zero
+    pub fn set_pmp_entries(&mut self, entries: usize) {
+        assert!(entries <= MAX_PMP_ENTRIES, "At most {} PMP entries are supported", MAX_PMP_ENTRIES);
+        self.pmp_entries = entries;
+        for i in entries..MAX_PMP_ENTRIES {
+            self.csrs[PMPADDR0 + i] = 0;
+            self.csrs[PMPCFG0 + (i / 8) * 2] &= !(0xff << (8 * (i % 8)));
+        }
+    }
+
+    pub fn pmp_config(&self, entry: usize) -> u8 {
+        return (self.csrs[PMPCFG0 + (entry / 8) * 2] >> (8 * (entry % 8))) as u8;
+    }
+
+    pub fn pmp_addr(&self, entry: usize) -> u64 {
+        return self.csrs[PMPADDR0 + entry];
+    }
+
+    fn pmp_locked(&self, entry: usize) -> bool {
+        return entry < self.pmp_entries && self.pmp_config(entry) & pmp::PMP_L != 0;
+    }
+
+    // Locked and unimplemented entries keep their configuration, and W without R is reserved
+    fn store_pmpcfg(&mut self, addr: usize, value: u64) {
+        let mut updated = 0;
+        for i in 0..8 {
+            let entry = (addr - PMPCFG0) / 2 * 8 + i;
+            let mut cfg = (value >> (8 * i)) as u8 & !0x60;
+            if (entry >= self.pmp_entries || self.pmp_locked(entry)) {
+                cfg = self.pmp_config(entry);
+            } else if (cfg & pmp::PMP_R == 0) {
+                cfg &= !pmp::PMP_W;
+            }
+            updated |= (cfg as u64) << (8 * i);
+        }
+        self.csrs[addr] = updated;
+    }
+
+    // A locked entry also locks the address below it when it is TOR, since that is its start
+    fn store_pmpaddr(&mut self, addr: usize, value: u64) {
+        let entry = addr - PMPADDR0;
+        let next_locked_tor = self.pmp_locked(entry + 1) && pmp::address_mode(self.pmp_config(entry + 1)) == pmp::PMP_TOR;
+        if (entry >= self.pmp_entries || self.pmp_locked(entry) || next_locked_tor) {
+            return;
+        }
+        self.csrs[addr] = value & PMPADDR_MASK;
     }
 
     pub fn load(&self, addr: usize) -> u64 {
@@ -88,6 +205,8 @@ impl Csr {
             SIE => self.csrs[MIE] & self.csrs[MIDELEG],
             SIP => self.csrs[MIP] & self.csrs[MIDELEG],
             SSTATUS => self.csrs[MSTATUS] & MASK_SSTATUS,
+            FFLAGS => self.csrs[FCSR] & 0x1f,
+            FRM => (self.csrs[FCSR] >> 5) & 0x7,
             _ => self.csrs[addr],
         }
     }
@@ -95,8 +214,24 @@ impl Csr {
     pub fn store(&mut self, addr: usize, value: u64) {
         match addr {
             SIE => self.csrs[MIE] = (self.csrs[MIE] & !self.csrs[MIDELEG]) | (value & self.csrs[MIDELEG]),
-            SIP => self.csrs[MIP] = (self.csrs[MIE] & !self.csrs[MIDELEG]) | (value & self.csrs[MIDELEG]),
+            // Of sip, only SSIP is writable, and only once delegated
+            SIP => {
+                let writable = self.csrs[MIDELEG] & MASK_SSIP;
+                self.csrs[MIP] = (self.csrs[MIP] & !writable) | (value & writable);
+            },
             SSTATUS => self.csrs[MSTATUS] = (self.csrs[MSTATUS] & !MASK_SSTATUS)| (value & MASK_SSTATUS),
+            FFLAGS => self.csrs[FCSR] = (self.csrs[FCSR] & !0x1f) | (value & 0x1f),
+            FRM => self.csrs[FCSR] = (self.csrs[FCSR] & !0xe0) | ((value & 0x7) << 5),
+            FCSR => self.csrs[FCSR] = value & 0xff,
+            // RV64 has no odd pmpcfg registers, and accessing them traps before getting here
+            PMPCFG0..=PMPCFG15 => if (addr.is_multiple_of(2)) {
+                self.store_pmpcfg(addr, value);
+            },
+            PMPADDR0..=PMPADDR63 => self.store_pmpaddr(addr, value),
+            // Writes selecting a paging mode we don't support are ignored altogether
+            SATP => if (matches!(value >> 60, SATP_MODE_BARE | SATP_MODE_SV39 | SATP_MODE_SV48 | SATP_MODE_SV57)) {
+                self.csrs[SATP] = value;
+            },
             _ => self.csrs[addr] = value,
         }
     } 
@@ -0,0 +1,495 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+#[cfg(not(unix))]
+use std::io::prelude::*;
+#[cfg(unix)]
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::time::UNIX_EPOCH;
+
+// Disk images for the storage devices. A CowDisk keeps its base image untouched by sending writes
+// to an overlay, which can be thrown away or committed back to the base
+pub const COW_BLOCK_SIZE: u64 = 4096;
+pub const COW_MAGIC: &[u8; 8] = b"RVEMUCOW";
+
+// Positional reads and writes, which elsewhere have to go through the file's cursor
+#[cfg(unix)]
+fn read_exact_at(file: &File, data: &mut [u8], offset: u64) -> io::Result<()> {
+    return file.read_exact_at(data, offset);
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    return file.write_all_at(data, offset);
+}
+
+#[cfg(not(unix))]
+fn read_exact_at(mut file: &File, data: &mut [u8], offset: u64) -> io::Result<()> {
+    file.seek(io::SeekFrom::Start(offset))?;
+    return file.read_exact(data);
+}
+
+#[cfg(not(unix))]
+fn write_all_at(mut file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    file.seek(io::SeekFrom::Start(offset))?;
+    return file.write_all(data);
+}
+
+// Whatever holds a disk's contents
+pub trait Disk: Send {
+    fn size(&self) -> u64;
+    fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()>;
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+// A disk image file on the host
+pub struct FileDisk {
+    file: File,
+    size: u64,
+}
+
+impl FileDisk {
+    pub fn open(path: &str, read_only: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+        let size = file.metadata()?.len();
+        return Ok(FileDisk { file: file, size: size });
+    }
+}
+
+impl Disk for FileDisk {
+    fn size(&self) -> u64 {
+        return self.size;
+    }
+
+    fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        return read_exact_at(&self.file, data, offset);
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        return write_all_at(&self.file, data, offset);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.file.sync_data();
+    }
+}
+
+// Where a CowDisk keeps the blocks written since the base image was opened. Blocks are always
+// COW_BLOCK_SIZE bytes, the last one padded with zeros past the end of the disk
+pub trait Overlay: Send {
+    fn contains(&self, block: u64) -> bool;
+    fn read_block(&mut self, block: u64, data: &mut [u8]) -> io::Result<()>;
+    fn write_block(&mut self, block: u64, data: &[u8]) -> io::Result<()>;
+    // The blocks held, in order
+    fn blocks(&self) -> Vec<u64>;
+    fn flush(&mut self) -> io::Result<()>;
+    // Forgets every block, after they have been committed
+    fn clear(&mut self) -> io::Result<()>;
+}
+
+// An overlay lost when the emulator exits, like QEMU's -snapshot
+#[derive(Default)]
+pub struct MemoryOverlay {
+    blocks: BTreeMap<u64, Vec<u8>>,
+}
+
+impl MemoryOverlay {
+    pub fn new() -> Self {
+        return MemoryOverlay { blocks: BTreeMap::new() };
+    }
+}
+
+impl Overlay for MemoryOverlay {
+    fn contains(&self, block: u64) -> bool {
+        return self.blocks.contains_key(&block);
+    }
+
+    fn read_block(&mut self, block: u64, data: &mut [u8]) -> io::Result<()> {
+        let Some(stored) = self.blocks.get(&block) else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "block not in the overlay"));
+        };
+        data.copy_from_slice(stored);
+        return Ok(());
+    }
+
+    fn write_block(&mut self, block: u64, data: &[u8]) -> io::Result<()> {
+        self.blocks.insert(block, data.to_vec());
+        return Ok(());
+    }
+
+    fn blocks(&self) -> Vec<u64> {
+        return self.blocks.keys().copied().collect();
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.blocks.clear();
+        return Ok(());
+    }
+}
+
+// Which base image an overlay belongs to: its full path, size and modification time, and on Unix
+// the file it is. Writing to the base, other than by committing the overlay, changes it
+#[derive(Debug, PartialEq)]
+struct BaseIdentity {
+    path: Vec<u8>,
+    size: u64,
+    modified: (u64, u32),
+    file: (u64, u64),
+}
+
+impl BaseIdentity {
+    fn of(path: &str) -> io::Result<Self> {
+        let path = std::fs::canonicalize(path)?;
+        let metadata = std::fs::metadata(&path)?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        #[cfg(unix)]
+        let file = (metadata.dev(), metadata.ino());
+        #[cfg(not(unix))]
+        let file = (0, 0);
+        return Ok(BaseIdentity {
+            path: path.into_os_string().into_encoded_bytes(),
+            size: metadata.len(),
+            modified: (modified.as_secs(), modified.subsec_nanos()),
+            file: file,
+        });
+    }
+
+    // After COW_MAGIC in the header block: size, modification time, device and inode, then the
+    // length of the path and the path
+    fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut header = COW_MAGIC.to_vec();
+        for field in [self.size, self.modified.0, self.modified.1 as u64, self.file.0, self.file.1, self.path.len() as u64] {
+            header.extend_from_slice(&field.to_le_bytes());
+        }
+        header.extend_from_slice(&self.path);
+        if (header.len() as u64 > COW_BLOCK_SIZE) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "base image path too long for the overlay header"));
+        }
+        return Ok(header);
+    }
+
+    fn from_bytes(header: &[u8]) -> Option<Self> {
+        if (!header.starts_with(COW_MAGIC)) {
+            return None;
+        }
+        let field = |i: usize| u64::from_le_bytes(header[8 + 8 * i..16 + 8 * i].try_into().unwrap());
+        let path_start: usize = 8 + 6 * 8;
+        let path = header.get(path_start..path_start.checked_add(field(5) as usize)?)?;
+        return Some(BaseIdentity {
+            path: path.to_vec(),
+            size: field(0),
+            modified: (field(1), field(2) as u32),
+            file: (field(3), field(4)),
+        });
+    }
+}
+
+// An overlay in a sidecar file, kept between runs. The file starts with a header block holding
+// COW_MAGIC and the identity of the base image, then a bitmap of the blocks held, then the blocks
+// themselves at their offset in the disk. Blocks never written stay holes in the file
+pub struct FileOverlay {
+    file: File,
+    base_path: String,
+    bitmap: Vec<u8>,
+    data_start: u64,
+}
+
+impl FileOverlay {
+    // Opens the overlay at path for the base image at base_path, creating it if there is none. An
+    // existing overlay has to have been made for that image, unchanged since
+    pub fn open(path: &str, base_path: &str) -> io::Result<Self> {
+        let identity = BaseIdentity::of(base_path)?;
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let blocks = identity.size.div_ceil(COW_BLOCK_SIZE);
+        let bitmap_size = blocks.div_ceil(8);
+        let mut overlay = FileOverlay {
+            file: file,
+            base_path: base_path.to_string(),
+            bitmap: vec![0; bitmap_size as usize],
+            data_start: COW_BLOCK_SIZE + bitmap_size.div_ceil(COW_BLOCK_SIZE) * COW_BLOCK_SIZE,
+        };
+
+        if (overlay.file.metadata()?.len() == 0) {
+            write_all_at(&overlay.file, &identity.to_bytes()?, 0)?;
+            write_all_at(&overlay.file, &overlay.bitmap, COW_BLOCK_SIZE)?;
+            overlay.file.set_len(overlay.data_start)?;
+            return Ok(overlay);
+        }
+
+        let mut header = vec![0; COW_BLOCK_SIZE as usize];
+        read_exact_at(&overlay.file, &mut header, 0)?;
+        let Some(recorded) = BaseIdentity::from_bytes(&header) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a copy-on-write overlay"));
+        };
+        if (recorded != identity) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "overlay was made for {} as it was then, not {} as it is now",
+                String::from_utf8_lossy(&recorded.path), base_path)));
+        }
+        read_exact_at(&overlay.file, &mut overlay.bitmap, COW_BLOCK_SIZE)?;
+        return Ok(overlay);
+    }
+}
+
+impl Overlay for FileOverlay {
+    fn contains(&self, block: u64) -> bool {
+        return self.bitmap.get((block / 8) as usize).is_some_and(|byte| byte & (1 << (block % 8)) != 0);
+    }
+
+    fn read_block(&mut self, block: u64, data: &mut [u8]) -> io::Result<()> {
+        return read_exact_at(&self.file, data, self.data_start + block * COW_BLOCK_SIZE);
+    }
+
+    fn write_block(&mut self, block: u64, data: &[u8]) -> io::Result<()> {
+        let index = (block / 8) as usize;
+        if (index >= self.bitmap.len()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "block past the end of the disk"));
+        }
+        write_all_at(&self.file, data, self.data_start + block * COW_BLOCK_SIZE)?;
+        if (self.contains(block)) {
+            return Ok(());
+        }
+        // A new block's data reaches the disk before its bit in the bitmap, so a crash never
+        // leaves it marked present without being there
+        self.file.sync_data()?;
+        self.bitmap[index] |= 1 << (block % 8);
+        return write_all_at(&self.file, &self.bitmap[index..index + 1], COW_BLOCK_SIZE + index as u64);
+    }
+
+    fn blocks(&self) -> Vec<u64> {
+        let blocks = self.bitmap.len() as u64 * 8;
+        return (0..blocks).filter(|block| self.contains(*block)).collect();
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.file.sync_data();
+    }
+
+    // Called after committing, which changed the base, so the overlay is made over for it as it
+    // is now
+    fn clear(&mut self) -> io::Result<()> {
+        self.bitmap.fill(0);
+        write_all_at(&self.file, &self.bitmap, COW_BLOCK_SIZE)?;
+        self.file.set_len(self.data_start)?;
+        let mut header = BaseIdentity::of(&self.base_path)?.to_bytes()?;
+        header.resize(COW_BLOCK_SIZE as usize, 0);
+        return write_all_at(&self.file, &header, 0);
+    }
+}
+
+// A base image opened read-only, with writes going to an overlay
+pub struct CowDisk {
+    base: FileDisk,
+    base_path: String,
+    overlay: Box<dyn Overlay>,
+    commit_on_exit: bool,
+}
+
+impl CowDisk {
+    pub fn open(base_path: &str, overlay: Box<dyn Overlay>) -> io::Result<Self> {
+        return Ok(CowDisk {
+            base: FileDisk::open(base_path, true)?,
+            base_path: base_path.to_string(),
+            overlay: overlay,
+            commit_on_exit: false,
+        });
+    }
+
+    // Commits the overlay when the disk is dropped, which is when the emulator exits
+    pub fn commit_on_exit(mut self, commit: bool) -> Self {
+        self.commit_on_exit = commit;
+        return self;
+    }
+
+    // The disk bytes in a block, fewer than COW_BLOCK_SIZE for a partial last block
+    fn block_len(&self, block: u64) -> usize {
+        return (self.base.size() - block * COW_BLOCK_SIZE).min(COW_BLOCK_SIZE) as usize;
+    }
+
+    // Writes every block in the overlay back to the base image, then empties the overlay
+    pub fn commit(&mut self) -> io::Result<()> {
+        let mut base = FileDisk::open(&self.base_path, false)?;
+        let mut data = vec![0; COW_BLOCK_SIZE as usize];
+        for block in self.overlay.blocks() {
+            self.overlay.read_block(block, &mut data)?;
+            let len = self.block_len(block);
+            base.write_at(block * COW_BLOCK_SIZE, &data[..len])?;
+        }
+        base.flush()?;
+        return self.overlay.clear();
+    }
+
+    // Calls f with each block an access at offset touches, the range of the block it covers and
+    // the range of the access's data that goes with it
+    fn split(offset: u64, len: usize, mut f: impl FnMut(u64, std::ops::Range<usize>, std::ops::Range<usize>) -> io::Result<()>) -> io::Result<()> {
+        let mut done = 0;
+        while (done < len) {
+            let position = offset + done as u64;
+            let block = position / COW_BLOCK_SIZE;
+            let start = (position % COW_BLOCK_SIZE) as usize;
+            let count = (COW_BLOCK_SIZE as usize - start).min(len - done);
+            f(block, start..start + count, done..done + count)?;
+            done += count;
+        }
+        return Ok(());
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> io::Result<()> {
+        if (offset.checked_add(len as u64).is_none_or(|end| end > self.base.size())) {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "access past the end of the disk"));
+        }
+        return Ok(());
+    }
+}
+
+impl Disk for CowDisk {
+    fn size(&self) -> u64 {
+        return self.base.size();
+    }
+
+    fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        self.check_range(offset, data.len())?;
+        let mut buffer = vec![0; COW_BLOCK_SIZE as usize];
+        return Self::split(offset, data.len(), |block, within, part| {
+            if (self.overlay.contains(block)) {
+                self.overlay.read_block(block, &mut buffer)?;
+                data[part].copy_from_slice(&buffer[within]);
+                return Ok(());
+            }
+            return self.base.read_at(block * COW_BLOCK_SIZE + within.start as u64, &mut data[part]);
+        });
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.check_range(offset, data.len())?;
+        let mut buffer = vec![0; COW_BLOCK_SIZE as usize];
+        return Self::split(offset, data.len(), |block, within, part| {
+            // A block is copied up from the base on its first write
+            if (self.overlay.contains(block)) {
+                self.overlay.read_block(block, &mut buffer)?;
+            } else {
+                buffer.fill(0);
+                let len = self.block_len(block);
+                self.base.read_at(block * COW_BLOCK_SIZE, &mut buffer[..len])?;
+            }
+            buffer[within].copy_from_slice(&data[part]);
+            return self.overlay.write_block(block, &buffer);
+        });
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.overlay.flush();
+    }
+}
+
+impl Drop for CowDisk {
+    fn drop(&mut self) {
+        if (self.commit_on_exit) {
+            if let Err(e) = self.commit() {
+                eprintln!("Failed to commit the overlay to {}: {}", self.base_path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A base image in the temporary directory, whose sectors hold their index
+    fn base_image(name: &str, size: u64) -> String {
+        let path = std::env::temp_dir().join(format!("rv-emulator-{}-{}", std::process::id(), name));
+        let data: Vec<u8> = (0..size).map(|i| (i / 512) as u8).collect();
+        std::fs::write(&path, data).unwrap();
+        return path.to_str().unwrap().to_string();
+    }
+
+    #[test]
+    fn test_memory_overlay_leaves_base_untouched() {
+        // Not a whole number of blocks
+        let path = base_image("memory", 2 * COW_BLOCK_SIZE + 512);
+        let original = std::fs::read(&path).unwrap();
+        let mut disk = CowDisk::open(&path, Box::new(MemoryOverlay::new())).unwrap();
+
+        // Across a block boundary, and into the partial last block
+        disk.write_at(COW_BLOCK_SIZE - 2, &[0xaa; 4]).unwrap();
+        disk.write_at(2 * COW_BLOCK_SIZE + 510, &[0xbb; 2]).unwrap();
+        let mut data = [0; 8];
+        disk.read_at(COW_BLOCK_SIZE - 4, &mut data).unwrap();
+        assert_eq!(data, [7, 7, 0xaa, 0xaa, 0xaa, 0xaa, 8, 8]);
+        disk.read_at(2 * COW_BLOCK_SIZE + 508, &mut data[..4]).unwrap();
+        assert_eq!(data[..4], [16, 16, 0xbb, 0xbb]);
+        assert!(disk.write_at(2 * COW_BLOCK_SIZE + 511, &[0; 2]).is_err());
+
+        drop(disk);
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_commit() {
+        let path = base_image("commit", 2 * COW_BLOCK_SIZE + 512);
+        let disk = CowDisk::open(&path, Box::new(MemoryOverlay::new())).unwrap();
+        let mut disk = disk.commit_on_exit(true);
+        disk.write_at(2 * COW_BLOCK_SIZE + 1, &[0xcc]).unwrap();
+        drop(disk);
+
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len() as u64, 2 * COW_BLOCK_SIZE + 512);
+        assert_eq!(data[2 * COW_BLOCK_SIZE as usize..][..3], [16, 0xcc, 16]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_overlay_persists() {
+        let path = base_image("file", 4 * COW_BLOCK_SIZE);
+        let overlay_path = format!("{}.cow", path);
+        let original = std::fs::read(&path).unwrap();
+
+        let overlay = FileOverlay::open(&overlay_path, &path).unwrap();
+        let mut disk = CowDisk::open(&path, Box::new(overlay)).unwrap();
+        disk.write_at(3 * COW_BLOCK_SIZE, &[1, 2, 3]).unwrap();
+        disk.flush().unwrap();
+        drop(disk);
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+
+        // Another run picks up where the last left off
+        let overlay = FileOverlay::open(&overlay_path, &path).unwrap();
+        assert_eq!(overlay.blocks(), vec![3]);
+        let mut disk = CowDisk::open(&path, Box::new(overlay)).unwrap();
+        let mut data = [0; 4];
+        disk.read_at(3 * COW_BLOCK_SIZE, &mut data).unwrap();
+        assert_eq!(data, [1, 2, 3, 24]);
+
+        // An overlay can't be used with another image, even one of the same size
+        let other = base_image("file-other", 4 * COW_BLOCK_SIZE);
+        let error = FileOverlay::open(&overlay_path, &other).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&other).unwrap();
+
+        // Committing changes the base, and the overlay goes along with that
+        disk.commit().unwrap();
+        drop(disk);
+        assert!(FileOverlay::open(&overlay_path, &path).unwrap().blocks().is_empty());
+        assert_eq!(std::fs::read(&path).unwrap()[3 * COW_BLOCK_SIZE as usize..][..4], [1, 2, 3, 24]);
+
+        // Nor with its own image once that has been written to some other way
+        let overlay = FileOverlay::open(&overlay_path, &path).unwrap();
+        let mut disk = CowDisk::open(&path, Box::new(overlay)).unwrap();
+        disk.write_at(0, &[1]).unwrap();
+        drop(disk);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let mut base = FileDisk::open(&path, false).unwrap();
+        base.write_at(0, &[2]).unwrap();
+        drop(base);
+        assert!(FileOverlay::open(&overlay_path, &path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&overlay_path).unwrap();
+    }
+}
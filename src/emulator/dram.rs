@@ -1,5 +1,7 @@
 use crate::emulator::errors::Exception;
 
+use super::bus;
+use super::constants::*;
 use super::errors;
 
 pub struct Dram {
@@ -8,40 +10,106 @@ pub struct Dram {
 
 impl Dram {
     pub fn new(code: Vec<u8>) -> Dram {
-        let mut dram = vec![0; 1024*1024*128 as usize];
-        dram.splice(..code.len(), code.into_iter());
+        let mut dram = vec![0; DRAM_SIZE as usize];
+        dram.splice(..code.len(), code);
         Self { dram }
     }
 
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, errors::Exception> {
+    // The index range of an access at offset, if the whole access fits in dram
+    fn range(&self, offset: u64, nbytes: u64) -> Option<std::ops::Range<usize>> {
+        if (offset.checked_add(nbytes)? > self.dram.len() as u64) {
+            return None;
+        }
+        return Some(offset as usize..(offset + nbytes) as usize);
+    }
+}
+
+impl bus::Device for Dram {
+    fn load(&mut self, offset: u64, size: u64) -> Result<u64, errors::Exception> {
         if (![8, 16, 32, 64].contains(&size)) {
-            return Err(Exception::LoadAccessFault(addr));
+            return Err(Exception::LoadAccessFault(offset));
         }
 
-        let nbytes = size / 8;
-        let index = addr as usize;
-        let mut code = self.dram[index] as u64;
-        for offset in 1..nbytes as usize {
-            code = (code << 8) | (self.dram[index+offset] as u64); // TODO: Switch to small
-            // endianness
+        let Some(range) = self.range(offset, size / 8) else {
+            return Err(Exception::LoadAccessFault(offset));
+        };
+
+        // RISC-V memory is little-endian, so the lowest address holds the least significant byte
+        let mut code = 0;
+        for (i, byte) in self.dram[range].iter().enumerate() {
+            code |= (*byte as u64) << (8*i);
         }
 
         return Ok(code);
     }
 
-    pub fn store(&mut self, addr: u64, size: u64, data: u64) -> Result<(), errors::Exception> {
+    fn store(&mut self, offset: u64, size: u64, data: u64) -> Result<(), errors::Exception> {
         if (![8, 16, 32, 64].contains(&size)) {
-            return Err(Exception::StoreAMOAccessFault(addr));
+            return Err(Exception::StoreAMOAccessFault(offset));
         }
 
-        let nbytes = size / 8;
-        let index = addr as usize - self.dram.len();
-        for offset in 0..nbytes {
-            self.dram[index+offset as usize] = ((data >> (8*offset)) & 0xff) as u8;
+        let Some(range) = self.range(offset, size / 8) else {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        };
+
+        for (i, byte) in self.dram[range].iter_mut().enumerate() {
+            *byte = ((data >> (8*i)) & 0xff) as u8;
         }
         
         return Ok(());
     }
+
+    // Copies an image into memory in one go
+    fn write_bytes(&mut self, offset: u64, data: &[u8]) -> Result<(), errors::Exception> {
+        let Some(range) = self.range(offset, data.len() as u64) else {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        };
+        self.dram[range].copy_from_slice(data);
+        return Ok(());
+    }
+
+    fn read_bytes(&mut self, offset: u64, data: &mut [u8]) -> Result<(), errors::Exception> {
+        let Some(range) = self.range(offset, data.len() as u64) else {
+            return Err(Exception::LoadAccessFault(offset));
+        };
+        data.copy_from_slice(&self.dram[range]);
+        return Ok(());
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::bus::Device;
+
+    #[test]
+    fn test_load_little_endian() {
+        let mut dram = Dram::new(vec![0x13, 0x05, 0x15, 0x00, 0xef, 0xbe, 0xad, 0xde]);
+
+        assert_eq!(dram.load(0, 8), Ok(0x13));
+        assert_eq!(dram.load(0, 16), Ok(0x0513));
+        assert_eq!(dram.load(0, 32), Ok(0x0015_0513));
+        assert_eq!(dram.load(0, 64), Ok(0xdead_beef_0015_0513));
+    }
+
+    #[test]
+    fn test_store_load_round_trip() {
+        let mut dram = Dram::new(Vec::new());
 
+        dram.store(0x100, 64, 0x0123_4567_89ab_cdef).unwrap();
+        assert_eq!(dram.load(0x100, 64), Ok(0x0123_4567_89ab_cdef));
+        assert_eq!(dram.load(0x100, 8), Ok(0xef));
+        assert_eq!(dram.load(0x104, 32), Ok(0x0123_4567));
+    }
+
+    #[test]
+    fn test_out_of_range_access_faults() {
+        let mut dram = Dram::new(Vec::new());
+
+        assert_eq!(dram.load(DRAM_SIZE - 1, 8), Ok(0));
+        // An access starting inside dram but running off its end faults as well
+        assert_eq!(dram.load(DRAM_SIZE - 2, 32), Err(Exception::LoadAccessFault(DRAM_SIZE - 2)));
+        assert_eq!(dram.store(DRAM_SIZE - 4, 64, 0), Err(Exception::StoreAMOAccessFault(DRAM_SIZE - 4)));
+        assert!(dram.write_bytes(DRAM_SIZE - 1, &[0, 0]).is_err());
+    }
+}
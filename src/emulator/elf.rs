@@ -0,0 +1,348 @@
+use std::{error::Error, fmt};
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+const SYMBOL_SIZE: usize = 24;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+const SECTION_HEADER_SIZE: u64 = 64;
+
+#[derive(Debug, PartialEq)]
+pub enum ElfError {
+    Truncated,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    NotExecutable,
+    NotRiscV(u16),
+    // A loadable segment doesn't fit in physical memory
+    SegmentOutOfRange(u64),
+}
+
+impl Error for ElfError {}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match(self) {
+            ElfError::Truncated => write!(f, "ELF file is truncated"),
+            ElfError::BadMagic => write!(f, "not an ELF file"),
+            ElfError::NotElf64 => write!(f, "not a 64 bit ELF file"),
+            ElfError::NotLittleEndian => write!(f, "not a little-endian ELF file"),
+            ElfError::NotExecutable => write!(f, "not an executable ELF file"),
+            ElfError::NotRiscV(machine) => write!(f, "ELF file is for machine {}, not RISC-V", machine),
+            ElfError::SegmentOutOfRange(addr) => write!(f, "segment at {:#x} is outside of physical memory", addr),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SymbolKind {
+    Function,
+    Object,
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u64,
+    pub size: u64,
+    pub kind: SymbolKind,
+}
+
+// A PT_LOAD segment. Memory past the file contents, up to mem_size, is zero-filled (BSS)
+#[derive(Debug, PartialEq)]
+pub struct Segment {
+    pub paddr: u64,
+    pub data: Vec<u8>,
+    pub mem_size: u64,
+}
+
+#[derive(Debug)]
+pub struct Elf {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<Symbol>,
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    return bytes.starts_with(&ELF_MAGIC);
+}
+
+// Little-endian field readers, failing instead of panicking on a truncated file
+fn read_bytes(bytes: &[u8], offset: u64, len: u64) -> Result<&[u8], ElfError> {
+    let end = offset.checked_add(len).ok_or(ElfError::Truncated)?;
+    return bytes.get(offset as usize..end as usize).ok_or(ElfError::Truncated);
+}
+
+fn read_u16(bytes: &[u8], offset: u64) -> Result<u16, ElfError> {
+    return Ok(u16::from_le_bytes(read_bytes(bytes, offset, 2)?.try_into().unwrap()));
+}
+
+fn read_u32(bytes: &[u8], offset: u64) -> Result<u32, ElfError> {
+    return Ok(u32::from_le_bytes(read_bytes(bytes, offset, 4)?.try_into().unwrap()));
+}
+
+fn read_u64(bytes: &[u8], offset: u64) -> Result<u64, ElfError> {
+    return Ok(u64::from_le_bytes(read_bytes(bytes, offset, 8)?.try_into().unwrap()));
+}
+
+// Checks that a table of count entries, each at least min_size long, lies within the file. The
+// offsets of the entries and their fields can then be computed without overflowing
+fn check_table(bytes: &[u8], offset: u64, entry_size: u64, count: u64, min_size: u64) -> Result<(), ElfError> {
+    if (count == 0) {
+        return Ok(());
+    }
+    if (entry_size < min_size) {
+        return Err(ElfError::Truncated);
+    }
+    let len = entry_size.checked_mul(count).ok_or(ElfError::Truncated)?;
+    read_bytes(bytes, offset, len)?;
+    return Ok(());
+}
+
+impl Elf {
+    pub fn parse(bytes: &[u8]) -> Result<Elf, ElfError> {
+        if (!is_elf(bytes)) {
+            return Err(ElfError::BadMagic);
+        }
+        let ident = read_bytes(bytes, 0, 16)?;
+        if (ident[4] != ELFCLASS64) {
+            return Err(ElfError::NotElf64);
+        }
+        if (ident[5] != ELFDATA2LSB) {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if (read_u16(bytes, 16)? != ET_EXEC) {
+            return Err(ElfError::NotExecutable);
+        }
+        let machine = read_u16(bytes, 18)?;
+        if (machine != EM_RISCV) {
+            return Err(ElfError::NotRiscV(machine));
+        }
+
+        let entry = read_u64(bytes, 24)?;
+        let segments = Self::parse_segments(bytes)?;
+        let symbols = Self::parse_symbols(bytes)?;
+        return Ok(Elf { entry: entry, segments: segments, symbols: symbols });
+    }
+
+    fn parse_segments(bytes: &[u8]) -> Result<Vec<Segment>, ElfError> {
+        let phoff = read_u64(bytes, 32)?;
+        let phentsize = read_u16(bytes, 54)? as u64;
+        let phnum = read_u16(bytes, 56)? as u64;
+        check_table(bytes, phoff, phentsize, phnum, PROGRAM_HEADER_SIZE)?;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let header = phoff + i * phentsize;
+            if (read_u32(bytes, header)? != PT_LOAD) {
+                continue;
+            }
+            let offset = read_u64(bytes, header + 8)?;
+            let paddr = read_u64(bytes, header + 24)?;
+            let file_size = read_u64(bytes, header + 32)?;
+            let mem_size = read_u64(bytes, header + 40)?;
+            let data = read_bytes(bytes, offset, file_size)?.to_vec();
+            // Addresses wrapping around would make image_end meaningless
+            if (paddr.checked_add(mem_size.max(file_size)).is_none()) {
+                return Err(ElfError::SegmentOutOfRange(paddr));
+            }
+            segments.push(Segment { paddr: paddr, data: data, mem_size: mem_size.max(file_size) });
+        }
+        return Ok(segments);
+    }
+
+    // Stripped executables have no symbol table, which leaves the list empty
+    fn parse_symbols(bytes: &[u8]) -> Result<Vec<Symbol>, ElfError> {
+        let shoff = read_u64(bytes, 40)?;
+        let shentsize = read_u16(bytes, 58)? as u64;
+        let shnum = read_u16(bytes, 60)? as u64;
+        check_table(bytes, shoff, shentsize, shnum, SECTION_HEADER_SIZE)?;
+
+        let mut symbols = Vec::new();
+        for i in 0..shnum {
+            let header = shoff + i * shentsize;
+            if (read_u32(bytes, header + 4)? != SHT_SYMTAB) {
+                continue;
+            }
+            let offset = read_u64(bytes, header + 24)?;
+            let size = read_u64(bytes, header + 32)?;
+            // The linked section holds the symbol names
+            let link = read_u32(bytes, header + 40)? as u64;
+            if (link >= shnum) {
+                return Err(ElfError::Truncated);
+            }
+            let strtab_header = shoff + link * shentsize;
+            let strtab = read_bytes(bytes, read_u64(bytes, strtab_header + 24)?, read_u64(bytes, strtab_header + 32)?)?;
+
+            for entry in read_bytes(bytes, offset, size)?.chunks_exact(SYMBOL_SIZE) {
+                let name_offset = u32::from_le_bytes(entry[0..4].try_into().unwrap()) as usize;
+                let name = match(strtab.get(name_offset..)) {
+                    Some(name) => name.split(|&b| b == 0).next().unwrap_or(&[]),
+                    None => return Err(ElfError::Truncated),
+                };
+                if (name.is_empty()) {
+                    continue;
+                }
+                let kind = match(entry[4] & 0xf) {
+                    1 => SymbolKind::Object,
+                    2 => SymbolKind::Function,
+                    _ => SymbolKind::Other,
+                };
+                symbols.push(Symbol {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    value: u64::from_le_bytes(entry[8..16].try_into().unwrap()),
+                    size: u64::from_le_bytes(entry[16..24].try_into().unwrap()),
+                    kind: kind,
+                });
+            }
+        }
+        return Ok(symbols);
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        return self.symbols.iter().find(|symbol| symbol.name == name);
+    }
+
+    // Resolves an address to the function containing it, e.g. to name the pc in a trace
+    pub fn function_at(&self, addr: u64) -> Option<&Symbol> {
+        return self.symbols.iter().find(|symbol| {
+            symbol.kind == SymbolKind::Function
+                && (addr == symbol.value || (symbol.value..symbol.value.wrapping_add(symbol.size)).contains(&addr))
+        });
+    }
+
+    // End of the highest loadable segment
+    pub fn image_end(&self) -> u64 {
+        return self.segments.iter().map(|segment| segment.paddr + segment.mem_size).max().unwrap_or(0);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Builds a minimal executable: one PT_LOAD segment holding `code` followed by `bss` zero
+    // bytes, and a symbol table with `main` covering the code and an object `counter` in the BSS
+    pub fn build_elf(entry: u64, code: &[u8], bss: u64) -> Vec<u8> {
+        let code_offset = 64 + 56;
+        let strtab = b"\0main\0counter\0".to_vec();
+        let strtab_offset = code_offset + code.len();
+        let symtab_offset = strtab_offset + strtab.len();
+        let shoff = symtab_offset + 3 * SYMBOL_SIZE;
+
+        let mut elf = Vec::new();
+        // ELF header
+        elf.extend_from_slice(&ELF_MAGIC);
+        elf.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        elf.extend_from_slice(&ET_EXEC.to_le_bytes());
+        elf.extend_from_slice(&EM_RISCV.to_le_bytes());
+        elf.extend_from_slice(&1u32.to_le_bytes());
+        elf.extend_from_slice(&entry.to_le_bytes());
+        elf.extend_from_slice(&64u64.to_le_bytes()); // e_phoff
+        elf.extend_from_slice(&(shoff as u64).to_le_bytes());
+        elf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        for half in [64u16, 56, 1, 64, 3, 0] { // ehsize, phentsize, phnum, shentsize, shnum, shstrndx
+            elf.extend_from_slice(&half.to_le_bytes());
+        }
+
+        // Program header
+        elf.extend_from_slice(&PT_LOAD.to_le_bytes());
+        elf.extend_from_slice(&7u32.to_le_bytes());
+        for field in [code_offset as u64, entry, entry, code.len() as u64, code.len() as u64 + bss, 4] {
+            elf.extend_from_slice(&field.to_le_bytes());
+        }
+
+        elf.extend_from_slice(code);
+        elf.extend_from_slice(&strtab);
+
+        // Symbol table: the null symbol, main and counter
+        let symbol = |name: u32, info: u8, value: u64, size: u64| {
+            let mut entry = Vec::new();
+            entry.extend_from_slice(&name.to_le_bytes());
+            entry.extend_from_slice(&[info, 0, 1, 0]);
+            entry.extend_from_slice(&value.to_le_bytes());
+            entry.extend_from_slice(&size.to_le_bytes());
+            return entry;
+        };
+        elf.extend(symbol(0, 0, 0, 0));
+        elf.extend(symbol(1, 0x12, entry, code.len() as u64));
+        elf.extend(symbol(6, 0x11, entry + code.len() as u64, bss));
+
+        // Section headers: null, .strtab and .symtab (linked to .strtab)
+        let section = |kind: u32, offset: usize, size: usize, link: u32| {
+            let mut header = Vec::new();
+            header.extend_from_slice(&0u32.to_le_bytes());
+            header.extend_from_slice(&kind.to_le_bytes());
+            for field in [0, 0, offset as u64, size as u64] {
+                header.extend_from_slice(&field.to_le_bytes());
+            }
+            header.extend_from_slice(&link.to_le_bytes());
+            header.extend_from_slice(&0u32.to_le_bytes());
+            header.extend_from_slice(&8u64.to_le_bytes());
+            header.extend_from_slice(&(if (kind == SHT_SYMTAB) { SYMBOL_SIZE as u64 } else { 0 }).to_le_bytes());
+            return header;
+        };
+        elf.extend(vec![0; 64]);
+        elf.extend(section(3, strtab_offset, strtab.len(), 0));
+        elf.extend(section(SHT_SYMTAB, symtab_offset, 3 * SYMBOL_SIZE, 1));
+        return elf;
+    }
+
+    #[test]
+    fn test_parse() {
+        let bytes = build_elf(0x8000_0000, &[0x13, 0, 0, 0], 16);
+        let elf = Elf::parse(&bytes).unwrap();
+
+        assert_eq!(elf.entry, 0x8000_0000);
+        assert_eq!(elf.segments, vec![Segment { paddr: 0x8000_0000, data: vec![0x13, 0, 0, 0], mem_size: 20 }]);
+        assert_eq!(elf.image_end(), 0x8000_0014);
+        assert_eq!(elf.symbol("counter").unwrap().kind, SymbolKind::Object);
+        assert_eq!(elf.function_at(0x8000_0002).unwrap().name, "main");
+        // Objects don't resolve as functions
+        assert_eq!(elf.function_at(0x8000_0004), None);
+    }
+
+    #[test]
+    fn test_parse_rejects_other_files() {
+        let mut bytes = build_elf(0x8000_0000, &[0x13, 0, 0, 0], 0);
+
+        assert_eq!(Elf::parse(&bytes[..40]).unwrap_err(), ElfError::Truncated);
+        assert_eq!(Elf::parse(&[0x13, 0, 0, 0]).unwrap_err(), ElfError::BadMagic);
+
+        bytes[18] = 62; // EM_X86_64
+        assert_eq!(Elf::parse(&bytes).unwrap_err(), ElfError::NotRiscV(62));
+        bytes[4] = 1; // ELFCLASS32
+        assert_eq!(Elf::parse(&bytes).unwrap_err(), ElfError::NotElf64);
+    }
+
+    #[test]
+    fn test_parse_rejects_overflowing_offsets() {
+        let bytes = build_elf(0x8000_0000, &[0x13, 0, 0, 0], 0);
+
+        // e_phoff at the very end of the address space
+        let mut elf = bytes.clone();
+        elf[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(Elf::parse(&elf).unwrap_err(), ElfError::Truncated);
+
+        // e_shoff likewise, and a section header too small to hold its fields
+        let mut elf = bytes.clone();
+        elf[40..48].copy_from_slice(&(u64::MAX - 8).to_le_bytes());
+        assert_eq!(Elf::parse(&elf).unwrap_err(), ElfError::Truncated);
+        let mut elf = bytes.clone();
+        elf[58..60].copy_from_slice(&8u16.to_le_bytes());
+        assert_eq!(Elf::parse(&elf).unwrap_err(), ElfError::Truncated);
+
+        // A segment whose end wraps around
+        let mut elf = bytes.clone();
+        elf[64 + 40..64 + 48].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(Elf::parse(&elf).unwrap_err(), ElfError::SegmentOutOfRange(0x8000_0000));
+    }
+}
@@ -11,7 +11,7 @@ impl fmt::Display for Thing {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Exception {
     InstructionAddrMisaligned(u64),
     InstructionAccessFault(u64),
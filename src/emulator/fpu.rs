@@ -0,0 +1,762 @@
+// Software implementation of IEEE 754 binary32/binary64 arithmetic as required by the F and D
+// extensions. The host FPU can't be used directly because it only rounds to nearest and doesn't
+// report the exception flags, so every operation computes its exact result on integer
+// significands and rounds it once in round_pack.
+//
+// Values are passed around as raw bit patterns of the format (the low 32 bits for single
+// precision). NaN-boxing is the Cpu's concern, not this module's.
+
+use std::cmp::Ordering;
+
+// fflags bits
+pub const FLAG_NX: u64 = 1 << 0; // Inexact
+pub const FLAG_UF: u64 = 1 << 1; // Underflow
+pub const FLAG_OF: u64 = 1 << 2; // Overflow
+pub const FLAG_DZ: u64 = 1 << 3; // Divide by zero
+pub const FLAG_NV: u64 = 1 << 4; // Invalid operation
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RoundingMode {
+    NearestEven,
+    TowardZero,
+    Down,
+    Up,
+    NearestMaxMagnitude,
+}
+
+impl RoundingMode {
+    /// Decodes the rm encoding used by instructions and the frm CSR. 0b101 and 0b110 are
+    /// reserved, and 0b111 (dynamic) must be resolved against frm by the caller.
+    pub fn from_bits(rm: u64) -> Option<RoundingMode> {
+        match(rm) {
+            0b000 => Some(RoundingMode::NearestEven),
+            0b001 => Some(RoundingMode::TowardZero),
+            0b010 => Some(RoundingMode::Down),
+            0b011 => Some(RoundingMode::Up),
+            0b100 => Some(RoundingMode::NearestMaxMagnitude),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Format {
+    exp_bits: u32,
+    frac_bits: u32,
+}
+
+pub const SINGLE: Format = Format { exp_bits: 8, frac_bits: 23 };
+pub const DOUBLE: Format = Format { exp_bits: 11, frac_bits: 52 };
+
+impl Format {
+    fn bias(&self) -> i32 {
+        return (1 << (self.exp_bits - 1)) - 1;
+    }
+
+    // Unbiased exponent of the smallest normal number
+    fn emin(&self) -> i32 {
+        return 1 - self.bias();
+    }
+
+    fn exp_mask(&self) -> u64 {
+        return (1 << self.exp_bits) - 1;
+    }
+
+    fn frac_mask(&self) -> u64 {
+        return (1 << self.frac_bits) - 1;
+    }
+
+    fn sign_bit(&self) -> u64 {
+        return 1 << (self.exp_bits + self.frac_bits);
+    }
+
+    fn quiet_bit(&self) -> u64 {
+        return 1 << (self.frac_bits - 1);
+    }
+
+    /// The NaN RISC-V returns from every operation that produces a NaN
+    pub fn canonical_nan(&self) -> u64 {
+        return (self.exp_mask() << self.frac_bits) | self.quiet_bit();
+    }
+
+    fn zero(&self, sign: bool) -> u64 {
+        return if (sign) { self.sign_bit() } else { 0 };
+    }
+
+    fn infinity(&self, sign: bool) -> u64 {
+        return self.zero(sign) | (self.exp_mask() << self.frac_bits);
+    }
+
+    fn largest(&self, sign: bool) -> u64 {
+        return self.infinity(sign) - 1;
+    }
+}
+
+// A decoded floating point value. Finite values are sig * 2^exp, where sig may be wider than the
+// format's significand while an operation is in progress.
+#[derive(Debug, Copy, Clone)]
+enum Value {
+    Nan { signaling: bool },
+    Inf { sign: bool },
+    Zero { sign: bool },
+    Finite { sign: bool, exp: i32, sig: u128 },
+}
+
+fn unpack(fmt: Format, bits: u64) -> Value {
+    let sign = bits & fmt.sign_bit() != 0;
+    let exp = (bits >> fmt.frac_bits) & fmt.exp_mask();
+    let frac = bits & fmt.frac_mask();
+
+    if (exp == fmt.exp_mask()) {
+        if (frac == 0) {
+            return Value::Inf { sign };
+        }
+        return Value::Nan { signaling: frac & fmt.quiet_bit() == 0 };
+    }
+    if (exp == 0) {
+        if (frac == 0) {
+            return Value::Zero { sign };
+        }
+        // Subnormal, which has no implicit leading bit
+        return Value::Finite { sign, exp: fmt.emin() - fmt.frac_bits as i32, sig: frac as u128 };
+    }
+    let sig = (frac | (1 << fmt.frac_bits)) as u128;
+    return Value::Finite { sign, exp: exp as i32 - fmt.bias() - fmt.frac_bits as i32, sig };
+}
+
+fn is_signaling(fmt: Format, bits: u64) -> bool {
+    return matches!(unpack(fmt, bits), Value::Nan { signaling: true });
+}
+
+fn is_nan(fmt: Format, bits: u64) -> bool {
+    return matches!(unpack(fmt, bits), Value::Nan { .. });
+}
+
+// Shifts right, ORing every bit shifted out into the lowest bit so later rounding still sees
+// that the value was inexact
+fn shift_right_jam(sig: u128, shift: u32) -> u128 {
+    if (shift == 0) {
+        return sig;
+    }
+    if (shift >= 128) {
+        return (sig != 0) as u128;
+    }
+    let lost = sig & ((1 << shift) - 1);
+    return (sig >> shift) | (lost != 0) as u128;
+}
+
+// Drops the low `shift` bits of sig, rounding the remaining integer according to rm. Returns the
+// rounded integer and whether any of the dropped bits were set.
+fn shift_right_round(sig: u128, shift: u32, sign: bool, rm: RoundingMode) -> (u128, bool) {
+    if (shift == 0) {
+        return (sig, false);
+    }
+    let (kept, round_bit, sticky) = if (shift > 128) {
+        (0, false, sig != 0)
+    } else if (shift == 128) {
+        (0, sig >> 127 == 1, sig & (u128::MAX >> 1) != 0)
+    } else {
+        (sig >> shift, (sig >> (shift - 1)) & 1 == 1, sig & ((1 << (shift - 1)) - 1) != 0)
+    };
+
+    let inexact = round_bit || sticky;
+    let increment = match(rm) {
+        RoundingMode::NearestEven => round_bit && (sticky || kept & 1 == 1),
+        RoundingMode::TowardZero => false,
+        RoundingMode::Down => inexact && sign,
+        RoundingMode::Up => inexact && !sign,
+        RoundingMode::NearestMaxMagnitude => round_bit,
+    };
+    return (kept + increment as u128, inexact);
+}
+
+// Rounds the exact value (-1)^sign * sig * 2^exp into the format, raising NX, UF and OF as
+// appropriate. Tininess is detected after rounding, as RISC-V requires.
+fn round_pack(fmt: Format, sign: bool, exp: i32, sig: u128, rm: RoundingMode, flags: &mut u64) -> u64 {
+    if (sig == 0) {
+        return fmt.zero(sign);
+    }
+    let frac_bits = fmt.frac_bits as i32;
+    let msb = 127 - sig.leading_zeros() as i32;
+    // The value lies in [2^value_exp, 2^(value_exp + 1))
+    let value_exp = exp + msb;
+
+    // Exponent of the lowest significand bit that survives rounding
+    let mut lsb_exp = (value_exp - frac_bits).max(fmt.emin() - frac_bits);
+    let (mut rounded, inexact) = if (lsb_exp > exp) {
+        shift_right_round(sig, (lsb_exp - exp) as u32, sign, rm)
+    } else {
+        (sig << (exp - lsb_exp), false)
+    };
+    if (rounded >> (frac_bits + 1) != 0) {
+        // Rounding carried into a new leading bit, which leaves only zeros below it
+        rounded >>= 1;
+        lsb_exp += 1;
+    }
+
+    if (lsb_exp + frac_bits > fmt.bias()) {
+        *flags |= FLAG_OF | FLAG_NX;
+        return match(rm) {
+            RoundingMode::NearestEven | RoundingMode::NearestMaxMagnitude => fmt.infinity(sign),
+            RoundingMode::TowardZero => fmt.largest(sign),
+            RoundingMode::Down => if (sign) { fmt.infinity(sign) } else { fmt.largest(sign) },
+            RoundingMode::Up => if (sign) { fmt.largest(sign) } else { fmt.infinity(sign) },
+        };
+    }
+
+    if (inexact) {
+        *flags |= FLAG_NX;
+        // A result is tiny if it would still be below the smallest normal after rounding to
+        // full precision with an unbounded exponent range
+        if (value_exp < fmt.emin()) {
+            let shift = value_exp - frac_bits - exp;
+            let rounds_to_normal = value_exp == fmt.emin() - 1 && shift > 0
+                && shift_right_round(sig, shift as u32, sign, rm).0 >> (frac_bits + 1) != 0;
+            if (!rounds_to_normal) {
+                *flags |= FLAG_UF;
+            }
+        }
+    }
+
+    let bits = if (rounded >> frac_bits != 0) {
+        let biased_exp = (lsb_exp + frac_bits + fmt.bias()) as u64;
+        (biased_exp << fmt.frac_bits) | (rounded as u64 & fmt.frac_mask())
+    } else {
+        // Subnormal (or zero if everything rounded away)
+        rounded as u64
+    };
+    return fmt.zero(sign) | bits;
+}
+
+// Returns the canonical NaN, raising NV if any operand is a signaling NaN
+fn propagate_nan(fmt: Format, operands: &[u64], flags: &mut u64) -> u64 {
+    if (operands.iter().any(|bits| is_signaling(fmt, *bits))) {
+        *flags |= FLAG_NV;
+    }
+    return fmt.canonical_nan();
+}
+
+// Shifts a significand left until its leading bit is bit 125, adjusting the exponent to match
+fn widen(exp: i32, sig: u128) -> (i32, u128) {
+    let shift = sig.leading_zeros() as i32 - 2;
+    return (exp - shift, sig << shift);
+}
+
+fn add_values(fmt: Format, a: Value, b: Value, rm: RoundingMode, flags: &mut u64) -> u64 {
+    match(a, b) {
+        (Value::Nan { .. }, _) | (_, Value::Nan { .. }) => fmt.canonical_nan(),
+        (Value::Inf { sign: sa }, Value::Inf { sign: sb }) => {
+            if (sa != sb) {
+                *flags |= FLAG_NV;
+                return fmt.canonical_nan();
+            }
+            fmt.infinity(sa)
+        }
+        (Value::Inf { sign }, _) | (_, Value::Inf { sign }) => fmt.infinity(sign),
+        (Value::Zero { sign: sa }, Value::Zero { sign: sb }) => {
+            // Zeros of opposite sign sum to +0, except when rounding down
+            if (sa == sb) {
+                fmt.zero(sa)
+            } else {
+                fmt.zero(rm == RoundingMode::Down)
+            }
+        }
+        (Value::Zero { .. }, Value::Finite { sign, exp, sig }) | (Value::Finite { sign, exp, sig }, Value::Zero { .. }) => {
+            round_pack(fmt, sign, exp, sig, rm, flags)
+        }
+        (Value::Finite { sign: sa, exp: ea, sig: ma }, Value::Finite { sign: sb, exp: eb, sig: mb }) => {
+            // Line both significands up with their leading bit at bit 125, leaving room for the
+            // carry out of the addition and far more guard bits than either format needs
+            let (mut ea, mut ma) = widen(ea, ma);
+            let (mut eb, mut mb) = widen(eb, mb);
+            let (mut sa, mut sb) = (sa, sb);
+            if ((eb, mb) > (ea, ma)) {
+                std::mem::swap(&mut ea, &mut eb);
+                std::mem::swap(&mut ma, &mut mb);
+                std::mem::swap(&mut sa, &mut sb);
+            }
+            let mb = shift_right_jam(mb, (ea - eb) as u32);
+
+            if (sa == sb) {
+                return round_pack(fmt, sa, ea, ma + mb, rm, flags);
+            }
+            let difference = ma - mb;
+            if (difference == 0) {
+                return fmt.zero(rm == RoundingMode::Down);
+            }
+            round_pack(fmt, sa, ea, difference, rm, flags)
+        }
+    }
+}
+
+pub fn add(fmt: Format, a: u64, b: u64, rm: RoundingMode, flags: &mut u64) -> u64 {
+    if (is_nan(fmt, a) || is_nan(fmt, b)) {
+        return propagate_nan(fmt, &[a, b], flags);
+    }
+    return add_values(fmt, unpack(fmt, a), unpack(fmt, b), rm, flags);
+}
+
+pub fn sub(fmt: Format, a: u64, b: u64, rm: RoundingMode, flags: &mut u64) -> u64 {
+    if (is_nan(fmt, a) || is_nan(fmt, b)) {
+        return propagate_nan(fmt, &[a, b], flags);
+    }
+    return add_values(fmt, unpack(fmt, a), unpack(fmt, b ^ fmt.sign_bit()), rm, flags);
+}
+
+// Exact product of two non-NaN values, or None for the invalid 0 * inf
+fn multiply_values(a: Value, b: Value) -> Option<Value> {
+    match(a, b) {
+        (Value::Inf { .. }, Value::Zero { .. }) | (Value::Zero { .. }, Value::Inf { .. }) => None,
+        (Value::Inf { sign: sa }, other) | (other, Value::Inf { sign: sa }) => {
+            let sb = match(other) {
+                Value::Inf { sign } | Value::Zero { sign } | Value::Finite { sign, .. } => sign,
+                Value::Nan { .. } => false,
+            };
+            Some(Value::Inf { sign: sa ^ sb })
+        }
+        (Value::Zero { sign: sa }, other) | (other, Value::Zero { sign: sa }) => {
+            let sb = match(other) {
+                Value::Zero { sign } | Value::Finite { sign, .. } => sign,
+                _ => false,
+            };
+            Some(Value::Zero { sign: sa ^ sb })
+        }
+        (Value::Finite { sign: sa, exp: ea, sig: ma }, Value::Finite { sign: sb, exp: eb, sig: mb }) => {
+            Some(Value::Finite { sign: sa ^ sb, exp: ea + eb, sig: ma * mb })
+        }
+        _ => None,
+    }
+}
+
+fn negate(value: Value) -> Value {
+    match(value) {
+        Value::Nan { signaling } => Value::Nan { signaling },
+        Value::Inf { sign } => Value::Inf { sign: !sign },
+        Value::Zero { sign } => Value::Zero { sign: !sign },
+        Value::Finite { sign, exp, sig } => Value::Finite { sign: !sign, exp, sig },
+    }
+}
+
+pub fn mul(fmt: Format, a: u64, b: u64, rm: RoundingMode, flags: &mut u64) -> u64 {
+    if (is_nan(fmt, a) || is_nan(fmt, b)) {
+        return propagate_nan(fmt, &[a, b], flags);
+    }
+    match(multiply_values(unpack(fmt, a), unpack(fmt, b))) {
+        Some(Value::Inf { sign }) => fmt.infinity(sign),
+        Some(Value::Zero { sign }) => fmt.zero(sign),
+        Some(Value::Finite { sign, exp, sig }) => round_pack(fmt, sign, exp, sig, rm, flags),
+        _ => {
+            *flags |= FLAG_NV;
+            fmt.canonical_nan()
+        }
+    }
+}
+
+/// Computes (a * b) + c with a single rounding, negating the product and/or the addend first.
+/// This covers FMADD, FMSUB, FNMSUB and FNMADD.
+#[allow(clippy::too_many_arguments)]
+pub fn fused_mul_add(fmt: Format, a: u64, b: u64, c: u64, negate_product: bool, negate_addend: bool,
+        rm: RoundingMode, flags: &mut u64) -> u64 {
+    let product = multiply_values(unpack(fmt, a), unpack(fmt, b));
+    // inf * 0 is invalid even when the addend is a quiet NaN
+    if (product.is_none() && !is_nan(fmt, a) && !is_nan(fmt, b)) {
+        *flags |= FLAG_NV;
+        return fmt.canonical_nan();
+    }
+    if (is_nan(fmt, a) || is_nan(fmt, b) || is_nan(fmt, c)) {
+        return propagate_nan(fmt, &[a, b, c], flags);
+    }
+
+    let mut product = product.unwrap();
+    if (negate_product) {
+        product = negate(product);
+    }
+    let mut addend = unpack(fmt, c);
+    if (negate_addend) {
+        addend = negate(addend);
+    }
+    return add_values(fmt, product, addend, rm, flags);
+}
+
+pub fn div(fmt: Format, a: u64, b: u64, rm: RoundingMode, flags: &mut u64) -> u64 {
+    if (is_nan(fmt, a) || is_nan(fmt, b)) {
+        return propagate_nan(fmt, &[a, b], flags);
+    }
+    match(unpack(fmt, a), unpack(fmt, b)) {
+        (Value::Inf { .. }, Value::Inf { .. }) | (Value::Zero { .. }, Value::Zero { .. }) => {
+            *flags |= FLAG_NV;
+            fmt.canonical_nan()
+        }
+        (Value::Inf { sign: sa }, Value::Zero { sign: sb } | Value::Finite { sign: sb, .. }) => fmt.infinity(sa ^ sb),
+        (Value::Finite { sign: sa, .. }, Value::Zero { sign: sb }) => {
+            *flags |= FLAG_DZ;
+            fmt.infinity(sa ^ sb)
+        }
+        (Value::Zero { sign: sa }, Value::Inf { sign: sb } | Value::Finite { sign: sb, .. })
+        | (Value::Finite { sign: sa, .. }, Value::Inf { sign: sb }) => fmt.zero(sa ^ sb),
+        (Value::Finite { sign: sa, exp: ea, sig: ma }, Value::Finite { sign: sb, exp: eb, sig: mb }) => {
+            // Widening the dividend leaves the quotient at least 70 bits, and the remainder is
+            // folded into the lowest bit
+            let (ea, dividend) = widen(ea, ma);
+            let quotient = dividend / mb;
+            let sig = quotient | (dividend % mb != 0) as u128;
+            round_pack(fmt, sa ^ sb, ea - eb, sig, rm, flags)
+        }
+        _ => fmt.canonical_nan(),
+    }
+}
+
+fn isqrt(n: u128) -> u128 {
+    // The host estimate is only good to 53 bits. One Newton step from it lands at or above the
+    // true root, from where the iteration decreases monotonically onto it
+    let mut root = (n as f64).sqrt() as u128;
+    root = (root + n / root) / 2;
+    while (root * root > n) {
+        root = (root + n / root) / 2;
+    }
+    return root;
+}
+
+pub fn sqrt(fmt: Format, a: u64, rm: RoundingMode, flags: &mut u64) -> u64 {
+    match(unpack(fmt, a)) {
+        Value::Nan { .. } => propagate_nan(fmt, &[a], flags),
+        Value::Zero { sign } => fmt.zero(sign),
+        Value::Inf { sign: false } => fmt.infinity(false),
+        Value::Inf { sign: true } | Value::Finite { sign: true, .. } => {
+            *flags |= FLAG_NV;
+            fmt.canonical_nan()
+        }
+        Value::Finite { sign: false, exp, sig } => {
+            // Widen the significand to around 125 bits, keeping the exponent even so it can be
+            // halved exactly
+            let mut shift = 124 - (127 - sig.leading_zeros() as i32);
+            if ((exp - shift) & 1 != 0) {
+                shift += 1;
+            }
+            let radicand = sig << shift;
+            let root = isqrt(radicand);
+            let sig = root | (root * root != radicand) as u128;
+            round_pack(fmt, false, (exp - shift) / 2, sig, rm, flags)
+        }
+    }
+}
+
+// Total order on non-NaN values where -0 == +0
+fn compare(fmt: Format, a: u64, b: u64) -> Ordering {
+    let key = |bits: u64| {
+        let magnitude = (bits & !fmt.sign_bit()) as i128;
+        if (bits & fmt.sign_bit() != 0) { -magnitude } else { magnitude }
+    };
+    return key(a).cmp(&key(b));
+}
+
+/// FEQ is a quiet comparison, so only signaling NaNs raise NV
+pub fn eq(fmt: Format, a: u64, b: u64, flags: &mut u64) -> bool {
+    if (is_nan(fmt, a) || is_nan(fmt, b)) {
+        propagate_nan(fmt, &[a, b], flags);
+        return false;
+    }
+    return compare(fmt, a, b) == Ordering::Equal;
+}
+
+/// FLT is a signaling comparison, so any NaN raises NV
+pub fn lt(fmt: Format, a: u64, b: u64, flags: &mut u64) -> bool {
+    if (is_nan(fmt, a) || is_nan(fmt, b)) {
+        *flags |= FLAG_NV;
+        return false;
+    }
+    return compare(fmt, a, b) == Ordering::Less;
+}
+
+/// FLE is a signaling comparison, so any NaN raises NV
+pub fn le(fmt: Format, a: u64, b: u64, flags: &mut u64) -> bool {
+    if (is_nan(fmt, a) || is_nan(fmt, b)) {
+        *flags |= FLAG_NV;
+        return false;
+    }
+    return compare(fmt, a, b) != Ordering::Greater;
+}
+
+// FMIN/FMAX return the non-NaN operand if only one is a NaN, and order -0 below +0
+fn min_max(fmt: Format, a: u64, b: u64, max: bool, flags: &mut u64) -> u64 {
+    let (a_nan, b_nan) = (is_nan(fmt, a), is_nan(fmt, b));
+    if (a_nan || b_nan) {
+        let result = propagate_nan(fmt, &[a, b], flags);
+        return match(a_nan, b_nan) {
+            (true, false) => b,
+            (false, true) => a,
+            _ => result,
+        };
+    }
+    let a_first = match(compare(fmt, a, b)) {
+        Ordering::Less => !max,
+        Ordering::Greater => max,
+        Ordering::Equal => (a & fmt.sign_bit() != 0) != max,
+    };
+    return if (a_first) { a } else { b };
+}
+
+pub fn min(fmt: Format, a: u64, b: u64, flags: &mut u64) -> u64 {
+    return min_max(fmt, a, b, false, flags);
+}
+
+pub fn max(fmt: Format, a: u64, b: u64, flags: &mut u64) -> u64 {
+    return min_max(fmt, a, b, true, flags);
+}
+
+/// FCLASS result: a one-hot mask with bits 0-9 meaning -inf, -normal, -subnormal, -0, +0,
+/// +subnormal, +normal, +inf, signaling NaN and quiet NaN
+pub fn classify(fmt: Format, a: u64) -> u64 {
+    let sign = a & fmt.sign_bit() != 0;
+    let bit = match(unpack(fmt, a)) {
+        Value::Inf { .. } => if (sign) { 0 } else { 7 },
+        Value::Zero { .. } => if (sign) { 3 } else { 4 },
+        Value::Finite { .. } => {
+            let subnormal = (a >> fmt.frac_bits) & fmt.exp_mask() == 0;
+            match(sign, subnormal) {
+                (true, false) => 1,
+                (true, true) => 2,
+                (false, true) => 5,
+                (false, false) => 6,
+            }
+        }
+        Value::Nan { signaling: true } => 8,
+        Value::Nan { signaling: false } => 9,
+    };
+    return 1 << bit;
+}
+
+/// Converts to a 32 or 64 bit integer register value (32 bit results are sign-extended, even the
+/// unsigned ones). Out of range inputs saturate and raise NV instead of NX.
+pub fn to_int(fmt: Format, a: u64, signed: bool, width: u32, rm: RoundingMode, flags: &mut u64) -> u64 {
+    let (min, max): (i128, i128) = if (signed) {
+        (-(1 << (width - 1)), (1 << (width - 1)) - 1)
+    } else {
+        (0, (1 << width) - 1)
+    };
+    let to_register = |value: i128| {
+        if (width == 32) { value as i32 as i64 as u64 } else { value as u64 }
+    };
+
+    let (sign, exp, sig) = match(unpack(fmt, a)) {
+        Value::Nan { .. } => {
+            *flags |= FLAG_NV;
+            return to_register(max);
+        }
+        Value::Inf { sign } => {
+            *flags |= FLAG_NV;
+            return to_register(if (sign) { min } else { max });
+        }
+        Value::Zero { .. } => return 0,
+        Value::Finite { sign, exp, sig } => (sign, exp, sig),
+    };
+
+    // Anything scaled beyond 2^64 is out of range for every width, so clamp the shift
+    let (magnitude, inexact) = if (exp >= 0) {
+        (sig << exp.min(64), false)
+    } else {
+        shift_right_round(sig, (-exp) as u32, sign, rm)
+    };
+    let value = if (sign) { -(magnitude as i128) } else { magnitude as i128 };
+    if (value < min || value > max) {
+        *flags |= FLAG_NV;
+        return to_register(if (sign) { min } else { max });
+    }
+    if (inexact) {
+        *flags |= FLAG_NX;
+    }
+    return to_register(value);
+}
+
+/// Converts the low `width` bits of an integer register, interpreted as signed or unsigned
+pub fn from_int(fmt: Format, value: u64, signed: bool, width: u32, rm: RoundingMode, flags: &mut u64) -> u64 {
+    let value: i128 = match(signed, width) {
+        (true, 32) => value as i32 as i128,
+        (false, 32) => value as u32 as i128,
+        (true, _) => value as i64 as i128,
+        (false, _) => value as i128,
+    };
+    return round_pack(fmt, value < 0, 0, value.unsigned_abs(), rm, flags);
+}
+
+/// Converts between single and double precision
+pub fn convert(from: Format, to: Format, a: u64, rm: RoundingMode, flags: &mut u64) -> u64 {
+    match(unpack(from, a)) {
+        Value::Nan { .. } => {
+            propagate_nan(from, &[a], flags);
+            to.canonical_nan()
+        }
+        Value::Inf { sign } => to.infinity(sign),
+        Value::Zero { sign } => to.zero(sign),
+        Value::Finite { sign, exp, sig } => round_pack(to, sign, exp, sig, rm, flags),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single(value: f32) -> u64 {
+        return value.to_bits() as u64;
+    }
+
+    fn double(value: f64) -> u64 {
+        return value.to_bits();
+    }
+
+    #[test]
+    fn test_add_matches_host_nearest_even() {
+        let mut flags = 0;
+        assert_eq!(add(DOUBLE, double(1.5), double(2.25), RoundingMode::NearestEven, &mut flags), double(3.75));
+        assert_eq!(flags, 0);
+
+        assert_eq!(add(DOUBLE, double(0.1), double(0.2), RoundingMode::NearestEven, &mut flags), double(0.1 + 0.2));
+        assert_eq!(flags, FLAG_NX);
+
+        let mut flags = 0;
+        assert_eq!(sub(SINGLE, single(1.0), single(1.0), RoundingMode::NearestEven, &mut flags), single(0.0));
+        assert_eq!(sub(SINGLE, single(1.0), single(1.0), RoundingMode::Down, &mut flags), single(-0.0));
+        assert_eq!(flags, 0);
+    }
+
+    #[test]
+    fn test_directed_rounding() {
+        // 1 + 2^-30 is not representable in single precision
+        let (a, b) = (single(1.0), single(2f32.powi(-30)));
+        let mut flags = 0;
+
+        assert_eq!(add(SINGLE, a, b, RoundingMode::NearestEven, &mut flags), single(1.0));
+        assert_eq!(add(SINGLE, a, b, RoundingMode::TowardZero, &mut flags), single(1.0));
+        assert_eq!(add(SINGLE, a, b, RoundingMode::Down, &mut flags), single(1.0));
+        assert_eq!(add(SINGLE, a, b, RoundingMode::Up, &mut flags), single(1.0 + f32::EPSILON));
+        assert_eq!(add(SINGLE, a ^ SINGLE.sign_bit(), b ^ SINGLE.sign_bit(), RoundingMode::Down, &mut flags),
+            single(-1.0 - f32::EPSILON));
+        assert_eq!(flags, FLAG_NX);
+    }
+
+    #[test]
+    fn test_ties_rounding() {
+        // 1 + 2^-24 is exactly halfway between 1 and the next single
+        let (a, b) = (single(1.0), single(2f32.powi(-24)));
+        let mut flags = 0;
+
+        assert_eq!(add(SINGLE, a, b, RoundingMode::NearestEven, &mut flags), single(1.0));
+        assert_eq!(add(SINGLE, a, b, RoundingMode::NearestMaxMagnitude, &mut flags), single(1.0 + f32::EPSILON));
+    }
+
+    #[test]
+    fn test_overflow_and_underflow() {
+        let mut flags = 0;
+        assert_eq!(mul(DOUBLE, double(f64::MAX), double(2.0), RoundingMode::NearestEven, &mut flags), double(f64::INFINITY));
+        assert_eq!(flags, FLAG_OF | FLAG_NX);
+
+        let mut flags = 0;
+        assert_eq!(mul(DOUBLE, double(f64::MAX), double(2.0), RoundingMode::TowardZero, &mut flags), double(f64::MAX));
+        assert_eq!(flags, FLAG_OF | FLAG_NX);
+
+        let mut flags = 0;
+        let tiny = mul(SINGLE, single(f32::MIN_POSITIVE), single(0.75), RoundingMode::NearestEven, &mut flags);
+        assert_eq!(tiny, single(f32::MIN_POSITIVE * 0.75));
+        assert_eq!(flags, 0); // Exact subnormal results don't underflow
+
+        let mut flags = 0;
+        mul(SINGLE, single(f32::MIN_POSITIVE), single(1.0 / 3.0), RoundingMode::NearestEven, &mut flags);
+        assert_eq!(flags, FLAG_UF | FLAG_NX);
+    }
+
+    #[test]
+    fn test_invalid_and_divide_by_zero() {
+        let mut flags = 0;
+        assert_eq!(div(SINGLE, single(1.0), single(0.0), RoundingMode::NearestEven, &mut flags), single(f32::INFINITY));
+        assert_eq!(flags, FLAG_DZ);
+
+        let mut flags = 0;
+        assert_eq!(div(SINGLE, single(0.0), single(0.0), RoundingMode::NearestEven, &mut flags), SINGLE.canonical_nan());
+        assert_eq!(flags, FLAG_NV);
+
+        let mut flags = 0;
+        assert_eq!(sqrt(DOUBLE, double(-1.0), RoundingMode::NearestEven, &mut flags), DOUBLE.canonical_nan());
+        assert_eq!(flags, FLAG_NV);
+
+        // inf * 0 + qNaN is still invalid
+        let mut flags = 0;
+        fused_mul_add(DOUBLE, double(f64::INFINITY), double(0.0), DOUBLE.canonical_nan(), false, false,
+            RoundingMode::NearestEven, &mut flags);
+        assert_eq!(flags, FLAG_NV);
+    }
+
+    #[test]
+    fn test_div_sqrt_fma() {
+        let mut flags = 0;
+        assert_eq!(div(DOUBLE, double(1.0), double(3.0), RoundingMode::NearestEven, &mut flags), double(1.0 / 3.0));
+        assert_eq!(sqrt(DOUBLE, double(2.0), RoundingMode::NearestEven, &mut flags), double(2f64.sqrt()));
+        assert_eq!(sqrt(SINGLE, single(2.0), RoundingMode::NearestEven, &mut flags), single(2f32.sqrt()));
+        assert_eq!(flags, FLAG_NX);
+
+        let mut flags = 0;
+        assert_eq!(sqrt(DOUBLE, double(0.25), RoundingMode::NearestEven, &mut flags), double(0.5));
+        assert_eq!(flags, 0);
+
+        // (0.1 * 10) - 1 is only non-zero without an intermediate rounding
+        let (a, b, c) = (0.1f64, 10.0f64, 1.0f64);
+        let result = fused_mul_add(DOUBLE, double(a), double(b), double(c), false, true, RoundingMode::NearestEven, &mut flags);
+        assert_eq!(result, double(a.mul_add(b, -c)));
+        assert_ne!(result, double(0.0));
+    }
+
+    #[test]
+    fn test_min_max_compare_classify() {
+        let mut flags = 0;
+        assert_eq!(min(SINGLE, single(-0.0), single(0.0), &mut flags), single(-0.0));
+        assert_eq!(max(SINGLE, single(-0.0), single(0.0), &mut flags), single(0.0));
+        assert_eq!(max(SINGLE, SINGLE.canonical_nan(), single(3.0), &mut flags), single(3.0));
+        assert!(eq(DOUBLE, double(-0.0), double(0.0), &mut flags));
+        assert!(lt(DOUBLE, double(-2.0), double(1.0), &mut flags));
+        assert_eq!(flags, 0);
+
+        assert!(!eq(DOUBLE, DOUBLE.canonical_nan(), double(1.0), &mut flags));
+        assert_eq!(flags, 0);
+        assert!(!le(DOUBLE, DOUBLE.canonical_nan(), double(1.0), &mut flags));
+        assert_eq!(flags, FLAG_NV);
+
+        assert_eq!(classify(DOUBLE, double(f64::NEG_INFINITY)), 1 << 0);
+        assert_eq!(classify(DOUBLE, double(-0.0)), 1 << 3);
+        assert_eq!(classify(SINGLE, single(f32::MIN_POSITIVE / 2.0)), 1 << 5);
+        assert_eq!(classify(SINGLE, SINGLE.canonical_nan()), 1 << 9);
+        assert_eq!(classify(SINGLE, 0x7f80_0001), 1 << 8);
+    }
+
+    #[test]
+    fn test_integer_conversions() {
+        let mut flags = 0;
+        assert_eq!(to_int(DOUBLE, double(-2.5), true, 64, RoundingMode::NearestEven, &mut flags), -2i64 as u64);
+        assert_eq!(to_int(DOUBLE, double(-2.5), true, 64, RoundingMode::NearestMaxMagnitude, &mut flags), -3i64 as u64);
+        assert_eq!(to_int(DOUBLE, double(2.5), true, 64, RoundingMode::Up, &mut flags), 3);
+        assert_eq!(to_int(SINGLE, single(-0.5), false, 32, RoundingMode::TowardZero, &mut flags), 0);
+        assert_eq!(flags, FLAG_NX);
+
+        let mut flags = 0;
+        assert_eq!(to_int(DOUBLE, double(1e20), true, 32, RoundingMode::NearestEven, &mut flags), i32::MAX as u64);
+        assert_eq!(to_int(DOUBLE, DOUBLE.canonical_nan(), false, 32, RoundingMode::NearestEven, &mut flags), u64::MAX);
+        assert_eq!(to_int(SINGLE, single(-1.0), false, 64, RoundingMode::NearestEven, &mut flags), 0);
+        assert_eq!(flags, FLAG_NV);
+
+        let mut flags = 0;
+        assert_eq!(from_int(DOUBLE, -7i64 as u64, true, 64, RoundingMode::NearestEven, &mut flags), double(-7.0));
+        assert_eq!(from_int(SINGLE, u64::MAX, false, 64, RoundingMode::NearestEven, &mut flags), single(u64::MAX as f32));
+        assert_eq!(flags, FLAG_NX);
+    }
+
+    #[test]
+    fn test_precision_conversions() {
+        let mut flags = 0;
+        assert_eq!(convert(SINGLE, DOUBLE, single(1.25), RoundingMode::NearestEven, &mut flags), double(1.25));
+        assert_eq!(convert(DOUBLE, SINGLE, double(0.1), RoundingMode::NearestEven, &mut flags), single(0.1));
+        assert_eq!(convert(DOUBLE, SINGLE, double(0.1), RoundingMode::TowardZero, &mut flags), single(0.1) - 1);
+        assert_eq!(flags, FLAG_NX);
+
+        let mut flags = 0;
+        assert_eq!(convert(SINGLE, DOUBLE, 0x7f80_0001, RoundingMode::NearestEven, &mut flags), DOUBLE.canonical_nan());
+        assert_eq!(flags, FLAG_NV);
+    }
+}
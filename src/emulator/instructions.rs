@@ -14,8 +14,11 @@
 +---------+-----------+--------+-----------+-----------------+--------+
 */
 
+use super::errors;
+
 #[derive(Debug)]
 #[derive(PartialEq)]
+#[allow(non_camel_case_types)]
 pub struct R_Instr {
     pub opcode: u32,
     pub rd: usize,
@@ -39,6 +42,7 @@ impl R_Instr {
 
 #[derive(Debug)]
 #[derive(PartialEq)]
+#[allow(non_camel_case_types)]
 pub struct I_Instr {
     pub opcode: u32,
     pub rd: usize,
@@ -60,6 +64,7 @@ impl I_Instr {
 
 #[derive(Debug)]
 #[derive(PartialEq)]
+#[allow(non_camel_case_types)]
 pub struct S_Instr {
     pub opcode: u32,
     pub funct3: usize,
@@ -83,6 +88,7 @@ impl S_Instr {
 
 #[derive(Debug)]
 #[derive(PartialEq)]
+#[allow(non_camel_case_types)]
 pub struct B_Instr {
     pub opcode: u32,
     pub funct3: usize,
@@ -112,6 +118,7 @@ impl B_Instr {
 
 #[derive(Debug)]
 #[derive(PartialEq)]
+#[allow(non_camel_case_types)]
 pub struct U_Instr {
     pub opcode: u32,
     pub rd: usize,
@@ -131,6 +138,7 @@ impl U_Instr {
 
 #[derive(Debug)]
 #[derive(PartialEq)]
+#[allow(non_camel_case_types)]
 pub struct J_Instr {
     pub opcode: u32,
     pub rd: usize,
@@ -150,7 +158,515 @@ impl J_Instr {
     }
 }
 
+/// Sign-extends the lowest `bits` bits of `value` to a full 64 bit register value
+pub fn sign_extend(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    return ((value << shift) as i64) >> shift;
+}
+
+/// A fully decoded instruction. Register fields are indices into the register file and every
+/// immediate is already reassembled from its format and sign-extended, so execute never has to
+/// look at the raw instruction bits.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Instruction {
+    // U-type
+    Lui { rd: usize, imm: i64 },
+    Auipc { rd: usize, imm: i64 },
+
+    // Jumps
+    Jal { rd: usize, imm: i64 },
+    Jalr { rd: usize, rs1: usize, imm: i64 },
+
+    // Branches
+    Beq { rs1: usize, rs2: usize, imm: i64 },
+    Bne { rs1: usize, rs2: usize, imm: i64 },
+    Blt { rs1: usize, rs2: usize, imm: i64 },
+    Bge { rs1: usize, rs2: usize, imm: i64 },
+    Bltu { rs1: usize, rs2: usize, imm: i64 },
+    Bgeu { rs1: usize, rs2: usize, imm: i64 },
+
+    // Loads
+    Lb { rd: usize, rs1: usize, imm: i64 },
+    Lh { rd: usize, rs1: usize, imm: i64 },
+    Lw { rd: usize, rs1: usize, imm: i64 },
+    Ld { rd: usize, rs1: usize, imm: i64 },
+    Lbu { rd: usize, rs1: usize, imm: i64 },
+    Lhu { rd: usize, rs1: usize, imm: i64 },
+    Lwu { rd: usize, rs1: usize, imm: i64 },
+
+    // Stores
+    Sb { rs1: usize, rs2: usize, imm: i64 },
+    Sh { rs1: usize, rs2: usize, imm: i64 },
+    Sw { rs1: usize, rs2: usize, imm: i64 },
+    Sd { rs1: usize, rs2: usize, imm: i64 },
+
+    // Register-immediate operations
+    Addi { rd: usize, rs1: usize, imm: i64 },
+    Slti { rd: usize, rs1: usize, imm: i64 },
+    Sltiu { rd: usize, rs1: usize, imm: i64 },
+    Xori { rd: usize, rs1: usize, imm: i64 },
+    Ori { rd: usize, rs1: usize, imm: i64 },
+    Andi { rd: usize, rs1: usize, imm: i64 },
+    Slli { rd: usize, rs1: usize, shamt: u32 },
+    Srli { rd: usize, rs1: usize, shamt: u32 },
+    Srai { rd: usize, rs1: usize, shamt: u32 },
+
+    // Register-register operations
+    Add { rd: usize, rs1: usize, rs2: usize },
+    Sub { rd: usize, rs1: usize, rs2: usize },
+    Sll { rd: usize, rs1: usize, rs2: usize },
+    Slt { rd: usize, rs1: usize, rs2: usize },
+    Sltu { rd: usize, rs1: usize, rs2: usize },
+    Xor { rd: usize, rs1: usize, rs2: usize },
+    Srl { rd: usize, rs1: usize, rs2: usize },
+    Sra { rd: usize, rs1: usize, rs2: usize },
+    Or { rd: usize, rs1: usize, rs2: usize },
+    And { rd: usize, rs1: usize, rs2: usize },
+
+    // RV64 word operations, which operate on the low 32 bits and sign-extend the result
+    Addiw { rd: usize, rs1: usize, imm: i64 },
+    Slliw { rd: usize, rs1: usize, shamt: u32 },
+    Srliw { rd: usize, rs1: usize, shamt: u32 },
+    Sraiw { rd: usize, rs1: usize, shamt: u32 },
+    Addw { rd: usize, rs1: usize, rs2: usize },
+    Subw { rd: usize, rs1: usize, rs2: usize },
+    Sllw { rd: usize, rs1: usize, rs2: usize },
+    Srlw { rd: usize, rs1: usize, rs2: usize },
+    Sraw { rd: usize, rs1: usize, rs2: usize },
+
+    // M extension
+    Mul { rd: usize, rs1: usize, rs2: usize },
+    Mulh { rd: usize, rs1: usize, rs2: usize },
+    Mulhsu { rd: usize, rs1: usize, rs2: usize },
+    Mulhu { rd: usize, rs1: usize, rs2: usize },
+    Div { rd: usize, rs1: usize, rs2: usize },
+    Divu { rd: usize, rs1: usize, rs2: usize },
+    Rem { rd: usize, rs1: usize, rs2: usize },
+    Remu { rd: usize, rs1: usize, rs2: usize },
+    Mulw { rd: usize, rs1: usize, rs2: usize },
+    Divw { rd: usize, rs1: usize, rs2: usize },
+    Divuw { rd: usize, rs1: usize, rs2: usize },
+    Remw { rd: usize, rs1: usize, rs2: usize },
+    Remuw { rd: usize, rs1: usize, rs2: usize },
+
+    // A extension
+    LrW { rd: usize, rs1: usize },
+    ScW { rd: usize, rs1: usize, rs2: usize },
+    AmoswapW { rd: usize, rs1: usize, rs2: usize },
+    AmoaddW { rd: usize, rs1: usize, rs2: usize },
+    AmoxorW { rd: usize, rs1: usize, rs2: usize },
+    AmoandW { rd: usize, rs1: usize, rs2: usize },
+    AmoorW { rd: usize, rs1: usize, rs2: usize },
+    AmominW { rd: usize, rs1: usize, rs2: usize },
+    AmomaxW { rd: usize, rs1: usize, rs2: usize },
+    AmominuW { rd: usize, rs1: usize, rs2: usize },
+    AmomaxuW { rd: usize, rs1: usize, rs2: usize },
+    LrD { rd: usize, rs1: usize },
+    ScD { rd: usize, rs1: usize, rs2: usize },
+    AmoswapD { rd: usize, rs1: usize, rs2: usize },
+    AmoaddD { rd: usize, rs1: usize, rs2: usize },
+    AmoxorD { rd: usize, rs1: usize, rs2: usize },
+    AmoandD { rd: usize, rs1: usize, rs2: usize },
+    AmoorD { rd: usize, rs1: usize, rs2: usize },
+    AmominD { rd: usize, rs1: usize, rs2: usize },
+    AmomaxD { rd: usize, rs1: usize, rs2: usize },
+    AmominuD { rd: usize, rs1: usize, rs2: usize },
+    AmomaxuD { rd: usize, rs1: usize, rs2: usize },
+
+    // F and D extensions
+    Fp(FpInstruction),
+
+    Fence,
+
+    // SYSTEM
+    Ecall,
+    Ebreak,
+    Sret,
+    Mret,
+    Wfi,
+    SfenceVma { rs1: usize, rs2: usize },
+    Csrrw { rd: usize, rs1: usize, csr: usize },
+    Csrrs { rd: usize, rs1: usize, csr: usize },
+    Csrrc { rd: usize, rs1: usize, csr: usize },
+    // The immediate forms use the rs1 field as a 5 bit zero-extended immediate
+    Csrrwi { rd: usize, uimm: u64, csr: usize },
+    Csrrsi { rd: usize, uimm: u64, csr: usize },
+    Csrrci { rd: usize, uimm: u64, csr: usize },
+}
+
+/// Floating-point instructions, kept apart from Instruction so they can be checked against
+/// mstatus.FS in one place. `rm` is the raw rounding mode field, where 0b111 selects frm.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FpInstruction {
+    Flw { rd: usize, rs1: usize, imm: i64 },
+    Fsw { rs1: usize, rs2: usize, imm: i64 },
+    FmaddS { rd: usize, rs1: usize, rs2: usize, rs3: usize, rm: u64 },
+    FmsubS { rd: usize, rs1: usize, rs2: usize, rs3: usize, rm: u64 },
+    FnmsubS { rd: usize, rs1: usize, rs2: usize, rs3: usize, rm: u64 },
+    FnmaddS { rd: usize, rs1: usize, rs2: usize, rs3: usize, rm: u64 },
+    FaddS { rd: usize, rs1: usize, rs2: usize, rm: u64 },
+    FsubS { rd: usize, rs1: usize, rs2: usize, rm: u64 },
+    FmulS { rd: usize, rs1: usize, rs2: usize, rm: u64 },
+    FdivS { rd: usize, rs1: usize, rs2: usize, rm: u64 },
+    FsqrtS { rd: usize, rs1: usize, rm: u64 },
+    FsgnjS { rd: usize, rs1: usize, rs2: usize },
+    FsgnjnS { rd: usize, rs1: usize, rs2: usize },
+    FsgnjxS { rd: usize, rs1: usize, rs2: usize },
+    FminS { rd: usize, rs1: usize, rs2: usize },
+    FmaxS { rd: usize, rs1: usize, rs2: usize },
+    FcvtWS { rd: usize, rs1: usize, rm: u64 },
+    FcvtWuS { rd: usize, rs1: usize, rm: u64 },
+    FcvtLS { rd: usize, rs1: usize, rm: u64 },
+    FcvtLuS { rd: usize, rs1: usize, rm: u64 },
+    FmvXW { rd: usize, rs1: usize },
+    FeqS { rd: usize, rs1: usize, rs2: usize },
+    FltS { rd: usize, rs1: usize, rs2: usize },
+    FleS { rd: usize, rs1: usize, rs2: usize },
+    FclassS { rd: usize, rs1: usize },
+    FcvtSW { rd: usize, rs1: usize, rm: u64 },
+    FcvtSWu { rd: usize, rs1: usize, rm: u64 },
+    FcvtSL { rd: usize, rs1: usize, rm: u64 },
+    FcvtSLu { rd: usize, rs1: usize, rm: u64 },
+    FmvWX { rd: usize, rs1: usize },
+
+    Fld { rd: usize, rs1: usize, imm: i64 },
+    Fsd { rs1: usize, rs2: usize, imm: i64 },
+    FmaddD { rd: usize, rs1: usize, rs2: usize, rs3: usize, rm: u64 },
+    FmsubD { rd: usize, rs1: usize, rs2: usize, rs3: usize, rm: u64 },
+    FnmsubD { rd: usize, rs1: usize, rs2: usize, rs3: usize, rm: u64 },
+    FnmaddD { rd: usize, rs1: usize, rs2: usize, rs3: usize, rm: u64 },
+    FaddD { rd: usize, rs1: usize, rs2: usize, rm: u64 },
+    FsubD { rd: usize, rs1: usize, rs2: usize, rm: u64 },
+    FmulD { rd: usize, rs1: usize, rs2: usize, rm: u64 },
+    FdivD { rd: usize, rs1: usize, rs2: usize, rm: u64 },
+    FsqrtD { rd: usize, rs1: usize, rm: u64 },
+    FsgnjD { rd: usize, rs1: usize, rs2: usize },
+    FsgnjnD { rd: usize, rs1: usize, rs2: usize },
+    FsgnjxD { rd: usize, rs1: usize, rs2: usize },
+    FminD { rd: usize, rs1: usize, rs2: usize },
+    FmaxD { rd: usize, rs1: usize, rs2: usize },
+    FcvtSD { rd: usize, rs1: usize, rm: u64 },
+    FcvtDS { rd: usize, rs1: usize, rm: u64 },
+    FeqD { rd: usize, rs1: usize, rs2: usize },
+    FltD { rd: usize, rs1: usize, rs2: usize },
+    FleD { rd: usize, rs1: usize, rs2: usize },
+    FclassD { rd: usize, rs1: usize },
+    FcvtWD { rd: usize, rs1: usize, rm: u64 },
+    FcvtWuD { rd: usize, rs1: usize, rm: u64 },
+    FcvtLD { rd: usize, rs1: usize, rm: u64 },
+    FcvtLuD { rd: usize, rs1: usize, rm: u64 },
+    FmvXD { rd: usize, rs1: usize },
+    FcvtDW { rd: usize, rs1: usize, rm: u64 },
+    FcvtDWu { rd: usize, rs1: usize, rm: u64 },
+    FcvtDL { rd: usize, rs1: usize, rm: u64 },
+    FcvtDLu { rd: usize, rs1: usize, rm: u64 },
+    FmvDX { rd: usize, rs1: usize },
+}
+
+impl FpInstruction {
+    fn decode(inst: u32) -> Result<FpInstruction, errors::Exception> {
+        let illegal = errors::Exception::IllegalInstruction(inst as u64);
+        let r = R_Instr::from_u32(inst);
+        let (rd, rs1, rs2, rm) = (r.rd, r.rs1, r.rs2, r.funct3 as u64);
+        // R4-type: rs3 | fmt | rs2 | rs1 | rm | rd | opcode, where fmt 0 is single and 1 double
+        let rs3 = r.funct7 >> 2;
+        let fmt = r.funct7 & 0x3;
+
+        let instruction = match(r.opcode) {
+            0x07 => {
+                let i = I_Instr::from_u32(inst);
+                let imm = sign_extend(i.imm as u64, 12);
+                match(i.funct3) {
+                    0x2 => FpInstruction::Flw { rd, rs1, imm },
+                    0x3 => FpInstruction::Fld { rd, rs1, imm },
+                    _ => return Err(illegal),
+                }
+            }
+            0x27 => {
+                let s = S_Instr::from_u32(inst);
+                let imm = sign_extend(s.imm as u64, 12);
+                match(s.funct3) {
+                    0x2 => FpInstruction::Fsw { rs1, rs2, imm },
+                    0x3 => FpInstruction::Fsd { rs1, rs2, imm },
+                    _ => return Err(illegal),
+                }
+            }
+            0x43 | 0x47 | 0x4b | 0x4f => {
+                match(r.opcode, fmt) {
+                    (0x43, 0x0) => FpInstruction::FmaddS { rd, rs1, rs2, rs3, rm },
+                    (0x47, 0x0) => FpInstruction::FmsubS { rd, rs1, rs2, rs3, rm },
+                    (0x4b, 0x0) => FpInstruction::FnmsubS { rd, rs1, rs2, rs3, rm },
+                    (0x4f, 0x0) => FpInstruction::FnmaddS { rd, rs1, rs2, rs3, rm },
+                    (0x43, 0x1) => FpInstruction::FmaddD { rd, rs1, rs2, rs3, rm },
+                    (0x47, 0x1) => FpInstruction::FmsubD { rd, rs1, rs2, rs3, rm },
+                    (0x4b, 0x1) => FpInstruction::FnmsubD { rd, rs1, rs2, rs3, rm },
+                    (0x4f, 0x1) => FpInstruction::FnmaddD { rd, rs1, rs2, rs3, rm },
+                    (_, _) => return Err(illegal),
+                }
+            }
+            0x53 => {
+                match(r.funct7, r.funct3, rs2) {
+                    (0x00, _, _) => FpInstruction::FaddS { rd, rs1, rs2, rm },
+                    (0x04, _, _) => FpInstruction::FsubS { rd, rs1, rs2, rm },
+                    (0x08, _, _) => FpInstruction::FmulS { rd, rs1, rs2, rm },
+                    (0x0c, _, _) => FpInstruction::FdivS { rd, rs1, rs2, rm },
+                    (0x2c, _, 0) => FpInstruction::FsqrtS { rd, rs1, rm },
+                    (0x10, 0x0, _) => FpInstruction::FsgnjS { rd, rs1, rs2 },
+                    (0x10, 0x1, _) => FpInstruction::FsgnjnS { rd, rs1, rs2 },
+                    (0x10, 0x2, _) => FpInstruction::FsgnjxS { rd, rs1, rs2 },
+                    (0x14, 0x0, _) => FpInstruction::FminS { rd, rs1, rs2 },
+                    (0x14, 0x1, _) => FpInstruction::FmaxS { rd, rs1, rs2 },
+                    (0x60, _, 0) => FpInstruction::FcvtWS { rd, rs1, rm },
+                    (0x60, _, 1) => FpInstruction::FcvtWuS { rd, rs1, rm },
+                    (0x60, _, 2) => FpInstruction::FcvtLS { rd, rs1, rm },
+                    (0x60, _, 3) => FpInstruction::FcvtLuS { rd, rs1, rm },
+                    (0x70, 0x0, 0) => FpInstruction::FmvXW { rd, rs1 },
+                    (0x50, 0x2, _) => FpInstruction::FeqS { rd, rs1, rs2 },
+                    (0x50, 0x1, _) => FpInstruction::FltS { rd, rs1, rs2 },
+                    (0x50, 0x0, _) => FpInstruction::FleS { rd, rs1, rs2 },
+                    (0x70, 0x1, 0) => FpInstruction::FclassS { rd, rs1 },
+                    (0x68, _, 0) => FpInstruction::FcvtSW { rd, rs1, rm },
+                    (0x68, _, 1) => FpInstruction::FcvtSWu { rd, rs1, rm },
+                    (0x68, _, 2) => FpInstruction::FcvtSL { rd, rs1, rm },
+                    (0x68, _, 3) => FpInstruction::FcvtSLu { rd, rs1, rm },
+                    (0x78, 0x0, 0) => FpInstruction::FmvWX { rd, rs1 },
+
+                    (0x01, _, _) => FpInstruction::FaddD { rd, rs1, rs2, rm },
+                    (0x05, _, _) => FpInstruction::FsubD { rd, rs1, rs2, rm },
+                    (0x09, _, _) => FpInstruction::FmulD { rd, rs1, rs2, rm },
+                    (0x0d, _, _) => FpInstruction::FdivD { rd, rs1, rs2, rm },
+                    (0x2d, _, 0) => FpInstruction::FsqrtD { rd, rs1, rm },
+                    (0x11, 0x0, _) => FpInstruction::FsgnjD { rd, rs1, rs2 },
+                    (0x11, 0x1, _) => FpInstruction::FsgnjnD { rd, rs1, rs2 },
+                    (0x11, 0x2, _) => FpInstruction::FsgnjxD { rd, rs1, rs2 },
+                    (0x15, 0x0, _) => FpInstruction::FminD { rd, rs1, rs2 },
+                    (0x15, 0x1, _) => FpInstruction::FmaxD { rd, rs1, rs2 },
+                    (0x20, _, 1) => FpInstruction::FcvtSD { rd, rs1, rm },
+                    (0x21, _, 0) => FpInstruction::FcvtDS { rd, rs1, rm },
+                    (0x51, 0x2, _) => FpInstruction::FeqD { rd, rs1, rs2 },
+                    (0x51, 0x1, _) => FpInstruction::FltD { rd, rs1, rs2 },
+                    (0x51, 0x0, _) => FpInstruction::FleD { rd, rs1, rs2 },
+                    (0x71, 0x1, 0) => FpInstruction::FclassD { rd, rs1 },
+                    (0x61, _, 0) => FpInstruction::FcvtWD { rd, rs1, rm },
+                    (0x61, _, 1) => FpInstruction::FcvtWuD { rd, rs1, rm },
+                    (0x61, _, 2) => FpInstruction::FcvtLD { rd, rs1, rm },
+                    (0x61, _, 3) => FpInstruction::FcvtLuD { rd, rs1, rm },
+                    (0x71, 0x0, 0) => FpInstruction::FmvXD { rd, rs1 },
+                    (0x69, _, 0) => FpInstruction::FcvtDW { rd, rs1, rm },
+                    (0x69, _, 1) => FpInstruction::FcvtDWu { rd, rs1, rm },
+                    (0x69, _, 2) => FpInstruction::FcvtDL { rd, rs1, rm },
+                    (0x69, _, 3) => FpInstruction::FcvtDLu { rd, rs1, rm },
+                    (0x79, 0x0, 0) => FpInstruction::FmvDX { rd, rs1 },
+                    (_, _, _) => return Err(illegal),
+                }
+            }
+            _ => return Err(illegal),
+        };
+
+        return Ok(instruction);
+    }
+}
+
+impl Instruction {
+    pub fn decode(inst: u32) -> Result<Instruction, errors::Exception> {
+        let illegal = errors::Exception::IllegalInstruction(inst as u64);
+
+        let instruction = match(inst & 0x7f) {
+            0x03 => {
+                let i = I_Instr::from_u32(inst);
+                let (rd, rs1, imm) = (i.rd, i.rs1, sign_extend(i.imm as u64, 12));
+                match(i.funct3) {
+                    0x0 => Instruction::Lb { rd, rs1, imm },
+                    0x1 => Instruction::Lh { rd, rs1, imm },
+                    0x2 => Instruction::Lw { rd, rs1, imm },
+                    0x3 => Instruction::Ld { rd, rs1, imm },
+                    0x4 => Instruction::Lbu { rd, rs1, imm },
+                    0x5 => Instruction::Lhu { rd, rs1, imm },
+                    0x6 => Instruction::Lwu { rd, rs1, imm },
+                    _ => return Err(illegal),
+                }
+            }
+            0x07 | 0x27 | 0x43 | 0x47 | 0x4b | 0x4f | 0x53 => Instruction::Fp(FpInstruction::decode(inst)?),
+            0x0f => {
+                match(I_Instr::from_u32(inst).funct3) {
+                    0x0 => Instruction::Fence,
+                    _ => return Err(illegal),
+                }
+            }
+            0x13 => {
+                let i = I_Instr::from_u32(inst);
+                let (rd, rs1, imm) = (i.rd, i.rs1, sign_extend(i.imm as u64, 12));
+                // RV64 shifts use a 6 bit shamt, leaving 6 bits above it to select the operation
+                let shamt = (i.imm & 0x3f) as u32;
+                match(i.funct3, i.imm >> 6) {
+                    (0x0, _) => Instruction::Addi { rd, rs1, imm },
+                    (0x1, 0x0) => Instruction::Slli { rd, rs1, shamt },
+                    (0x2, _) => Instruction::Slti { rd, rs1, imm },
+                    (0x3, _) => Instruction::Sltiu { rd, rs1, imm },
+                    (0x4, _) => Instruction::Xori { rd, rs1, imm },
+                    (0x5, 0x0) => Instruction::Srli { rd, rs1, shamt },
+                    (0x5, 0x10) => Instruction::Srai { rd, rs1, shamt },
+                    (0x6, _) => Instruction::Ori { rd, rs1, imm },
+                    (0x7, _) => Instruction::Andi { rd, rs1, imm },
+                    (_, _) => return Err(illegal),
+                }
+            }
+            0x17 => {
+                let u = U_Instr::from_u32(inst);
+                Instruction::Auipc { rd: u.rd, imm: sign_extend((u.imm as u64) << 12, 32) }
+            }
+            0x1b => {
+                let i = I_Instr::from_u32(inst);
+                let (rd, rs1, imm) = (i.rd, i.rs1, sign_extend(i.imm as u64, 12));
+                let shamt = (i.imm & 0x1f) as u32;
+                match(i.funct3, i.imm >> 5) {
+                    (0x0, _) => Instruction::Addiw { rd, rs1, imm },
+                    (0x1, 0x0) => Instruction::Slliw { rd, rs1, shamt },
+                    (0x5, 0x0) => Instruction::Srliw { rd, rs1, shamt },
+                    (0x5, 0x20) => Instruction::Sraiw { rd, rs1, shamt },
+                    (_, _) => return Err(illegal),
+                }
+            }
+            0x23 => {
+                let s = S_Instr::from_u32(inst);
+                let (rs1, rs2, imm) = (s.rs1, s.rs2, sign_extend(s.imm as u64, 12));
+                match(s.funct3) {
+                    0x0 => Instruction::Sb { rs1, rs2, imm },
+                    0x1 => Instruction::Sh { rs1, rs2, imm },
+                    0x2 => Instruction::Sw { rs1, rs2, imm },
+                    0x3 => Instruction::Sd { rs1, rs2, imm },
+                    _ => return Err(illegal),
+                }
+            }
+            0x2f => {
+                let r = R_Instr::from_u32(inst);
+                let (rd, rs1, rs2) = (r.rd, r.rs1, r.rs2);
+                // funct7 is funct5 followed by the aq/rl ordering bits, which a single hart that
+                // performs its accesses in program order can ignore
+                match(r.funct3, r.funct7 >> 2) {
+                    (0x2, 0x02) if rs2 == 0 => Instruction::LrW { rd, rs1 },
+                    (0x2, 0x03) => Instruction::ScW { rd, rs1, rs2 },
+                    (0x2, 0x01) => Instruction::AmoswapW { rd, rs1, rs2 },
+                    (0x2, 0x00) => Instruction::AmoaddW { rd, rs1, rs2 },
+                    (0x2, 0x04) => Instruction::AmoxorW { rd, rs1, rs2 },
+                    (0x2, 0x0c) => Instruction::AmoandW { rd, rs1, rs2 },
+                    (0x2, 0x08) => Instruction::AmoorW { rd, rs1, rs2 },
+                    (0x2, 0x10) => Instruction::AmominW { rd, rs1, rs2 },
+                    (0x2, 0x14) => Instruction::AmomaxW { rd, rs1, rs2 },
+                    (0x2, 0x18) => Instruction::AmominuW { rd, rs1, rs2 },
+                    (0x2, 0x1c) => Instruction::AmomaxuW { rd, rs1, rs2 },
+                    (0x3, 0x02) if rs2 == 0 => Instruction::LrD { rd, rs1 },
+                    (0x3, 0x03) => Instruction::ScD { rd, rs1, rs2 },
+                    (0x3, 0x01) => Instruction::AmoswapD { rd, rs1, rs2 },
+                    (0x3, 0x00) => Instruction::AmoaddD { rd, rs1, rs2 },
+                    (0x3, 0x04) => Instruction::AmoxorD { rd, rs1, rs2 },
+                    (0x3, 0x0c) => Instruction::AmoandD { rd, rs1, rs2 },
+                    (0x3, 0x08) => Instruction::AmoorD { rd, rs1, rs2 },
+                    (0x3, 0x10) => Instruction::AmominD { rd, rs1, rs2 },
+                    (0x3, 0x14) => Instruction::AmomaxD { rd, rs1, rs2 },
+                    (0x3, 0x18) => Instruction::AmominuD { rd, rs1, rs2 },
+                    (0x3, 0x1c) => Instruction::AmomaxuD { rd, rs1, rs2 },
+                    (_, _) => return Err(illegal),
+                }
+            }
+            0x33 => {
+                let r = R_Instr::from_u32(inst);
+                let (rd, rs1, rs2) = (r.rd, r.rs1, r.rs2);
+                match(r.funct3, r.funct7) {
+                    (0x0, 0x0) => Instruction::Add { rd, rs1, rs2 },
+                    (0x0, 0x20) => Instruction::Sub { rd, rs1, rs2 },
+                    (0x1, 0x0) => Instruction::Sll { rd, rs1, rs2 },
+                    (0x2, 0x0) => Instruction::Slt { rd, rs1, rs2 },
+                    (0x3, 0x0) => Instruction::Sltu { rd, rs1, rs2 },
+                    (0x4, 0x0) => Instruction::Xor { rd, rs1, rs2 },
+                    (0x5, 0x0) => Instruction::Srl { rd, rs1, rs2 },
+                    (0x5, 0x20) => Instruction::Sra { rd, rs1, rs2 },
+                    (0x6, 0x0) => Instruction::Or { rd, rs1, rs2 },
+                    (0x7, 0x0) => Instruction::And { rd, rs1, rs2 },
+                    (0x0, 0x1) => Instruction::Mul { rd, rs1, rs2 },
+                    (0x1, 0x1) => Instruction::Mulh { rd, rs1, rs2 },
+                    (0x2, 0x1) => Instruction::Mulhsu { rd, rs1, rs2 },
+                    (0x3, 0x1) => Instruction::Mulhu { rd, rs1, rs2 },
+                    (0x4, 0x1) => Instruction::Div { rd, rs1, rs2 },
+                    (0x5, 0x1) => Instruction::Divu { rd, rs1, rs2 },
+                    (0x6, 0x1) => Instruction::Rem { rd, rs1, rs2 },
+                    (0x7, 0x1) => Instruction::Remu { rd, rs1, rs2 },
+                    (_, _) => return Err(illegal),
+                }
+            }
+            0x37 => {
+                let u = U_Instr::from_u32(inst);
+                Instruction::Lui { rd: u.rd, imm: sign_extend((u.imm as u64) << 12, 32) }
+            }
+            0x3b => {
+                let r = R_Instr::from_u32(inst);
+                let (rd, rs1, rs2) = (r.rd, r.rs1, r.rs2);
+                match(r.funct3, r.funct7) {
+                    (0x0, 0x0) => Instruction::Addw { rd, rs1, rs2 },
+                    (0x0, 0x20) => Instruction::Subw { rd, rs1, rs2 },
+                    (0x1, 0x0) => Instruction::Sllw { rd, rs1, rs2 },
+                    (0x5, 0x0) => Instruction::Srlw { rd, rs1, rs2 },
+                    (0x5, 0x20) => Instruction::Sraw { rd, rs1, rs2 },
+                    (0x0, 0x1) => Instruction::Mulw { rd, rs1, rs2 },
+                    (0x4, 0x1) => Instruction::Divw { rd, rs1, rs2 },
+                    (0x5, 0x1) => Instruction::Divuw { rd, rs1, rs2 },
+                    (0x6, 0x1) => Instruction::Remw { rd, rs1, rs2 },
+                    (0x7, 0x1) => Instruction::Remuw { rd, rs1, rs2 },
+                    (_, _) => return Err(illegal),
+                }
+            }
+            0x63 => {
+                let b = B_Instr::from_u32(inst);
+                let (rs1, rs2, imm) = (b.rs1, b.rs2, sign_extend(b.imm as u64, 13));
+                match(b.funct3) {
+                    0x0 => Instruction::Beq { rs1, rs2, imm },
+                    0x1 => Instruction::Bne { rs1, rs2, imm },
+                    0x4 => Instruction::Blt { rs1, rs2, imm },
+                    0x5 => Instruction::Bge { rs1, rs2, imm },
+                    0x6 => Instruction::Bltu { rs1, rs2, imm },
+                    0x7 => Instruction::Bgeu { rs1, rs2, imm },
+                    _ => return Err(illegal),
+                }
+            }
+            0x67 => {
+                let i = I_Instr::from_u32(inst);
+                match(i.funct3) {
+                    0x0 => Instruction::Jalr { rd: i.rd, rs1: i.rs1, imm: sign_extend(i.imm as u64, 12) },
+                    _ => return Err(illegal),
+                }
+            }
+            0x6f => {
+                let j = J_Instr::from_u32(inst);
+                Instruction::Jal { rd: j.rd, imm: sign_extend(j.imm as u64, 21) }
+            }
+            0x73 => {
+                let i = I_Instr::from_u32(inst);
+                match(i.funct3, i.imm, i.rs1, i.rd) {
+                    (0x0, 0x000, 0, 0) => Instruction::Ecall,
+                    (0x0, 0x001, 0, 0) => Instruction::Ebreak,
+                    (0x0, 0x102, 0, 0) => Instruction::Sret,
+                    (0x0, 0x302, 0, 0) => Instruction::Mret,
+                    (0x0, 0x105, 0, 0) => Instruction::Wfi,
+                    // SFENCE.VMA is funct7 0x09 with rs2 in the low bits of the immediate
+                    (0x0, imm, rs1, 0) if (imm >> 5 == 0x09) => Instruction::SfenceVma { rs1, rs2: imm & 0x1f },
+                    (0x1, csr, rs1, rd) => Instruction::Csrrw { rd, rs1, csr },
+                    (0x2, csr, rs1, rd) => Instruction::Csrrs { rd, rs1, csr },
+                    (0x3, csr, rs1, rd) => Instruction::Csrrc { rd, rs1, csr },
+                    (0x5, csr, uimm, rd) => Instruction::Csrrwi { rd, uimm: uimm as u64, csr },
+                    (0x6, csr, uimm, rd) => Instruction::Csrrsi { rd, uimm: uimm as u64, csr },
+                    (0x7, csr, uimm, rd) => Instruction::Csrrci { rd, uimm: uimm as u64, csr },
+                    (_, _, _, _) => return Err(illegal),
+                }
+            }
+            _ => return Err(illegal),
+        };
+
+        return Ok(instruction);
+    }
+}
+
 #[cfg(test)]
+#[allow(non_snake_case)]
 mod tests {
     use super::*;
 
@@ -239,5 +755,67 @@ mod tests {
         assert_eq!(J_Instr{ opcode: 0x6f, rd: 0x1d, imm: 0x0fe }, jal_instr);
         assert_eq!(J_Instr{ opcode: 0x7f, rd: 0x0, imm: 0x1ffffe }, test_instr);
     }
-}
 
+    #[test]
+    fn test_instruction_decode_sign_extends_immediates() {
+        // addi x30, x28, -1
+        let addi = u32::from_str_radix("11111111111111100000111100010011", 2).unwrap();
+        // sw x30, -4(x29)
+        let sw = u32::from_str_radix("11111111111011101010111000100011", 2).unwrap();
+        // beq x29, x30, -8
+        let beq = u32::from_str_radix("11111111111011101000110011100011", 2).unwrap();
+        // jal x1, -16
+        let jal = u32::from_str_radix("11111111000111111111000011101111", 2).unwrap();
+        // lui x29, 0x80000
+        let lui = u32::from_str_radix("10000000000000000000111010110111", 2).unwrap();
+
+        assert_eq!(Instruction::decode(addi), Ok(Instruction::Addi { rd: 30, rs1: 28, imm: -1 }));
+        assert_eq!(Instruction::decode(sw), Ok(Instruction::Sw { rs1: 29, rs2: 30, imm: -4 }));
+        assert_eq!(Instruction::decode(beq), Ok(Instruction::Beq { rs1: 29, rs2: 30, imm: -8 }));
+        assert_eq!(Instruction::decode(jal), Ok(Instruction::Jal { rd: 1, imm: -16 }));
+        assert_eq!(Instruction::decode(lui), Ok(Instruction::Lui { rd: 29, imm: -0x8000_0000 }));
+    }
+
+    #[test]
+    fn test_instruction_decode_shifts() {
+        // srai x5, x6, 63
+        let srai = u32::from_str_radix("01000011111100110101001010010011", 2).unwrap();
+        // sraiw x5, x6, 31
+        let sraiw = u32::from_str_radix("01000001111100110101001010011011", 2).unwrap();
+
+        assert_eq!(Instruction::decode(srai), Ok(Instruction::Srai { rd: 5, rs1: 6, shamt: 63 }));
+        assert_eq!(Instruction::decode(sraiw), Ok(Instruction::Sraiw { rd: 5, rs1: 6, shamt: 31 }));
+    }
+
+    #[test]
+    fn test_instruction_decode_system() {
+        assert_eq!(Instruction::decode(0x0000_0073), Ok(Instruction::Ecall));
+        assert_eq!(Instruction::decode(0x0010_0073), Ok(Instruction::Ebreak));
+        assert_eq!(Instruction::decode(0x1020_0073), Ok(Instruction::Sret));
+        assert_eq!(Instruction::decode(0x3020_0073), Ok(Instruction::Mret));
+        assert_eq!(Instruction::decode(0x1050_0073), Ok(Instruction::Wfi));
+        // sfence.vma a0, a1
+        assert_eq!(Instruction::decode(0x12b5_0073), Ok(Instruction::SfenceVma { rs1: 10, rs2: 11 }));
+        // ecall with a non-zero rd is reserved
+        assert_eq!(Instruction::decode(0x0000_00f3), Err(errors::Exception::IllegalInstruction(0xf3)));
+    }
+
+    #[test]
+    fn test_instruction_decode_csr() {
+        // csrrw t1, mscratch, t0
+        assert_eq!(Instruction::decode(0x3402_9373), Ok(Instruction::Csrrw { rd: 6, rs1: 5, csr: 0x340 }));
+        // csrrsi t1, mscratch, 31
+        assert_eq!(Instruction::decode(0x340f_e373), Ok(Instruction::Csrrsi { rd: 6, uimm: 31, csr: 0x340 }));
+        // funct3 4 is not a CSR instruction
+        assert_eq!(Instruction::decode(0x3402_c373), Err(errors::Exception::IllegalInstruction(0x3402_c373)));
+    }
+
+    #[test]
+    fn test_instruction_decode_illegal() {
+        // An all-zero word is defined to be illegal
+        assert_eq!(Instruction::decode(0), Err(errors::Exception::IllegalInstruction(0)));
+        // slliw with shamt[5] set is reserved
+        let slliw = u32::from_str_radix("00000010000000110001001010011011", 2).unwrap();
+        assert_eq!(Instruction::decode(slliw), Err(errors::Exception::IllegalInstruction(slliw as u64)));
+    }
+}
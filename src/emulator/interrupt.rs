@@ -1,6 +1,7 @@
 pub const MASK_INTERRUPT_BIT: u64 = 1 << 63;
 
 #[derive(Debug, Copy, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Interrupt {
     SupervisorSoftwareInterrupt,
     MachineSoftwareInterrupt,
@@ -14,12 +15,12 @@ impl Interrupt {
     pub fn code(&self) -> u64 {
         // [INTERRUPT_FLAG][INTERRUPT_CODE]
         let interrupt_code = match(self) {
-            SupervisorSoftwareInterrupt => 1,
-            MachineSoftwareInterrupt => 3,
-            SupervisorTimerInterrupt => 5,
-            MachineTimerInterrupt => 7,
-            SupervisorExternalInterrupt => 9,
-            MachineExternalInterrupt => 11,
+            Interrupt::SupervisorSoftwareInterrupt => 1,
+            Interrupt::MachineSoftwareInterrupt => 3,
+            Interrupt::SupervisorTimerInterrupt => 5,
+            Interrupt::MachineTimerInterrupt => 7,
+            Interrupt::SupervisorExternalInterrupt => 9,
+            Interrupt::MachineExternalInterrupt => 11,
         };
 
         // Set interrupt flag to 1 so we know the code should be interpreted as an
@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+
+use super::bus;
+use super::csr;
+use super::errors;
+use super::pmp;
+use super::{Mode, User, Supervisor, Machine};
+
+pub const PAGE_SIZE: u64 = 4096;
+
+// Page table entry fields
+pub const PTE_V: u64 = 1 << 0;
+pub const PTE_R: u64 = 1 << 1;
+pub const PTE_W: u64 = 1 << 2;
+pub const PTE_X: u64 = 1 << 3;
+pub const PTE_U: u64 = 1 << 4;
+pub const PTE_G: u64 = 1 << 5;
+pub const PTE_A: u64 = 1 << 6;
+pub const PTE_D: u64 = 1 << 7;
+const PTE_PPN_SHIFT: u64 = 10;
+const PTE_PPN_MASK: u64 = (1 << 44) - 1;
+// Bits 63:54 are reserved for extensions we don't implement (Svpbmt, Svnapot)
+const PTE_RESERVED: u64 = 0x3ff << 54;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AccessType {
+    Instruction,
+    Load,
+    // Stores and AMOs
+    Store,
+}
+
+impl AccessType {
+    pub fn page_fault(self, addr: u64) -> errors::Exception {
+        match(self) {
+            AccessType::Instruction => errors::Exception::InstructionPageFault(addr),
+            AccessType::Load => errors::Exception::LoadPageFault(addr),
+            AccessType::Store => errors::Exception::StoreAMOPageFault(addr),
+        }
+    }
+
+    pub fn access_fault(self, addr: u64) -> errors::Exception {
+        match(self) {
+            AccessType::Instruction => errors::Exception::InstructionAccessFault(addr),
+            AccessType::Load => errors::Exception::LoadAccessFault(addr),
+            AccessType::Store => errors::Exception::StoreAMOAccessFault(addr),
+        }
+    }
+
+    pub fn misaligned(self, addr: u64) -> errors::Exception {
+        match(self) {
+            AccessType::Instruction => errors::Exception::InstructionAddrMisaligned(addr),
+            AccessType::Load => errors::Exception::LoadAccessMisaligned(addr),
+            AccessType::Store => errors::Exception::StoreAMOAddrMisaligned(addr),
+        }
+    }
+}
+
+// Layout of the page tables for a paging mode. Every level indexes its table with 9 bits of the
+// virtual page number
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Scheme {
+    pub levels: u32,
+    pub va_bits: u32,
+}
+
+pub const SV39: Scheme = Scheme { levels: 3, va_bits: 39 };
+pub const SV48: Scheme = Scheme { levels: 4, va_bits: 48 };
+pub const SV57: Scheme = Scheme { levels: 5, va_bits: 57 };
+
+// The paging scheme selected by satp.MODE, None for Bare (no translation) or unsupported modes
+pub fn scheme(satp: u64) -> Option<Scheme> {
+    match(satp >> 60) {
+        csr::SATP_MODE_SV39 => Some(SV39),
+        csr::SATP_MODE_SV48 => Some(SV48),
+        csr::SATP_MODE_SV57 => Some(SV57),
+        _ => None,
+    }
+}
+
+// A cached leaf translation. The PTE is kept to recheck permissions on every hit, since SUM, MXR
+// and the privilege can change without a fence
+#[derive(Debug, Copy, Clone)]
+struct TlbEntry {
+    pte: u64,
+    level: u32,
+}
+
+impl TlbEntry {
+    // Mask of the virtual address bits passed through untranslated, more for superpages
+    fn offset_mask(&self) -> u64 {
+        return (PAGE_SIZE << (9 * self.level)) - 1;
+    }
+
+    fn physical(&self, addr: u64) -> u64 {
+        let ppn = (self.pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK;
+        return ((ppn * PAGE_SIZE) & !self.offset_mask()) | (addr & self.offset_mask());
+    }
+
+    fn covers(&self, vpn: u64, addr: u64) -> bool {
+        return (vpn * PAGE_SIZE) & !self.offset_mask() == addr & !self.offset_mask();
+    }
+}
+
+/// Translation cache, keyed by 4KiB virtual page number and ASID. Superpages are cached one
+/// 4KiB page at a time, and global mappings are stored without an ASID so every address space
+/// shares them.
+pub struct Tlb {
+    entries: HashMap<(u64, Option<u64>), TlbEntry>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Tlb {
+    pub fn new() -> Tlb {
+        return Tlb { entries: HashMap::new(), hits: 0, misses: 0 };
+    }
+
+    fn lookup(&self, vpn: u64, asid: u64) -> Option<TlbEntry> {
+        return self.entries.get(&(vpn, Some(asid))).or_else(|| self.entries.get(&(vpn, None))).copied();
+    }
+
+    fn insert(&mut self, vpn: u64, asid: u64, global: bool, entry: TlbEntry) {
+        let asid = if (global) { None } else { Some(asid) };
+        self.entries.insert((vpn, asid), entry);
+    }
+
+    /// Implements SFENCE.VMA: `addr` restricts the flush to the translations covering that
+    /// address, and `asid` to that address space's non-global translations.
+    pub fn flush(&mut self, addr: Option<u64>, asid: Option<u64>) {
+        self.entries.retain(|&(vpn, entry_asid), entry| {
+            let address_matches = addr.is_none_or(|addr| entry.covers(vpn, addr));
+            let asid_matches = asid.is_none_or(|asid| entry_asid == Some(asid));
+            return !(address_matches && asid_matches);
+        });
+    }
+}
+
+/// Translates a virtual address into a physical one for an access of `size` bits, through the
+/// TLB or by walking the page tables rooted at satp. `privilege` is the effective privilege of
+/// the access, so MPRV is already accounted for by the caller.
+pub fn translate(bus: &mut bus::Bus, tlb: &mut Tlb, csr: &csr::Csr, privilege: Mode, addr: u64, size: u64,
+        access: AccessType) -> Result<u64, errors::Exception> {
+    let (satp, status) = (csr.load(csr::SATP), csr.load(csr::MSTATUS));
+    let Some(scheme) = scheme(satp) else {
+        return Ok(addr);
+    };
+    if (privilege == Machine) {
+        return Ok(addr);
+    }
+
+    // An access straddling two pages would need two translations. The spec allows raising a
+    // misaligned exception instead, which software emulates with smaller accesses
+    if ((addr & (PAGE_SIZE - 1)) + size / 8 > PAGE_SIZE) {
+        return Err(access.misaligned(addr));
+    }
+
+    // Virtual addresses must be sign extended from their top bit
+    let top = ((addr as i64) >> (scheme.va_bits - 1)) as u64;
+    if (top != 0 && top != u64::MAX) {
+        return Err(access.page_fault(addr));
+    }
+
+    let vpn = addr / PAGE_SIZE;
+    let asid = (satp & csr::SATP_ASID_MASK) >> 44;
+    if let Some(entry) = tlb.lookup(vpn, asid) {
+        // Cached entries always have A set, but the first store to a clean page must go through
+        // the walk to set D
+        if (access != AccessType::Store || entry.pte & PTE_D != 0) {
+            tlb.hits += 1;
+            if (!permitted(entry.pte, status, privilege, access)) {
+                return Err(access.page_fault(addr));
+            }
+            return Ok(entry.physical(addr));
+        }
+    }
+
+    tlb.misses += 1;
+    let (entry, global) = walk(bus, csr, scheme, privilege, addr, access)?;
+    tlb.insert(vpn, asid, global, entry);
+    return Ok(entry.physical(addr));
+}
+
+// Walks the page tables for a leaf entry allowing the access, updating its A/D bits. Also
+// returns whether the mapping is global, which a G bit at any level makes it
+fn walk(bus: &mut bus::Bus, csr: &csr::Csr, scheme: Scheme, privilege: Mode, addr: u64,
+        access: AccessType) -> Result<(TlbEntry, bool), errors::Exception> {
+    let (satp, status) = (csr.load(csr::SATP), csr.load(csr::MSTATUS));
+    let mut table = (satp & csr::SATP_PPN_MASK) * PAGE_SIZE;
+    let mut level = scheme.levels - 1;
+    let mut global = false;
+    let (pte_addr, pte) = loop {
+        let vpn = (addr >> (12 + 9 * level)) & 0x1ff;
+        let pte_addr = table + vpn * 8;
+        // Page table accesses are checked against PMP as S-mode accesses
+        if (!pmp::check(csr, pte_addr, 8, Supervisor, AccessType::Load)) {
+            return Err(access.access_fault(addr));
+        }
+        let pte = bus.load(pte_addr, 64).map_err(|_| access.access_fault(addr))?;
+
+        if (pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte & PTE_RESERVED != 0) {
+            return Err(access.page_fault(addr));
+        }
+        global |= pte & PTE_G != 0;
+        if (pte & (PTE_R | PTE_X) != 0) {
+            break (pte_addr, pte);
+        }
+        // Not a leaf, so this points to the next level table
+        if (level == 0) {
+            return Err(access.page_fault(addr));
+        }
+        table = ((pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK) * PAGE_SIZE;
+        level -= 1;
+    };
+
+    if (!permitted(pte, status, privilege, access)) {
+        return Err(access.page_fault(addr));
+    }
+
+    // A superpage's physical page number must be aligned to its size
+    let ppn = (pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK;
+    let superpage_mask = (1 << (9 * level)) - 1;
+    if (ppn & superpage_mask != 0) {
+        return Err(access.page_fault(addr));
+    }
+
+    // Update the accessed and dirty bits in the page table, as hardware managed A/D bits do
+    let mut updated = pte | PTE_A;
+    if (access == AccessType::Store) {
+        updated |= PTE_D;
+    }
+    if (updated != pte) {
+        if (!pmp::check(csr, pte_addr, 8, Supervisor, AccessType::Store)) {
+            return Err(access.access_fault(addr));
+        }
+        bus.store(pte_addr, 64, updated).map_err(|_| access.access_fault(addr))?;
+    }
+
+    return Ok((TlbEntry { pte: updated, level: level }, global));
+}
+
+#[allow(non_upper_case_globals)]
+fn permitted(pte: u64, status: u64, privilege: Mode, access: AccessType) -> bool {
+    let allowed = match(access) {
+        AccessType::Instruction => pte & PTE_X != 0,
+        // mstatus.MXR makes executable pages readable too
+        AccessType::Load => pte & PTE_R != 0 || (status & csr::MASK_MXR != 0 && pte & PTE_X != 0),
+        AccessType::Store => pte & PTE_W != 0,
+    };
+    let user_page = pte & PTE_U != 0;
+    let privilege_ok = match(privilege) {
+        User => user_page,
+        // S-mode only touches user pages with mstatus.SUM, and can never execute them
+        Supervisor => !user_page || (access != AccessType::Instruction && status & csr::MASK_SUM != 0),
+        _ => true,
+    };
+    return allowed && privilege_ok;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::constants::DRAM_BASE;
+    use crate::emulator::dram;
+    use crate::emulator::clint;
+    use crate::emulator::uart::backends::MemoryBackend;
+
+    const ROOT: u64 = DRAM_BASE;
+    const SATP: u64 = (csr::SATP_MODE_SV39 << 60) | (ROOT / PAGE_SIZE);
+
+    // Translates with satp and mstatus set to the given values
+    #[allow(clippy::too_many_arguments)]
+    fn translate(bus: &mut bus::Bus, tlb: &mut Tlb, satp: u64, status: u64, privilege: Mode, addr: u64, size: u64,
+            access: AccessType) -> Result<u64, errors::Exception> {
+        let mut csr = csr::Csr::new();
+        csr.store(csr::SATP, satp);
+        csr.store(csr::MSTATUS, status);
+        return super::translate(bus, tlb, &csr, privilege, addr, size, access);
+    }
+
+    fn pointer(table: u64) -> u64 {
+        return ((table / PAGE_SIZE) << PTE_PPN_SHIFT) | PTE_V;
+    }
+
+    fn leaf(addr: u64, flags: u64) -> u64 {
+        return ((addr / PAGE_SIZE) << PTE_PPN_SHIFT) | flags | PTE_V;
+    }
+
+    // Maps virtual page 0x4000_1000 to DRAM_BASE + 0x10000 with the given flags through three
+    // levels of tables, and the 1GiB virtual gigapage at 0x8000_0000 identity mapped for S-mode
+    fn setup(flags: u64) -> bus::Bus {
+        let mut bus = bus::Bus::with_devices(dram::Dram::new(Vec::new()), clint::TimeSource::Instructions, Box::new(MemoryBackend::new()));
+        bus.store(ROOT + 8, 64, pointer(ROOT + 0x1000)).unwrap();
+        bus.store(ROOT + 0x1000, 64, pointer(ROOT + 0x2000)).unwrap();
+        bus.store(ROOT + 0x2000 + 8, 64, leaf(DRAM_BASE + 0x10000, flags)).unwrap();
+        bus.store(ROOT + 2 * 8, 64, leaf(DRAM_BASE, PTE_R | PTE_W | PTE_X | PTE_A | PTE_D)).unwrap();
+        return bus;
+    }
+
+    #[test]
+    fn test_translate() {
+        let mut bus = setup(PTE_R | PTE_W | PTE_U | PTE_A | PTE_D);
+
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), SATP, 0, User, 0x4000_1234, 32, AccessType::Load), Ok(DRAM_BASE + 0x10234));
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), SATP, 0, Supervisor, 0x8765_4321, 8, AccessType::Instruction), Ok(DRAM_BASE + 0x0765_4321));
+        // Bare mode and M-mode accesses aren't translated
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), 0, 0, User, 0x4000_1234, 32, AccessType::Load), Ok(0x4000_1234));
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), SATP, 0, Machine, 0x4000_1234, 32, AccessType::Load), Ok(0x4000_1234));
+    }
+
+    #[test]
+    fn test_page_faults() {
+        let mut bus = setup(PTE_R | PTE_U | PTE_A);
+
+        // Unmapped
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), SATP, 0, User, 0x4000_2000, 32, AccessType::Load), Err(errors::Exception::LoadPageFault(0x4000_2000)));
+        // Not writable or executable
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), SATP, 0, User, 0x4000_1000, 32, AccessType::Store), Err(errors::Exception::StoreAMOPageFault(0x4000_1000)));
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), SATP, 0, User, 0x4000_1000, 16, AccessType::Instruction), Err(errors::Exception::InstructionPageFault(0x4000_1000)));
+        // Not a user page
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), SATP, 0, User, 0x8000_0000, 32, AccessType::Load), Err(errors::Exception::LoadPageFault(0x8000_0000)));
+        // Not sign extended from bit 38
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), SATP, 0, User, 1 << 39, 32, AccessType::Load), Err(errors::Exception::LoadPageFault(1 << 39)));
+        // Straddling two pages
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), SATP, 0, User, 0x4000_1ffe, 32, AccessType::Load), Err(errors::Exception::LoadAccessMisaligned(0x4000_1ffe)));
+
+        // A gigapage whose physical address isn't 1GiB aligned is malformed
+        bus.store(ROOT + 2 * 8, 64, leaf(DRAM_BASE + 0x20_0000, PTE_R | PTE_A)).unwrap();
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), SATP, 0, Supervisor, 0x8000_0000, 32, AccessType::Load), Err(errors::Exception::LoadPageFault(0x8000_0000)));
+    }
+
+    #[test]
+    fn test_sum_and_mxr() {
+        let mut bus = setup(PTE_X | PTE_U | PTE_A);
+
+        // S-mode can't touch user pages without SUM, and can never execute them
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), SATP, csr::MASK_MXR, Supervisor, 0x4000_1000, 32, AccessType::Load), Err(errors::Exception::LoadPageFault(0x4000_1000)));
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), SATP, csr::MASK_SUM, Supervisor, 0x4000_1000, 16, AccessType::Instruction), Err(errors::Exception::InstructionPageFault(0x4000_1000)));
+        // An execute-only page is only readable with MXR
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), SATP, csr::MASK_SUM, Supervisor, 0x4000_1000, 32, AccessType::Load), Err(errors::Exception::LoadPageFault(0x4000_1000)));
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), SATP, csr::MASK_SUM | csr::MASK_MXR, Supervisor, 0x4000_1000, 32, AccessType::Load), Ok(DRAM_BASE + 0x10000));
+    }
+
+    #[test]
+    fn test_accessed_dirty_bits() {
+        let mut bus = setup(PTE_R | PTE_W | PTE_U);
+        let pte_addr = ROOT + 0x2000 + 8;
+
+        translate(&mut bus, &mut Tlb::new(), SATP, 0, User, 0x4000_1000, 32, AccessType::Load).unwrap();
+        assert_eq!(bus.load(pte_addr, 64).unwrap() & (PTE_A | PTE_D), PTE_A);
+        translate(&mut bus, &mut Tlb::new(), SATP, 0, User, 0x4000_1000, 32, AccessType::Store).unwrap();
+        assert_eq!(bus.load(pte_addr, 64).unwrap() & (PTE_A | PTE_D), PTE_A | PTE_D);
+    }
+
+    #[test]
+    fn test_translate_sv48_sv57() {
+        let mut bus = bus::Bus::with_devices(dram::Dram::new(Vec::new()), clint::TimeSource::Instructions, Box::new(MemoryBackend::new()));
+        // Sv48: map the first page of the upper half of the address space through four levels
+        let sv48 = (csr::SATP_MODE_SV48 << 60) | (ROOT / PAGE_SIZE);
+        bus.store(ROOT + 256 * 8, 64, pointer(ROOT + 0x1000)).unwrap();
+        bus.store(ROOT + 0x1000, 64, pointer(ROOT + 0x2000)).unwrap();
+        bus.store(ROOT + 0x2000, 64, pointer(ROOT + 0x3000)).unwrap();
+        bus.store(ROOT + 0x3000, 64, leaf(DRAM_BASE + 0x10000, PTE_R | PTE_A)).unwrap();
+
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), sv48, 0, Supervisor, 0xffff_8000_0000_0010, 32, AccessType::Load), Ok(DRAM_BASE + 0x10010));
+        // Bit 47 set without the bits above it
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), sv48, 0, Supervisor, 0x8000_0000_0010, 32, AccessType::Load), Err(errors::Exception::LoadPageFault(0x8000_0000_0010)));
+
+        // Sv57 adds a fifth level on top, so the same tables hang off entry 256 of a new root
+        let sv57_root = ROOT + 0x4000;
+        let sv57 = (csr::SATP_MODE_SV57 << 60) | (sv57_root / PAGE_SIZE);
+        bus.store(sv57_root + 256 * 8, 64, pointer(ROOT)).unwrap();
+        let va = 0xff00_0000_0000_0000 | (256 << 39) | 0x10;
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), sv57, 0, Supervisor, va, 32, AccessType::Load), Ok(DRAM_BASE + 0x10010));
+        assert_eq!(translate(&mut bus, &mut Tlb::new(), sv57, 0, Supervisor, 1 << 57, 32, AccessType::Load), Err(errors::Exception::LoadPageFault(1 << 57)));
+    }
+
+    #[test]
+    fn test_tlb_caches_translations() {
+        let mut bus = setup(PTE_R | PTE_U | PTE_A);
+        let mut tlb = Tlb::new();
+        let pte_addr = ROOT + 0x2000 + 8;
+
+        assert_eq!(translate(&mut bus, &mut tlb, SATP, 0, User, 0x4000_1000, 32, AccessType::Load), Ok(DRAM_BASE + 0x10000));
+        assert_eq!(translate(&mut bus, &mut tlb, SATP, 0, User, 0x4000_1008, 32, AccessType::Load), Ok(DRAM_BASE + 0x10008));
+        assert_eq!((tlb.hits, tlb.misses), (1, 1));
+
+        // Remapping the page isn't visible until a fence
+        bus.store(pte_addr, 64, leaf(DRAM_BASE + 0x20000, PTE_R | PTE_U | PTE_A)).unwrap();
+        assert_eq!(translate(&mut bus, &mut tlb, SATP, 0, User, 0x4000_1000, 32, AccessType::Load), Ok(DRAM_BASE + 0x10000));
+        tlb.flush(Some(0x4000_1abc), None);
+        assert_eq!(translate(&mut bus, &mut tlb, SATP, 0, User, 0x4000_1000, 32, AccessType::Load), Ok(DRAM_BASE + 0x20000));
+
+        // Permissions are checked on hits too
+        assert_eq!(translate(&mut bus, &mut tlb, SATP, 0, Supervisor, 0x4000_1000, 32, AccessType::Load), Err(errors::Exception::LoadPageFault(0x4000_1000)));
+    }
+
+    #[test]
+    fn test_tlb_store_sets_dirty_bit() {
+        let mut bus = setup(PTE_R | PTE_W | PTE_U | PTE_A);
+        let mut tlb = Tlb::new();
+        let pte_addr = ROOT + 0x2000 + 8;
+
+        translate(&mut bus, &mut tlb, SATP, 0, User, 0x4000_1000, 32, AccessType::Load).unwrap();
+        translate(&mut bus, &mut tlb, SATP, 0, User, 0x4000_1000, 32, AccessType::Store).unwrap();
+        assert_eq!(bus.load(pte_addr, 64).unwrap() & PTE_D, PTE_D);
+        assert_eq!((tlb.hits, tlb.misses), (0, 2));
+        translate(&mut bus, &mut tlb, SATP, 0, User, 0x4000_1000, 32, AccessType::Store).unwrap();
+        assert_eq!((tlb.hits, tlb.misses), (1, 2));
+    }
+
+    #[test]
+    fn test_tlb_flush_by_asid() {
+        let mut bus = setup(PTE_R | PTE_U | PTE_A);
+        // Make the gigapage global
+        bus.store(ROOT + 2 * 8, 64, leaf(DRAM_BASE, PTE_R | PTE_G | PTE_A)).unwrap();
+        let mut tlb = Tlb::new();
+        let asid1 = SATP | (1 << 44);
+        let asid2 = SATP | (2 << 44);
+
+        translate(&mut bus, &mut tlb, asid1, 0, User, 0x4000_1000, 32, AccessType::Load).unwrap();
+        translate(&mut bus, &mut tlb, asid1, 0, Supervisor, 0x8000_1000, 32, AccessType::Load).unwrap();
+        // Global mappings are shared across address spaces, others aren't
+        translate(&mut bus, &mut tlb, asid2, 0, Supervisor, 0x8000_1000, 32, AccessType::Load).unwrap();
+        translate(&mut bus, &mut tlb, asid2, 0, User, 0x4000_1000, 32, AccessType::Load).unwrap();
+        assert_eq!((tlb.hits, tlb.misses), (1, 3));
+
+        // Flushing an ASID keeps global mappings and other address spaces
+        tlb.flush(None, Some(1));
+        translate(&mut bus, &mut tlb, asid1, 0, Supervisor, 0x8000_1000, 32, AccessType::Load).unwrap();
+        translate(&mut bus, &mut tlb, asid2, 0, User, 0x4000_1000, 32, AccessType::Load).unwrap();
+        translate(&mut bus, &mut tlb, asid1, 0, User, 0x4000_1000, 32, AccessType::Load).unwrap();
+        assert_eq!((tlb.hits, tlb.misses), (3, 4));
+
+        // Flushing an address inside a superpage drops the cached pieces of all of it
+        tlb.flush(Some(0x8765_0000), None);
+        translate(&mut bus, &mut tlb, asid1, 0, Supervisor, 0x8000_1000, 32, AccessType::Load).unwrap();
+        assert_eq!((tlb.hits, tlb.misses), (3, 5));
+    }
+}
@@ -1,58 +1,290 @@
+use super::bus;
+use super::csr;
 use super::errors;
 
-pub const NUM_INPUTS: u64 = 64; // Defining our architecture to support 64 inputs max, because it
-// can fit all flags on one u64
+pub const NUM_SOURCES: usize = 64; // Defining our architecture to support 64 sources max (source 0
+// is reserved, meaning no interrupt), because the pending and enable bits all fit on one u64
+
+// One hart with an M-mode and an S-mode context, in that order, like QEMU's virt machine
+pub const NUM_CONTEXTS: usize = 2;
+pub const CONTEXT_MACHINE: usize = 0;
+pub const CONTEXT_SUPERVISOR: usize = 1;
+
+// Register offsets from PLIC_BASE, following the SiFive PLIC memory map
+pub const PLIC_PRIORITY: u64 = 0x0;
+pub const PLIC_PENDING: u64 = 0x1000;
+pub const PLIC_ENABLE: u64 = 0x2000;
+pub const PLIC_ENABLE_STRIDE: u64 = 0x80;
+pub const PLIC_CONTEXT: u64 = 0x20_0000;
+pub const PLIC_CONTEXT_STRIDE: u64 = 0x1000;
+// Offsets within a context's block
+pub const PLIC_THRESHOLD: u64 = 0x0;
+pub const PLIC_CLAIM: u64 = 0x4;
+
+// Priorities are 3 bits wide, 0 meaning never interrupt
+const PRIORITY_MASK: u32 = 0x7;
+// Source 0 doesn't exist, so its bit is hardwired to zero
+const SOURCE_MASK: u64 = !1;
 
 pub struct Plic {
-    spriority: [u64; NUM_INPUTS as usize],
+    priority: [u32; NUM_SOURCES],
     pending: u64,
-    claim: u64,
-    completed: u64,
+    // Sources claimed by a context and not completed yet, which can't become pending meanwhile
+    claimed: u64,
+    // The last level of each source's line, so one still raised pends again on completion
+    levels: u64,
+    enable: [u64; NUM_CONTEXTS],
+    threshold: [u32; NUM_CONTEXTS],
 }
 
-pub const PLIC_BASE: u64 = 0x1000_0000;
-pub const PLIC_SPRIORITY_ADDR: u64 = PLIC_BASE+4;
-pub const PLIC_SPRIORITY_END: u64 = PLIC_SPRIORITY_ADDR+(NUM_INPUTS*8*8)-1;
-pub const PLIC_SPENDING_ADDR: u64 = PLIC_SPRIORITY_END + 4;
-pub const PLIC_CLAIM_ADDR: u64 = PLIC_SPENDING_ADDR + 4;
-pub const PLIC_COMPLETED_ADDR: u64 = PLIC_CLAIM_ADDR + 4;
-pub const PLIC_END: u64 = PLIC_COMPLETED_ADDR;
-
 impl Plic {
     pub fn new() -> Self {
-        let spriority = [0; NUM_INPUTS as usize];
         return Plic {
-            spriority : spriority,
-            pending : 0,
-            claim : 0,
-            completed : 0,
+            priority: [0; NUM_SOURCES],
+            pending: 0,
+            claimed: 0,
+            levels: 0,
+            enable: [0; NUM_CONTEXTS],
+            threshold: [0; NUM_CONTEXTS],
         };
     }
 
-    pub fn load(&self, addr: u64) -> Result<u64, errors::Exception> {
-        match(addr) {
-            PLIC_SPRIORITY_ADDR..=PLIC_SPRIORITY_END => {
-                let index = (addr - PLIC_SPRIORITY_ADDR) / 64;
-                return Ok(self.spriority[index as usize]);
-            },
-            PLIC_SPENDING_ADDR => Ok(self.pending),
-            PLIC_CLAIM_ADDR => Ok(self.claim),
-            PLIC_COMPLETED_ADDR => Ok(self.completed),
-            _ => Err(errors::Exception::LoadAccessFault(addr))
+    // The interrupt gateway: a raised source becomes pending unless it is still being handled
+    pub fn update(&mut self, source: u64, level: bool) {
+        let bit = (1 << source) & SOURCE_MASK;
+        if (level) {
+            self.levels |= bit;
+        } else {
+            self.levels &= !bit;
+        }
+        if (level && self.claimed & bit == 0) {
+            self.pending |= bit;
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_pending(&self, source: u64) -> bool {
+        return self.pending & (1 << source) != 0;
+    }
+
+    // The highest priority source pending and enabled for the context, above its threshold.
+    // Ties go to the lowest source number
+    fn best_source(&self, context: usize) -> Option<u64> {
+        let candidates = self.pending & self.enable[context];
+        let mut best: Option<(u64, u32)> = None;
+        for source in 1..NUM_SOURCES as u64 {
+            let priority = self.priority[source as usize];
+            if (candidates & (1 << source) == 0 || priority <= self.threshold[context]) {
+                continue;
+            }
+            if (best.is_none_or(|(_, best_priority)| priority > best_priority)) {
+                best = Some((source, priority));
+            }
+        }
+        return best.map(|(source, _)| source);
+    }
+
+    // The MEIP and SEIP bits of mip as driven by the PLIC
+    pub fn interrupts(&self) -> u64 {
+        let mut pending = 0;
+        if (self.best_source(CONTEXT_MACHINE).is_some()) {
+            pending |= csr::MASK_MEIP;
+        }
+        if (self.best_source(CONTEXT_SUPERVISOR).is_some()) {
+            pending |= csr::MASK_SEIP;
+        }
+        return pending;
+    }
+
+    // Reading the claim register hands out the best source, 0 when there is none
+    fn claim(&mut self, context: usize) -> u64 {
+        let Some(source) = self.best_source(context) else {
+            return 0;
+        };
+        self.pending &= !(1 << source);
+        self.claimed |= 1 << source;
+        return source;
+    }
+
+    // Completing a source that isn't enabled for the context is ignored, as the spec allows
+    fn complete(&mut self, context: usize, source: u64) {
+        if (source < NUM_SOURCES as u64 && self.enable[context] & (1 << source) != 0) {
+            self.claimed &= !(1 << source);
+            self.pending |= self.levels & (1 << source);
+        }
+    }
+
+    // The context whose threshold/claim block or enable bits the offset falls in, and the
+    // offset within it
+    fn context(offset: u64, base: u64, stride: u64) -> Option<(usize, u64)> {
+        let context = ((offset - base) / stride) as usize;
+        if (context >= NUM_CONTEXTS) {
+            return None;
         }
+        return Some((context, (offset - base) % stride));
+    }
+}
+
+impl bus::Device for Plic {
+    // All registers are 32 bits wide, so only aligned word accesses are supported
+    fn load(&mut self, offset: u64, size: u64) -> Result<u64, errors::Exception> {
+        let fault = errors::Exception::LoadAccessFault(offset);
+        if (size != 32 || !offset.is_multiple_of(4)) {
+            return Err(fault);
+        }
+
+        let value = match(offset) {
+            PLIC_PRIORITY..PLIC_PENDING => {
+                let source = (offset / 4) as usize;
+                if (source >= NUM_SOURCES) {
+                    return Err(fault);
+                }
+                self.priority[source] as u64
+            },
+            PLIC_PENDING..PLIC_ENABLE => match((offset - PLIC_PENDING) / 4) {
+                word @ 0..=1 => (self.pending >> (32 * word)) & 0xffff_ffff,
+                _ => return Err(fault),
+            },
+            PLIC_ENABLE..PLIC_CONTEXT => match(Self::context(offset, PLIC_ENABLE, PLIC_ENABLE_STRIDE)) {
+                Some((context, word @ (0 | 4))) => (self.enable[context] >> (8 * word)) & 0xffff_ffff,
+                _ => return Err(fault),
+            },
+            _ => match(Self::context(offset, PLIC_CONTEXT, PLIC_CONTEXT_STRIDE)) {
+                Some((context, PLIC_THRESHOLD)) => self.threshold[context] as u64,
+                Some((context, PLIC_CLAIM)) => self.claim(context),
+                _ => return Err(fault),
+            },
+        };
+        return Ok(value);
     }
 
-    pub fn store(&mut self, addr: u64, value: u64) -> Result<(), errors::Exception> {
-        match(addr) {
-            PLIC_SPRIORITY_ADDR..=PLIC_SPRIORITY_END => {
-                let index = (addr - PLIC_SPRIORITY_ADDR) / 64;
-                return Ok(self.spriority[index as usize] = value);
+    fn store(&mut self, offset: u64, size: u64, value: u64) -> Result<(), errors::Exception> {
+        let fault = errors::Exception::StoreAMOAccessFault(offset);
+        if (size != 32 || !offset.is_multiple_of(4)) {
+            return Err(fault);
+        }
+
+        let value = value & 0xffff_ffff;
+        match(offset) {
+            PLIC_PRIORITY..PLIC_PENDING => {
+                let source = (offset / 4) as usize;
+                if (source >= NUM_SOURCES) {
+                    return Err(fault);
+                }
+                if (source != 0) {
+                    self.priority[source] = value as u32 & PRIORITY_MASK;
+                }
+            },
+            // Pending bits are read-only
+            PLIC_PENDING..PLIC_ENABLE => {
+                if ((offset - PLIC_PENDING) / 4 > 1) {
+                    return Err(fault);
+                }
+            },
+            PLIC_ENABLE..PLIC_CONTEXT => match(Self::context(offset, PLIC_ENABLE, PLIC_ENABLE_STRIDE)) {
+                Some((context, word @ (0 | 4))) => {
+                    let shift = 8 * word;
+                    let enable = (self.enable[context] & !(0xffff_ffff << shift)) | (value << shift);
+                    self.enable[context] = enable & SOURCE_MASK;
+                },
+                _ => return Err(fault),
+            },
+            _ => match(Self::context(offset, PLIC_CONTEXT, PLIC_CONTEXT_STRIDE)) {
+                Some((context, PLIC_THRESHOLD)) => self.threshold[context] = value as u32 & PRIORITY_MASK,
+                Some((context, PLIC_CLAIM)) => self.complete(context, value),
+                _ => return Err(fault),
             },
-            PLIC_SPENDING_ADDR => Ok(self.pending = value),
-            PLIC_CLAIM_ADDR => Ok(self.claim = value),
-            PLIC_COMPLETED_ADDR => Ok(self.completed = value),
-            _ => Err(errors::Exception::StoreAMOPageFault(addr)),
         }
+        return Ok(());
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn hart_interrupts(&self) -> u64 {
+        return self.interrupts();
+    }
+
+    fn set_source_level(&mut self, source: u64, level: bool) {
+        self.update(source, level);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::bus::Device;
+
+    const UART_IRQ: u64 = 10;
+    const DISK_IRQ: u64 = 1;
+
+    fn context_register(context: usize, register: u64) -> u64 {
+        return PLIC_CONTEXT + PLIC_CONTEXT_STRIDE * context as u64 + register;
+    }
+
+    fn enable_register(context: usize) -> u64 {
+        return PLIC_ENABLE + PLIC_ENABLE_STRIDE * context as u64;
+    }
+
+    #[test]
+    fn test_claim_complete() {
+        let mut plic = Plic::new();
+        plic.store(PLIC_PRIORITY + 4 * UART_IRQ, 32, 1).unwrap();
+        plic.store(enable_register(CONTEXT_SUPERVISOR), 32, 1 << UART_IRQ).unwrap();
+
+        plic.update(UART_IRQ, true);
+        assert_eq!(plic.load(PLIC_PENDING, 32), Ok(1 << UART_IRQ));
+        assert_eq!(plic.interrupts(), csr::MASK_SEIP);
+
+        // Claiming clears the pending bit, and the source stays quiet until it is completed
+        assert_eq!(plic.load(context_register(CONTEXT_SUPERVISOR, PLIC_CLAIM), 32), Ok(UART_IRQ));
+        assert_eq!(plic.interrupts(), 0);
+        plic.update(UART_IRQ, true);
+        assert!(!plic.is_pending(UART_IRQ));
+        assert_eq!(plic.load(context_register(CONTEXT_SUPERVISOR, PLIC_CLAIM), 32), Ok(0));
+
+        plic.store(context_register(CONTEXT_SUPERVISOR, PLIC_CLAIM), 32, UART_IRQ).unwrap();
+        plic.update(UART_IRQ, true);
+        assert_eq!(plic.interrupts(), csr::MASK_SEIP);
+    }
+
+    #[test]
+    fn test_priorities_and_thresholds() {
+        let mut plic = Plic::new();
+        plic.store(PLIC_PRIORITY + 4 * UART_IRQ, 32, 2).unwrap();
+        plic.store(PLIC_PRIORITY + 4 * DISK_IRQ, 32, 1).unwrap();
+        plic.store(enable_register(CONTEXT_MACHINE), 32, (1 << UART_IRQ) | (1 << DISK_IRQ)).unwrap();
+        plic.update(UART_IRQ, true);
+        plic.update(DISK_IRQ, true);
+
+        // Disabled for the S-mode context, so only MEIP is raised
+        assert_eq!(plic.interrupts(), csr::MASK_MEIP);
+
+        // Only sources with a priority above the threshold interrupt
+        plic.store(context_register(CONTEXT_MACHINE, PLIC_THRESHOLD), 32, 2).unwrap();
+        assert_eq!(plic.interrupts(), 0);
+        plic.store(context_register(CONTEXT_MACHINE, PLIC_THRESHOLD), 32, 1).unwrap();
+        assert_eq!(plic.load(context_register(CONTEXT_MACHINE, PLIC_CLAIM), 32), Ok(UART_IRQ));
+        plic.store(context_register(CONTEXT_MACHINE, PLIC_THRESHOLD), 32, 0).unwrap();
+        assert_eq!(plic.load(context_register(CONTEXT_MACHINE, PLIC_CLAIM), 32), Ok(DISK_IRQ));
     }
 
+    #[test]
+    fn test_register_access() {
+        let mut plic = Plic::new();
+        // Source 0 doesn't exist, and priorities are 3 bits
+        plic.store(PLIC_PRIORITY, 32, 7).unwrap();
+        plic.store(PLIC_PRIORITY + 4, 32, 0xff).unwrap();
+        assert_eq!(plic.load(PLIC_PRIORITY, 32), Ok(0));
+        assert_eq!(plic.load(PLIC_PRIORITY + 4, 32), Ok(7));
+
+        plic.store(enable_register(CONTEXT_SUPERVISOR) + 4, 32, 0x8000_0000).unwrap();
+        assert_eq!(plic.load(enable_register(CONTEXT_SUPERVISOR) + 4, 32), Ok(0x8000_0000));
+        assert_eq!(plic.load(enable_register(CONTEXT_SUPERVISOR), 32), Ok(0));
+
+        assert!(plic.load(PLIC_PRIORITY, 64).is_err());
+        assert!(plic.load(enable_register(NUM_CONTEXTS), 32).is_err());
+        assert!(plic.load(context_register(NUM_CONTEXTS, PLIC_CLAIM), 32).is_err());
+    }
 }
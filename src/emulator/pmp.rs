@@ -0,0 +1,155 @@
+use super::csr;
+use super::mmu::AccessType;
+use super::{Mode, Machine};
+
+// pmpcfg entry fields
+pub const PMP_R: u8 = 1 << 0;
+pub const PMP_W: u8 = 1 << 1;
+pub const PMP_X: u8 = 1 << 2;
+pub const PMP_A_SHIFT: u8 = 3;
+pub const PMP_L: u8 = 1 << 7;
+
+// Address matching modes, from the A field
+#[allow(dead_code)]
+pub const PMP_OFF: u8 = 0;
+pub const PMP_TOR: u8 = 1;
+pub const PMP_NA4: u8 = 2;
+pub const PMP_NAPOT: u8 = 3;
+
+pub fn address_mode(cfg: u8) -> u8 {
+    return (cfg >> PMP_A_SHIFT) & 0b11;
+}
+
+// The byte range [start, end) covered by entry i, None when it is switched off
+fn range(csr: &csr::Csr, i: usize) -> Option<(u128, u128)> {
+    // pmpaddr registers hold bits 55:2 of the address
+    let addr = (csr.pmp_addr(i) as u128) << 2;
+    match(address_mode(csr.pmp_config(i))) {
+        PMP_TOR => {
+            let start = if (i == 0) { 0 } else { (csr.pmp_addr(i - 1) as u128) << 2 };
+            return Some((start, addr));
+        }
+        PMP_NA4 => return Some((addr, addr + 4)),
+        PMP_NAPOT => {
+            // The trailing ones of pmpaddr encode the size, 8 bytes for none
+            let size = 8u128 << csr.pmp_addr(i).trailing_ones();
+            let start = addr & !(size - 1);
+            return Some((start, start + size));
+        }
+        _ => return None,
+    }
+}
+
+/// Checks a physical access of `size` bytes against the PMP entries. The lowest numbered entry
+/// matching any byte of the access decides, and it must cover all of it. M-mode is only held to
+/// locked entries.
+pub fn check(csr: &csr::Csr, addr: u64, size: u64, privilege: Mode, access: AccessType) -> bool {
+    let (start, end) = (addr as u128, addr as u128 + size as u128);
+    let mut active = false;
+    for i in 0..csr.pmp_entries() {
+        let Some((entry_start, entry_end)) = range(csr, i) else {
+            continue;
+        };
+        active = true;
+        if (end <= entry_start || start >= entry_end) {
+            continue;
+        }
+        if (start < entry_start || end > entry_end) {
+            return false;
+        }
+
+        let cfg = csr.pmp_config(i);
+        if (privilege == Machine && cfg & PMP_L == 0) {
+            return true;
+        }
+        let permission = match(access) {
+            AccessType::Instruction => PMP_X,
+            AccessType::Load => PMP_R,
+            AccessType::Store => PMP_W,
+        };
+        return cfg & permission != 0;
+    }
+
+    // Without a matching entry only M-mode gets through. Like QEMU, don't restrict anything
+    // before any entry is programmed, so programs run without firmware setting PMP up
+    return privilege == Machine || !active;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{User, Supervisor};
+
+    fn cfg(mode: u8, permissions: u8) -> u64 {
+        return ((mode << PMP_A_SHIFT) | permissions) as u64;
+    }
+
+    #[test]
+    fn test_no_entries_allow_everything() {
+        let csr = csr::Csr::new();
+        assert!(check(&csr, 0x8000_0000, 8, User, AccessType::Store));
+    }
+
+    #[test]
+    fn test_matching_modes() {
+        let mut csr = csr::Csr::new();
+        // Entry 0: NAPOT 0x8000_0000-0x8000_ffff read-only
+        // Entry 1: TOR from entry 0's address up to 0x8010_0000 read/write
+        // Entry 2: NA4 0x1000_0000 execute-only
+        csr.store(csr::PMPADDR0, (0x8000_0000 >> 2) | 0x1fff);
+        csr.store(csr::PMPADDR0 + 1, 0x8010_0000 >> 2);
+        csr.store(csr::PMPADDR0 + 2, 0x1000_0000 >> 2);
+        csr.store(csr::PMPCFG0, cfg(PMP_NAPOT, PMP_R) | (cfg(PMP_TOR, PMP_R | PMP_W) << 8) | (cfg(PMP_NA4, PMP_X) << 16));
+
+        assert!(check(&csr, 0x8000_fff8, 8, User, AccessType::Load));
+        assert!(!check(&csr, 0x8000_0000, 8, Supervisor, AccessType::Store));
+        // TOR starts at pmpaddr0 itself, so writes past the NAPOT region are allowed
+        assert!(check(&csr, 0x8001_0000, 8, User, AccessType::Store));
+        assert!(!check(&csr, 0x8010_0000, 1, User, AccessType::Load));
+        assert!(check(&csr, 0x1000_0000, 4, User, AccessType::Instruction));
+        // Only partially covered by an entry
+        assert!(!check(&csr, 0x1000_0002, 4, User, AccessType::Instruction));
+        // Not covered at all
+        assert!(!check(&csr, 0x2000_0000, 4, User, AccessType::Load));
+        // but M-mode ignores unlocked entries
+        assert!(check(&csr, 0x8000_0000, 8, Machine, AccessType::Store));
+        assert!(check(&csr, 0x2000_0000, 4, Machine, AccessType::Load));
+    }
+
+    #[test]
+    fn test_locked_entries() {
+        let mut csr = csr::Csr::new();
+        csr.store(csr::PMPADDR0, 0x8000_0000 >> 2);
+        csr.store(csr::PMPADDR0 + 1, 0x9000_0000 >> 2);
+        csr.store(csr::PMPCFG0, (cfg(PMP_TOR, PMP_R | PMP_X) | PMP_L as u64) << 8);
+
+        // Locked entries apply to M-mode as well
+        assert!(!check(&csr, 0x8000_0000, 8, Machine, AccessType::Store));
+        assert!(check(&csr, 0x8000_0000, 8, Machine, AccessType::Load));
+
+        // and can't be changed, including the pmpaddr below a locked TOR entry
+        csr.store(csr::PMPCFG0, cfg(PMP_TOR, PMP_R | PMP_W) << 8);
+        csr.store(csr::PMPADDR0, 0);
+        csr.store(csr::PMPADDR0 + 1, 0);
+        assert_eq!(csr.pmp_config(1), (cfg(PMP_TOR, PMP_R | PMP_X) as u8) | PMP_L);
+        assert_eq!(csr.load(csr::PMPADDR0), 0x8000_0000 >> 2);
+        assert_eq!(csr.load(csr::PMPADDR0 + 1), 0x9000_0000 >> 2);
+    }
+
+    #[test]
+    fn test_configurable_entries() {
+        let mut csr = csr::Csr::new();
+        // Entries past the implemented ones read as zero
+        csr.store(csr::PMPADDR0 + 16, 0x1234);
+        assert_eq!(csr.load(csr::PMPADDR0 + 16), 0);
+
+        csr.set_pmp_entries(64);
+        csr.store(csr::PMPADDR0 + 63, 0x1234);
+        csr.store(csr::PMPCFG0 + 14, cfg(PMP_NA4, PMP_R) << 56);
+        assert_eq!(csr.load(csr::PMPADDR0 + 63), 0x1234);
+        assert_eq!(csr.pmp_config(63), cfg(PMP_NA4, PMP_R) as u8);
+        // The reserved W without R combination isn't kept
+        csr.store(csr::PMPCFG0, cfg(PMP_NA4, PMP_W));
+        assert_eq!(csr.pmp_config(0), cfg(PMP_NA4, 0) as u8);
+    }
+}
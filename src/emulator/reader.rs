@@ -0,0 +1,52 @@
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+// How long to wait before reading again after an error. A pty master fails with EIO for as long
+// as nobody has the slave side open, so retrying straight away would spin
+pub const READ_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// Runs the host side of a device's input on its own thread: read blocks for the next item,
+// returning None at the end of the input, and each item is passed to receive. Errors are logged
+// once for each run of them, and reading is retried after a pause
+pub fn spawn_reader<T, R, F>(mut read: R, mut receive: F)
+        where R: FnMut() -> io::Result<Option<T>> + Send + 'static, F: FnMut(T) + Send + 'static {
+    thread::spawn(move || {
+        let mut failing = false;
+        loop {
+            match(read()) {
+                Ok(Some(item)) => {
+                    failing = false;
+                    receive(item);
+                },
+                Ok(None) => return,
+                Err(e) if (e.kind() == io::ErrorKind::Interrupted) => {},
+                Err(e) => {
+                    if (!failing) {
+                        eprintln!("Error reading host input: {}", e);
+                        failing = true;
+                    }
+                    thread::sleep(READ_RETRY_DELAY);
+                },
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_errors_are_retried_until_the_end() {
+        let mut results = vec![Ok(None), Ok(Some(2)), Err(io::Error::from(io::ErrorKind::Other)), Ok(Some(1))];
+        let (sender, receiver) = mpsc::channel();
+        spawn_reader(move || results.pop().unwrap(), move |item| sender.send(item).unwrap());
+
+        assert_eq!(receiver.recv(), Ok(1));
+        assert_eq!(receiver.recv(), Ok(2));
+        // The thread returned at the end, dropping the sender
+        assert!(receiver.recv().is_err());
+    }
+}
@@ -1,117 +1,448 @@
-use std::io;
+use std::collections::VecDeque;
 use std::io::prelude::*;
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Condvar, Mutex
-};
-use std::thread;
+use std::sync::{Arc, Condvar, Mutex};
+use super::bus;
 use super::errors;
+use super::reader;
 
-pub const UART_SIZE: u64 = 128;
+pub mod backends;
+pub mod console;
 
-// RHR is the Receiver Holding Register (a register holding input bytes)
-// THE UART_RHR_INDEX is the index of the RHR register in Uart
-pub const UART_RHR_INDEX: u64 = 0;
+use self::backends::UartBackend;
+use self::console::ConsoleCommand;
 
-// THR is the Transmissions Holding Register (same as RHR but for output)
-// My best understanding is because UART has single signal, the same register
-//    is used for both input and output, with mode being determined by LSR flags.
-pub const UART_THR_INDEX: u64 = 0;
+// A 16550A UART. The registers are one byte apart, and some share an offset: reads and writes
+// reach different registers, and LCR.DLAB switches the first two over to the divisor latch
+pub const UART_REGISTERS: u64 = 8;
 
-// LSR is the Line Status Register (a register storing the status of Uart)
-// Bit 0 is 1: data is stored in RHR for processing (0 is empty)
-// Bit 5 is 0: THR is full and waiting to be sent out (1 is empty)
+// RHR is the Receiver Holding Register (the head of the receive FIFO), THR the Transmitter
+// Holding Register. DLL is the low byte of the divisor latch
+pub const UART_RHR_INDEX: u64 = 0;
+pub const UART_THR_INDEX: u64 = 0;
+pub const UART_DLL_INDEX: u64 = 0;
+// IER is the Interrupt Enable Register, DLM the high byte of the divisor latch
+pub const UART_IER_INDEX: u64 = 1;
+pub const UART_DLM_INDEX: u64 = 1;
+// IIR is the Interrupt Identification Register (read), FCR the FIFO Control Register (write)
+pub const UART_IIR_INDEX: u64 = 2;
+pub const UART_FCR_INDEX: u64 = 2;
+// LCR is the Line Control Register, MCR the Modem Control Register
+pub const UART_LCR_INDEX: u64 = 3;
+pub const UART_MCR_INDEX: u64 = 4;
+// LSR is the Line Status Register, MSR the Modem Status Register
 pub const UART_LSR_INDEX: u64 = 5;
-pub const UART_LSR_RHR_STATUS_FLAG: u8 = 1;
-pub const UART_LSR_THR_STATUS_FLAG: u8 = 1 << 5;
+pub const UART_MSR_INDEX: u64 = 6;
+// SCR is the Scratch Register, which only holds a byte for software
+pub const UART_SCR_INDEX: u64 = 7;
+
+// IER fields
+pub const UART_IER_RDI: u8 = 1 << 0; // Received data available
+pub const UART_IER_THRI: u8 = 1 << 1; // THR empty
+pub const UART_IER_RLSI: u8 = 1 << 2; // Receiver line status
+pub const UART_IER_MSI: u8 = 1 << 3; // Modem status
+
+// IIR values, in decreasing priority after the line status
+pub const UART_IIR_NO_INT: u8 = 0x01;
+pub const UART_IIR_RLSI: u8 = 0x06;
+pub const UART_IIR_RDI: u8 = 0x04;
+pub const UART_IIR_TIMEOUT: u8 = 0x0c;
+pub const UART_IIR_THRI: u8 = 0x02;
+pub const UART_IIR_MSI: u8 = 0x00;
+pub const UART_IIR_FIFO_ENABLED: u8 = 0xc0;
 
-pub const UART_BASE: u64 = 0x1000_0000;
+// FCR fields
+pub const UART_FCR_ENABLE_FIFO: u8 = 1 << 0;
+pub const UART_FCR_CLEAR_RCVR: u8 = 1 << 1;
+pub const UART_FCR_CLEAR_XMIT: u8 = 1 << 2;
+pub const UART_FCR_TRIGGER_SHIFT: u8 = 6;
+
+// LCR fields
+pub const UART_LCR_DLAB: u8 = 1 << 7;
+
+// MCR fields
+pub const UART_MCR_DTR: u8 = 1 << 0;
+pub const UART_MCR_RTS: u8 = 1 << 1;
+pub const UART_MCR_OUT1: u8 = 1 << 2;
+pub const UART_MCR_OUT2: u8 = 1 << 3;
+pub const UART_MCR_LOOP: u8 = 1 << 4;
+
+// LSR fields
+pub const UART_LSR_DR: u8 = 1 << 0; // Data ready in RHR
+pub const UART_LSR_OE: u8 = 1 << 1; // Overrun, a received byte was lost
+pub const UART_LSR_THRE: u8 = 1 << 5; // THR empty
+pub const UART_LSR_TEMT: u8 = 1 << 6; // Transmitter empty
+
+// MSR fields
+pub const UART_MSR_CTS: u8 = 1 << 4;
+pub const UART_MSR_DSR: u8 = 1 << 5;
+pub const UART_MSR_RI: u8 = 1 << 6;
+pub const UART_MSR_DCD: u8 = 1 << 7;
+
+pub const UART_FIFO_SIZE: usize = 16;
 
 pub const UART_IRQ: u64 = 10;
 
-pub struct Uart {
-    uart: Arc<(Mutex<[u8; UART_SIZE as usize]>, Condvar)>,
-    interrupt: Arc<AtomicBool>
+struct UartState {
+    // Received bytes not read yet. Without the FIFO enabled only one is held, like a 16450
+    rx_fifo: VecDeque<u8>,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    // FCR is write-only, so only the FIFO enable and the receive trigger level are kept
+    fifo_enabled: bool,
+    rx_trigger: usize,
+    overrun: bool,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    // The THR empty interrupt is pending until IIR reports it or THR is written again
+    thr_interrupt: bool,
 }
 
-impl Uart {
-    pub fn new() -> Self {
-        let mut array = [0; UART_SIZE as usize];
-        let uart = Arc::new((Mutex::new(array), Condvar::new()));
+impl UartState {
+    fn new() -> Self {
+        return UartState {
+            rx_fifo: VecDeque::with_capacity(UART_FIFO_SIZE),
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            fifo_enabled: false,
+            rx_trigger: 1,
+            overrun: false,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+            thr_interrupt: false,
+        };
+    }
 
-        let interrupt = Arc::new(AtomicBool::new(false));
+    fn rx_capacity(&self) -> usize {
+        return if (self.fifo_enabled) { UART_FIFO_SIZE } else { 1 };
+    }
 
-        Self::spawn_io_listener_thread(&uart, &interrupt);
+    // An overrun loses the new byte when the FIFO is full, but without the FIFO the new byte
+    // replaces the one in RHR, as on a 16550
+    fn receive(&mut self, byte: u8) {
+        if (self.rx_fifo.len() >= self.rx_capacity()) {
+            self.overrun = true;
+            if (self.fifo_enabled) {
+                return;
+            }
+            self.rx_fifo.clear();
+        }
+        self.rx_fifo.push_back(byte);
+    }
 
-        return Uart { uart: uart, interrupt: interrupt};
+    fn lsr(&self) -> u8 {
+        // Bytes are sent as soon as they are written, so the transmitter is always empty
+        let mut lsr = UART_LSR_THRE | UART_LSR_TEMT;
+        if (!self.rx_fifo.is_empty()) {
+            lsr |= UART_LSR_DR;
+        }
+        if (self.overrun) {
+            lsr |= UART_LSR_OE;
+        }
+        return lsr;
+    }
+
+    fn msr(&self) -> u8 {
+        // In loopback mode the modem outputs are wired back to the inputs. Otherwise the line
+        // always looks connected, and since it never changes no delta bits or modem status
+        // interrupts are raised
+        if (self.mcr & UART_MCR_LOOP == 0) {
+            return UART_MSR_DCD | UART_MSR_DSR | UART_MSR_CTS;
+        }
+        let mut msr = 0;
+        if (self.mcr & UART_MCR_DTR != 0) { msr |= UART_MSR_DSR; }
+        if (self.mcr & UART_MCR_RTS != 0) { msr |= UART_MSR_CTS; }
+        if (self.mcr & UART_MCR_OUT1 != 0) { msr |= UART_MSR_RI; }
+        if (self.mcr & UART_MCR_OUT2 != 0) { msr |= UART_MSR_DCD; }
+        return msr;
     }
 
-    fn spawn_io_listener_thread(uart: &Arc<(Mutex<[u8; 128]>, Condvar)>, interrupt: &Arc<AtomicBool>) {
+    // The highest priority interrupt enabled and pending
+    fn iir(&self) -> u8 {
+        let received = self.rx_fifo.len();
+        let id = if (self.ier & UART_IER_RLSI != 0 && self.overrun) {
+            UART_IIR_RLSI
+        } else if (self.ier & UART_IER_RDI != 0 && received >= self.rx_trigger) {
+            UART_IIR_RDI
+        } else if (self.ier & UART_IER_RDI != 0 && received > 0) {
+            // There is no notion of time between received bytes, so data below the trigger
+            // level reports a character timeout straight away
+            UART_IIR_TIMEOUT
+        } else if (self.ier & UART_IER_THRI != 0 && self.thr_interrupt) {
+            UART_IIR_THRI
+        } else {
+            UART_IIR_NO_INT
+        };
+        return if (self.fifo_enabled) { id | UART_IIR_FIFO_ENABLED } else { id };
+    }
+
+    fn dlab(&self) -> bool {
+        return self.lcr & UART_LCR_DLAB != 0;
+    }
+}
+
+pub struct Uart {
+    uart: Arc<(Mutex<UartState>, Condvar)>,
+    backend: Box<dyn UartBackend>,
+}
+
+impl Uart {
+    // Input is read from the backend once the UART is attached to a bus
+    pub fn with_backend(backend: Box<dyn UartBackend>) -> Self {
+        let uart = Arc::new((Mutex::new(UartState::new()), Condvar::new()));
+        return Uart { uart: uart, backend: backend };
+    }
+
+    fn spawn_io_listener_thread(uart: &Arc<(Mutex<UartState>, Condvar)>, mut input: Box<dyn Read + Send>, doorbell: bus::Doorbell) {
         let mut byte = [0];
 
         // Create reference to Uart for IO to load data into
-        let read_uart = Arc::clone(&uart);
-        let read_interrupt = Arc::clone(&interrupt);
+        let read_uart = Arc::clone(uart);
 
         // Create a thread that continuously reads io
-        thread::spawn(move || loop {
-            let read_io = io::stdin().read(&mut byte);
-            match(read_io) {
-                Ok(_) => {
-                    let (uart, cvar) = &*read_uart;
-                    let mut array = uart.lock().unwrap();
-
-                    // While the receiver flag (index 0) is set
-                    while (array[UART_LSR_INDEX as usize] & UART_LSR_RHR_STATUS_FLAG == 1) {
-                        // Wait and reload the status register
-                        array = cvar.wait(array).unwrap();
-                    }
-
-                    // Receiver flag is 0, so load next one
-                    array[UART_RHR_INDEX as usize] = byte[0];
-                    read_interrupt.store(true, Ordering::Release);
-                    array[UART_LSR_INDEX as usize] |= UART_LSR_RHR_STATUS_FLAG; // Maybe should be at beginning?
-                },
-                Err(e) => println!("Error: {}", e),
+        let read = move || input.read(&mut byte).map(|len| if (len == 0) { None } else { Some(byte[0]) });
+        reader::spawn_reader(read, move |byte| {
+            let (uart, cvar) = &*read_uart;
+            let mut state = uart.lock().unwrap();
+
+            // Hold the byte back until there is room for it rather than overrunning
+            while (state.rx_fifo.len() >= state.rx_capacity()) {
+                state = cvar.wait(state).unwrap();
             }
+            state.receive(byte);
+            doorbell.ring();
         });
     }
 
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, errors::Exception> {
+    fn load_byte(&self, index: u64) -> u8 {
         let (uart, cvar) = &*self.uart;
-        let mut array = uart.lock().unwrap(); // Must be mut because we reset flag
-        let index = addr - UART_BASE;
+        let mut state = uart.lock().unwrap();
 
         match (index) {
+            UART_DLL_INDEX if (state.dlab()) => state.dll,
+            UART_DLM_INDEX if (state.dlab()) => state.dlm,
             UART_RHR_INDEX => {
+                // Wake the listener thread up in case it is waiting for room
                 cvar.notify_one();
-                array[UART_LSR_INDEX as usize] &= !1; // Reset flag
-                return Ok(array[index as usize] as u64);
+                return state.rx_fifo.pop_front().unwrap_or(0);
+            },
+            UART_IER_INDEX => state.ier,
+            UART_IIR_INDEX => {
+                let iir = state.iir();
+                // Reading IIR acknowledges a THR empty interrupt
+                if (iir & 0x0f == UART_IIR_THRI) {
+                    state.thr_interrupt = false;
+                }
+                return iir;
+            },
+            UART_LCR_INDEX => state.lcr,
+            UART_MCR_INDEX => state.mcr,
+            UART_LSR_INDEX => {
+                let lsr = state.lsr();
+                state.overrun = false;
+                return lsr;
             },
-            _ => return Ok(array[index as usize] as u64),
+            UART_MSR_INDEX => state.msr(),
+            _ => state.scr,
         }
     }
 
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), errors::Exception> {
+    fn store_byte(&mut self, index: u64, value: u8) {
         let (uart, cvar) = &*self.uart;
-        let mut array = uart.lock().unwrap();
-
-        let index = addr - UART_BASE;
+        let mut state = uart.lock().unwrap();
 
         match (index) {
+            UART_DLL_INDEX if (state.dlab()) => state.dll = value,
+            UART_DLM_INDEX if (state.dlab()) => state.dlm = value,
             UART_THR_INDEX => {
-                print!("{}", value as u8 as char);
-                io::stdout().flush().unwrap();
-                return Ok(());
-            }
-            _ => {
-                array[index as usize] = value as u8;
-                return Ok(());
-            }
+                if (state.mcr & UART_MCR_LOOP != 0) {
+                    state.receive(value);
+                } else {
+                    self.backend.write(value);
+                }
+                // The byte goes out immediately, leaving THR empty again
+                state.thr_interrupt = true;
+            },
+            UART_IER_INDEX => {
+                // Enabling the THR empty interrupt while THR is empty raises it straight away
+                if (value & UART_IER_THRI != 0 && state.ier & UART_IER_THRI == 0) {
+                    state.thr_interrupt = true;
+                }
+                state.ier = value & 0x0f;
+            },
+            UART_FCR_INDEX => {
+                let enable = value & UART_FCR_ENABLE_FIFO != 0;
+                // Switching the FIFO on or off empties it
+                if (value & UART_FCR_CLEAR_RCVR != 0 || enable != state.fifo_enabled) {
+                    state.rx_fifo.clear();
+                    cvar.notify_one();
+                }
+                state.fifo_enabled = enable;
+                state.rx_trigger = [1, 4, 8, 14][(value >> UART_FCR_TRIGGER_SHIFT) as usize];
+            },
+            UART_LCR_INDEX => state.lcr = value,
+            UART_MCR_INDEX => state.mcr = value & 0x1f,
+            // LSR and MSR are read-only
+            UART_LSR_INDEX | UART_MSR_INDEX => {},
+            _ => state.scr = value,
         }
     }
 
+    pub fn command(&mut self) -> Option<ConsoleCommand> {
+        return self.backend.command();
+    }
+
+    pub fn print(&mut self, text: &str) {
+        self.backend.print(text);
+    }
+
+    // The interrupt line into the PLIC, raised while any enabled interrupt is pending
     pub fn is_interrupting(&self) -> bool {
-        return self.interrupt.swap(false, Ordering::Acquire);
+        let (uart, _cvar) = &*self.uart;
+        return uart.lock().unwrap().iir() & UART_IIR_NO_INT == 0;
+    }
+}
+
+impl bus::Device for Uart {
+    // Multi-byte accesses cover consecutive byte registers, least significant byte first
+    fn load(&mut self, index: u64, size: u64) -> Result<u64, errors::Exception> {
+        if (index + size / 8 > UART_REGISTERS) {
+            return Err(errors::Exception::LoadAccessFault(index));
+        }
+
+        let mut value = 0;
+        for offset in 0..size / 8 {
+            value |= (self.load_byte(index + offset) as u64) << (8*offset);
+        }
+        return Ok(value);
+    }
+
+    fn store(&mut self, index: u64, size: u64, value: u64) -> Result<(), errors::Exception> {
+        if (index + size / 8 > UART_REGISTERS) {
+            return Err(errors::Exception::StoreAMOAccessFault(index));
+        }
+
+        for offset in 0..size / 8 {
+            self.store_byte(index + offset, (value >> (8*offset)) as u8);
+        }
+        return Ok(());
+    }
+
+    // The backend stays attached, and bytes it has already delivered are dropped
+    fn reset(&mut self) {
+        let (uart, cvar) = &*self.uart;
+        *uart.lock().unwrap() = UartState::new();
+        cvar.notify_one();
+    }
+
+    fn connect(&mut self, doorbell: &bus::Doorbell) {
+        if let Some(input) = self.backend.input() {
+            Self::spawn_io_listener_thread(&self.uart, input, doorbell.clone());
+        }
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        return self.is_interrupting();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::bus::Device;
+    use std::thread;
+
+    fn load(uart: &mut Uart, index: u64) -> u8 {
+        return uart.load(index, 8).unwrap() as u8;
+    }
+
+    fn store(uart: &mut Uart, index: u64, value: u8) {
+        uart.store(index, 8, value as u64).unwrap();
+    }
+
+    #[test]
+    fn test_divisor_latch_and_scratch() {
+        let mut uart = Uart::with_backend(Box::new(backends::MemoryBackend::new()));
+        store(&mut uart, UART_LCR_INDEX, UART_LCR_DLAB | 0x03);
+        store(&mut uart, UART_DLL_INDEX, 0x0c);
+        store(&mut uart, UART_DLM_INDEX, 0x01);
+        assert_eq!((load(&mut uart, UART_DLL_INDEX), load(&mut uart, UART_DLM_INDEX)), (0x0c, 0x01));
+
+        // With DLAB cleared the same offsets reach RHR and IER again
+        store(&mut uart, UART_LCR_INDEX, 0x03);
+        store(&mut uart, UART_IER_INDEX, UART_IER_RDI);
+        assert_eq!(load(&mut uart, UART_IER_INDEX), UART_IER_RDI);
+        assert_eq!(load(&mut uart, UART_LCR_INDEX), 0x03);
+
+        store(&mut uart, UART_SCR_INDEX, 0x5a);
+        assert_eq!(load(&mut uart, UART_SCR_INDEX), 0x5a);
+        assert!(uart.load(UART_REGISTERS, 8).is_err());
+    }
+
+    #[test]
+    fn test_loopback_fifo_and_interrupts() {
+        let mut uart = Uart::with_backend(Box::new(backends::MemoryBackend::new()));
+        store(&mut uart, UART_MCR_INDEX, UART_MCR_LOOP | UART_MCR_RTS);
+        assert_eq!(load(&mut uart, UART_MSR_INDEX), UART_MSR_CTS);
+        // FIFO on, with a trigger level of 4 bytes
+        store(&mut uart, UART_FCR_INDEX, UART_FCR_ENABLE_FIFO | (1 << UART_FCR_TRIGGER_SHIFT));
+        assert_eq!(load(&mut uart, UART_IIR_INDEX), UART_IIR_FIFO_ENABLED | UART_IIR_NO_INT);
+
+        // Nothing is raised until enabled in IER
+        for byte in b"abc" {
+            store(&mut uart, UART_THR_INDEX, *byte);
+        }
+        assert!(!uart.is_interrupting());
+        store(&mut uart, UART_IER_INDEX, UART_IER_RDI);
+        assert_eq!(load(&mut uart, UART_IIR_INDEX), UART_IIR_FIFO_ENABLED | UART_IIR_TIMEOUT);
+        store(&mut uart, UART_THR_INDEX, b'd');
+        assert_eq!(load(&mut uart, UART_IIR_INDEX), UART_IIR_FIFO_ENABLED | UART_IIR_RDI);
+        assert!(uart.is_interrupting());
+
+        for byte in b"abcd" {
+            assert_ne!(load(&mut uart, UART_LSR_INDEX) & UART_LSR_DR, 0);
+            assert_eq!(load(&mut uart, UART_RHR_INDEX), *byte);
+        }
+        assert_eq!(load(&mut uart, UART_LSR_INDEX), UART_LSR_THRE | UART_LSR_TEMT);
+        assert!(!uart.is_interrupting());
+    }
+
+    #[test]
+    fn test_backend_input_output() {
+        let backend = backends::MemoryBackend::new();
+        let mut uart = Uart::with_backend(Box::new(backend.clone()));
+        uart.connect(&bus::Doorbell::default());
+        store(&mut uart, UART_THR_INDEX, b'h');
+        store(&mut uart, UART_THR_INDEX, b'i');
+        assert_eq!(backend.output(), b"hi");
+
+        backend.send(b"x");
+        while (load(&mut uart, UART_LSR_INDEX) & UART_LSR_DR == 0) {
+            thread::yield_now();
+        }
+        assert_eq!(load(&mut uart, UART_RHR_INDEX), b'x');
+    }
+
+    #[test]
+    fn test_thr_empty_interrupt_and_overrun() {
+        let mut uart = Uart::with_backend(Box::new(backends::MemoryBackend::new()));
+        store(&mut uart, UART_MCR_INDEX, UART_MCR_LOOP);
+        // Enabling the THR empty interrupt raises it until IIR is read
+        store(&mut uart, UART_IER_INDEX, UART_IER_THRI);
+        assert!(uart.is_interrupting());
+        assert_eq!(load(&mut uart, UART_IIR_INDEX), UART_IIR_THRI);
+        assert!(!uart.is_interrupting());
+
+        // Without the FIFO a second byte overruns the first, taking its place
+        store(&mut uart, UART_IER_INDEX, UART_IER_RLSI);
+        store(&mut uart, UART_THR_INDEX, b'x');
+        store(&mut uart, UART_THR_INDEX, b'y');
+        assert_eq!(load(&mut uart, UART_IIR_INDEX), UART_IIR_RLSI);
+        assert_eq!(load(&mut uart, UART_LSR_INDEX), UART_LSR_DR | UART_LSR_OE | UART_LSR_THRE | UART_LSR_TEMT);
+        assert_eq!(load(&mut uart, UART_RHR_INDEX), b'y');
+        assert_eq!(load(&mut uart, UART_IIR_INDEX), UART_IIR_NO_INT);
     }
 }
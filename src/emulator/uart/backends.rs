@@ -0,0 +1,311 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::sync::{mpsc, Arc, Mutex};
+#[cfg(unix)]
+use std::os::unix::{io::FromRawFd, net::{UnixListener, UnixStream}};
+#[cfg(unix)]
+use std::path::Path;
+use super::console::ConsoleCommand;
+
+// Where the UART's character stream goes. Received bytes are read from the input on a background
+// thread, so it may block, while transmitted bytes are written from the emulator's thread
+pub trait UartBackend: Send {
+    // Hands over the source of received bytes. Called once, None for output-only backends
+    fn input(&mut self) -> Option<Box<dyn Read + Send>>;
+    fn write(&mut self, byte: u8);
+
+    // Commands typed at the console's escape prefix, for backends that have one
+    fn command(&mut self) -> Option<ConsoleCommand> {
+        return None;
+    }
+
+    // Emulator messages, such as monitor output, as opposed to the guest's
+    fn print(&mut self, text: &str) {
+        for byte in text.bytes() {
+            self.write(byte);
+        }
+    }
+}
+
+// The host's stdin and stdout
+pub struct StdioBackend;
+
+impl UartBackend for StdioBackend {
+    fn input(&mut self) -> Option<Box<dyn Read + Send>> {
+        return Some(Box::new(io::stdin()));
+    }
+
+    fn write(&mut self, byte: u8) {
+        // Output is dropped once stdout is closed, say by the end of a pipe going away
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&[byte]).and_then(|_| stdout.flush());
+    }
+}
+
+// Logs the output to a file, with no input
+pub struct FileBackend {
+    file: File,
+}
+
+impl FileBackend {
+    pub fn create(path: &str) -> io::Result<Self> {
+        return Ok(FileBackend { file: File::create(path)? });
+    }
+}
+
+impl UartBackend for FileBackend {
+    fn input(&mut self) -> Option<Box<dyn Read + Send>> {
+        return None;
+    }
+
+    fn write(&mut self, byte: u8) {
+        // Unbuffered, so the log is complete even if the guest never exits cleanly
+        if let Err(e) = self.file.write_all(&[byte]) {
+            eprintln!("Error writing the UART log: {}", e);
+        }
+    }
+}
+
+// A Unix domain socket connection, either accepted on a socket we create or made to an existing
+// one. Output is dropped once the peer goes away
+#[cfg(unix)]
+pub struct UnixSocketBackend {
+    stream: UnixStream,
+}
+
+#[cfg(unix)]
+impl UnixSocketBackend {
+    // Blocks until a client connects
+    pub fn listen<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let listener = UnixListener::bind(path)?;
+        let (stream, _) = listener.accept()?;
+        return Ok(UnixSocketBackend { stream: stream });
+    }
+
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        return Ok(UnixSocketBackend { stream: UnixStream::connect(path)? });
+    }
+}
+
+#[cfg(unix)]
+impl UartBackend for UnixSocketBackend {
+    fn input(&mut self) -> Option<Box<dyn Read + Send>> {
+        return self.stream.try_clone().ok().map(|stream| Box::new(stream) as Box<dyn Read + Send>);
+    }
+
+    fn write(&mut self, byte: u8) {
+        let _ = self.stream.write_all(&[byte]);
+    }
+}
+
+// The master side of a new pseudo-terminal, for a terminal program to attach to the slave side
+#[cfg(unix)]
+pub struct PtyBackend {
+    master: File,
+    slave_path: String,
+}
+
+#[cfg(unix)]
+impl PtyBackend {
+    pub fn open() -> io::Result<Self> {
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if (fd < 0) {
+                return Err(io::Error::last_os_error());
+            }
+            // Take ownership right away so the descriptor is closed on the error paths below
+            let master = File::from_raw_fd(fd);
+            if (libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0) {
+                return Err(io::Error::last_os_error());
+            }
+            // ptsname_r rather than ptsname, whose static buffer is shared with every other thread
+            let mut name = [0; 128];
+            let error = libc::ptsname_r(fd, name.as_mut_ptr(), name.len());
+            if (error != 0) {
+                return Err(io::Error::from_raw_os_error(error));
+            }
+            let slave_path = std::ffi::CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+            return Ok(PtyBackend { master: master, slave_path: slave_path });
+        }
+    }
+
+    // The /dev/pts path to open from a terminal program
+    pub fn slave_path(&self) -> &str {
+        return &self.slave_path;
+    }
+}
+
+#[cfg(unix)]
+impl UartBackend for PtyBackend {
+    fn input(&mut self) -> Option<Box<dyn Read + Send>> {
+        return self.master.try_clone().ok().map(|master| Box::new(master) as Box<dyn Read + Send>);
+    }
+
+    fn write(&mut self, byte: u8) {
+        // Nobody has the slave side open yet, which is fine
+        let _ = self.master.write_all(&[byte]);
+    }
+}
+
+// Keeps the output in memory and takes input from send(), for tests. Clones share the buffers,
+// so one can be given to the UART while another feeds and inspects it
+#[derive(Clone)]
+pub struct MemoryBackend {
+    sender: mpsc::Sender<u8>,
+    receiver: Arc<Mutex<Option<mpsc::Receiver<u8>>>>,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        return MemoryBackend {
+            sender: sender,
+            receiver: Arc::new(Mutex::new(Some(receiver))),
+            output: Arc::new(Mutex::new(Vec::new())),
+        };
+    }
+
+    // Queues bytes for the guest to receive
+    pub fn send(&self, data: &[u8]) {
+        for byte in data.iter() {
+            self.sender.send(*byte).unwrap();
+        }
+    }
+
+    // Everything the guest has transmitted so far
+    pub fn output(&self) -> Vec<u8> {
+        return self.output.lock().unwrap().clone();
+    }
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+// Blocks until a byte is sent, reaching the end once every sender is gone
+struct ChannelReader {
+    receiver: mpsc::Receiver<u8>,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if (buf.is_empty()) {
+            return Ok(0);
+        }
+        match(self.receiver.recv()) {
+            Ok(byte) => {
+                buf[0] = byte;
+                return Ok(1);
+            },
+            Err(_) => return Ok(0),
+        }
+    }
+}
+
+impl UartBackend for MemoryBackend {
+    fn input(&mut self) -> Option<Box<dyn Read + Send>> {
+        let receiver = self.receiver.lock().unwrap().take()?;
+        return Some(Box::new(ChannelReader { receiver: receiver }));
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.output.lock().unwrap().push(byte);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_backend() {
+        let backend = MemoryBackend::new();
+        let mut uart_side = backend.clone();
+        backend.send(b"ok");
+
+        let mut input = uart_side.input().unwrap();
+        let mut byte = [0];
+        input.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'o');
+        // The input can only be taken once
+        assert!(uart_side.input().is_none());
+
+        uart_side.write(b'x');
+        assert_eq!(backend.output(), b"x");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_backend() {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let mut backend = UnixSocketBackend { stream: ours };
+        backend.write(b'a');
+        let mut byte = [0];
+        theirs.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'a');
+
+        theirs.write_all(b"b").unwrap();
+        backend.input().unwrap().read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'b');
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_backend_listen() {
+        let path = std::env::temp_dir().join(format!("rv-emulator-{}-uart.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let client_path = path.clone();
+        // Keep trying until the listening socket exists
+        let client = std::thread::spawn(move || loop {
+            if let Ok(stream) = UnixStream::connect(&client_path) {
+                return stream;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        });
+        let mut backend = UnixSocketBackend::listen(&path).unwrap();
+        let mut theirs = client.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        backend.write(b'a');
+        let mut byte = [0];
+        theirs.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'a');
+
+        theirs.write_all(b"b").unwrap();
+        backend.input().unwrap().read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'b');
+    }
+
+    #[test]
+    fn test_file_backend() {
+        let path = std::env::temp_dir().join(format!("rv-emulator-{}-uart.log", std::process::id()));
+        let mut backend = FileBackend::create(path.to_str().unwrap()).unwrap();
+        assert!(backend.input().is_none());
+        backend.print("log");
+        assert_eq!(std::fs::read(&path).unwrap(), b"log");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pty_backend() {
+        let mut backend = PtyBackend::open().unwrap();
+        let mut slave = std::fs::OpenOptions::new().read(true).write(true).open(backend.slave_path()).unwrap();
+
+        let mut input = backend.input().unwrap();
+        slave.write_all(b"b").unwrap();
+        let mut byte = [0];
+        input.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'b');
+
+        // The slave is in canonical mode, so its reads wait for a whole line
+        backend.print("a\n");
+        let mut line = [0; 2];
+        slave.read_exact(&mut line).unwrap();
+        assert_eq!(&line[..1], b"a");
+    }
+}
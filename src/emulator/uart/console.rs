@@ -0,0 +1,235 @@
+use std::io;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use super::backends::UartBackend;
+
+// The escape prefix, Ctrl-A as in QEMU. It is followed by one of the keys below, and typing it
+// twice sends a literal Ctrl-A to the guest
+pub const ESCAPE_KEY: u8 = 0x01;
+pub const ESCAPE_QUIT: u8 = b'x';
+pub const ESCAPE_MONITOR: u8 = b'c';
+pub const ESCAPE_HELP: u8 = b'h';
+
+const HELP: &str = "\r\nC-a x    exit the emulator\r\nC-a c    switch between the console and the monitor\r\nC-a h    print this help\r\nC-a C-a  send C-a to the guest\r\n";
+pub const MONITOR_PROMPT: &str = "(monitor) ";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConsoleCommand {
+    Quit,
+    // A line typed at the monitor prompt
+    Monitor(String),
+}
+
+// Puts the terminal on stdin into raw mode, so every key including Ctrl-C reaches the guest as
+// soon as it is typed, and restores the original settings when dropped
+#[cfg(unix)]
+pub struct RawMode {
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl RawMode {
+    // None when stdin isn't a terminal, which needs no changing
+    pub fn enable() -> io::Result<Option<Self>> {
+        unsafe {
+            if (libc::isatty(libc::STDIN_FILENO) == 0) {
+                return Ok(None);
+            }
+            let mut original: libc::termios = std::mem::zeroed();
+            if (libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0) {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            if (libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0) {
+                return Err(io::Error::last_os_error());
+            }
+            return Ok(Some(RawMode { original: original }));
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+// Takes the escape sequences out of the input before it reaches the guest. While the monitor is
+// selected, typed bytes are echoed and collected into lines instead
+struct EscapeFilter<R: Read, W: Write> {
+    input: R,
+    echo: W,
+    commands: Arc<Mutex<Vec<ConsoleCommand>>>,
+    escape: bool,
+    monitor: bool,
+    line: Vec<u8>,
+}
+
+impl<R: Read, W: Write> EscapeFilter<R, W> {
+    // Returns the byte to pass on to the guest, if any
+    fn filter(&mut self, byte: u8) -> io::Result<Option<u8>> {
+        if (self.escape) {
+            self.escape = false;
+            match(byte) {
+                ESCAPE_KEY if (!self.monitor) => return Ok(Some(ESCAPE_KEY)),
+                ESCAPE_QUIT => self.commands.lock().unwrap().push(ConsoleCommand::Quit),
+                ESCAPE_MONITOR => {
+                    self.monitor = !self.monitor;
+                    self.line.clear();
+                    let prompt = if (self.monitor) { MONITOR_PROMPT } else { "" };
+                    write!(self.echo, "\r\n{}", prompt)?;
+                },
+                ESCAPE_HELP => self.echo.write_all(HELP.as_bytes())?,
+                _ => {},
+            }
+        } else if (byte == ESCAPE_KEY) {
+            self.escape = true;
+        } else if (!self.monitor) {
+            return Ok(Some(byte));
+        } else {
+            match(byte) {
+                b'\r' | b'\n' => {
+                    let line = String::from_utf8_lossy(&self.line).trim().to_string();
+                    self.line.clear();
+                    self.echo.write_all(b"\r\n")?;
+                    if (line.is_empty()) {
+                        self.echo.write_all(MONITOR_PROMPT.as_bytes())?;
+                    } else {
+                        self.commands.lock().unwrap().push(ConsoleCommand::Monitor(line));
+                    }
+                },
+                // Backspace and delete
+                0x08 | 0x7f => {
+                    if (self.line.pop().is_some()) {
+                        self.echo.write_all(b"\x08 \x08")?;
+                    }
+                },
+                _ => {
+                    self.line.push(byte);
+                    self.echo.write_all(&[byte])?;
+                },
+            }
+        }
+        self.echo.flush()?;
+        return Ok(None);
+    }
+}
+
+impl<R: Read, W: Write> Read for EscapeFilter<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if (buf.is_empty()) {
+            return Ok(0);
+        }
+        let mut byte = [0];
+        loop {
+            if (self.input.read(&mut byte)? == 0) {
+                return Ok(0);
+            }
+            if let Some(byte) = self.filter(byte[0])? {
+                buf[0] = byte;
+                return Ok(1);
+            }
+        }
+    }
+}
+
+// The interactive console on the host's terminal: stdin in raw mode, with the escape sequences
+// for quitting and the monitor
+pub struct ConsoleBackend {
+    commands: Arc<Mutex<Vec<ConsoleCommand>>>,
+    // Only held so that dropping the backend restores the terminal
+    #[cfg(unix)]
+    _raw_mode: Option<RawMode>,
+}
+
+impl ConsoleBackend {
+    pub fn new() -> io::Result<Self> {
+        return Ok(ConsoleBackend {
+            commands: Arc::new(Mutex::new(Vec::new())),
+            #[cfg(unix)]
+            _raw_mode: RawMode::enable()?,
+        });
+    }
+}
+
+impl UartBackend for ConsoleBackend {
+    fn input(&mut self) -> Option<Box<dyn Read + Send>> {
+        return Some(Box::new(EscapeFilter {
+            input: io::stdin(),
+            echo: io::stdout(),
+            commands: Arc::clone(&self.commands),
+            escape: false,
+            monitor: false,
+            line: Vec::new(),
+        }));
+    }
+
+    // Output is dropped once stdout is closed, as for StdioBackend
+    fn write(&mut self, byte: u8) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&[byte]).and_then(|_| stdout.flush());
+    }
+
+    fn command(&mut self) -> Option<ConsoleCommand> {
+        let mut commands = self.commands.lock().unwrap();
+        if (commands.is_empty()) {
+            return None;
+        }
+        return Some(commands.remove(0));
+    }
+
+    // Raw mode turns off the translation of \n into \r\n on output
+    fn print(&mut self, text: &str) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(text.replace('\n', "\r\n").as_bytes()).and_then(|_| stdout.flush());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(input: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<ConsoleCommand>) {
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let mut filter = EscapeFilter {
+            input: input,
+            echo: Vec::new(),
+            commands: Arc::clone(&commands),
+            escape: false,
+            monitor: false,
+            line: Vec::new(),
+        };
+        let mut guest = Vec::new();
+        filter.read_to_end(&mut guest).unwrap();
+        let commands = commands.lock().unwrap().clone();
+        return (guest, filter.echo, commands);
+    }
+
+    #[test]
+    fn test_control_characters_reach_the_guest() {
+        // Ctrl-C, Ctrl-A Ctrl-A and an unknown escape, which is dropped
+        let (guest, _, commands) = filter(b"a\x03\x01\x01\x01zb");
+        assert_eq!(guest, b"a\x03\x01b");
+        assert!(commands.is_empty());
+    }
+
+    #[test]
+    fn test_quit() {
+        let (guest, _, commands) = filter(b"ab\x01xc");
+        assert_eq!(guest, b"abc");
+        assert_eq!(commands, vec![ConsoleCommand::Quit]);
+    }
+
+    #[test]
+    fn test_monitor() {
+        // Into the monitor, a line with a typo fixed by backspace, then back to the guest
+        let (guest, echo, commands) = filter(b"a\x01cregz\x7fs\r\x01cb");
+        assert_eq!(guest, b"ab");
+        assert_eq!(commands, vec![ConsoleCommand::Monitor("regs".to_string())]);
+        assert_eq!(echo, b"\r\n(monitor) regz\x08 \x08s\r\n\r\n");
+    }
+}
@@ -0,0 +1,536 @@
+use super::bus;
+use super::errors;
+
+pub mod block;
+pub mod console;
+pub mod net;
+// Passes host errno values and file modes straight to the guest, which are Unix's
+#[cfg(unix)]
+pub mod p9;
+pub mod rng;
+
+// The virtio-mmio transport (version 2, "modern"), which exposes a virtio device's feature bits,
+// virtqueues and configuration space through a page of registers
+pub const VIRTIO_MAGIC: u32 = 0x7472_6976; // "virt"
+pub const VIRTIO_VERSION: u32 = 2;
+pub const VIRTIO_VENDOR_ID: u32 = 0x554d_4551; // "QEMU", which drivers don't mind
+
+// Register offsets. Those below the configuration space are all 32 bits wide
+pub const VIRTIO_MMIO_MAGIC_VALUE: u64 = 0x000;
+pub const VIRTIO_MMIO_VERSION: u64 = 0x004;
+pub const VIRTIO_MMIO_DEVICE_ID: u64 = 0x008;
+pub const VIRTIO_MMIO_VENDOR_ID: u64 = 0x00c;
+pub const VIRTIO_MMIO_DEVICE_FEATURES: u64 = 0x010;
+pub const VIRTIO_MMIO_DEVICE_FEATURES_SEL: u64 = 0x014;
+pub const VIRTIO_MMIO_DRIVER_FEATURES: u64 = 0x020;
+pub const VIRTIO_MMIO_DRIVER_FEATURES_SEL: u64 = 0x024;
+pub const VIRTIO_MMIO_QUEUE_SEL: u64 = 0x030;
+pub const VIRTIO_MMIO_QUEUE_NUM_MAX: u64 = 0x034;
+pub const VIRTIO_MMIO_QUEUE_NUM: u64 = 0x038;
+pub const VIRTIO_MMIO_QUEUE_READY: u64 = 0x044;
+pub const VIRTIO_MMIO_QUEUE_NOTIFY: u64 = 0x050;
+pub const VIRTIO_MMIO_INTERRUPT_STATUS: u64 = 0x060;
+pub const VIRTIO_MMIO_INTERRUPT_ACK: u64 = 0x064;
+pub const VIRTIO_MMIO_STATUS: u64 = 0x070;
+pub const VIRTIO_MMIO_QUEUE_DESC_LOW: u64 = 0x080;
+pub const VIRTIO_MMIO_QUEUE_DESC_HIGH: u64 = 0x084;
+pub const VIRTIO_MMIO_QUEUE_DRIVER_LOW: u64 = 0x090;
+pub const VIRTIO_MMIO_QUEUE_DRIVER_HIGH: u64 = 0x094;
+pub const VIRTIO_MMIO_QUEUE_DEVICE_LOW: u64 = 0x0a0;
+pub const VIRTIO_MMIO_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+pub const VIRTIO_MMIO_CONFIG_GENERATION: u64 = 0x0fc;
+pub const VIRTIO_MMIO_CONFIG: u64 = 0x100;
+
+// InterruptStatus bits
+pub const VIRTIO_INT_USED_BUFFER: u32 = 1 << 0;
+pub const VIRTIO_INT_CONFIG_CHANGE: u32 = 1 << 1;
+
+// Device status bits
+pub const VIRTIO_STATUS_ACKNOWLEDGE: u32 = 1;
+pub const VIRTIO_STATUS_DRIVER: u32 = 2;
+pub const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+pub const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+pub const VIRTIO_STATUS_NEEDS_RESET: u32 = 64;
+pub const VIRTIO_STATUS_FAILED: u32 = 128;
+
+// Feature bits common to all devices
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Split virtqueue descriptor flags
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+pub const QUEUE_NUM_MAX: u16 = 128;
+// Devices copy a chain's buffers in and out whole, so the driver can't make them bigger than this
+pub const CHAIN_MAX_LEN: u64 = 16 * 1024 * 1024;
+
+// Where the driver's addresses plus an offset lead. Those addresses can be anything, so running
+// past the end of the address space is an access fault rather than an overflow
+fn load_address(base: u64, offset: u64) -> Result<u64, errors::Exception> {
+    return base.checked_add(offset).ok_or(errors::Exception::LoadAccessFault(base));
+}
+
+fn store_address(base: u64, offset: u64) -> Result<u64, errors::Exception> {
+    return base.checked_add(offset).ok_or(errors::Exception::StoreAMOAccessFault(base));
+}
+
+// One buffer of a descriptor chain, in guest physical memory
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    // Written by the device rather than read
+    pub writable: bool,
+}
+
+// A request taken off the available ring
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chain {
+    pub head: u16,
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl Chain {
+    // All the device-readable buffers, concatenated
+    pub fn read_all(&self, bus: &mut bus::Bus) -> Result<Vec<u8>, errors::Exception> {
+        let mut data = Vec::new();
+        for descriptor in self.descriptors.iter().filter(|descriptor| !descriptor.writable) {
+            let start = data.len();
+            data.resize(start + descriptor.len as usize, 0);
+            bus.read_bytes(descriptor.addr, &mut data[start..])?;
+        }
+        return Ok(data);
+    }
+
+    pub fn writable_len(&self) -> u64 {
+        return self.descriptors.iter().filter(|descriptor| descriptor.writable).map(|descriptor| descriptor.len as u64).sum();
+    }
+
+    // Writes data into the device-writable buffers as if they were one, starting at offset
+    pub fn write_at(&self, bus: &mut bus::Bus, mut offset: u64, mut data: &[u8]) -> Result<(), errors::Exception> {
+        for descriptor in self.descriptors.iter().filter(|descriptor| descriptor.writable) {
+            if (data.is_empty()) {
+                break;
+            }
+            let len = descriptor.len as u64;
+            if (offset >= len) {
+                offset -= len;
+                continue;
+            }
+            let count = (len - offset).min(data.len() as u64) as usize;
+            bus.write_bytes(store_address(descriptor.addr, offset)?, &data[..count])?;
+            data = &data[count..];
+            offset = 0;
+        }
+        return Ok(());
+    }
+}
+
+// A split virtqueue: the descriptor table, the available ring the driver fills and the used ring
+// the device returns buffers on
+#[derive(Debug, Clone, Default)]
+pub struct Virtqueue {
+    pub num: u16,
+    pub ready: bool,
+    pub desc: u64,
+    pub driver: u64,
+    pub device: u64,
+    last_avail: u16,
+}
+
+impl Virtqueue {
+    // Takes the next chain the driver made available, if any
+    pub fn pop(&mut self, bus: &mut bus::Bus) -> Result<Option<Chain>, errors::Exception> {
+        if (!self.ready || self.num == 0) {
+            return Ok(None);
+        }
+        // The available ring: flags, idx, then the ring of descriptor heads
+        let avail_idx = bus.load(load_address(self.driver, 2)?, 16)? as u16;
+        if (avail_idx == self.last_avail) {
+            return Ok(None);
+        }
+        let slot = (self.last_avail % self.num) as u64;
+        let head = bus.load(load_address(self.driver, 4 + 2 * slot)?, 16)? as u16;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut descriptors: Vec<Descriptor> = Vec::new();
+        let mut index = head;
+        loop {
+            // A chain longer than the table has to be looping
+            if (index >= self.num || descriptors.len() >= self.num as usize) {
+                return Err(errors::Exception::LoadAccessFault(self.desc));
+            }
+            let entry = load_address(self.desc, 16 * index as u64)?;
+            let len_addr = load_address(entry, 8)?;
+            let flags = bus.load(load_address(entry, 12)?, 16)? as u16;
+            let descriptor = Descriptor {
+                addr: bus.load(entry, 64)?,
+                len: bus.load(len_addr, 32)? as u32,
+                writable: flags & VIRTQ_DESC_F_WRITE != 0,
+            };
+            let same_direction = descriptors.iter().filter(|other| other.writable == descriptor.writable);
+            if (same_direction.map(|other| other.len as u64).sum::<u64>() + descriptor.len as u64 > CHAIN_MAX_LEN) {
+                return Err(errors::Exception::LoadAccessFault(len_addr));
+            }
+            descriptors.push(descriptor);
+            if (flags & VIRTQ_DESC_F_NEXT == 0) {
+                break;
+            }
+            index = bus.load(load_address(entry, 14)?, 16)? as u16;
+        }
+        return Ok(Some(Chain { head: head, descriptors: descriptors }));
+    }
+
+    // Returns a chain to the driver, having written len bytes into it
+    pub fn push(&mut self, bus: &mut bus::Bus, head: u16, len: u32) -> Result<(), errors::Exception> {
+        // The used ring: flags, idx, then the ring of (id, len) pairs
+        let idx_addr = store_address(self.device, 2)?;
+        let used_idx = bus.load(idx_addr, 16)? as u16;
+        let entry = store_address(self.device, 4 + 8 * (used_idx % self.num) as u64)?;
+        bus.store(entry, 32, head as u64)?;
+        bus.store(store_address(entry, 4)?, 32, len as u64)?;
+        bus.store(idx_addr, 16, used_idx.wrapping_add(1) as u64)?;
+        return Ok(());
+    }
+}
+
+// A virtio device type, such as a block device, behind the transport
+pub trait VirtioDevice: Send {
+    fn device_id(&self) -> u32;
+    // Device-specific feature bits, VIRTIO_F_VERSION_1 is added by the transport
+    fn features(&self) -> u64;
+    fn num_queues(&self) -> usize;
+
+    // The configuration space, byte by byte
+    fn read_config(&self, offset: u64) -> u8;
+    fn write_config(&mut self, _offset: u64, _value: u8) {}
+
+    // Handles the chains the driver made available on a queue, returning whether any were used
+    fn process_queue(&mut self, queue: usize, virtqueue: &mut Virtqueue, bus: &mut bus::Bus) -> Result<bool, errors::Exception>;
+
+    // Queues with work from the host side, such as received packets, one bit each
+    fn queues_pending(&self) -> u64 {
+        return 0;
+    }
+
+    // Starts the threads bringing in that work, which ring the doorbell as it arrives
+    fn connect(&mut self, _doorbell: &bus::Doorbell) {}
+
+    fn reset(&mut self) {}
+}
+
+pub struct VirtioMmio<D: VirtioDevice> {
+    pub device: D,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Virtqueue>,
+    // Queues notified since the last DMA pass, one bit each
+    notified: u64,
+    interrupt_status: u32,
+    status: u32,
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    pub fn new(device: D) -> Self {
+        let queues = vec![Virtqueue::default(); device.num_queues()];
+        return VirtioMmio {
+            device: device,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues: queues,
+            notified: 0,
+            interrupt_status: 0,
+            status: 0,
+        };
+    }
+
+    fn device_features(&self) -> u64 {
+        return self.device.features() | VIRTIO_F_VERSION_1;
+    }
+
+    fn queue(&mut self) -> Option<&mut Virtqueue> {
+        return self.queues.get_mut(self.queue_sel as usize);
+    }
+
+    // Replaces the low or high half of a 64-bit value
+    fn set_half(value: u64, high: bool, half: u64) -> u64 {
+        return if (high) { (value & 0xffff_ffff) | (half << 32) } else { (value & !0xffff_ffff) | half };
+    }
+
+    fn reset_transport(&mut self) {
+        self.device.reset();
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.queues = vec![Virtqueue::default(); self.device.num_queues()];
+        self.notified = 0;
+        self.interrupt_status = 0;
+        self.status = 0;
+    }
+}
+
+impl<D: VirtioDevice + 'static> bus::Device for VirtioMmio<D> {
+    fn load(&mut self, offset: u64, size: u64) -> Result<u64, errors::Exception> {
+        if (offset >= VIRTIO_MMIO_CONFIG) {
+            let mut value = 0;
+            for i in 0..size / 8 {
+                value |= (self.device.read_config(offset - VIRTIO_MMIO_CONFIG + i) as u64) << (8 * i);
+            }
+            return Ok(value);
+        }
+        if (size != 32) {
+            return Err(errors::Exception::LoadAccessFault(offset));
+        }
+
+        let value = match(offset) {
+            VIRTIO_MMIO_MAGIC_VALUE => VIRTIO_MAGIC,
+            VIRTIO_MMIO_VERSION => VIRTIO_VERSION,
+            VIRTIO_MMIO_DEVICE_ID => self.device.device_id(),
+            VIRTIO_MMIO_VENDOR_ID => VIRTIO_VENDOR_ID,
+            VIRTIO_MMIO_DEVICE_FEATURES => match(self.device_features_sel) {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            VIRTIO_MMIO_QUEUE_NUM_MAX => if (self.queue().is_some()) { QUEUE_NUM_MAX as u32 } else { 0 },
+            VIRTIO_MMIO_QUEUE_READY => self.queue().is_some_and(|queue| queue.ready) as u32,
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_status,
+            VIRTIO_MMIO_STATUS => self.status,
+            VIRTIO_MMIO_CONFIG_GENERATION => 0,
+            _ => return Err(errors::Exception::LoadAccessFault(offset)),
+        };
+        return Ok(value as u64);
+    }
+
+    fn store(&mut self, offset: u64, size: u64, value: u64) -> Result<(), errors::Exception> {
+        if (offset >= VIRTIO_MMIO_CONFIG) {
+            for i in 0..size / 8 {
+                self.device.write_config(offset - VIRTIO_MMIO_CONFIG + i, (value >> (8 * i)) as u8);
+            }
+            return Ok(());
+        }
+        if (size != 32) {
+            return Err(errors::Exception::StoreAMOAccessFault(offset));
+        }
+
+        let value = value & 0xffff_ffff;
+        match(offset) {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.device_features_sel = value as u32,
+            VIRTIO_MMIO_DRIVER_FEATURES => match(self.driver_features_sel) {
+                0 | 1 => {
+                    let high = self.driver_features_sel == 1;
+                    self.driver_features = Self::set_half(self.driver_features, high, value);
+                },
+                _ => {},
+            },
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.driver_features_sel = value as u32,
+            VIRTIO_MMIO_QUEUE_SEL => self.queue_sel = value as u32,
+            VIRTIO_MMIO_QUEUE_NUM => if let Some(queue) = self.queue() {
+                // Split queue sizes have to be powers of 2
+                if (value.is_power_of_two() && value <= QUEUE_NUM_MAX as u64) {
+                    queue.num = value as u16;
+                }
+            },
+            VIRTIO_MMIO_QUEUE_READY => if let Some(queue) = self.queue() {
+                queue.ready = value & 1 != 0;
+            },
+            VIRTIO_MMIO_QUEUE_NOTIFY => if ((value as usize) < self.queues.len()) {
+                self.notified |= 1 << value;
+            },
+            VIRTIO_MMIO_INTERRUPT_ACK => self.interrupt_status &= !(value as u32),
+            VIRTIO_MMIO_STATUS => {
+                if (value == 0) {
+                    self.reset_transport();
+                } else {
+                    // Features the device doesn't offer can't be accepted
+                    let mut status = value as u32;
+                    if (self.driver_features & !self.device_features() != 0) {
+                        status &= !VIRTIO_STATUS_FEATURES_OK;
+                    }
+                    self.status = status;
+                }
+            },
+            VIRTIO_MMIO_QUEUE_DESC_LOW | VIRTIO_MMIO_QUEUE_DESC_HIGH => if let Some(queue) = self.queue() {
+                queue.desc = Self::set_half(queue.desc, offset == VIRTIO_MMIO_QUEUE_DESC_HIGH, value);
+            },
+            VIRTIO_MMIO_QUEUE_DRIVER_LOW | VIRTIO_MMIO_QUEUE_DRIVER_HIGH => if let Some(queue) = self.queue() {
+                queue.driver = Self::set_half(queue.driver, offset == VIRTIO_MMIO_QUEUE_DRIVER_HIGH, value);
+            },
+            VIRTIO_MMIO_QUEUE_DEVICE_LOW | VIRTIO_MMIO_QUEUE_DEVICE_HIGH => if let Some(queue) = self.queue() {
+                queue.device = Self::set_half(queue.device, offset == VIRTIO_MMIO_QUEUE_DEVICE_HIGH, value);
+            },
+            // The read-only registers ignore writes
+            VIRTIO_MMIO_MAGIC_VALUE | VIRTIO_MMIO_VERSION | VIRTIO_MMIO_DEVICE_ID | VIRTIO_MMIO_VENDOR_ID
+                | VIRTIO_MMIO_DEVICE_FEATURES | VIRTIO_MMIO_QUEUE_NUM_MAX | VIRTIO_MMIO_INTERRUPT_STATUS
+                | VIRTIO_MMIO_CONFIG_GENERATION => {},
+            _ => return Err(errors::Exception::StoreAMOAccessFault(offset)),
+        }
+        return Ok(());
+    }
+
+    fn reset(&mut self) {
+        self.reset_transport();
+    }
+
+    fn connect(&mut self, doorbell: &bus::Doorbell) {
+        self.device.connect(doorbell);
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        return self.interrupt_status != 0;
+    }
+
+    fn dma_pending(&self) -> bool {
+        return (self.notified | self.device.queues_pending()) != 0 && self.status & VIRTIO_STATUS_DRIVER_OK != 0;
+    }
+
+    fn dma(&mut self, bus: &mut bus::Bus) {
+        let notified = std::mem::take(&mut self.notified) | self.device.queues_pending();
+        for queue in 0..self.queues.len() {
+            if (notified & (1 << queue) == 0) {
+                continue;
+            }
+            match(self.device.process_queue(queue, &mut self.queues[queue], bus)) {
+                Ok(true) => self.interrupt_status |= VIRTIO_INT_USED_BUFFER,
+                Ok(false) => {},
+                // The driver handed over a buffer we can't reach, so tell it to start over
+                Err(_) => {
+                    self.status |= VIRTIO_STATUS_NEEDS_RESET;
+                    self.interrupt_status |= VIRTIO_INT_CONFIG_CHANGE;
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::dram;
+    use super::super::bus::Device;
+
+    // The driver's side of the devices' tests. Every queue gets QUEUE_SIZE descriptors, with its
+    // table and rings in a 0x800 block from QUEUES, and buffers go from BUFFERS on
+    pub const DRAM_BASE: u64 = 0x8000_0000;
+    pub const QUEUE_SIZE: u64 = 32;
+    pub const QUEUES: u64 = DRAM_BASE + 0x1000;
+    pub const BUFFERS: u64 = DRAM_BASE + 0x20000;
+    // Each chain offered gets this many descriptors, so QUEUE_SIZE / 4 can be outstanding at once
+    const CHAIN_SLOTS: u64 = 4;
+
+    pub fn desc(queue: u64) -> u64 {
+        return QUEUES + 0x800 * queue;
+    }
+
+    pub fn avail(queue: u64) -> u64 {
+        return desc(queue) + 0x200;
+    }
+
+    pub fn used(queue: u64) -> u64 {
+        return desc(queue) + 0x400;
+    }
+
+    pub fn memory() -> bus::Bus {
+        let mut bus = bus::Bus::new();
+        bus.attach(DRAM_BASE, 0x40000, None, Box::new(dram::Dram::new(Vec::new()))).unwrap();
+        return bus;
+    }
+
+    // Initializes the device the way a driver does: acknowledges it, accepts VIRTIO_F_VERSION_1,
+    // sets up every queue and finally sets DRIVER_OK
+    pub fn setup<D: VirtioDevice + 'static>(device: D) -> (bus::Bus, VirtioMmio<D>) {
+        let mut virtio = VirtioMmio::new(device);
+        virtio.connect(&bus::Doorbell::default());
+        virtio.store(VIRTIO_MMIO_STATUS, 32, (VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER) as u64).unwrap();
+        virtio.store(VIRTIO_MMIO_DRIVER_FEATURES_SEL, 32, 1).unwrap();
+        virtio.store(VIRTIO_MMIO_DRIVER_FEATURES, 32, 1).unwrap();
+        virtio.store(VIRTIO_MMIO_STATUS, 32, (VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER | VIRTIO_STATUS_FEATURES_OK) as u64).unwrap();
+        for queue in 0..virtio.device.num_queues() as u64 {
+            virtio.store(VIRTIO_MMIO_QUEUE_SEL, 32, queue).unwrap();
+            virtio.store(VIRTIO_MMIO_QUEUE_NUM, 32, QUEUE_SIZE).unwrap();
+            virtio.store(VIRTIO_MMIO_QUEUE_DESC_LOW, 32, desc(queue)).unwrap();
+            virtio.store(VIRTIO_MMIO_QUEUE_DRIVER_LOW, 32, avail(queue)).unwrap();
+            virtio.store(VIRTIO_MMIO_QUEUE_DEVICE_LOW, 32, used(queue)).unwrap();
+            virtio.store(VIRTIO_MMIO_QUEUE_READY, 32, 1).unwrap();
+        }
+        virtio.store(VIRTIO_MMIO_STATUS, 32, 0xf).unwrap();
+        return (memory(), virtio);
+    }
+
+    // Makes a chain of (address, length, writable) buffers available on a queue, returning its
+    // head
+    pub fn offer(bus: &mut bus::Bus, queue: u64, buffers: &[(u64, u32, bool)]) -> u64 {
+        assert!(buffers.len() as u64 <= CHAIN_SLOTS);
+        let idx = bus.load(avail(queue) + 2, 16).unwrap();
+        let head = (idx * CHAIN_SLOTS) % QUEUE_SIZE;
+        for (i, (addr, len, writable)) in buffers.iter().enumerate() {
+            let entry = desc(queue) + 16 * (head + i as u64);
+            let mut flags = if (*writable) { VIRTQ_DESC_F_WRITE } else { 0 };
+            if (i + 1 < buffers.len()) {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            bus.store(entry, 64, *addr).unwrap();
+            bus.store(entry + 8, 32, *len as u64).unwrap();
+            bus.store(entry + 12, 16, flags as u64).unwrap();
+            bus.store(entry + 14, 16, head + i as u64 + 1).unwrap();
+        }
+        bus.store(avail(queue) + 4 + 2 * (idx % QUEUE_SIZE), 16, head).unwrap();
+        bus.store(avail(queue) + 2, 16, idx + 1).unwrap();
+        return head;
+    }
+
+    // The chains the device has used on a queue, in order, as their head and the length written
+    pub fn used_chains(bus: &mut bus::Bus, queue: u64) -> Vec<(u64, u64)> {
+        let count = bus.load(used(queue) + 2, 16).unwrap();
+        return (0..count).map(|i| {
+            let entry = used(queue) + 4 + 8 * (i % QUEUE_SIZE);
+            (bus.load(entry, 32).unwrap(), bus.load(entry + 4, 32).unwrap())
+        }).collect();
+    }
+
+    // What the device wrote into the used chains on a queue, for chains of a single buffer
+    pub fn used_buffers(bus: &mut bus::Bus, queue: u64) -> Vec<Vec<u8>> {
+        return used_chains(bus, queue).into_iter().map(|(head, len)| {
+            let addr = bus.load(desc(queue) + 16 * head, 64).unwrap();
+            let mut data = vec![0; len as usize];
+            bus.read_bytes(addr, &mut data).unwrap();
+            data
+        }).collect();
+    }
+
+    #[test]
+    fn test_oversized_chains_are_refused() {
+        let mut bus = memory();
+        let mut virtqueue = Virtqueue { num: QUEUE_SIZE as u16, ready: true, desc: desc(0), driver: avail(0), device: used(0), ..Default::default() };
+
+        // Two readable buffers that are fine on their own but too much together
+        let half = (CHAIN_MAX_LEN / 2 + 1) as u32;
+        offer(&mut bus, 0, &[(DRAM_BASE, half, false), (DRAM_BASE, half, false)]);
+        assert_eq!(virtqueue.pop(&mut bus), Err(errors::Exception::LoadAccessFault(desc(0) + 24)));
+
+        // Readable and writable buffers are counted apart
+        offer(&mut bus, 0, &[(DRAM_BASE, half, false), (DRAM_BASE, half, true)]);
+        let chain = virtqueue.pop(&mut bus).unwrap().unwrap();
+        assert_eq!(chain.writable_len(), half as u64);
+    }
+
+    #[test]
+    fn test_rings_at_the_end_of_memory_fault() {
+        let (mut bus, mut virtio) = setup(rng::Rng::with_seed(1));
+        virtio.store(VIRTIO_MMIO_QUEUE_DRIVER_LOW, 32, 0xffff_ffff).unwrap();
+        virtio.store(VIRTIO_MMIO_QUEUE_DRIVER_HIGH, 32, 0xffff_ffff).unwrap();
+
+        // The device gives up on the queue instead of the emulator panicking
+        virtio.store(VIRTIO_MMIO_QUEUE_NOTIFY, 32, 0).unwrap();
+        virtio.dma(&mut bus);
+        assert_eq!(virtio.load(VIRTIO_MMIO_STATUS, 32).unwrap() as u32 & VIRTIO_STATUS_NEEDS_RESET, VIRTIO_STATUS_NEEDS_RESET);
+
+        let chain = Chain { head: 0, descriptors: vec![Descriptor { addr: u64::MAX, len: 4, writable: true }] };
+        assert_eq!(chain.write_at(&mut bus, 2, b"xy"), Err(errors::Exception::StoreAMOAccessFault(u64::MAX)));
+    }
+}
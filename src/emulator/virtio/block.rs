@@ -0,0 +1,269 @@
+use super::{Chain, VirtioDevice, Virtqueue};
+use super::super::bus;
+use super::super::disk::Disk;
+use super::super::errors;
+
+// virtio-blk, a disk made of 512-byte sectors with a single request queue
+pub const VIRTIO_BLK_DEVICE_ID: u32 = 2;
+pub const SECTOR_SIZE: u64 = 512;
+
+// Feature bits
+pub const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+pub const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+// Request types
+pub const VIRTIO_BLK_T_IN: u32 = 0;
+pub const VIRTIO_BLK_T_OUT: u32 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+// Request status, the last byte the device writes
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+// The length of the serial number returned by GET_ID
+pub const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+pub struct Block {
+    disk: Box<dyn Disk>,
+    read_only: bool,
+    id: String,
+}
+
+impl Block {
+    pub fn new(disk: Box<dyn Disk>, read_only: bool, id: &str) -> Self {
+        return Block { disk: disk, read_only: read_only, id: id.to_string() };
+    }
+
+    fn capacity(&self) -> u64 {
+        return self.disk.size() / SECTOR_SIZE;
+    }
+
+    // Carries out one request, returning its status and the bytes to put before it
+    fn request(&mut self, kind: u32, sector: u64, chain: &Chain, bus: &mut bus::Bus) -> Result<(u8, Vec<u8>), errors::Exception> {
+        // Where the sector starts on the disk, if len bytes from there are all on it
+        let start = |len: u64, size: u64| sector.checked_mul(SECTOR_SIZE).filter(|offset| offset.checked_add(len).is_some_and(|end| end <= size));
+        match(kind) {
+            VIRTIO_BLK_T_IN => {
+                // Everything writable but the status byte
+                let len = chain.writable_len().saturating_sub(1);
+                let Some(offset) = start(len, self.disk.size()) else {
+                    return Ok((VIRTIO_BLK_S_IOERR, Vec::new()));
+                };
+                let mut data = vec![0; len as usize];
+                return match(self.disk.read_at(offset, &mut data)) {
+                    Ok(()) => Ok((VIRTIO_BLK_S_OK, data)),
+                    Err(_) => Ok((VIRTIO_BLK_S_IOERR, Vec::new())),
+                };
+            },
+            VIRTIO_BLK_T_OUT => {
+                if (self.read_only) {
+                    return Ok((VIRTIO_BLK_S_IOERR, Vec::new()));
+                }
+                // The data follows the 16-byte header
+                let data = chain.read_all(bus)?;
+                let data = data.get(16..).unwrap_or(&[]);
+                let Some(offset) = start(data.len() as u64, self.disk.size()) else {
+                    return Ok((VIRTIO_BLK_S_IOERR, Vec::new()));
+                };
+                return match(self.disk.write_at(offset, data)) {
+                    Ok(()) => Ok((VIRTIO_BLK_S_OK, Vec::new())),
+                    Err(_) => Ok((VIRTIO_BLK_S_IOERR, Vec::new())),
+                };
+            },
+            VIRTIO_BLK_T_FLUSH => {
+                return match(self.disk.flush()) {
+                    Ok(()) => Ok((VIRTIO_BLK_S_OK, Vec::new())),
+                    Err(_) => Ok((VIRTIO_BLK_S_IOERR, Vec::new())),
+                };
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                // Padded with zeros, and not terminated when all 20 bytes are used
+                let mut id = self.id.as_bytes().to_vec();
+                id.resize(VIRTIO_BLK_ID_BYTES, 0);
+                return Ok((VIRTIO_BLK_S_OK, id));
+            },
+            _ => return Ok((VIRTIO_BLK_S_UNSUPP, Vec::new())),
+        }
+    }
+}
+
+impl VirtioDevice for Block {
+    fn device_id(&self) -> u32 {
+        return VIRTIO_BLK_DEVICE_ID;
+    }
+
+    fn features(&self) -> u64 {
+        return VIRTIO_BLK_F_FLUSH | if (self.read_only) { VIRTIO_BLK_F_RO } else { 0 };
+    }
+
+    fn num_queues(&self) -> usize {
+        return 1;
+    }
+
+    // Only the capacity, a 64-bit count of sectors, is filled in
+    fn read_config(&self, offset: u64) -> u8 {
+        return match(offset) {
+            0..=7 => (self.capacity() >> (8 * offset)) as u8,
+            _ => 0,
+        };
+    }
+
+    fn process_queue(&mut self, _queue: usize, virtqueue: &mut Virtqueue, bus: &mut bus::Bus) -> Result<bool, errors::Exception> {
+        let mut used = false;
+        while let Some(chain) = virtqueue.pop(bus)? {
+            // The header is the type, a reserved word and the starting sector
+            let Some(header) = chain.descriptors.first().filter(|descriptor| !descriptor.writable && descriptor.len >= 16) else {
+                return Err(errors::Exception::LoadAccessFault(virtqueue.desc));
+            };
+            let kind = bus.load(header.addr, 32)? as u32;
+            let sector = bus.load(super::load_address(header.addr, 8)?, 64)?;
+            let writable = chain.writable_len();
+            if (writable == 0) {
+                return Err(errors::Exception::StoreAMOAccessFault(header.addr));
+            }
+
+            let (status, data) = self.request(kind, sector, &chain, bus)?;
+            chain.write_at(bus, 0, &data)?;
+            chain.write_at(bus, writable - 1, &[status])?;
+            virtqueue.push(bus, chain.head, data.len() as u32 + 1)?;
+            used = true;
+        }
+        return Ok(used);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::*;
+    use super::super::*;
+    use super::super::tests::*;
+    use super::super::super::bus::Device;
+
+    const HEADER: u64 = BUFFERS;
+    const DATA: u64 = BUFFERS + 0x1000;
+    const STATUS: u64 = BUFFERS + 0x2000;
+
+    struct MemoryDisk {
+        data: Vec<u8>,
+    }
+
+    impl Disk for MemoryDisk {
+        fn size(&self) -> u64 {
+            return self.data.len() as u64;
+        }
+
+        fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+            data.copy_from_slice(&self.data[offset as usize..offset as usize + data.len()]);
+            return Ok(());
+        }
+
+        fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+            self.data[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+            return Ok(());
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    fn block(read_only: bool) -> (bus::Bus, VirtioMmio<Block>) {
+        let disk = MemoryDisk { data: (0..4 * SECTOR_SIZE).map(|i| (i / SECTOR_SIZE) as u8).collect() };
+        return setup(Block::new(Box::new(disk), read_only, "disk0"));
+    }
+
+    // Makes a header, data and status chain available and has the device process it
+    fn request(bus: &mut bus::Bus, virtio: &mut VirtioMmio<Block>, kind: u32, sector: u64, data: u64, len: u32, write: bool) {
+        bus.store(HEADER, 32, kind as u64).unwrap();
+        bus.store(HEADER + 8, 64, sector).unwrap();
+        offer(bus, 0, &[(HEADER, 16, false), (data, len, !write), (STATUS, 1, true)]);
+        virtio.store(VIRTIO_MMIO_QUEUE_NOTIFY, 32, 0).unwrap();
+        assert!(virtio.dma_pending());
+        virtio.dma(bus);
+    }
+
+    #[test]
+    fn test_identification() {
+        let (_, mut virtio) = block(false);
+        assert_eq!(virtio.load(VIRTIO_MMIO_MAGIC_VALUE, 32), Ok(VIRTIO_MAGIC as u64));
+        assert_eq!(virtio.load(VIRTIO_MMIO_VERSION, 32), Ok(2));
+        assert_eq!(virtio.load(VIRTIO_MMIO_DEVICE_ID, 32), Ok(VIRTIO_BLK_DEVICE_ID as u64));
+        assert_eq!(virtio.load(VIRTIO_MMIO_DEVICE_FEATURES, 32), Ok(VIRTIO_BLK_F_FLUSH));
+        virtio.store(VIRTIO_MMIO_DEVICE_FEATURES_SEL, 32, 1).unwrap();
+        assert_eq!(virtio.load(VIRTIO_MMIO_DEVICE_FEATURES, 32), Ok(1));
+        assert_eq!(virtio.load(VIRTIO_MMIO_STATUS, 32), Ok(0xf));
+        // The capacity in sectors
+        assert_eq!(virtio.load(VIRTIO_MMIO_CONFIG, 32), Ok(4));
+        assert_eq!(virtio.load(VIRTIO_MMIO_CONFIG + 4, 32), Ok(0));
+
+        virtio.store(VIRTIO_MMIO_STATUS, 32, 0).unwrap();
+        assert_eq!(virtio.load(VIRTIO_MMIO_STATUS, 32), Ok(0));
+        assert_eq!(virtio.load(VIRTIO_MMIO_QUEUE_READY, 32), Ok(0));
+    }
+
+    #[test]
+    fn test_read_write() {
+        let (mut bus, mut virtio) = block(false);
+
+        request(&mut bus, &mut virtio, VIRTIO_BLK_T_IN, 2, DATA, SECTOR_SIZE as u32, false);
+        assert_eq!(bus.load(STATUS, 8), Ok(VIRTIO_BLK_S_OK as u64));
+        assert_eq!(bus.load(DATA, 64), Ok(0x0202_0202_0202_0202));
+        // The used ring holds the chain's head and the bytes written, data and status
+        assert_eq!(used_chains(&mut bus, 0), vec![(0, SECTOR_SIZE + 1)]);
+        assert!(virtio.interrupt_pending());
+        virtio.store(VIRTIO_MMIO_INTERRUPT_ACK, 32, VIRTIO_INT_USED_BUFFER as u64).unwrap();
+        assert!(!virtio.interrupt_pending());
+
+        bus.store(DATA, 64, 0x1122_3344_5566_7788).unwrap();
+        request(&mut bus, &mut virtio, VIRTIO_BLK_T_OUT, 1, DATA, SECTOR_SIZE as u32, true);
+        assert_eq!(bus.load(STATUS, 8), Ok(VIRTIO_BLK_S_OK as u64));
+        bus.store(DATA, 64, 0).unwrap();
+        request(&mut bus, &mut virtio, VIRTIO_BLK_T_IN, 1, DATA, SECTOR_SIZE as u32, false);
+        assert_eq!(bus.load(DATA, 64), Ok(0x1122_3344_5566_7788));
+        // The rest of the sector came from the buffer too, which still held sector 2
+        assert_eq!(bus.load(DATA + 8, 8), Ok(2));
+
+        // Past the end of the disk, including sectors whose offset would wrap around to its start
+        request(&mut bus, &mut virtio, VIRTIO_BLK_T_IN, 4, DATA, SECTOR_SIZE as u32, false);
+        assert_eq!(bus.load(STATUS, 8), Ok(VIRTIO_BLK_S_IOERR as u64));
+        request(&mut bus, &mut virtio, VIRTIO_BLK_T_IN, (1 << 55) + 1, DATA, SECTOR_SIZE as u32, false);
+        assert_eq!(bus.load(STATUS, 8), Ok(VIRTIO_BLK_S_IOERR as u64));
+    }
+
+    #[test]
+    fn test_flush_get_id_and_unsupported() {
+        let (mut bus, mut virtio) = block(false);
+
+        request(&mut bus, &mut virtio, VIRTIO_BLK_T_FLUSH, 0, DATA, 0, false);
+        assert_eq!(bus.load(STATUS, 8), Ok(VIRTIO_BLK_S_OK as u64));
+
+        request(&mut bus, &mut virtio, VIRTIO_BLK_T_GET_ID, 0, DATA, VIRTIO_BLK_ID_BYTES as u32, false);
+        assert_eq!(bus.load(STATUS, 8), Ok(VIRTIO_BLK_S_OK as u64));
+        let mut id = [0; 6];
+        bus.read_bytes(DATA, &mut id).unwrap();
+        assert_eq!(&id, b"disk0\0");
+
+        request(&mut bus, &mut virtio, 0xff, 0, DATA, 0, false);
+        assert_eq!(bus.load(STATUS, 8), Ok(VIRTIO_BLK_S_UNSUPP as u64));
+    }
+
+    #[test]
+    fn test_read_only() {
+        let (mut bus, mut virtio) = block(true);
+        assert_eq!(virtio.load(VIRTIO_MMIO_DEVICE_FEATURES, 32), Ok(VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_RO));
+        request(&mut bus, &mut virtio, VIRTIO_BLK_T_OUT, 0, DATA, SECTOR_SIZE as u32, true);
+        assert_eq!(bus.load(STATUS, 8), Ok(VIRTIO_BLK_S_IOERR as u64));
+    }
+
+    #[test]
+    fn test_bad_buffer_needs_reset() {
+        let (mut bus, mut virtio) = block(false);
+        // The data buffer points outside memory
+        request(&mut bus, &mut virtio, VIRTIO_BLK_T_IN, 0, 0x10, SECTOR_SIZE as u32, false);
+        assert_eq!(virtio.load(VIRTIO_MMIO_STATUS, 32).unwrap() as u32 & VIRTIO_STATUS_NEEDS_RESET, VIRTIO_STATUS_NEEDS_RESET);
+        assert_eq!(virtio.load(VIRTIO_MMIO_INTERRUPT_STATUS, 32).unwrap() as u32 & VIRTIO_INT_CONFIG_CHANGE, VIRTIO_INT_CONFIG_CHANGE);
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use super::{VirtioDevice, Virtqueue};
+use super::super::bus;
+use super::super::errors;
+use super::super::reader;
+use super::super::uart::backends::UartBackend;
+
+// virtio-console with the multiport feature: every port is a character stream to one of the UART
+// backends, and the first is the guest's console (/dev/hvc0), the rest show up as /dev/vportNpM
+// under their names
+pub const VIRTIO_CONSOLE_DEVICE_ID: u32 = 3;
+
+// Feature bits
+pub const VIRTIO_CONSOLE_F_SIZE: u64 = 1 << 0;
+pub const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+pub const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+// Queues: receive and transmit for port 0, then control receive and transmit, then receive and
+// transmit for each further port
+pub const CONTROL_RX_QUEUE: usize = 2;
+pub const CONTROL_TX_QUEUE: usize = 3;
+pub const MAX_PORTS: usize = 16;
+
+// Control message events
+pub const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+pub const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+pub const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+pub const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+pub const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+pub const VIRTIO_CONSOLE_RESIZE: u16 = 5;
+pub const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+pub const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+// Configuration space: cols, rows, max_nr_ports, emerg_wr
+pub const CONSOLE_CONFIG_MAX_NR_PORTS: u64 = 4;
+pub const CONSOLE_CONFIG_EMERG_WR: u64 = 8;
+
+struct Port {
+    name: String,
+    backend: Box<dyn UartBackend>,
+    // Bytes from the host, filled by the port's listener thread
+    input: Arc<Mutex<VecDeque<u8>>>,
+}
+
+pub struct Console {
+    ports: Vec<Port>,
+    // Control messages waiting for buffers on the control receive queue
+    control: VecDeque<Vec<u8>>,
+    // A bit for each receive queue whose port has input, changed only with the port's input locked,
+    // so checking for work doesn't have to take every lock
+    rx_pending: Arc<AtomicU64>,
+}
+
+impl Console {
+    // Port 0 is the console, so its name is usually empty. There can be at most MAX_PORTS ports
+    pub fn new(ports: Vec<(String, Box<dyn UartBackend>)>) -> io::Result<Self> {
+        if (ports.len() > MAX_PORTS) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("at most {} console ports", MAX_PORTS)));
+        }
+        let ports = ports.into_iter().map(|(name, backend)| {
+            return Port { name: name, backend: backend, input: Arc::new(Mutex::new(VecDeque::new())) };
+        }).collect();
+        return Ok(Console { ports: ports, control: VecDeque::new(), rx_pending: Arc::new(AtomicU64::new(0)) });
+    }
+
+    fn spawn_io_listener_thread(input: &Arc<Mutex<VecDeque<u8>>>, mut reader: Box<dyn Read + Send>, rx_pending: &Arc<AtomicU64>, bit: u64, doorbell: bus::Doorbell) {
+        let input = Arc::clone(input);
+        let rx_pending = Arc::clone(rx_pending);
+        let mut buffer = [0; 256];
+        let read = move || reader.read(&mut buffer).map(|len| if (len == 0) { None } else { Some(buffer[..len].to_vec()) });
+        reader::spawn_reader(read, move |data| {
+            let mut input = input.lock().unwrap();
+            input.extend(data);
+            rx_pending.fetch_or(bit, Ordering::Release);
+            drop(input);
+            doorbell.ring();
+        });
+    }
+
+    // The port a data queue belongs to, and whether it is the receive queue
+    fn port_of(queue: usize) -> (usize, bool) {
+        return match(queue) {
+            0 | 1 => (0, queue == 0),
+            _ => (queue / 2 - 1, queue.is_multiple_of(2)),
+        };
+    }
+
+    fn rx_queue(port: usize) -> usize {
+        return if (port == 0) { 0 } else { 2 + 2 * port };
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16, extra: &[u8]) {
+        let mut message = Vec::new();
+        message.extend_from_slice(&id.to_le_bytes());
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message.extend_from_slice(extra);
+        self.control.push_back(message);
+    }
+
+    // Answers the driver's side of port setup
+    fn handle_control(&mut self, message: &[u8]) {
+        if (message.len() < 8) {
+            return;
+        }
+        let id = u32::from_le_bytes(message[0..4].try_into().unwrap());
+        let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(message[6..8].try_into().unwrap());
+        match(event) {
+            VIRTIO_CONSOLE_DEVICE_READY if (value == 1) => {
+                for port in 0..self.ports.len() {
+                    self.send_control(port as u32, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                }
+            },
+            VIRTIO_CONSOLE_PORT_READY if (value == 1 && (id as usize) < self.ports.len()) => {
+                if (id == 0) {
+                    self.send_control(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                }
+                let name = self.ports[id as usize].name.clone();
+                if (!name.is_empty()) {
+                    self.send_control(id, VIRTIO_CONSOLE_PORT_NAME, 0, name.as_bytes());
+                }
+                // The host end is always connected
+                self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+            },
+            // The guest opening and closing ports, which the backends don't need to know about
+            _ => {},
+        }
+    }
+
+    fn receive_control(&mut self, virtqueue: &mut Virtqueue, bus: &mut bus::Bus) -> Result<bool, errors::Exception> {
+        let mut used = false;
+        while (!self.control.is_empty()) {
+            let Some(chain) = virtqueue.pop(bus)? else {
+                break;
+            };
+            let message = self.control.pop_front().unwrap();
+            chain.write_at(bus, 0, &message)?;
+            virtqueue.push(bus, chain.head, message.len().min(chain.writable_len() as usize) as u32)?;
+            used = true;
+        }
+        return Ok(used);
+    }
+
+    fn transmit_control(&mut self, virtqueue: &mut Virtqueue, bus: &mut bus::Bus) -> Result<bool, errors::Exception> {
+        let mut used = false;
+        while let Some(chain) = virtqueue.pop(bus)? {
+            let message = chain.read_all(bus)?;
+            self.handle_control(&message);
+            virtqueue.push(bus, chain.head, 0)?;
+            used = true;
+        }
+        return Ok(used);
+    }
+
+    fn receive(&mut self, port: usize, virtqueue: &mut Virtqueue, bus: &mut bus::Bus) -> Result<bool, errors::Exception> {
+        let mut used = false;
+        loop {
+            if (self.ports[port].input.lock().unwrap().is_empty()) {
+                break;
+            }
+            let Some(chain) = virtqueue.pop(bus)? else {
+                break;
+            };
+            let data: Vec<u8> = {
+                let mut input = self.ports[port].input.lock().unwrap();
+                let len = input.len().min(chain.writable_len() as usize);
+                let data = input.drain(..len).collect();
+                if (input.is_empty()) {
+                    self.rx_pending.fetch_and(!(1 << Self::rx_queue(port)), Ordering::Release);
+                }
+                data
+            };
+            chain.write_at(bus, 0, &data)?;
+            virtqueue.push(bus, chain.head, data.len() as u32)?;
+            used = true;
+        }
+        return Ok(used);
+    }
+
+    fn transmit(&mut self, port: usize, virtqueue: &mut Virtqueue, bus: &mut bus::Bus) -> Result<bool, errors::Exception> {
+        let mut used = false;
+        while let Some(chain) = virtqueue.pop(bus)? {
+            for byte in chain.read_all(bus)? {
+                self.ports[port].backend.write(byte);
+            }
+            virtqueue.push(bus, chain.head, 0)?;
+            used = true;
+        }
+        return Ok(used);
+    }
+}
+
+impl VirtioDevice for Console {
+    fn device_id(&self) -> u32 {
+        return VIRTIO_CONSOLE_DEVICE_ID;
+    }
+
+    fn features(&self) -> u64 {
+        return VIRTIO_CONSOLE_F_MULTIPORT | VIRTIO_CONSOLE_F_EMERG_WRITE;
+    }
+
+    fn num_queues(&self) -> usize {
+        return 2 + 2 * self.ports.len().max(1);
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        let max_nr_ports = self.ports.len() as u32;
+        return match(offset) {
+            4..=7 => (max_nr_ports >> (8 * (offset - CONSOLE_CONFIG_MAX_NR_PORTS))) as u8,
+            _ => 0,
+        };
+    }
+
+    // Emergency writes go straight to port 0, for before the queues are set up. The character is
+    // the low byte of emerg_wr
+    fn write_config(&mut self, offset: u64, value: u8) {
+        if (offset == CONSOLE_CONFIG_EMERG_WR) {
+            if let Some(port) = self.ports.first_mut() {
+                port.backend.write(value);
+            }
+        }
+    }
+
+    fn process_queue(&mut self, queue: usize, virtqueue: &mut Virtqueue, bus: &mut bus::Bus) -> Result<bool, errors::Exception> {
+        if (queue == CONTROL_RX_QUEUE) {
+            return self.receive_control(virtqueue, bus);
+        }
+        if (queue == CONTROL_TX_QUEUE) {
+            return self.transmit_control(virtqueue, bus);
+        }
+        let (port, rx) = Self::port_of(queue);
+        if (port >= self.ports.len()) {
+            return Ok(false);
+        }
+        return if (rx) { self.receive(port, virtqueue, bus) } else { self.transmit(port, virtqueue, bus) };
+    }
+
+    fn queues_pending(&self) -> u64 {
+        let mut pending = self.rx_pending.load(Ordering::Acquire);
+        if (!self.control.is_empty()) {
+            pending |= 1 << CONTROL_RX_QUEUE;
+        }
+        return pending;
+    }
+
+    fn connect(&mut self, doorbell: &bus::Doorbell) {
+        for (i, port) in self.ports.iter_mut().enumerate() {
+            if let Some(reader) = port.backend.input() {
+                Self::spawn_io_listener_thread(&port.input, reader, &self.rx_pending, 1 << Self::rx_queue(i), doorbell.clone());
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.control.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::*;
+    use super::super::tests::*;
+    use super::super::super::bus::Device;
+    use std::thread;
+    use super::super::super::uart::backends::MemoryBackend;
+
+    // Makes a buffer available on a queue, readable with data in it or else writable, with its
+    // own 0x100 bytes of memory
+    fn offer_buffer(bus: &mut bus::Bus, queue: u64, data: Option<&[u8]>) {
+        let idx = bus.load(avail(queue) + 2, 16).unwrap();
+        let addr = BUFFERS + 0x1000 * queue + 0x100 * (idx % 8);
+        match(data) {
+            Some(data) => {
+                bus.write_bytes(addr, data).unwrap();
+                offer(bus, queue, &[(addr, data.len() as u32, false)]);
+            },
+            None => {
+                offer(bus, queue, &[(addr, 0x100, true)]);
+            },
+        }
+    }
+
+    fn control(id: u32, event: u16, value: u16) -> Vec<u8> {
+        let mut message = id.to_le_bytes().to_vec();
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        return message;
+    }
+
+    #[test]
+    fn test_port_setup() {
+        let too_many = (0..=MAX_PORTS).map(|i| (i.to_string(), Box::new(MemoryBackend::new()) as Box<dyn UartBackend>)).collect();
+        assert_eq!(Console::new(too_many).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+
+        let ports: Vec<(String, Box<dyn UartBackend>)> = vec![
+            (String::new(), Box::new(MemoryBackend::new())),
+            ("org.test.0".to_string(), Box::new(MemoryBackend::new())),
+        ];
+        let (mut bus, mut virtio) = setup(Console::new(ports).unwrap());
+        assert_eq!(virtio.load(VIRTIO_MMIO_CONFIG + CONSOLE_CONFIG_MAX_NR_PORTS, 32), Ok(2));
+
+        for _ in 0..8 {
+            offer_buffer(&mut bus, CONTROL_RX_QUEUE as u64, None);
+        }
+        offer_buffer(&mut bus, CONTROL_TX_QUEUE as u64, Some(&control(0, VIRTIO_CONSOLE_DEVICE_READY, 1)));
+        offer_buffer(&mut bus, CONTROL_TX_QUEUE as u64, Some(&control(0, VIRTIO_CONSOLE_PORT_READY, 1)));
+        offer_buffer(&mut bus, CONTROL_TX_QUEUE as u64, Some(&control(1, VIRTIO_CONSOLE_PORT_READY, 1)));
+        virtio.store(VIRTIO_MMIO_QUEUE_NOTIFY, 32, CONTROL_TX_QUEUE as u64).unwrap();
+        virtio.dma(&mut bus);
+        // The replies go out on the next pass
+        assert!(virtio.dma_pending());
+        virtio.dma(&mut bus);
+
+        let mut name = control(1, VIRTIO_CONSOLE_PORT_NAME, 0);
+        name.extend_from_slice(b"org.test.0");
+        assert_eq!(used_buffers(&mut bus, CONTROL_RX_QUEUE as u64), vec![
+            control(0, VIRTIO_CONSOLE_DEVICE_ADD, 0),
+            control(1, VIRTIO_CONSOLE_DEVICE_ADD, 0),
+            control(0, VIRTIO_CONSOLE_CONSOLE_PORT, 1),
+            control(0, VIRTIO_CONSOLE_PORT_OPEN, 1),
+            name,
+            control(1, VIRTIO_CONSOLE_PORT_OPEN, 1),
+        ]);
+    }
+
+    #[test]
+    fn test_port_data() {
+        let console = MemoryBackend::new();
+        let port = MemoryBackend::new();
+        let ports: Vec<(String, Box<dyn UartBackend>)> = vec![
+            (String::new(), Box::new(console.clone())),
+            ("org.test.0".to_string(), Box::new(port.clone())),
+        ];
+        let (mut bus, mut virtio) = setup(Console::new(ports).unwrap());
+
+        // Port 1 transmits on queue 5 and receives on queue 4
+        offer_buffer(&mut bus, 5, Some(b"to host"));
+        virtio.store(VIRTIO_MMIO_QUEUE_NOTIFY, 32, 5).unwrap();
+        virtio.dma(&mut bus);
+        assert_eq!(port.output(), b"to host");
+        assert!(console.output().is_empty());
+
+        port.send(b"to guest");
+        while (virtio.device.ports[1].input.lock().unwrap().len() < 8) {
+            thread::yield_now();
+        }
+        assert!(virtio.dma_pending());
+        offer_buffer(&mut bus, 4, None);
+        virtio.dma(&mut bus);
+        assert_eq!(used_buffers(&mut bus, 4), vec![b"to guest".to_vec()]);
+        assert!(!virtio.dma_pending());
+
+        // An emergency write, as a 32-bit store
+        virtio.store(VIRTIO_MMIO_CONFIG + CONSOLE_CONFIG_EMERG_WR, 32, b'!' as u64).unwrap();
+        assert_eq!(console.output(), b"!");
+    }
+}
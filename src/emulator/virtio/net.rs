@@ -0,0 +1,199 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use super::{VirtioDevice, Virtqueue};
+use super::super::bus;
+use super::super::errors;
+use super::super::reader;
+use self::backends::NetBackend;
+
+pub mod backends;
+
+// virtio-net, an Ethernet card with a receive and a transmit queue
+pub const VIRTIO_NET_DEVICE_ID: u32 = 1;
+pub const VIRTIO_NET_RX_QUEUE: usize = 0;
+pub const VIRTIO_NET_TX_QUEUE: usize = 1;
+
+// Feature bits
+pub const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+pub const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+pub const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+// The header in front of every frame: flags, gso_type, hdr_len, gso_size, csum_start,
+// csum_offset and num_buffers. Without offloads only num_buffers means anything
+pub const VIRTIO_NET_HDR_SIZE: usize = 12;
+
+// Frames waiting for receive buffers beyond this many are dropped, like on a real card
+pub const RX_QUEUE_LIMIT: usize = 256;
+
+// The address QEMU gives its first card
+pub const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+pub struct Net {
+    backend: Box<dyn NetBackend>,
+    mac: [u8; 6],
+    // Frames from the host, filled by the receiver thread
+    rx_frames: Arc<Mutex<VecDeque<Vec<u8>>>>,
+}
+
+impl Net {
+    // Frames are received from the backend once the card is attached to a bus
+    pub fn new(backend: Box<dyn NetBackend>, mac: [u8; 6]) -> Self {
+        return Net { backend: backend, mac: mac, rx_frames: Arc::new(Mutex::new(VecDeque::new())) };
+    }
+
+    fn spawn_receiver_thread(rx_frames: &Arc<Mutex<VecDeque<Vec<u8>>>>, mut receiver: Box<dyn backends::FrameReceiver>, doorbell: bus::Doorbell) {
+        let rx_frames = Arc::clone(rx_frames);
+        reader::spawn_reader(move || receiver.receive(), move |frame| {
+            let mut frames = rx_frames.lock().unwrap();
+            if (frames.len() < RX_QUEUE_LIMIT) {
+                frames.push_back(frame);
+            }
+            doorbell.ring();
+        });
+    }
+
+    // Hands received frames to the driver for as long as it has buffers for them
+    fn receive(&mut self, virtqueue: &mut Virtqueue, bus: &mut bus::Bus) -> Result<bool, errors::Exception> {
+        let mut used = false;
+        loop {
+            let Some(frame) = self.rx_frames.lock().unwrap().pop_front() else {
+                break;
+            };
+            let Some(chain) = virtqueue.pop(bus)? else {
+                self.rx_frames.lock().unwrap().push_front(frame);
+                break;
+            };
+            // Each frame fits in one buffer, so num_buffers is 1. Frames too big for the buffer
+            // are cut short
+            let mut packet = vec![0; VIRTIO_NET_HDR_SIZE];
+            packet[10] = 1;
+            packet.extend_from_slice(&frame);
+            packet.truncate(chain.writable_len() as usize);
+            chain.write_at(bus, 0, &packet)?;
+            virtqueue.push(bus, chain.head, packet.len() as u32)?;
+            used = true;
+        }
+        return Ok(used);
+    }
+
+    fn transmit(&mut self, virtqueue: &mut Virtqueue, bus: &mut bus::Bus) -> Result<bool, errors::Exception> {
+        let mut used = false;
+        while let Some(chain) = virtqueue.pop(bus)? {
+            let packet = chain.read_all(bus)?;
+            if let Some(frame) = packet.get(VIRTIO_NET_HDR_SIZE..) {
+                // A host that isn't listening is like an unplugged cable
+                let _ = self.backend.send(frame);
+            }
+            virtqueue.push(bus, chain.head, 0)?;
+            used = true;
+        }
+        return Ok(used);
+    }
+}
+
+impl VirtioDevice for Net {
+    fn device_id(&self) -> u32 {
+        return VIRTIO_NET_DEVICE_ID;
+    }
+
+    fn features(&self) -> u64 {
+        return VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS;
+    }
+
+    fn num_queues(&self) -> usize {
+        return 2;
+    }
+
+    // The MAC address followed by the 16-bit link status
+    fn read_config(&self, offset: u64) -> u8 {
+        return match(offset) {
+            0..=5 => self.mac[offset as usize],
+            6 => VIRTIO_NET_S_LINK_UP as u8,
+            _ => 0,
+        };
+    }
+
+    fn process_queue(&mut self, queue: usize, virtqueue: &mut Virtqueue, bus: &mut bus::Bus) -> Result<bool, errors::Exception> {
+        return match(queue) {
+            VIRTIO_NET_RX_QUEUE => self.receive(virtqueue, bus),
+            VIRTIO_NET_TX_QUEUE => self.transmit(virtqueue, bus),
+            _ => Ok(false),
+        };
+    }
+
+    fn queues_pending(&self) -> u64 {
+        if (self.rx_frames.lock().unwrap().is_empty()) {
+            return 0;
+        }
+        return 1 << VIRTIO_NET_RX_QUEUE;
+    }
+
+    fn connect(&mut self, doorbell: &bus::Doorbell) {
+        if let Some(receiver) = self.backend.receiver() {
+            Self::spawn_receiver_thread(&self.rx_frames, receiver, doorbell.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::*;
+    use super::super::tests::*;
+    use super::super::super::bus::Device;
+    use std::thread;
+    use super::backends::UnixDatagramBackend;
+
+    fn net(backend: UnixDatagramBackend) -> (bus::Bus, VirtioMmio<Net>) {
+        return setup(Net::new(Box::new(backend), DEFAULT_MAC));
+    }
+
+    #[test]
+    fn test_config() {
+        let (ours, _theirs) = UnixDatagramBackend::pair().unwrap();
+        let (_, mut virtio) = net(ours);
+        assert_eq!(virtio.load(VIRTIO_MMIO_DEVICE_ID, 32), Ok(VIRTIO_NET_DEVICE_ID as u64));
+        assert_eq!(virtio.load(VIRTIO_MMIO_DEVICE_FEATURES, 32), Ok(VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS));
+        assert_eq!(virtio.load(VIRTIO_MMIO_CONFIG, 32), Ok(0x1200_5452));
+        assert_eq!(virtio.load(VIRTIO_MMIO_CONFIG + 4, 16), Ok(0x5634));
+        assert_eq!(virtio.load(VIRTIO_MMIO_CONFIG + 6, 16), Ok(VIRTIO_NET_S_LINK_UP as u64));
+    }
+
+    #[test]
+    fn test_transmit_and_receive() {
+        let (ours, theirs) = UnixDatagramBackend::pair().unwrap();
+        let (mut bus, mut virtio) = net(ours);
+
+        // A frame from the guest reaches the other end without its header
+        let mut packet = vec![0; VIRTIO_NET_HDR_SIZE];
+        packet.extend_from_slice(b"outgoing frame");
+        bus.write_bytes(BUFFERS, &packet).unwrap();
+        offer(&mut bus, VIRTIO_NET_TX_QUEUE as u64, &[(BUFFERS, packet.len() as u32, false)]);
+        virtio.store(VIRTIO_MMIO_QUEUE_NOTIFY, 32, VIRTIO_NET_TX_QUEUE as u64).unwrap();
+        virtio.dma(&mut bus);
+        let mut frame = [0; 64];
+        let len = theirs.socket().recv(&mut frame).unwrap();
+        assert_eq!(&frame[..len], b"outgoing frame");
+        assert_eq!(used_chains(&mut bus, VIRTIO_NET_TX_QUEUE as u64).len(), 1);
+        assert!(virtio.interrupt_pending());
+        virtio.store(VIRTIO_MMIO_INTERRUPT_ACK, 32, VIRTIO_INT_USED_BUFFER as u64).unwrap();
+
+        // A frame from the other end waits for a receive buffer
+        theirs.socket().send(b"incoming frame").unwrap();
+        while (!virtio.dma_pending()) {
+            thread::yield_now();
+        }
+        virtio.dma(&mut bus);
+        assert!(!virtio.interrupt_pending());
+        offer(&mut bus, VIRTIO_NET_RX_QUEUE as u64, &[(BUFFERS + 0x1000, 1526, true)]);
+        virtio.dma(&mut bus);
+        assert!(virtio.interrupt_pending());
+        assert!(!virtio.dma_pending());
+
+        let packet = used_buffers(&mut bus, VIRTIO_NET_RX_QUEUE as u64).remove(0);
+        assert_eq!(packet.len(), VIRTIO_NET_HDR_SIZE + 14);
+        assert_eq!(packet[10], 1);
+        assert_eq!(&packet[VIRTIO_NET_HDR_SIZE..], b"incoming frame");
+    }
+}
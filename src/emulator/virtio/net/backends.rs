@@ -0,0 +1,271 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+#[cfg(unix)]
+use std::os::unix::{fs::FileTypeExt, net::UnixDatagram};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The largest frame we expect from the host, a little over the 64KiB IP limit
+pub const MAX_FRAME_SIZE: usize = 65550;
+
+// Where the card's Ethernet frames go. Received frames are read by a FrameReceiver on a
+// background thread, so it may block, while transmitted frames are sent from the emulator's thread
+pub trait NetBackend: Send {
+    // Hands over the source of received frames. Called once, None for transmit-only backends
+    fn receiver(&mut self) -> Option<Box<dyn FrameReceiver>>;
+    fn send(&mut self, frame: &[u8]) -> io::Result<()>;
+}
+
+pub trait FrameReceiver: Send {
+    // Blocks for the next frame, None once the host side has gone away
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>>;
+}
+
+// pcap file format, with microsecond timestamps
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const PCAP_LINKTYPE_ETHERNET: u32 = 1;
+
+// Records every transmitted frame in a pcap file for Wireshark or tcpdump, with nothing received
+pub struct PcapBackend {
+    file: File,
+}
+
+impl PcapBackend {
+    pub fn create(path: &str) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let mut header = Vec::new();
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&PCAP_VERSION_MAJOR.to_le_bytes());
+        header.extend_from_slice(&PCAP_VERSION_MINOR.to_le_bytes());
+        // Time zone and timestamp accuracy, both always 0
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&(MAX_FRAME_SIZE as u32).to_le_bytes());
+        header.extend_from_slice(&PCAP_LINKTYPE_ETHERNET.to_le_bytes());
+        file.write_all(&header)?;
+        return Ok(PcapBackend { file: file });
+    }
+}
+
+impl NetBackend for PcapBackend {
+    fn receiver(&mut self) -> Option<Box<dyn FrameReceiver>> {
+        return None;
+    }
+
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut record = Vec::new();
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(frame);
+        // One write per frame, so the capture is complete even if the guest never exits cleanly
+        return self.file.write_all(&record);
+    }
+}
+
+// A Unix datagram socket, one frame per datagram. Two emulators each bind their own path and send
+// to the other's, which makes a cable between them
+#[cfg(unix)]
+pub struct UnixDatagramBackend {
+    socket: UnixDatagram,
+    peer: Option<PathBuf>,
+}
+
+#[cfg(unix)]
+impl UnixDatagramBackend {
+    // Binds local, replacing a socket left over from an earlier run, and sends to peer
+    pub fn bind<P: AsRef<Path>>(local: P, peer: P) -> io::Result<Self> {
+        if let Ok(metadata) = std::fs::symlink_metadata(&local) {
+            if (metadata.file_type().is_socket()) {
+                std::fs::remove_file(&local)?;
+            }
+        }
+        return Ok(UnixDatagramBackend {
+            socket: UnixDatagram::bind(local)?,
+            peer: Some(peer.as_ref().to_path_buf()),
+        });
+    }
+
+    // Two backends already connected to each other
+    pub fn pair() -> io::Result<(Self, Self)> {
+        let (a, b) = UnixDatagram::pair()?;
+        return Ok((UnixDatagramBackend { socket: a, peer: None }, UnixDatagramBackend { socket: b, peer: None }));
+    }
+
+    pub fn socket(&self) -> &UnixDatagram {
+        return &self.socket;
+    }
+}
+
+#[cfg(unix)]
+struct DatagramReceiver {
+    socket: UnixDatagram,
+    // Made by pair(), so the end of the input is the other end closing
+    connected: bool,
+}
+
+#[cfg(unix)]
+impl FrameReceiver for DatagramReceiver {
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut frame = vec![0; MAX_FRAME_SIZE];
+        loop {
+            let len = self.socket.recv(&mut frame)?;
+            if (len > 0) {
+                frame.truncate(len);
+                return Ok(Some(frame));
+            }
+            // An empty datagram is no frame. On a pair only the other end can send one, so it
+            // ends the input, while a bound socket keeps working whatever anyone sends it
+            if (self.connected) {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl NetBackend for UnixDatagramBackend {
+    fn receiver(&mut self) -> Option<Box<dyn FrameReceiver>> {
+        let socket = self.socket.try_clone().ok()?;
+        return Some(Box::new(DatagramReceiver { socket: socket, connected: self.peer.is_none() }));
+    }
+
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        match(&self.peer) {
+            Some(peer) => self.socket.send_to(frame, peer)?,
+            None => self.socket.send(frame)?,
+        };
+        return Ok(());
+    }
+}
+
+// A TAP interface on the host, which needs CAP_NET_ADMIN or an interface made beforehand with
+// `ip tuntap add <name> mode tap user <user>`
+#[cfg(target_os = "linux")]
+pub struct TapBackend {
+    file: File,
+}
+
+#[cfg(target_os = "linux")]
+impl TapBackend {
+    pub fn open(name: &str) -> io::Result<Self> {
+        if (name.len() >= libc::IFNAMSIZ) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "interface name too long"));
+        }
+        let file = std::fs::OpenOptions::new().read(true).write(true).open("/dev/net/tun")?;
+        unsafe {
+            let mut ifreq: libc::ifreq = std::mem::zeroed();
+            for (i, byte) in name.bytes().enumerate() {
+                ifreq.ifr_name[i] = byte as libc::c_char;
+            }
+            // Ethernet frames, without the extra packet information header
+            ifreq.ifr_ifru.ifru_flags = (libc::IFF_TAP | libc::IFF_NO_PI) as libc::c_short;
+            if (libc::ioctl(std::os::unix::io::AsRawFd::as_raw_fd(&file), libc::TUNSETIFF, &ifreq) < 0) {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        return Ok(TapBackend { file: file });
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct TapReceiver {
+    file: File,
+}
+
+#[cfg(target_os = "linux")]
+impl FrameReceiver for TapReceiver {
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        // Every read returns exactly one frame. The interface stays up for as long as the file is
+        // open, so an empty read is no frame rather than the end
+        let mut frame = vec![0; MAX_FRAME_SIZE];
+        loop {
+            let len = self.file.read(&mut frame)?;
+            if (len > 0) {
+                frame.truncate(len);
+                return Ok(Some(frame));
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl NetBackend for TapBackend {
+    fn receiver(&mut self) -> Option<Box<dyn FrameReceiver>> {
+        let file = self.file.try_clone().ok()?;
+        return Some(Box::new(TapReceiver { file: file }));
+    }
+
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        return self.file.write_all(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pcap_backend() {
+        let path = std::env::temp_dir().join(format!("rv-emulator-{}.pcap", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut backend = PcapBackend::create(path).unwrap();
+        assert!(backend.receiver().is_none());
+        backend.send(b"frame").unwrap();
+
+        let capture = std::fs::read(path).unwrap();
+        assert_eq!(capture.len(), 24 + 16 + 5);
+        assert_eq!(capture[..4], PCAP_MAGIC.to_le_bytes());
+        assert_eq!(capture[20..24], PCAP_LINKTYPE_ETHERNET.to_le_bytes());
+        // Captured and original lengths, then the frame
+        assert_eq!(capture[32..40], [5, 0, 0, 0, 5, 0, 0, 0]);
+        assert_eq!(&capture[40..], b"frame");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_datagram_backend() {
+        let directory = std::env::temp_dir();
+        let a_path = directory.join(format!("rv-emulator-{}-a.sock", std::process::id()));
+        let b_path = directory.join(format!("rv-emulator-{}-b.sock", std::process::id()));
+        let mut a = UnixDatagramBackend::bind(&a_path, &b_path).unwrap();
+        let mut b = UnixDatagramBackend::bind(&b_path, &a_path).unwrap();
+
+        // Frame boundaries are kept
+        a.send(b"one").unwrap();
+        a.send(b"two").unwrap();
+        let mut receiver = b.receiver().unwrap();
+        assert_eq!(receiver.receive().unwrap(), Some(b"one".to_vec()));
+        assert_eq!(receiver.receive().unwrap(), Some(b"two".to_vec()));
+        // Anyone can send an empty datagram to a bound socket, which doesn't end the input
+        a.send(b"").unwrap();
+        a.send(b"three").unwrap();
+        assert_eq!(receiver.receive().unwrap(), Some(b"three".to_vec()));
+        b.send(b"back").unwrap();
+        assert_eq!(a.receiver().unwrap().receive().unwrap(), Some(b"back".to_vec()));
+
+        // A stale socket from an earlier run is replaced
+        drop(a);
+        assert!(UnixDatagramBackend::bind(&a_path, &b_path).is_ok());
+        std::fs::remove_file(&a_path).unwrap();
+        std::fs::remove_file(&b_path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_datagram_pair_ends() {
+        let (mut a, mut b) = UnixDatagramBackend::pair().unwrap();
+        let mut receiver = b.receiver().unwrap();
+        // Only the other end can send on a pair, and an empty datagram is its end of the input
+        a.send(b"one").unwrap();
+        a.send(b"").unwrap();
+        assert_eq!(receiver.receive().unwrap(), Some(b"one".to_vec()));
+        assert_eq!(receiver.receive().unwrap(), None);
+    }
+}
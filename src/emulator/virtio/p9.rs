@@ -0,0 +1,852 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use super::{VirtioDevice, Virtqueue};
+use super::super::bus;
+use super::super::errors;
+
+// virtio-9p, which shares a host directory with the guest over the 9P2000.L protocol. The guest
+// mounts it with `mount -t 9p -o trans=virtio,version=9p2000.L <tag> <dir>`. Symlinks are never
+// followed on the host, so wherever the guest points one it can't reach outside the directory
+pub const VIRTIO_9P_DEVICE_ID: u32 = 9;
+pub const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+pub const P9_VERSION: &str = "9P2000.L";
+// The largest message we agree to, whatever the client asks for
+pub const P9_MAX_MSIZE: u32 = 512 * 1024;
+// size, type and tag
+const P9_HEADER_SIZE: usize = 7;
+// The header and count of Rread and Rreaddir
+const P9_IO_HEADER_SIZE: u32 = 11;
+
+// Message types. Each response is the request's type plus one
+pub const P9_RLERROR: u8 = 7;
+pub const P9_TSTATFS: u8 = 8;
+pub const P9_TLOPEN: u8 = 12;
+pub const P9_TLCREATE: u8 = 14;
+pub const P9_TSYMLINK: u8 = 16;
+pub const P9_TMKNOD: u8 = 18;
+pub const P9_TRENAME: u8 = 20;
+pub const P9_TREADLINK: u8 = 22;
+pub const P9_TGETATTR: u8 = 24;
+pub const P9_TSETATTR: u8 = 26;
+pub const P9_TXATTRWALK: u8 = 30;
+pub const P9_TXATTRCREATE: u8 = 32;
+pub const P9_TREADDIR: u8 = 40;
+pub const P9_TFSYNC: u8 = 50;
+pub const P9_TLOCK: u8 = 52;
+pub const P9_TGETLOCK: u8 = 54;
+pub const P9_TLINK: u8 = 70;
+pub const P9_TMKDIR: u8 = 72;
+pub const P9_TRENAMEAT: u8 = 74;
+pub const P9_TUNLINKAT: u8 = 76;
+pub const P9_TVERSION: u8 = 100;
+pub const P9_TAUTH: u8 = 102;
+pub const P9_TATTACH: u8 = 104;
+pub const P9_TFLUSH: u8 = 108;
+pub const P9_TWALK: u8 = 110;
+pub const P9_TREAD: u8 = 116;
+pub const P9_TWRITE: u8 = 118;
+pub const P9_TCLUNK: u8 = 120;
+pub const P9_TREMOVE: u8 = 122;
+
+// Qid types
+pub const P9_QTDIR: u8 = 0x80;
+pub const P9_QTSYMLINK: u8 = 0x02;
+pub const P9_QTFILE: u8 = 0x00;
+
+// Tgetattr asks for, and Rgetattr returns, everything up to the block count
+pub const P9_GETATTR_BASIC: u64 = 0x7ff;
+
+// Tsetattr valid bits
+pub const P9_SETATTR_MODE: u32 = 0x01;
+pub const P9_SETATTR_SIZE: u32 = 0x08;
+pub const P9_SETATTR_ATIME: u32 = 0x10;
+pub const P9_SETATTR_MTIME: u32 = 0x20;
+pub const P9_SETATTR_ATIME_SET: u32 = 0x80;
+pub const P9_SETATTR_MTIME_SET: u32 = 0x100;
+
+// Tlopen and Tlcreate flags, which are Linux's open flags
+pub const P9_DOTL_WRONLY: u32 = 0o1;
+pub const P9_DOTL_RDWR: u32 = 0o2;
+pub const P9_DOTL_CREATE: u32 = 0o100;
+pub const P9_DOTL_EXCL: u32 = 0o200;
+pub const P9_DOTL_TRUNC: u32 = 0o1000;
+pub const P9_DOTL_APPEND: u32 = 0o2000;
+
+pub const P9_AT_REMOVEDIR: u32 = 0x200;
+pub const P9_LOCK_SUCCESS: u8 = 0;
+pub const P9_LOCK_TYPE_UNLCK: u8 = 2;
+// What statfs reports as the file system type
+pub const V9FS_MAGIC: u32 = 0x0102_1997;
+
+// The errno in an Rlerror
+type P9Result<T> = Result<T, u32>;
+
+fn errno(e: io::Error) -> u32 {
+    return e.raw_os_error().unwrap_or(libc::EIO) as u32;
+}
+
+// Takes the fields of a request apart, little-endian as all of 9P
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> P9Result<&'a [u8]> {
+        if (len > self.data.len()) {
+            return Err(libc::EINVAL as u32);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        return Ok(bytes);
+    }
+
+    fn u8(&mut self) -> P9Result<u8> {
+        return Ok(self.bytes(1)?[0]);
+    }
+
+    fn u16(&mut self) -> P9Result<u16> {
+        return Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()));
+    }
+
+    fn u32(&mut self) -> P9Result<u32> {
+        return Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()));
+    }
+
+    fn u64(&mut self) -> P9Result<u64> {
+        return Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()));
+    }
+
+    // A 16-bit length followed by UTF-8
+    fn string(&mut self) -> P9Result<String> {
+        let len = self.u16()? as usize;
+        return String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| libc::EINVAL as u32);
+    }
+}
+
+// Builds the body of a response
+#[derive(Default)]
+struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.data.push(value);
+        return self;
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        return self;
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        return self;
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        return self;
+    }
+
+    fn string(&mut self, value: &str) -> &mut Self {
+        self.u16(value.len() as u16);
+        self.data.extend_from_slice(value.as_bytes());
+        return self;
+    }
+
+    fn qid(&mut self, metadata: &fs::Metadata) -> &mut Self {
+        let kind = if (metadata.is_dir()) {
+            P9_QTDIR
+        } else if (metadata.file_type().is_symlink()) {
+            P9_QTSYMLINK
+        } else {
+            P9_QTFILE
+        };
+        return self.u8(kind).u32(0).u64(metadata.ino());
+    }
+}
+
+// A client's handle on a file: its path below the shared directory, and the file once opened
+struct Fid {
+    path: Vec<String>,
+    file: Option<File>,
+}
+
+pub struct P9 {
+    root: PathBuf,
+    tag: String,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl P9 {
+    pub fn new(root: &str, tag: &str) -> io::Result<Self> {
+        if (!fs::metadata(root)?.is_dir()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "can only share a directory"));
+        }
+        return Ok(P9 { root: PathBuf::from(root), tag: tag.to_string(), msize: P9_MAX_MSIZE, fids: HashMap::new() });
+    }
+
+    // Every component but the last has to be a real directory. A symlink there, which the guest
+    // may have made itself, would otherwise be followed by the host
+    fn host_path(&self, path: &[String]) -> P9Result<PathBuf> {
+        let mut host = self.root.clone();
+        for (i, name) in path.iter().enumerate() {
+            host.push(name);
+            if (i + 1 < path.len() && Self::metadata(&host)?.file_type().is_symlink()) {
+                return Err(libc::ELOOP as u32);
+            }
+        }
+        return Ok(host);
+    }
+
+    fn fid(&self, fid: u32) -> P9Result<&Fid> {
+        return self.fids.get(&fid).ok_or(libc::EBADF as u32);
+    }
+
+    fn fid_path(&self, fid: u32) -> P9Result<PathBuf> {
+        return self.host_path(&self.fid(fid)?.path);
+    }
+
+    // The path of a new name in a directory. Names are single components
+    fn child(&self, dfid: u32, name: &str) -> P9Result<(Vec<String>, PathBuf)> {
+        if (name.is_empty() || name == "." || name == ".." || name.contains('/')) {
+            return Err(libc::EINVAL as u32);
+        }
+        let mut path = self.fid(dfid)?.path.clone();
+        path.push(name.to_string());
+        let host = self.host_path(&path)?;
+        return Ok((path, host));
+    }
+
+    fn metadata(path: &Path) -> P9Result<fs::Metadata> {
+        return fs::symlink_metadata(path).map_err(errno);
+    }
+
+    // For the calls that would follow a symlink at the end of the path
+    fn metadata_no_symlink(path: &Path) -> P9Result<fs::Metadata> {
+        let metadata = Self::metadata(path)?;
+        if (metadata.file_type().is_symlink()) {
+            return Err(libc::ELOOP as u32);
+        }
+        return Ok(metadata);
+    }
+
+    // Handles one request, returning the response
+    pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut reader = Reader { data: request };
+        let header = (reader.u32(), reader.u8(), reader.u16());
+        let (Ok(_), Ok(kind), Ok(tag)) = header else {
+            return Vec::new();
+        };
+
+        let mut body = Writer::default();
+        let kind = match(self.dispatch(kind, &mut reader, &mut body)) {
+            Ok(()) => kind + 1,
+            Err(e) => {
+                body = Writer::default();
+                body.u32(e);
+                P9_RLERROR
+            },
+        };
+        let mut response = Writer::default();
+        response.u32((P9_HEADER_SIZE + body.data.len()) as u32).u8(kind).u16(tag);
+        response.data.extend_from_slice(&body.data);
+        return response.data;
+    }
+
+    fn dispatch(&mut self, kind: u8, request: &mut Reader, response: &mut Writer) -> P9Result<()> {
+        match(kind) {
+            P9_TVERSION => {
+                let msize = request.u32()?;
+                let version = request.string()?;
+                // A new session: every fid is forgotten
+                self.fids.clear();
+                self.msize = msize.clamp(4096, P9_MAX_MSIZE);
+                let version = if (version.starts_with(P9_VERSION)) { P9_VERSION } else { "unknown" };
+                response.u32(self.msize).string(version);
+            },
+            P9_TATTACH => {
+                let fid = request.u32()?;
+                let metadata = Self::metadata(&self.root)?;
+                self.fids.insert(fid, Fid { path: Vec::new(), file: None });
+                response.qid(&metadata);
+            },
+            P9_TWALK => {
+                let fid = request.u32()?;
+                let newfid = request.u32()?;
+                let count = request.u16()?;
+                let mut path = self.fid(fid)?.path.clone();
+                let mut qids = Writer::default();
+                let mut walked = 0;
+                for i in 0..count {
+                    let name = request.string()?;
+                    if (name.contains('/')) {
+                        return Err(libc::EINVAL as u32);
+                    }
+                    let mut next = path.clone();
+                    match(name.as_str()) {
+                        // Going up from the shared directory stays there
+                        ".." => { next.pop(); },
+                        "." => {},
+                        _ => next.push(name),
+                    }
+                    match(self.host_path(&next).and_then(|host| Self::metadata(&host))) {
+                        Ok(metadata) => {
+                            qids.qid(&metadata);
+                            path = next;
+                            walked += 1;
+                        },
+                        // Only a failure on the first name is an error
+                        Err(e) if (i == 0) => return Err(e),
+                        Err(_) => break,
+                    }
+                }
+                if (walked == count) {
+                    self.fids.insert(newfid, Fid { path: path, file: None });
+                }
+                response.u16(walked);
+                response.data.extend_from_slice(&qids.data);
+            },
+            P9_TGETATTR => {
+                let fid = request.u32()?;
+                let metadata = Self::metadata(&self.fid_path(fid)?)?;
+                response.u64(P9_GETATTR_BASIC).qid(&metadata);
+                response.u32(metadata.mode()).u32(metadata.uid()).u32(metadata.gid());
+                response.u64(metadata.nlink()).u64(metadata.rdev()).u64(metadata.size());
+                response.u64(metadata.blksize()).u64(metadata.blocks());
+                response.u64(metadata.atime() as u64).u64(metadata.atime_nsec() as u64);
+                response.u64(metadata.mtime() as u64).u64(metadata.mtime_nsec() as u64);
+                response.u64(metadata.ctime() as u64).u64(metadata.ctime_nsec() as u64);
+                // Birth time, generation and data version, which we don't have
+                response.u64(0).u64(0).u64(0).u64(0);
+            },
+            P9_TSETATTR => {
+                let fid = request.u32()?;
+                let valid = request.u32()?;
+                let mode = request.u32()?;
+                let _uid = request.u32()?;
+                let _gid = request.u32()?;
+                let size = request.u64()?;
+                let atime = (request.u64()?, request.u64()?);
+                let mtime = (request.u64()?, request.u64()?);
+                let path = self.fid_path(fid)?;
+                Self::metadata_no_symlink(&path)?;
+
+                if (valid & P9_SETATTR_MODE != 0) {
+                    fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777)).map_err(errno)?;
+                }
+                if (valid & P9_SETATTR_SIZE != 0) {
+                    OpenOptions::new().write(true).open(&path).and_then(|file| file.set_len(size)).map_err(errno)?;
+                }
+                // Ownership can't be changed without privileges, so uid and gid are ignored
+                if (valid & (P9_SETATTR_ATIME | P9_SETATTR_MTIME) != 0) {
+                    let now = std::time::SystemTime::now();
+                    let mut times = fs::FileTimes::new();
+                    if (valid & P9_SETATTR_ATIME != 0) {
+                        times = times.set_accessed(if (valid & P9_SETATTR_ATIME_SET != 0) { Self::time(atime)? } else { now });
+                    }
+                    if (valid & P9_SETATTR_MTIME != 0) {
+                        times = times.set_modified(if (valid & P9_SETATTR_MTIME_SET != 0) { Self::time(mtime)? } else { now });
+                    }
+                    File::open(&path).and_then(|file| file.set_times(times)).map_err(errno)?;
+                }
+            },
+            P9_TLOPEN => {
+                let fid = request.u32()?;
+                let flags = request.u32()?;
+                let path = self.fid_path(fid)?;
+                let metadata = Self::metadata(&path)?;
+                // Directories are only read through Treaddir
+                let file = if (metadata.is_dir()) { None } else { Some(Self::open(&path, flags, None)?) };
+                self.fids.get_mut(&fid).unwrap().file = file;
+                response.qid(&metadata).u32(0);
+            },
+            P9_TLCREATE => {
+                let fid = request.u32()?;
+                let name = request.string()?;
+                let flags = request.u32()?;
+                let mode = request.u32()?;
+                let _gid = request.u32()?;
+                let (path, host) = self.child(fid, &name)?;
+                let file = Self::open(&host, flags | P9_DOTL_CREATE, Some(mode))?;
+                let metadata = file.metadata().map_err(errno)?;
+                // The fid now stands for the new file
+                self.fids.insert(fid, Fid { path: path, file: Some(file) });
+                response.qid(&metadata).u32(0);
+            },
+            P9_TREAD => {
+                let fid = request.u32()?;
+                let offset = request.u64()?;
+                let count = request.u32()?.min(self.msize - P9_IO_HEADER_SIZE);
+                let file = self.fid(fid)?.file.as_ref().ok_or(libc::EBADF as u32)?;
+                let mut data = vec![0; count as usize];
+                let mut len = 0;
+                while (len < data.len()) {
+                    match(file.read_at(&mut data[len..], offset + len as u64)) {
+                        Ok(0) => break,
+                        Ok(read) => len += read,
+                        Err(e) if (e.kind() == io::ErrorKind::Interrupted) => {},
+                        Err(e) => return Err(errno(e)),
+                    }
+                }
+                response.u32(len as u32);
+                response.data.extend_from_slice(&data[..len]);
+            },
+            P9_TWRITE => {
+                let fid = request.u32()?;
+                let offset = request.u64()?;
+                let count = request.u32()?;
+                let data = request.bytes(count as usize)?;
+                let file = self.fid(fid)?.file.as_ref().ok_or(libc::EBADF as u32)?;
+                file.write_all_at(data, offset).map_err(errno)?;
+                response.u32(count);
+            },
+            P9_TCLUNK => {
+                let fid = request.u32()?;
+                self.fids.remove(&fid).ok_or(libc::EBADF as u32)?;
+            },
+            P9_TREMOVE => {
+                let fid = request.u32()?;
+                let path = self.fid_path(fid)?;
+                // The fid is clunked even if the remove fails
+                self.fids.remove(&fid);
+                if (Self::metadata(&path)?.is_dir()) {
+                    fs::remove_dir(&path).map_err(errno)?;
+                } else {
+                    fs::remove_file(&path).map_err(errno)?;
+                }
+            },
+            P9_TREADDIR => {
+                let fid = request.u32()?;
+                let offset = request.u64()?;
+                let count = request.u32()?.min(self.msize - P9_IO_HEADER_SIZE) as usize;
+                let entries = self.read_dir(fid)?;
+                let mut data = Writer::default();
+                for (i, (name, metadata)) in entries.iter().enumerate().skip(offset as usize) {
+                    // qid, offset, type and name
+                    if (data.data.len() + 13 + 8 + 1 + 2 + name.len() > count) {
+                        break;
+                    }
+                    data.qid(metadata).u64(i as u64 + 1).u8(Self::dirent_type(metadata)).string(name);
+                }
+                response.u32(data.data.len() as u32);
+                response.data.extend_from_slice(&data.data);
+            },
+            P9_TSTATFS => {
+                let fid = request.u32()?;
+                let path = self.fid_path(fid)?;
+                Self::metadata_no_symlink(&path)?;
+                let path = std::ffi::CString::new(path.as_os_str().as_encoded_bytes()).map_err(|_| libc::EINVAL as u32)?;
+                let stat = unsafe {
+                    let mut stat: libc::statvfs = std::mem::zeroed();
+                    if (libc::statvfs(path.as_ptr(), &mut stat) != 0) {
+                        return Err(errno(io::Error::last_os_error()));
+                    }
+                    stat
+                };
+                response.u32(V9FS_MAGIC).u32(stat.f_bsize as u32);
+                response.u64(stat.f_blocks as u64).u64(stat.f_bfree as u64).u64(stat.f_bavail as u64);
+                response.u64(stat.f_files as u64).u64(stat.f_ffree as u64).u64(stat.f_fsid as u64);
+                response.u32(stat.f_namemax as u32);
+            },
+            P9_TMKDIR => {
+                let dfid = request.u32()?;
+                let name = request.string()?;
+                let mode = request.u32()?;
+                let _gid = request.u32()?;
+                let (_, host) = self.child(dfid, &name)?;
+                fs::create_dir(&host).map_err(errno)?;
+                fs::set_permissions(&host, fs::Permissions::from_mode(mode & 0o7777)).map_err(errno)?;
+                response.qid(&Self::metadata(&host)?);
+            },
+            P9_TSYMLINK => {
+                let dfid = request.u32()?;
+                let name = request.string()?;
+                let target = request.string()?;
+                let _gid = request.u32()?;
+                let (_, host) = self.child(dfid, &name)?;
+                std::os::unix::fs::symlink(&target, &host).map_err(errno)?;
+                response.qid(&Self::metadata(&host)?);
+            },
+            P9_TREADLINK => {
+                let fid = request.u32()?;
+                let target = fs::read_link(self.fid_path(fid)?).map_err(errno)?;
+                response.string(&target.to_string_lossy());
+            },
+            P9_TLINK => {
+                let dfid = request.u32()?;
+                let fid = request.u32()?;
+                let name = request.string()?;
+                let (_, host) = self.child(dfid, &name)?;
+                fs::hard_link(self.fid_path(fid)?, &host).map_err(errno)?;
+            },
+            P9_TRENAME => {
+                let fid = request.u32()?;
+                let dfid = request.u32()?;
+                let name = request.string()?;
+                let (path, host) = self.child(dfid, &name)?;
+                fs::rename(self.fid_path(fid)?, &host).map_err(errno)?;
+                self.fids.get_mut(&fid).unwrap().path = path;
+            },
+            P9_TRENAMEAT => {
+                let old_dfid = request.u32()?;
+                let old_name = request.string()?;
+                let new_dfid = request.u32()?;
+                let new_name = request.string()?;
+                let (_, old) = self.child(old_dfid, &old_name)?;
+                let (_, new) = self.child(new_dfid, &new_name)?;
+                fs::rename(&old, &new).map_err(errno)?;
+            },
+            P9_TUNLINKAT => {
+                let dfid = request.u32()?;
+                let name = request.string()?;
+                let flags = request.u32()?;
+                let (_, host) = self.child(dfid, &name)?;
+                if (flags & P9_AT_REMOVEDIR != 0) {
+                    fs::remove_dir(&host).map_err(errno)?;
+                } else {
+                    fs::remove_file(&host).map_err(errno)?;
+                }
+            },
+            P9_TFSYNC => {
+                let fid = request.u32()?;
+                if let Some(file) = &self.fid(fid)?.file {
+                    file.sync_all().map_err(errno)?;
+                }
+            },
+            // Requests are handled as they arrive, so there is never one to cancel
+            P9_TFLUSH => {},
+            // Only one client uses the files, so every lock is granted and none is ever held
+            P9_TLOCK => {
+                response.u8(P9_LOCK_SUCCESS);
+            },
+            P9_TGETLOCK => {
+                let _fid = request.u32()?;
+                let _kind = request.u8()?;
+                let start = request.u64()?;
+                let length = request.u64()?;
+                let proc_id = request.u32()?;
+                let client_id = request.string()?;
+                response.u8(P9_LOCK_TYPE_UNLCK).u64(start).u64(length).u32(proc_id).string(&client_id);
+            },
+            // Authentication, extended attributes and device nodes aren't supported
+            P9_TAUTH | P9_TXATTRWALK | P9_TXATTRCREATE | P9_TMKNOD => return Err(libc::EOPNOTSUPP as u32),
+            _ => return Err(libc::ENOSYS as u32),
+        }
+        return Ok(());
+    }
+
+    // A time from the guest, in seconds and nanoseconds since the epoch
+    fn time((sec, nsec): (u64, u64)) -> P9Result<std::time::SystemTime> {
+        if (nsec >= 1_000_000_000) {
+            return Err(libc::EINVAL as u32);
+        }
+        let since_epoch = std::time::Duration::new(sec, nsec as u32);
+        return std::time::UNIX_EPOCH.checked_add(since_epoch).ok_or(libc::EINVAL as u32);
+    }
+
+    fn open(path: &Path, flags: u32, mode: Option<u32>) -> P9Result<File> {
+        let mut options = OpenOptions::new();
+        match(flags & 3) {
+            P9_DOTL_WRONLY => options.write(true),
+            P9_DOTL_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        if (flags & P9_DOTL_APPEND != 0) {
+            options.append(true);
+        }
+        if (flags & P9_DOTL_TRUNC != 0) {
+            options.write(true).truncate(true);
+        }
+        if (flags & P9_DOTL_CREATE != 0) {
+            // Creating needs write access, even for a file that will only be read
+            options.write(true);
+            if (flags & P9_DOTL_EXCL != 0) {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+        if let Some(mode) = mode {
+            options.mode(mode & 0o7777);
+        }
+        // A symlink is opened by the guest walking to its target, never by the host
+        options.custom_flags(libc::O_NOFOLLOW);
+        return options.open(path).map_err(errno);
+    }
+
+    // A directory's entries in a stable order, starting with . and ..
+    fn read_dir(&self, fid: u32) -> P9Result<Vec<(String, fs::Metadata)>> {
+        let fid = self.fid(fid)?;
+        let path = self.host_path(&fid.path)?;
+        let mut parent = fid.path.clone();
+        parent.pop();
+
+        let mut entries = vec![
+            (".".to_string(), Self::metadata_no_symlink(&path)?),
+            ("..".to_string(), Self::metadata(&self.host_path(&parent)?)?),
+        ];
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&path).map_err(errno)? {
+            let entry = entry.map_err(errno)?;
+            if let (Ok(name), Ok(metadata)) = (entry.file_name().into_string(), entry.metadata()) {
+                names.push((name, metadata));
+            }
+        }
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        entries.extend(names);
+        return Ok(entries);
+    }
+
+    // The d_type of a directory entry
+    fn dirent_type(metadata: &fs::Metadata) -> u8 {
+        let kind = metadata.file_type();
+        return if (kind.is_dir()) {
+            libc::DT_DIR
+        } else if (kind.is_symlink()) {
+            libc::DT_LNK
+        } else if (kind.is_file()) {
+            libc::DT_REG
+        } else {
+            libc::DT_UNKNOWN
+        };
+    }
+}
+
+impl VirtioDevice for P9 {
+    fn device_id(&self) -> u32 {
+        return VIRTIO_9P_DEVICE_ID;
+    }
+
+    fn features(&self) -> u64 {
+        return VIRTIO_9P_MOUNT_TAG;
+    }
+
+    fn num_queues(&self) -> usize {
+        return 1;
+    }
+
+    // The length of the mount tag, then the tag
+    fn read_config(&self, offset: u64) -> u8 {
+        let tag = self.tag.as_bytes();
+        return match(offset) {
+            0 | 1 => ((tag.len() as u16) >> (8 * offset)) as u8,
+            _ => tag.get(offset as usize - 2).copied().unwrap_or(0),
+        };
+    }
+
+    fn process_queue(&mut self, _queue: usize, virtqueue: &mut Virtqueue, bus: &mut bus::Bus) -> Result<bool, errors::Exception> {
+        let mut used = false;
+        while let Some(chain) = virtqueue.pop(bus)? {
+            let request = chain.read_all(bus)?;
+            let response = self.handle(&request);
+            chain.write_at(bus, 0, &response)?;
+            virtqueue.push(bus, chain.head, response.len().min(chain.writable_len() as usize) as u32)?;
+            used = true;
+        }
+        return Ok(used);
+    }
+
+    fn reset(&mut self) {
+        self.fids.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::*;
+    use super::super::tests::*;
+    use super::super::super::bus::Device;
+
+    const REQUEST: u64 = BUFFERS;
+    const RESPONSE: u64 = BUFFERS + 0x4000;
+
+    fn share(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("rv-emulator-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("hello.txt"), b"hello from the host").unwrap();
+        return path.to_str().unwrap().to_string();
+    }
+
+    // Sends a request and returns the response's type and body
+    fn call(server: &mut P9, kind: u8, body: &mut Writer) -> (u8, Vec<u8>) {
+        let mut request = Writer::default();
+        request.u32((P9_HEADER_SIZE + body.data.len()) as u32).u8(kind).u16(1);
+        request.data.extend_from_slice(&body.data);
+        let response = server.handle(&request.data);
+        assert_eq!(u32::from_le_bytes(response[..4].try_into().unwrap()) as usize, response.len());
+        assert_eq!(response[5..7], [1, 0]);
+        return (response[4], response[7..].to_vec());
+    }
+
+    fn attach(server: &mut P9) {
+        let (kind, body) = call(server, P9_TVERSION, Writer::default().u32(8192).string("9P2000.L"));
+        assert_eq!(kind, P9_TVERSION + 1);
+        assert_eq!(body[..4], 8192u32.to_le_bytes());
+        let (kind, _) = call(server, P9_TATTACH, Writer::default().u32(0).u32(!0).string("user").string("").u32(0));
+        assert_eq!(kind, P9_TATTACH + 1);
+    }
+
+    #[test]
+    fn test_read_host_file() {
+        let root = share("read");
+        let mut server = P9::new(&root, "share").unwrap();
+        attach(&mut server);
+
+        let (kind, body) = call(&mut server, P9_TWALK, Writer::default().u32(0).u32(1).u16(1).string("hello.txt"));
+        assert_eq!(kind, P9_TWALK + 1);
+        assert_eq!(body[..2], [1, 0]);
+        assert_eq!(body[2], P9_QTFILE);
+        let (kind, _) = call(&mut server, P9_TLOPEN, Writer::default().u32(1).u32(0));
+        assert_eq!(kind, P9_TLOPEN + 1);
+        let (_, body) = call(&mut server, P9_TREAD, Writer::default().u32(1).u64(6).u32(100));
+        assert_eq!(body[..4], 13u32.to_le_bytes());
+        assert_eq!(&body[4..], b"from the host");
+
+        let (kind, body) = call(&mut server, P9_TGETATTR, Writer::default().u32(1).u64(P9_GETATTR_BASIC));
+        assert_eq!(kind, P9_TGETATTR + 1);
+        // valid, qid, mode, uid, gid, nlink, rdev, then the size
+        assert_eq!(body[8 + 13 + 12 + 16..][..8], 19u64.to_le_bytes());
+
+        // Missing files are errors, with the errno
+        let (kind, body) = call(&mut server, P9_TWALK, Writer::default().u32(0).u32(2).u16(1).string("missing"));
+        assert_eq!(kind, P9_RLERROR);
+        assert_eq!(body, (libc::ENOENT as u32).to_le_bytes());
+        // And the walk can't leave the shared directory
+        let (_, body) = call(&mut server, P9_TWALK, Writer::default().u32(0).u32(2).u16(2).string("..").string("hello.txt"));
+        assert_eq!(body[..2], [2, 0]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_create_write_and_list() {
+        let root = share("write");
+        let mut server = P9::new(&root, "share").unwrap();
+        attach(&mut server);
+
+        // A directory, and a file in it through a clone of its fid
+        let (kind, _) = call(&mut server, P9_TMKDIR, Writer::default().u32(0).string("out").u32(0o755).u32(0));
+        assert_eq!(kind, P9_TMKDIR + 1);
+        call(&mut server, P9_TWALK, Writer::default().u32(0).u32(1).u16(1).string("out"));
+        let flags = P9_DOTL_RDWR | P9_DOTL_CREATE;
+        let (kind, _) = call(&mut server, P9_TLCREATE, Writer::default().u32(1).string("result").u32(flags).u32(0o644).u32(0));
+        assert_eq!(kind, P9_TLCREATE + 1);
+        let (kind, body) = call(&mut server, P9_TWRITE, Writer::default().u32(1).u64(0).u32(4).u32(0x2121_6b6f));
+        assert_eq!(kind, P9_TWRITE + 1);
+        assert_eq!(body, 4u32.to_le_bytes());
+        call(&mut server, P9_TCLUNK, Writer::default().u32(1));
+        assert_eq!(fs::read(format!("{}/out/result", root)).unwrap(), b"ok!!");
+
+        // The shared directory lists ., .., hello.txt and out
+        call(&mut server, P9_TWALK, Writer::default().u32(0).u32(2).u16(0));
+        call(&mut server, P9_TLOPEN, Writer::default().u32(2).u32(0));
+        let (_, body) = call(&mut server, P9_TREADDIR, Writer::default().u32(2).u64(0).u32(4096));
+        let mut reader = Reader { data: &body[4..] };
+        let mut names = Vec::new();
+        while (!reader.data.is_empty()) {
+            reader.bytes(13 + 8 + 1).unwrap();
+            names.push(reader.string().unwrap());
+        }
+        assert_eq!(names, vec![".", "..", "hello.txt", "out"]);
+        // Carrying on from the third entry
+        let (_, body) = call(&mut server, P9_TREADDIR, Writer::default().u32(2).u64(3).u32(4096));
+        assert_eq!(body[4 + 13 + 8 + 1..], *Writer::default().string("out").data);
+
+        // Times are set only when asked for, and ones out of range are refused
+        call(&mut server, P9_TWALK, Writer::default().u32(0).u32(3).u16(1).string("hello.txt"));
+        let mut setattr = Writer::default();
+        let valid = P9_SETATTR_MTIME | P9_SETATTR_MTIME_SET;
+        setattr.u32(3).u32(valid).u32(0).u32(0).u32(0).u64(0).u64(u64::MAX).u64(u64::MAX).u64(1_000_000_000).u64(5);
+        let (kind, _) = call(&mut server, P9_TSETATTR, &mut setattr);
+        assert_eq!(kind, P9_TSETATTR + 1);
+        assert_eq!(fs::metadata(format!("{}/hello.txt", root)).unwrap().mtime(), 1_000_000_000);
+        let mut setattr = Writer::default();
+        setattr.u32(3).u32(valid).u32(0).u32(0).u32(0).u64(0).u64(0).u64(0).u64(u64::MAX).u64(0);
+        let (_, body) = call(&mut server, P9_TSETATTR, &mut setattr);
+        assert_eq!(body, (libc::EINVAL as u32).to_le_bytes());
+
+        let (kind, _) = call(&mut server, P9_TRENAMEAT, Writer::default().u32(0).string("hello.txt").u32(0).string("moved"));
+        assert_eq!(kind, P9_TRENAMEAT + 1);
+        let (kind, _) = call(&mut server, P9_TUNLINKAT, Writer::default().u32(0).string("moved").u32(0));
+        assert_eq!(kind, P9_TUNLINKAT + 1);
+        assert!(!fs::exists(format!("{}/moved", root)).unwrap());
+        // Names can't be paths
+        let (kind, _) = call(&mut server, P9_TUNLINKAT, Writer::default().u32(0).string("../x").u32(0));
+        assert_eq!(kind, P9_RLERROR);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_symlinks_stay_inside_share() {
+        let root = share("symlink");
+        let outside = share("symlink-outside");
+        fs::write(format!("{}/secret.txt", outside), b"secret").unwrap();
+        let mut server = P9::new(&root, "share").unwrap();
+        attach(&mut server);
+
+        let (kind, _) = call(&mut server, P9_TSYMLINK, Writer::default().u32(0).string("esc").string(&outside).u32(0));
+        assert_eq!(kind, P9_TSYMLINK + 1);
+        // The walk stops at the link instead of going through it
+        let (kind, body) = call(&mut server, P9_TWALK, Writer::default().u32(0).u32(1).u16(2).string("esc").string("secret.txt"));
+        assert_eq!(kind, P9_TWALK + 1);
+        assert_eq!(body[..2], [1, 0]);
+        assert_eq!(body[2], P9_QTSYMLINK);
+        let (kind, _) = call(&mut server, P9_TLOPEN, Writer::default().u32(1).u32(P9_DOTL_RDWR));
+        assert_eq!(kind, P9_RLERROR);
+        let (kind, _) = call(&mut server, P9_TWRITE, Writer::default().u32(1).u64(0).u32(4).u32(0x656e_7770));
+        assert_eq!(kind, P9_RLERROR);
+
+        // Nor can the link itself be opened, listed or changed
+        call(&mut server, P9_TWALK, Writer::default().u32(0).u32(2).u16(1).string("esc"));
+        let (_, body) = call(&mut server, P9_TLOPEN, Writer::default().u32(2).u32(P9_DOTL_RDWR));
+        assert_eq!(body, (libc::ELOOP as u32).to_le_bytes());
+        let (kind, _) = call(&mut server, P9_TREADDIR, Writer::default().u32(2).u64(0).u32(4096));
+        assert_eq!(kind, P9_RLERROR);
+        let mode = fs::metadata(&outside).unwrap().permissions().mode();
+        let mut setattr = Writer::default();
+        setattr.u32(2).u32(P9_SETATTR_MODE).u32(0o700).u32(0).u32(0).u64(0).u64(0).u64(0).u64(0).u64(0);
+        let (kind, _) = call(&mut server, P9_TSETATTR, &mut setattr);
+        assert_eq!(kind, P9_RLERROR);
+        // A new file can't be created through it either
+        let (kind, _) = call(&mut server, P9_TLCREATE, Writer::default().u32(2).string("planted").u32(P9_DOTL_RDWR | P9_DOTL_CREATE).u32(0o644).u32(0));
+        assert_eq!(kind, P9_RLERROR);
+
+        assert_eq!(fs::read(format!("{}/secret.txt", outside)).unwrap(), b"secret");
+        assert!(!fs::exists(format!("{}/planted", outside)).unwrap());
+        assert_eq!(fs::metadata(&outside).unwrap().permissions().mode(), mode);
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn test_virtqueue() {
+        let root = share("virtqueue");
+        let (mut bus, mut virtio) = setup(P9::new(&root, "share").unwrap());
+        assert_eq!(virtio.load(VIRTIO_MMIO_DEVICE_ID, 32), Ok(VIRTIO_9P_DEVICE_ID as u64));
+        assert_eq!(virtio.load(VIRTIO_MMIO_CONFIG, 16), Ok(5));
+        assert_eq!(virtio.load(VIRTIO_MMIO_CONFIG + 2, 32), Ok(0x7261_6873));
+
+        let mut request = Writer::default();
+        request.u32(0).u8(P9_TVERSION).u16(0xffff).u32(8192).string("9P2000.L");
+        let len = request.data.len() as u32;
+        request.data[..4].copy_from_slice(&len.to_le_bytes());
+        bus.write_bytes(REQUEST, &request.data).unwrap();
+        offer(&mut bus, 0, &[(REQUEST, len, false), (RESPONSE, 8192, true)]);
+        virtio.store(VIRTIO_MMIO_QUEUE_NOTIFY, 32, 0).unwrap();
+        virtio.dma(&mut bus);
+
+        assert!(virtio.interrupt_pending());
+        assert_eq!(used_chains(&mut bus, 0), vec![(0, len as u64)]);
+        assert_eq!(bus.load(RESPONSE + 4, 8), Ok(P9_TVERSION as u64 + 1));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use super::{VirtioDevice, Virtqueue};
+use super::super::bus;
+use super::super::errors;
+
+// virtio-rng, which fills every buffer the driver offers with random bytes
+pub const VIRTIO_RNG_DEVICE_ID: u32 = 4;
+// Bytes handed out per buffer. The driver asks again when it wants more
+pub const RNG_MAX_LEN: u64 = 4096;
+
+// Where the bytes come from: the host's entropy, or a generator started from a fixed seed so
+// that runs can be repeated exactly
+pub enum Entropy {
+    Host(File),
+    Seeded(u64),
+}
+
+pub struct Rng {
+    entropy: Entropy,
+}
+
+impl Rng {
+    pub fn from_host() -> io::Result<Self> {
+        return Ok(Rng { entropy: Entropy::Host(File::open("/dev/urandom")?) });
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        return Rng { entropy: Entropy::Seeded(seed) };
+    }
+
+    fn fill(&mut self, data: &mut [u8]) -> io::Result<()> {
+        match(&mut self.entropy) {
+            Entropy::Host(file) => return file.read_exact(data),
+            // splitmix64, which is good enough for a guest's entropy pool and needs no state
+            // beyond the counter
+            Entropy::Seeded(state) => {
+                for chunk in data.chunks_mut(8) {
+                    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                    let mut z = *state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                    z ^= z >> 31;
+                    chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+                }
+                return Ok(());
+            },
+        }
+    }
+}
+
+impl VirtioDevice for Rng {
+    fn device_id(&self) -> u32 {
+        return VIRTIO_RNG_DEVICE_ID;
+    }
+
+    fn features(&self) -> u64 {
+        return 0;
+    }
+
+    fn num_queues(&self) -> usize {
+        return 1;
+    }
+
+    fn read_config(&self, _offset: u64) -> u8 {
+        return 0;
+    }
+
+    fn process_queue(&mut self, _queue: usize, virtqueue: &mut Virtqueue, bus: &mut bus::Bus) -> Result<bool, errors::Exception> {
+        let mut used = false;
+        while let Some(chain) = virtqueue.pop(bus)? {
+            let mut data = vec![0; chain.writable_len().min(RNG_MAX_LEN) as usize];
+            // Nothing is returned if the host runs dry, and the driver asks again
+            if (self.fill(&mut data).is_err()) {
+                data.clear();
+            }
+            chain.write_at(bus, 0, &data)?;
+            virtqueue.push(bus, chain.head, data.len() as u32)?;
+            used = true;
+        }
+        return Ok(used);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::*;
+    use super::super::tests::*;
+    use super::super::super::bus::Device;
+
+    // Has the device fill a 20-byte buffer, and returns what it put there
+    fn entropy(rng: Rng) -> Vec<u8> {
+        let (mut bus, mut virtio) = setup(rng);
+        offer(&mut bus, 0, &[(BUFFERS, 20, true)]);
+        virtio.store(VIRTIO_MMIO_QUEUE_NOTIFY, 32, 0).unwrap();
+        virtio.dma(&mut bus);
+
+        assert_eq!(virtio.load(VIRTIO_MMIO_DEVICE_ID, 32), Ok(VIRTIO_RNG_DEVICE_ID as u64));
+        assert!(virtio.interrupt_pending());
+        return used_buffers(&mut bus, 0).remove(0);
+    }
+
+    #[test]
+    fn test_seeded() {
+        let data = entropy(Rng::with_seed(1));
+        assert_eq!(data.len(), 20);
+        // The same seed gives the same bytes, and another seed different ones
+        assert_eq!(data, entropy(Rng::with_seed(1)));
+        assert_ne!(data, entropy(Rng::with_seed(2)));
+    }
+
+    #[test]
+    fn test_host() {
+        let data = entropy(Rng::from_host().unwrap());
+        assert_eq!(data.len(), 20);
+        assert_ne!(data, vec![0; 20]);
+    }
+}
@@ -0,0 +1,174 @@
+// The crate is named after the package, Risc-V-Emulator
+#![allow(non_snake_case)]
+// The code's style: parenthesised conditions and explicit returns
+#![allow(unused_parens, clippy::needless_return)]
+
+use std::io::prelude::*;
+use std::env;
+use std::fs::File;
+use std::io;
+
+use Risc_V_Emulator::emulator;
+
+#[derive(Default)]
+struct DiskOptions {
+    read_only: bool,
+    // Writes go to memory and are lost on exit
+    snapshot: bool,
+    // Writes go to this sidecar file instead of the image
+    overlay: Option<String>,
+    // Copy the overlay's writes into the image on exit
+    commit: bool,
+}
+
+fn open_disk(path: &str, options: &DiskOptions) -> io::Result<Box<dyn emulator::disk::Disk>> {
+    let overlay: Box<dyn emulator::disk::Overlay> = if let Some(overlay) = &options.overlay {
+        Box::new(emulator::disk::FileOverlay::open(overlay, path)?)
+    } else if (options.snapshot) {
+        Box::new(emulator::disk::MemoryOverlay::new())
+    } else {
+        return Ok(Box::new(emulator::disk::FileDisk::open(path, options.read_only)?));
+    };
+    let disk = emulator::disk::CowDisk::open(path, overlay)?.commit_on_exit(options.commit);
+    return Ok(Box::new(disk));
+}
+
+fn open_net_backend(spec: &str) -> io::Result<Box<dyn emulator::virtio::net::backends::NetBackend>> {
+    use emulator::virtio::net::backends;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("unknown network backend {}", spec));
+    let (kind, argument) = spec.split_once(':').ok_or_else(invalid)?;
+    match(kind) {
+        "pcap" => return Ok(Box::new(backends::PcapBackend::create(argument)?)),
+        #[cfg(unix)]
+        "unix" => {
+            let (local, peer) = argument.split_once(',').ok_or_else(invalid)?;
+            return Ok(Box::new(backends::UnixDatagramBackend::bind(local, peer)?));
+        },
+        #[cfg(target_os = "linux")]
+        "tap" => return Ok(Box::new(backends::TapBackend::open(argument)?)),
+        _ => return Err(invalid()),
+    }
+}
+
+fn open_port_backend(spec: &str) -> io::Result<Box<dyn emulator::uart::backends::UartBackend>> {
+    use emulator::uart::backends;
+    match(spec.split_once(':').unwrap_or((spec, ""))) {
+        ("stdio", _) => return Ok(Box::new(backends::StdioBackend)),
+        #[cfg(unix)]
+        ("pty", _) => {
+            let pty = backends::PtyBackend::open()?;
+            eprintln!("Port on {}", pty.slave_path());
+            return Ok(Box::new(pty));
+        },
+        ("file", path) => return Ok(Box::new(backends::FileBackend::create(path)?)),
+        #[cfg(unix)]
+        ("unix", path) => return Ok(Box::new(backends::UnixSocketBackend::listen(path)?)),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown port backend {}", spec))),
+    }
+}
+
+// Puts a virtio device in one of the virtio-mmio slots, each with its own PLIC source
+fn attach_virtio<D: emulator::virtio::VirtioDevice + 'static>(cpu: &mut emulator::Cpu, slot: u64, device: D) -> io::Result<()> {
+    use emulator::constants::{VIRTIO_BASE, VIRTIO_IRQ, VIRTIO_SIZE};
+    let virtio = emulator::virtio::VirtioMmio::new(device);
+    return cpu.bus.attach(VIRTIO_BASE + slot * VIRTIO_SIZE, VIRTIO_SIZE, Some(VIRTIO_IRQ + slot), Box::new(virtio))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+}
+
+fn main() -> io::Result<()> {
+    println!("Running Risc-V emulator!");
+
+    // Load program from file into memory. Options for the disk image can come anywhere
+    let mut args: Vec<String> = Vec::new();
+    let mut disk_options = DiskOptions::default();
+    let mut net = None;
+    let mut ports = Vec::new();
+    let mut rng = None;
+    let mut share = None;
+    let mut options = env::args();
+    while let Some(arg) = options.next() {
+        match(arg.as_str()) {
+            "--read-only" => disk_options.read_only = true,
+            "--snapshot" => disk_options.snapshot = true,
+            "--overlay" => disk_options.overlay = options.next(),
+            "--commit" => disk_options.commit = true,
+            "--net" => net = options.next(),
+            "--port" => {
+                // Port 0 is the console, so its name can be left out
+                let port = options.next().unwrap_or_default();
+                let (name, spec) = port.split_once('=').unwrap_or(("", &port));
+                ports.push((name.to_string(), spec.to_string()));
+            },
+            "--rng" => rng = options.next(),
+            "--share" => share = options.next(),
+            _ => args.push(arg),
+        }
+    }
+
+    if (args.len() != 2 && args.len() != 3) {
+        panic!("Usage: emulator <filename> [disk image] [--read-only | --snapshot | --overlay <file>] [--commit] [--net pcap:<file> | unix:<local>,<peer> | tap:<name>] [--port [<name>=]stdio | pty | file:<file> | unix:<socket>]... [--rng host | <seed>] [--share <tag>=<directory>]");
+    }
+
+    let mut file = File::open(&args[1])?;
+    let mut code = Vec::new();
+    file.read_to_end(&mut code)?;
+
+    // Create Cpu and load the program into memory, either from an ELF executable or as a flat
+    // binary placed at the start of DRAM. The UART is attached to the terminal, in raw mode until
+    // the Cpu is dropped
+    let console = Box::new(emulator::uart::console::ConsoleBackend::new()?);
+    let time_source = emulator::clint::TimeSource::HostClock;
+    let mut cpu = if (emulator::elf::is_elf(&code)) {
+        let elf = emulator::elf::Elf::parse(&code).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut cpu = emulator::Cpu::with_devices(Vec::new(), elf.entry, time_source, console);
+        cpu.load_elf(&elf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        cpu
+    } else {
+        emulator::Cpu::with_devices(code, 0x8000_0000, time_source, console)
+    };
+
+    // An optional disk image becomes a virtio-blk device in the first virtio-mmio slot
+    if let Some(path) = args.get(2) {
+        let disk = open_disk(path, &disk_options)?;
+        let block = emulator::virtio::block::Block::new(disk, disk_options.read_only, "disk0");
+        attach_virtio(&mut cpu, 0, block)?;
+    }
+
+    // And a network card in the second
+    if let Some(net) = &net {
+        let backend = open_net_backend(net)?;
+        let card = emulator::virtio::net::Net::new(backend, emulator::virtio::net::DEFAULT_MAC);
+        attach_virtio(&mut cpu, 1, card)?;
+    }
+
+    // Then the console ports, the random number generator and the shared directory
+    if (!ports.is_empty()) {
+        let mut backends = Vec::new();
+        for (name, spec) in ports {
+            backends.push((name, open_port_backend(&spec)?));
+        }
+        attach_virtio(&mut cpu, 2, emulator::virtio::console::Console::new(backends)?)?;
+    }
+    if let Some(rng) = &rng {
+        let rng = match(rng.as_str()) {
+            "host" => emulator::virtio::rng::Rng::from_host()?,
+            seed => emulator::virtio::rng::Rng::with_seed(seed.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?),
+        };
+        attach_virtio(&mut cpu, 3, rng)?;
+    }
+    #[cfg(unix)]
+    if let Some(share) = &share {
+        let (tag, directory) = share.split_once('=').ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "expected --share <tag>=<directory>"))?;
+        attach_virtio(&mut cpu, 4, emulator::virtio::p9::P9::new(directory, tag)?)?;
+    }
+    
+    // Start instruction fetch-decode-execute loop. Press Ctrl-A x to quit, Ctrl-A c for the monitor
+    cpu.run();
+    // Dropping the devices takes the terminal out of raw mode, which has to happen before
+    // printing anything else
+    cpu.bus = emulator::bus::Bus::new();
+    cpu.dump_registers();
+
+    Ok(())
+}
+
@@ -0,0 +1,47 @@
+extern crate Risc_V_Emulator;
+
+use std::sync::{Arc, Mutex};
+
+use Risc_V_Emulator::emulator;
+use Risc_V_Emulator::emulator::bus::{AttachError, Device};
+use Risc_V_Emulator::emulator::clint::TimeSource;
+use Risc_V_Emulator::emulator::errors::Exception;
+use Risc_V_Emulator::emulator::uart::backends::MemoryBackend;
+
+// Records every word written to it
+struct Recorder {
+    writes: Arc<Mutex<Vec<(u64, u64)>>>,
+}
+
+impl Device for Recorder {
+    fn load(&mut self, offset: u64, _size: u64) -> Result<u64, Exception> {
+        Err(Exception::LoadAccessFault(offset))
+    }
+
+    fn store(&mut self, offset: u64, _size: u64, value: u64) -> Result<(), Exception> {
+        self.writes.lock().unwrap().push((offset, value));
+        Ok(())
+    }
+}
+
+#[test]
+fn test_custom_device() {
+    let program: [u32; 4] = [
+        0x200002b7, // lui t0, 0x20000
+        0x02a00313, // addi t1, zero, 42
+        0x0062a223, // sw t1, 4(t0)
+        0x00000067, // jalr zero, 0(zero)
+    ];
+    let code = program.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    let mut cpu = emulator::Cpu::with_devices(code, 0x8000_0000, TimeSource::Instructions, Box::new(MemoryBackend::new()));
+
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    cpu.bus.attach(0x2000_0000, 0x1000, None, Box::new(Recorder { writes: Arc::clone(&writes) })).unwrap();
+    // The UART already sits at 0x1000_0000
+    let overlapping = Box::new(Recorder { writes: Arc::clone(&writes) });
+    assert_eq!(cpu.bus.attach(0x0fff_f000, 0x2000, None, overlapping), Err(AttachError::Overlap(0x1000_0000)));
+
+    cpu.run();
+
+    assert_eq!(*writes.lock().unwrap(), vec![(4, 42)]);
+}
@@ -0,0 +1,30 @@
+extern crate Risc_V_Emulator;
+
+use Risc_V_Emulator::emulator;
+use Risc_V_Emulator::emulator::clint::TimeSource;
+use Risc_V_Emulator::emulator::uart::backends::MemoryBackend;
+
+// Waits for a byte on the UART, echoes it followed by '!', then leaves the image
+const ECHO_PROGRAM: [u32; 9] = [
+    0x100002b7, // lui t0, 0x10000         t0 = UART base
+    0x0052c303, // lbu t1, 5(t0)           poll LSR
+    0x00137313, // andi t1, t1, 1          until data is ready
+    0xfe030ce3, // beq t1, zero, -8
+    0x0002c383, // lbu t2, 0(t0)           read RHR
+    0x00728023, // sb t2, 0(t0)            and write it back to THR
+    0x02100e13, // addi t3, zero, '!'
+    0x01c28023, // sb t3, 0(t0)
+    0x00000067, // jalr zero, 0(zero)
+];
+
+#[test]
+fn test_uart_echo() {
+    let code = ECHO_PROGRAM.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    let backend = MemoryBackend::new();
+    let mut cpu = emulator::Cpu::with_devices(code, 0x8000_0000, TimeSource::Instructions, Box::new(backend.clone()));
+
+    backend.send(b"a");
+    cpu.run();
+
+    assert_eq!(backend.output(), b"a!");
+}